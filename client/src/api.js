@@ -2,29 +2,46 @@ export class HttpError extends Error {
   /**
    * @param {number} status
    * @param {string} statusText
+   * @param {string} [code] e.g. `notFound` or `permissionDenied`
    */
-  constructor(status, statusText) {
+  constructor(status, statusText, code) {
     super(statusText);
     this.statusText = statusText;
     this.status = status;
+    this.code = code;
   }
 }
 
 /**
  * @param {Response} response
  */
-function getResponseData(response) {
+async function toHttpError(response) {
+  const body = await response.json().catch(() => ({}));
+  return new HttpError(response.status, body.message ?? response.statusText, body.code);
+}
+
+/**
+ * @param {Response} response
+ */
+async function getResponseData(response) {
   if (response.ok) {
     return response.json();
   } else {
-    throw new HttpError(response.status, response.statusText);
+    throw await toHttpError(response);
   }
 }
 
 export default class API {
   static async analyze(path, params) {
-    const resp = await fetch(`/analyze?path=${path}&dist=${params.distance}&hashType=${params.hashType}&hashSize=${params.hashSize}`, {
+    const resp = await fetch('/analyze', {
       method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({
+        path,
+        dist: Number(params.distance),
+        hashType: params.hashType,
+        hashSize: Number(params.hashSize),
+      }),
     });
 
     return getResponseData(resp);
@@ -35,21 +52,47 @@ export default class API {
     return getResponseData(resp);
   }
 
-  static subscribe(taskId, handler) {
+  static subscribe(taskId, handler, onCompleted, onFailed) {
     const evtSource = new EventSource(`/subscribe?taskId=${taskId}`);
     evtSource.onmessage = (event) => {
       handler(JSON.parse(event.data));
     };
+    evtSource.addEventListener('completed', (event) => {
+      evtSource.close();
+      onCompleted?.(JSON.parse(event.data));
+    });
+    evtSource.addEventListener('failed', (event) => {
+      evtSource.close();
+      onFailed?.(JSON.parse(event.data).error);
+    });
     evtSource.onerror = () => {
       evtSource.close();
     };
   }
 
   static async listDir(path) {
-    const resp = await fetch(`/list_folder?path=${path}`);
+    const resp = await fetch(`/list_folder?path=${encodeURIComponent(path)}`);
     return getResponseData(resp);
   }
 
+  static async listVolumes() {
+    const resp = await fetch('/volumes');
+    return getResponseData(resp);
+  }
+
+  static async downloadFiles(paths) {
+    const resp = await fetch('/download', {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ paths }),
+    });
+
+    if (!resp.ok) {
+      throw await toHttpError(resp);
+    }
+    return resp.blob();
+  }
+
   static async deleteFile(path) {
     const resp = await fetch(`/delete_file?path=${path}`, {
       method: 'POST',
@@ -70,7 +113,7 @@ export default class API {
     });
 
     if (!resp.ok) {
-      throw new HttpError(resp.status, resp.statusText);
+      throw await toHttpError(resp);
     }
   }
 
@@ -17,7 +17,8 @@ class Utils {
   }
 
   getFileName(path) {
-    const parts = path.split('/');
+    // Windows paths use either separator
+    const parts = path.split(/[\\/]/);
     return parts[parts.length - 1];
   }
 }
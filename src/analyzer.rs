@@ -1,195 +1,2214 @@
 use eyre::Result;
-use image_hasher::{Hasher, ImageHash, HasherConfig, HashAlg};
-use rayon::prelude::*;
-use std::fs::{self, DirEntry};
+use image::{DynamicImage, ImageError, ImageFormat, ImageResult};
+use image::codecs::jpeg::JpegDecoder;
+use image_hasher::ImageHash;
+use rayon::{prelude::*, ThreadPoolBuilder};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, DirEntry, File};
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::mem;
+use std::hash::Hasher as _;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::watch;
+use utoipa::{IntoParams, ToSchema};
 
-use crate::cache::Cache;
+use crate::archive::Archives;
+use crate::cache::{Cache, CacheStats};
 use crate::disjoint_set;
+use crate::downscale;
+use crate::error::PathError;
+use crate::hasher::{self, HasherName, ImageHasher};
+use crate::manager::{CancelToken, Priority};
+use crate::marks::GroupMarks;
+use crate::memo::DecodeMemo;
+use crate::metrics::metrics;
+use crate::index::SearchIndex;
+use crate::report::{self, ClassSavings, DuplicateStats};
+use crate::resolve::{KeepRules, Suggestion};
+use crate::roots::{Roots, StorageClass};
+use crate::sandbox::{Denied, Sandbox};
+use crate::storage::{self, Local, Remotes, Storage};
+use crate::ignore::IgnoreList;
+use crate::throttle::{ConcurrencyAdjustment, MemoryBudget, Throttle};
+use crate::timings::{Step, StepClock, Timings};
+use crate::workers::{self, Batch, HashedFile, Workers};
+use uuid::Uuid;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct FileInfo {
-    path: PathBuf,
-    size: u64,
-    date: u64,
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    pub size: u64,
+    pub date: u64,
+    /// mtime in ms, tells edited files apart from their cached hashes
+    pub modified: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_class: Option<StorageClass>,
+    /// perceptual hash in base64, set for the files of groups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// SHA-256 of the content, when it was read to compare the file with others
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
 }
 
 impl FileInfo {
     pub fn from_entry(entry: DirEntry) -> Result<Self> {
-        let metadata = entry.metadata()?;
+        Self::from_metadata(entry.path(), &entry.metadata()?)
+    }
+
+    /// follows symlinks, unlike listings
+    pub fn from_path(path: PathBuf) -> Result<Self> {
+        let metadata = fs::metadata(&path)?;
+        Self::from_metadata(path, &metadata)
+    }
+
+    fn from_metadata(path: PathBuf, metadata: &fs::Metadata) -> Result<Self> {
         let size = metadata.len();
         let ctime = metadata.created()?;
         let ctime = ctime.duration_since(SystemTime::UNIX_EPOCH)?;
+        let mtime = metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH)?;
         Ok(Self {
-            path: entry.path(),
+            path,
             size,
             date: ctime.as_millis() as u64,
+            modified: mtime.as_millis() as u64,
+            storage_class: None,
+            hash: None,
+            checksum: None,
         })
     }
+
+    pub(crate) fn stamp(&self) -> FileStamp {
+        FileStamp { size: self.size, modified: self.modified }
+    }
+}
+
+/// size and mtime of a file at the time it was hashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileStamp {
+    size: u64,
+    modified: u64,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH)?;
+        Ok(Self { size: metadata.len(), modified: mtime.as_millis() as u64 })
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, ToSchema)]
+pub enum SkipReason {
+    Hidden,
+    Unsupported,
+    /// a symlink leading out of the libraries
+    OutsideLibraries,
+    /// on the ignore list
+    Ignored,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ToSchema)]
+pub struct SkippedFile {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    reason: SkipReason,
+}
+
+impl SkippedFile {
+    pub(crate) fn new(path: PathBuf, reason: SkipReason) -> Self {
+        Self { path, reason }
+    }
+
+    pub fn reason(&self) -> SkipReason {
+        self.reason
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Tells images apart by their content rather than extension,
+/// so misnamed and extension-less files are picked up too.
+pub fn sniff_format(path: &Path) -> io::Result<Option<ImageFormat>> {
+    let mut header = [0; 32];
+    let mut file = File::open(path)?;
+    let mut len = 0;
+    while len < header.len() {
+        match file.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    Ok(image::guess_format(&header[..len]).ok())
+}
+
+/// a file that couldn't be read, doesn't fail the whole analysis
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ToSchema)]
+pub struct FileError {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    error: String,
+}
+
+impl FileError {
+    pub(crate) fn new(path: PathBuf, error: impl std::fmt::Display) -> Self {
+        Self { path, error: error.to_string() }
+    }
+}
+
+/// images found in a folder, together with files that were left out
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub files: Vec<FileInfo>,
+    /// the folders below, their size is 0
+    pub dirs: Vec<FileInfo>,
+    pub skipped: Vec<SkippedFile>,
+    pub errors: Vec<FileError>,
+    /// images by format, e.g. `jpeg`
+    pub formats: BTreeMap<String, usize>,
+    /// identify the content of remote files without reading it, e.g. ETags,
+    /// their hashes are cached by them
+    pub tags: HashMap<PathBuf, String>,
 }
 
-fn list_dir_rec(files: &mut Vec<FileInfo>, dir: &Path) -> Result<()> {
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            if list_dir_rec(files, &path).is_err() {
+impl Listing {
+    /// by path, listings come in the order of the storage
+    pub(crate) fn sort(&mut self) {
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+        self.dirs.sort_by(|a, b| a.path.cmp(&b.path));
+        self.skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        self.errors.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    pub(crate) fn merge(mut self, other: Self) -> Self {
+        self.files.extend(other.files);
+        self.dirs.extend(other.dirs);
+        self.skipped.extend(other.skipped);
+        self.errors.extend(other.errors);
+        for (format, count) in other.formats {
+            *self.formats.entry(format).or_default() += count;
+        }
+        self.tags.extend(other.tags);
+        self
+    }
+}
+
+/// Entries are looked at and folders walked on the rayon pool, stat-ing one entry after another
+/// is what takes scans of large libraries on network shares and spinning disks so long.
+fn list_dir_rec(sandbox: &Sandbox, dir: &Path) -> Result<Listing> {
+    let mut listing = Listing::default();
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|err| PathError::new(dir, err))? {
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(err) => listing.errors.push(FileError::new(dir.to_owned(), err)),
+        }
+    }
+
+    let found = entries
+        .into_par_iter()
+        .map(|entry| list_entry(sandbox, entry))
+        .reduce(Listing::default, Listing::merge);
+    Ok(listing.merge(found))
+}
+
+fn list_entry(sandbox: &Sandbox, entry: DirEntry) -> Listing {
+    let mut listing = Listing::default();
+    let path = entry.path();
+    // the folder itself was checked, only symlinks can lead out of it
+    let symlink = entry.file_type().is_ok_and(|file_type| file_type.is_symlink());
+    if is_hidden(&path) {
+        listing.skipped.push(SkippedFile { path, reason: SkipReason::Hidden });
+    } else if symlink && sandbox.check(&path).is_err() {
+        listing.skipped.push(SkippedFile { path, reason: SkipReason::OutsideLibraries });
+    } else if path.is_dir() {
+        match FileInfo::from_entry(entry) {
+            Ok(info) => listing.dirs.push(FileInfo { size: 0, ..info }),
+            Err(err) => listing.errors.push(FileError::new(path.clone(), err)),
+        }
+        match list_dir_rec(sandbox, &path) {
+            Ok(below) => listing = listing.merge(below),
+            Err(err) => {
                 tracing::error!("error reading folder content {:?}", path);
+                listing.errors.push(FileError::new(path, err));
             }
-        } else if let Some(ext) = path.extension() {
-            if ext.eq_ignore_ascii_case("jpg")
-                || ext.eq_ignore_ascii_case("jpeg")
-                || ext.eq_ignore_ascii_case("png")
-            {
-                let info = FileInfo::from_entry(entry)?;
-                files.push(info);
-            }
+        }
+    } else {
+        match sniff_format(&path) {
+            Ok(Some(format)) => match FileInfo::from_entry(entry) {
+                Ok(info) => {
+                    listing.files.push(info);
+                    *listing.formats.entry(format!("{:?}", format).to_lowercase()).or_default() += 1;
+                }
+                Err(err) => listing.errors.push(FileError::new(path, err)),
+            },
+            Ok(None) => listing.skipped.push(SkippedFile { path, reason: SkipReason::Unsupported }),
+            Err(err) => listing.errors.push(FileError::new(path, err)),
         }
     }
+    listing
+}
 
-    Ok(())
+pub fn scan_dir(dir: &Path, sandbox: &Sandbox) -> Result<Listing> {
+    let mut listing = list_dir_rec(sandbox, dir)?;
+    // directory order depends on the file system, and on the threads walking it
+    listing.sort();
+    Ok(listing)
 }
 
-pub fn list_dir(dir: &Path) -> Result<Vec<FileInfo>> {
-    let mut files = Vec::new();
-    list_dir_rec(&mut files, dir)?;
-    Ok(files)
+fn list_file(sandbox: &Sandbox, path: PathBuf) -> Listing {
+    let mut listing = Listing::default();
+    match sandbox.check(&path) {
+        Err(Denied::Outside) => listing.skipped.push(SkippedFile { path, reason: SkipReason::OutsideLibraries }),
+        Err(Denied::NotFound) => listing.errors.push(FileError::new(path, "no such file")),
+        Ok(()) if path.is_dir() => listing.skipped.push(SkippedFile { path, reason: SkipReason::Unsupported }),
+        Ok(()) => match sniff_format(&path) {
+            Ok(Some(format)) => match FileInfo::from_path(path.clone()) {
+                Ok(info) => {
+                    listing.files.push(info);
+                    *listing.formats.entry(format!("{:?}", format).to_lowercase()).or_default() += 1;
+                }
+                Err(err) => listing.errors.push(FileError::new(path, err)),
+            },
+            Ok(None) => listing.skipped.push(SkippedFile { path, reason: SkipReason::Unsupported }),
+            Err(err) => listing.errors.push(FileError::new(path, err)),
+        },
+    }
+    listing
 }
 
-type Hashes = Vec<(FileInfo, ImageHash)>;
+/// the images of a list of files, as a scan finds them, without walking any folder
+pub fn list_files(paths: &[PathBuf], sandbox: &Sandbox) -> Listing {
+    let mut paths = paths.to_vec();
+    paths.sort();
+    paths.dedup();
+    let mut listing = paths.into_par_iter().map(|path| list_file(sandbox, path)).reduce(Listing::default, Listing::merge);
+    listing.sort();
+    listing
+}
+
+
+pub(crate) type Hashes = Vec<(FileInfo, ImageHash)>;
 
 pub type Groups = Vec<Vec<FileInfo>>;
 
-fn create_groups(hashes: &Hashes, max_dist: u32) -> Groups {
+/// group of duplicates as reported
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    /// stable across runs as long as the group has the same files
+    fingerprint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    review_url: Option<String>,
+    files: Vec<FileInfo>,
+    /// zeros in results of older versions
+    #[serde(default)]
+    stats: GroupStats,
+    /// hash distance of each file to the first one, empty in results of older versions
+    #[serde(default)]
+    distances: Vec<u32>,
+    /// whether the files all have the same content, `None` for remote storage and in results of older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    likeness: Option<Likeness>,
+    /// indices of the files with the same content, a set for each content several of them have
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    identical: Vec<Vec<usize>>,
+    /// hash distances between every two files, in the order of `files`, only for groups of up to `MATRIX_FILES`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    matrix: Vec<Vec<u32>>,
+    /// the closest other file of each file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    nearest: Vec<Neighbor>,
+    /// the matches which put the files together, when the request asked for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    edges: Vec<Edge>,
+    /// index of the file closest to all the others, to show for the whole group, the first one without hashes
+    #[serde(default)]
+    representative: usize,
+    /// the copy the keep rules of the server would keep
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    suggestion: Option<Suggestion>,
+    /// review decisions, merged in when results are served
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    marks: Option<GroupMarks>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupStats {
+    pub files: usize,
+    pub total_bytes: u64,
+    /// freed by keeping only the largest copy
+    pub reclaimable_bytes: u64,
+}
+
+impl GroupStats {
+    fn of(files: &[FileInfo]) -> Self {
+        let total_bytes = files.iter().map(|file| file.size).sum();
+        let largest = files.iter().map(|file| file.size).max().unwrap_or(0);
+        Self { files: files.len(), total_bytes, reclaimable_bytes: total_bytes - largest }
+    }
+}
+
+/// another file of the group, by its index in `files`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+pub struct Neighbor {
+    pub index: usize,
+    pub distance: u32,
+}
+
+/// two files of a group which matched, by their indices in `files`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    /// of their hashes
+    pub distance: u32,
+    /// matched by the text they show rather than by their hashes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub text: bool,
+    /// of the same content
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exact: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Likeness {
+    /// every file has the same content, copies of one file
+    Exact,
+    /// some files only look alike, e.g. resized or recompressed
+    Near,
+}
+
+/// groups larger than that get no matrix, it grows with the square of their files
+pub const MATRIX_FILES: usize = 64;
+
+impl Group {
+    /// `hashes` are those of the files, in the same order, or empty when there are none
+    fn new(mut files: Vec<FileInfo>, hashes: &[&ImageHash], roots: &Roots, rules: &KeepRules) -> Self {
+        for (file, hash) in files.iter_mut().zip(hashes) {
+            file.hash = Some(hash.to_base64());
+        }
+        let mut paths: Vec<_> = files.iter().map(|file| file.path.to_string_lossy()).collect();
+        paths.sort();
+        let mut fingerprint = sha256::digest(paths.join("\n"));
+        fingerprint.truncate(16);
+        let review_url = files.iter().find_map(|file| roots.review_url(&file.path, &fingerprint));
+
+        let distances = hashes.iter().map(|hash| hashes[0].dist(hash)).collect();
+        let nearest = (0..hashes.len())
+            .filter_map(|i| {
+                (0..hashes.len())
+                    .filter(|&j| j != i)
+                    .map(|j| Neighbor { index: j, distance: hashes[i].dist(hashes[j]) })
+                    .min_by_key(|neighbor| (neighbor.distance, neighbor.index))
+            })
+            .collect();
+        // the medoid, the first one on ties
+        let representative = (0..hashes.len())
+            .min_by_key(|&i| (hashes.iter().map(|hash| hashes[i].dist(hash) as u64).sum::<u64>(), i))
+            .unwrap_or(0);
+        let matrix = match hashes.len() <= MATRIX_FILES {
+            true => hashes.iter().map(|a| hashes.iter().map(|b| a.dist(b)).collect()).collect(),
+            false => Vec::new(),
+        };
+        let stats = GroupStats::of(&files);
+        let suggestion = rules.suggest(&files);
+        Self {
+            fingerprint,
+            review_url,
+            files,
+            stats,
+            distances,
+            likeness: None,
+            identical: Vec::new(),
+            matrix,
+            nearest,
+            edges: Vec::new(),
+            representative,
+            suggestion,
+            marks: None,
+        }
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    pub fn distances(&self) -> &[u32] {
+        &self.distances
+    }
+
+    pub fn matrix(&self) -> &[Vec<u32>] {
+        &self.matrix
+    }
+
+    pub fn nearest(&self) -> &[Neighbor] {
+        &self.nearest
+    }
+
+    pub fn likeness(&self) -> Option<Likeness> {
+        self.likeness
+    }
+
+    pub fn identical(&self) -> &[Vec<usize>] {
+        &self.identical
+    }
+
+    /// Compares the checksums of the files, only those of the same size as another one are read.
+    /// By pixels, those of the same hash as another one, or all of them without hashes.
+    fn find_identical(&mut self, by: ExactBy, checksum: impl Fn(&FileInfo) -> Result<String>) {
+        let mut candidates: HashMap<(Option<u64>, Option<&str>), Vec<usize>> = HashMap::new();
+        for (i, file) in self.files.iter().enumerate() {
+            let key = match by {
+                ExactBy::Bytes => (Some(file.size), None),
+                ExactBy::Pixels => (None, file.hash.as_deref()),
+            };
+            candidates.entry(key).or_default().push(i);
+        }
+        let candidates: Vec<usize> = candidates.into_values().filter(|same| same.len() > 1).flatten().collect();
+        let mut by_checksum: HashMap<String, Vec<usize>> = HashMap::new();
+        for i in candidates {
+            match checksum(&self.files[i]) {
+                Ok(checksum) => {
+                    // of the content, which the pixel checksum isn't
+                    if by == ExactBy::Bytes {
+                        self.files[i].checksum = Some(checksum.clone());
+                    }
+                    by_checksum.entry(checksum).or_default().push(i);
+                }
+                // only looks alike then
+                Err(err) => tracing::debug!(path = self.files[i].path.to_str(), "unable to checksum: {:?}", err),
+            }
+        }
+        let mut identical: Vec<Vec<usize>> = by_checksum.into_values().filter(|same| same.len() > 1).collect();
+        for same in &mut identical {
+            same.sort();
+        }
+        identical.sort();
+        let exact = identical.len() == 1 && identical[0].len() == self.files.len();
+        self.likeness = Some(if exact { Likeness::Exact } else { Likeness::Near });
+        self.identical = identical;
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    pub fn representative(&self) -> usize {
+        self.representative
+    }
+
+    pub fn suggestion(&self) -> Option<&Suggestion> {
+        self.suggestion.as_ref()
+    }
+
+    pub(crate) fn set_marks(&mut self, marks: Option<GroupMarks>) {
+        self.marks = marks;
+    }
+
+    /// of the files, whatever the version of the result
+    pub fn stats(&self) -> GroupStats {
+        GroupStats::of(&self.files)
+    }
+
+    /// freed by keeping only the largest copy, as in the reclaimable space
+    pub fn wasted_bytes(&self) -> u64 {
+        self.stats().reclaimable_bytes
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ToSchema)]
+pub struct AnalyzeResult {
+    groups: Vec<Group>,
+    skipped: Vec<SkippedFile>,
+    corrupted: Vec<CorruptedFile>,
+    errors: Vec<FileError>,
+    reclaimable: Vec<ClassSavings>,
+    stats: DuplicateStats,
+    coverage: Coverage,
+    /// how the number of concurrent reads changed during hashing
+    concurrency: Vec<ConcurrencyAdjustment>,
+    /// how long each phase took
+    #[serde(default)]
+    timings: Timings,
+    /// of every hashed file, taken out to be stored apart from the result
+    #[serde(skip)]
+    hashes: Hashes,
+}
+
+impl AnalyzeResult {
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    pub(crate) fn groups_mut(&mut self) -> &mut [Group] {
+        &mut self.groups
+    }
+
+    pub fn reclaimable(&self) -> &[ClassSavings] {
+        &self.reclaimable
+    }
+
+    /// empty once taken, and for results which weren't hashed here
+    pub(crate) fn take_hashes(&mut self) -> Hashes {
+        mem::take(&mut self.hashes)
+    }
+}
+
+/// how much of the library is included in the groups,
+/// less than everything when the time limit was hit
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ToSchema)]
+pub struct Coverage {
+    hashed: usize,
+    deferred: usize,
+    total: usize,
+    /// of the hashed, those taken over from the latest run by an incremental analysis
+    #[serde(default)]
+    reused: usize,
+    /// of the hashed, those the coarse pass put in a bucket and hashed the requested way too, `None` without a coarse pass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refined: Option<usize>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ToSchema)]
+pub struct CorruptedFile {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    error: String,
+}
+
+enum HashOutcome {
+    Hashed(FileInfo, ImageHash),
+    Corrupted(CorruptedFile),
+    Unreadable(FileInfo, FileError),
+    Deferred,
+}
+
+impl HashOutcome {
+    fn unreadable(file: FileInfo, err: impl std::fmt::Display) -> Self {
+        let error = FileError::new(file.path.clone(), err);
+        Self::Unreadable(file, error)
+    }
+}
+
+/// wait before the first retry of unreadable files, doubled on each retry up to `MAX_RETRY_BACKOFF`
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+/// retries of unreadable files whatever a request asks for, a file failing for good would hold up the task
+const MAX_RETRIES: u32 = 5;
+
+/// concurrent reads the hashing starts with
+const MIN_WORKERS: usize = 2;
+
+/// Longest side of decoded images. Hashes are computed on much smaller grids,
+/// so there is no point in keeping full resolution around.
+pub(crate) const DECODE_SIZE: u32 = 512;
+
+/// decoded bytes per file byte assumed when the dimensions aren't known without reading the image,
+/// compressed photos are commonly a tenth of their pixels or less
+const UNKNOWN_DECODE_RATIO: u64 = 10;
+
+/// decodes the image downscaled to fit into `size` x `size`, JPEGs, PNGs and TIFFs are scaled down
+/// while decoding, the others are decoded at full size first
+pub fn open_image(path: &Path, size: u32) -> ImageResult<(DynamicImage, Option<ImageFormat>)> {
+    decode_image(image::io::Reader::open(path)?.with_guessed_format()?, size)
+}
+
+pub(crate) fn decode_image<R: BufRead + Seek>(reader: image::io::Reader<R>, size: u32) -> ImageResult<(DynamicImage, Option<ImageFormat>)> {
+    let format = reader.format();
+    let image = if format == Some(ImageFormat::Jpeg) {
+        // JPEG decoder is able to scale DCT blocks down while decoding,
+        // huge photos never get fully loaded into memory
+        let mut decoder = JpegDecoder::new(reader.into_inner())?;
+        let scale = size.min(u16::MAX as u32) as u16;
+        decoder.scale(scale, scale)?;
+        DynamicImage::from_decoder(decoder)?
+    } else if let Some(format @ (ImageFormat::Png | ImageFormat::Tiff)) = format {
+        let mut inner = reader.into_inner();
+        let start = inner.stream_position()?;
+        match downscale::decode(&mut inner, format, size)? {
+            Some(image) => image,
+            None => {
+                inner.seek(SeekFrom::Start(start))?;
+                image::io::Reader::with_format(inner, format).decode()?
+            }
+        }
+    } else {
+        reader.decode()?
+    };
+
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    Ok((image, format))
+}
+
+/// Bytes the pixels take while decoding into `size` x `size`, with 4 bytes per pixel,
+/// from the dimensions in the header. JPEGs are scaled down while decoding, by up to 8,
+/// PNGs and TIFFs by any factor, `format` is `None` for those `downscale` doesn't read.
+pub(crate) fn decoded_size(format: Option<ImageFormat>, (width, height): (u32, u32), size: u32) -> u64 {
+    if matches!(format, Some(ImageFormat::Png | ImageFormat::Tiff)) {
+        let factor = downscale::factor((width, height), size);
+        return width.div_ceil(factor) as u64 * height.div_ceil(factor) as u64 * 4;
+    }
+    let mut scale = 1;
+    while format == Some(ImageFormat::Jpeg) && scale < 8 && width / (scale * 2) >= size && height / (scale * 2) >= size {
+        scale *= 2;
+    }
+    (width / scale).max(1) as u64 * (height / scale).max(1) as u64 * 4
+}
+
+/// Decoders fill in missing trailing data, so a successfully decoded image
+/// may still be cut short: follow the structure of the file up to its end marker.
+pub(crate) fn is_truncated(path: &Path, format: Option<ImageFormat>) -> io::Result<bool> {
+    if !matches!(format, Some(ImageFormat::Jpeg | ImageFormat::Png)) {
+        return Ok(false);
+    }
+    Ok(is_cut_short(&fs::read(path)?, format))
+}
+
+/// whether `data` ends before the end marker, as in `is_truncated`. Phones and editors append data
+/// after it, and EXIF previews have markers of their own, so the marker is looked for where it belongs.
+pub(crate) fn is_cut_short(data: &[u8], format: Option<ImageFormat>) -> bool {
+    match format {
+        Some(ImageFormat::Jpeg) => !jpeg_ends(data),
+        Some(ImageFormat::Png) => !png_ends(data),
+        _ => false,
+    }
+}
+
+/// whether the segments of a JPEG, and the scans between them, lead to its end marker
+fn jpeg_ends(data: &[u8]) -> bool {
+    // after the start marker
+    let mut i = 2;
+    while i + 1 < data.len() {
+        match (data[i], data[i + 1]) {
+            (0xFF, 0xD9) => return true,
+            // fill bytes before a marker
+            (0xFF, 0xFF) => i += 1,
+            // markers without a segment
+            (0xFF, 0x01 | 0xD0..=0xD7) => i += 2,
+            (0xFF, marker) => {
+                let Some(len) = data.get(i + 2..i + 4) else {
+                    return false;
+                };
+                i += 2 + u16::from_be_bytes([len[0], len[1]]) as usize;
+                if marker == 0xDA {
+                    // the scan runs up to the next marker, other than a stuffed zero or a restart
+                    while i + 1 < data.len() && (data[i] != 0xFF || matches!(data[i + 1], 0x00 | 0xD0..=0xD7)) {
+                        i += 1;
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    false
+}
+
+/// whether the chunks of a PNG lead to its `IEND` one
+fn png_ends(data: &[u8]) -> bool {
+    // after the signature
+    let mut i = 8;
+    while let Some(header) = data.get(i..i + 8) {
+        if &header[4..] == b"IEND" {
+            return true;
+        }
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        // the header, the data and the CRC
+        i = i.saturating_add(12).saturating_add(len);
+    }
+    false
+}
+
+/// where the files of an analysis are read from, with the content tags of its listing
+struct Source<'a> {
+    storage: &'a dyn Storage,
+    tags: HashMap<PathBuf, String>,
+    /// of hashing the files, in both passes
+    clock: StepClock,
+    /// decoded images, for both passes and the pixel checksums
+    memo: DecodeMemo<'a>,
+}
+
+impl Source<'_> {
+    /// Tagged files are addressed by their tag whatever the cache mode, it changes
+    /// with the content and needs no extra read.
+    fn cache_key(&self, req: &AnalyzeRequest, file: &FileInfo) -> Option<CacheKey> {
+        let tag = self.tags.get(&file.path)?;
+        Some(CacheKey::content(req.hash_type, req.hash_size, tag.clone()))
+    }
+}
+
+/// Shards narrower than this match almost everything,
+/// so it gets cheaper to compare all files with each other.
+const MIN_SEGMENT_BITS: usize = 8;
+
+/// Splits hash bits into `max_dist + 1` segments. Two hashes within `max_dist`
+/// from each other are equal in at least one segment (pigeonhole principle),
+/// so it is enough to compare files sharing a segment value.
+fn hash_segments(hashes: &Hashes, max_dist: u32) -> Vec<Range<usize>> {
+    let bits = hashes.first().map_or(0, |(_, h)| h.as_bytes().len() * 8);
+    let uniform = hashes.iter().all(|(_, h)| h.as_bytes().len() * 8 == bits);
+    let count = max_dist as usize + 1;
+
+    if !uniform || bits / count < MIN_SEGMENT_BITS {
+        // an empty segment puts all files into a single shard
+        return std::iter::once(0..0).collect();
+    }
+
+    (0..count)
+        .map(|i| i * bits / count..(i + 1) * bits / count)
+        .collect()
+}
+
+fn segment_key(hash: &ImageHash, bits: &Range<usize>) -> u64 {
+    let bytes = hash.as_bytes();
+    let mut hasher = DefaultHasher::new();
+    for bit in bits.clone() {
+        hasher.write_u8(bytes[bit / 8] >> (bit % 8) & 1);
+    }
+    // collisions only add extra comparisons, not extra matches
+    hasher.finish()
+}
+
+fn match_shard(hashes: &Hashes, keys: &[Vec<u64>], segment: usize, shard: &[usize], max_dist: u32) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+
+    for (n, &i) in shard.iter().enumerate() {
+        for &j in &shard[n + 1..] {
+            // the pair has been compared in a shard of an earlier segment
+            let seen = keys[i][..segment]
+                .iter()
+                .zip(&keys[j][..segment])
+                .any(|(a, b)| a == b);
+
+            if !seen && hashes[i].1.dist(&hashes[j].1) <= max_dist {
+                pairs.push((i, j));
+            }
+        }
+    }
+
+    pairs
+}
+
+/// whether joining the sets of `i` and `j` would group files kept apart, directly or through the other files of the sets
+fn keeps_apart(ds: &mut disjoint_set::DisjointSet<usize>, partners: &HashMap<usize, Vec<usize>>, i: usize, j: usize) -> bool {
+    if partners.is_empty() {
+        return false;
+    }
+    let (Some(a), Some(b)) = (ds.find(&i), ds.find(&j)) else {
+        return false;
+    };
+    if a == b {
+        return false;
+    }
+    // the members of the smaller set are looked up in the other
+    let (small, other) = match ds.set_len(&i) < ds.set_len(&j) {
+        true => (i, b),
+        false => (j, a),
+    };
+    let kept: Vec<usize> = ds.members(&small).into_iter().flatten().filter(|m| partners.contains_key(m)).copied().collect();
+    kept.iter().flat_map(|m| &partners[m]).any(|p| ds.find(p) == Some(other))
+}
+
+/// `extra` pairs of indices are grouped too, whatever their hashes, those `apart` (the lower index first) aren't matched,
+/// not even through other files matching both,
+/// nor those of different `buckets` when there is one for each file, the pairs found within `max_dist` go to `matched` when given,
+/// the shards matched to `progress`
+fn create_groups(
+    hashes: &Hashes,
+    max_dist: u32,
+    extra: &[(usize, usize)],
+    apart: &HashSet<(usize, usize)>,
+    buckets: &[usize],
+    mut matched: Option<&mut Vec<(usize, usize)>>,
+    progress: Option<&watch::Sender<Progress>>,
+) -> Groups {
+    let segments = hash_segments(hashes, max_dist);
+    let keys: Vec<Vec<u64>> = hashes
+        .par_iter()
+        .map(|(_, hash)| segments.iter().map(|bits| segment_key(hash, bits)).collect())
+        .collect();
+
     let mut ds = disjoint_set::DisjointSet::new();
 
-    for (k, _) in hashes {
-        ds.insert(k.clone());
+    for i in 0..hashes.len() {
+        ds.insert(i);
     }
+    let mut partners: HashMap<usize, Vec<usize>> = HashMap::new();
+    for &(i, j) in apart {
+        partners.entry(i).or_default().push(j);
+        partners.entry(j).or_default().push(i);
+    }
+
+    // shards of one segment at a time are kept in memory
+    for segment in 0..segments.len() {
+        let mut shards: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+        for (i, k) in keys.iter().enumerate() {
+            shards.entry((buckets.get(i).copied().unwrap_or_default(), k[segment])).or_default().push(i);
+        }
+
+        let shards: Vec<Vec<usize>> = shards
+            .into_values()
+            .filter(|shard| shard.len() > 1)
+            .collect();
 
-    for (k1, h1) in hashes {
-        for (k2, h2) in hashes {
-            if k1.path != k2.path && h1.dist(h2) <= max_dist {
-                ds.union(k1, k2);
+        let total = shards.len();
+        let done = AtomicUsize::new(0);
+        if let Some(progress) = progress {
+            progress.send_modify(|progress| {
+                progress.segment = segment + 1;
+                progress.segments = segments.len();
+                progress.shards = 0;
+                progress.total_shards = total;
+            });
+        }
+        let matches: Vec<Vec<(usize, usize)>> = shards
+            .par_iter()
+            .enumerate()
+            .map(|(shard_num, shard)| {
+                let pairs = match_shard(hashes, &keys, segment, shard, max_dist);
+                tracing::debug!(segment, shard = shard_num, total, files = shard.len(), matches = pairs.len(), "shard matched");
+                let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                if let Some(progress) = progress {
+                    // every segment counts the same, however many shards it has
+                    let percent = (segment * total + done) * 100 / (segments.len() * total);
+                    progress.send_if_modified(|progress| {
+                        let newer = percent > progress.percent || done > progress.shards;
+                        progress.percent = progress.percent.max(percent);
+                        progress.shards = progress.shards.max(done);
+                        newer
+                    });
+                }
+                pairs
+            })
+            .collect();
+
+        // in order, so which of the pairs bridging files kept apart is refused doesn't depend on the shards
+        let mut matches: Vec<(usize, usize)> = matches.into_iter().flatten().collect();
+        matches.sort_unstable();
+        for (i, j) in matches {
+            if keeps_apart(&mut ds, &partners, i, j) {
+                continue;
+            }
+            ds.union(&i, &j);
+            if let Some(matched) = matched.as_deref_mut() {
+                matched.push((i, j));
             }
         }
+
+        if let Some(progress) = progress {
+            let percent = (segment + 1) * 100 / segments.len();
+            progress.send_modify(|progress| progress.percent = progress.percent.max(percent));
+        }
+        tracing::info!(segment, segments = segments.len(), shards = total, "segment matched");
+    }
+
+    for (i, j) in extra {
+        if !keeps_apart(&mut ds, &partners, *i, *j) {
+            ds.union(i, j);
+        }
     }
 
-    ds
+    // members sorted by path and groups by their first member,
+    // so the output doesn't depend on the order files were hashed in
+    let mut groups: Groups = ds
         .into_vec()
         .into_iter()
         .filter(|v| v.len() > 1)
-        .collect()
+        .map(|v| {
+            let mut group: Vec<FileInfo> = v.into_iter().map(|i| hashes[i].0.clone()).collect();
+            group.sort_by(|a, b| a.path.cmp(&b.path));
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+    groups
 }
 
-#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, serde::Deserialize)]
+/// by name, `DHash`, `AHash`, `PHash` or one of a registered hasher
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "&'static str")]
+#[allow(clippy::enum_variant_names)]
 pub enum HashType {
     AHash,
     PHash,
+    #[default]
     DHash,
+    Custom(HasherName),
+}
+
+impl HashType {
+    pub(crate) fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "AHash" => Some(Self::AHash),
+            "PHash" => Some(Self::PHash),
+            "DHash" => Some(Self::DHash),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::AHash => "AHash",
+            Self::PHash => "PHash",
+            Self::DHash => "DHash",
+            Self::Custom(name) => name.as_str(),
+        }
+    }
+}
+
+impl TryFrom<String> for HashType {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Self::builtin(&name).or_else(|| HasherName::lookup(&name).map(Self::Custom)).ok_or_else(|| format!("unknown hash type {}", name))
+    }
+}
+
+impl From<HashType> for &'static str {
+    fn from(hash_type: HashType) -> Self {
+        hash_type.name()
+    }
+}
+
+impl utoipa::PartialSchema for HashType {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::schema::Schema> {
+        utoipa::openapi::ObjectBuilder::new()
+            .schema_type(utoipa::openapi::schema::Type::String)
+            .examples(["DHash", "AHash", "PHash"])
+            .description(Some("DHash, AHash, PHash or the name of a registered hasher"))
+            .into()
+    }
 }
 
-#[derive(Debug, serde::Deserialize)]
+impl ToSchema for HashType {}
+
+/// side of the hash grid, a hash has `size * size` bits
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(try_from = "u32")]
+pub struct HashSize(u32);
+
+impl HashSize {
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for HashSize {
+    fn default() -> Self {
+        Self(8)
+    }
+}
+
+impl TryFrom<u32> for HashSize {
+    type Error = String;
+
+    fn try_from(size: u32) -> Result<Self, Self::Error> {
+        match size {
+            8 | 16 | 32 => Ok(Self(size)),
+            _ => Err(format!("unsupported hash size {}, expected 8, 16 or 32", size)),
+        }
+    }
+}
+
+/// The first pass of a two-phase analysis: every file is hashed this way and grouped within `dist`, these groups
+/// are the buckets. Only the files of a bucket are hashed the way of the request, and matched with those of their bucket,
+/// which keeps large hashes tractable on large libraries as most files have no duplicate.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CoarsePass {
+    #[serde(default)]
+    pub hash_type: HashType,
+    #[serde(default)]
+    pub hash_size: HashSize,
+    /// looser than that of the request, as files matching the fine way may be further apart the coarse way
+    pub dist: u32,
+    /// from the previews cameras embed, as `fast` analyses
+    #[serde(default)]
+    pub fast: bool,
+}
+
+/// the body of `/analyze`, older clients send it in the query string
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct AnalyzeRequest {
     pub dist: u32,
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    pub hash_type: HashType,
+    #[serde(default)]
+    pub hash_size: HashSize,
+    /// stop hashing new files after that many minutes, hashes computed
+    /// so far are kept in the cache and picked up by the next run
+    pub max_minutes: Option<u64>,
+    /// attempts at files failing with I/O errors, e.g. on flaky network shares,
+    /// before they are reported, 5 at most
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default)]
+    pub cache_mode: CacheMode,
+    /// cancel the scan after running that many minutes, unlike `max_minutes`
+    /// it fails as timed out, e.g. when stuck on an unresponsive network mount
+    pub timeout_minutes: Option<u64>,
+    /// queued scans of a higher priority start first
+    #[serde(default)]
+    pub priority: Priority,
+    /// also group screenshots showing the same text
+    #[cfg(feature = "ocr")]
+    #[serde(default)]
+    pub ocr: bool,
+    /// also report the matches which put the files of each group together
+    #[serde(default)]
+    pub edges: bool,
+    /// hash the previews cameras embed in their JPEGs rather than decoding the images, those without one in full,
+    /// for a quick first look, the hashes are rougher
+    #[serde(default)]
+    pub fast: bool,
+    /// take the hashes of the latest run of the folder over for the files unchanged since,
+    /// only new and modified ones are hashed, everything is hashed without such a run
+    #[serde(default)]
+    pub incremental: bool,
+    /// also hash the images inside `.zip` and `.cbz` archives, named `<archive>!<entry>`, local folders only
+    #[serde(default)]
+    pub archives: bool,
+    /// hash every file a cheaper way first, and only those it finds candidates the requested way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coarse: Option<CoarsePass>,
+    /// what the percent of the progress counts, the bytes hashed when a few large files take most of the time
+    #[serde(default)]
+    pub progress_unit: ProgressUnit,
+    /// what the files of a group are told exact copies by, pixels decode the files of the same hash in full
+    #[serde(default)]
+    pub exact_by: ExactBy,
+    /// hash exactly these local files below `path` rather than the images found in it, e.g. the output of `find`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<String>>)]
+    pub files: Option<Vec<PathBuf>>,
+    /// POSTed to when the analysis finishes, instead of the configured `webhook`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub(crate) callback: Option<url::Url>,
+    /// the session that submitted it, set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(ignore)]
+    pub(crate) owner: Option<uuid::Uuid>,
+}
+
+impl Default for AnalyzeRequest {
+    /// the defaults of the HTTP API, of the current folder
+    fn default() -> Self {
+        Self {
+            dist: 0,
+            path: PathBuf::new(),
+            hash_type: HashType::default(),
+            hash_size: HashSize::default(),
+            max_minutes: None,
+            retries: default_retries(),
+            cache_mode: CacheMode::default(),
+            timeout_minutes: None,
+            priority: Priority::default(),
+            #[cfg(feature = "ocr")]
+            ocr: false,
+            edges: false,
+            fast: false,
+            incremental: false,
+            archives: false,
+            coarse: None,
+            progress_unit: ProgressUnit::default(),
+            exact_by: ExactBy::default(),
+            files: None,
+            callback: None,
+            owner: None,
+        }
+    }
+}
+
+impl AnalyzeRequest {
+    /// a scan of `path` for exact copies, the other options set the way the HTTP API defaults them
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), ..Self::default() }
+    }
+
+    /// whether both would find the same groups, priority, progress and callback aside
+    pub fn same_scan(&self, other: &Self) -> bool {
+        *self == Self {
+            priority: self.priority,
+            incremental: self.incremental,
+            progress_unit: self.progress_unit,
+            callback: self.callback.clone(),
+            ..other.clone()
+        }
+    }
+
+    /// hashing the coarse way of the first pass, `None` without one
+    fn coarse_request(&self) -> Option<Self> {
+        let coarse = self.coarse.as_ref()?;
+        Some(Self { dist: coarse.dist, hash_type: coarse.hash_type, hash_size: coarse.hash_size, fast: coarse.fast, edges: false, coarse: None, ..self.clone() })
+    }
+
+    /// whether hashes of one are those the other would compute
+    pub fn hashed_like(&self, other: &Self) -> bool {
+        self.hash_type == other.hash_type && self.hash_size == other.hash_size && self.fast == other.fast
+    }
+}
+
+/// what cached hashes are looked up by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum CacheMode {
+    /// path, size and mtime, no extra reads
+    #[default]
+    Path,
+    /// checksum of the content, survives moves and renames
+    /// but reads every file in full on each run
+    Content,
+}
+
+/// what makes files of a group exact copies of each other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ExactBy {
+    /// the same bytes
+    #[default]
+    Bytes,
+    /// the same decoded pixels, whatever the EXIF, XMP or IPTC tags, e.g. after geotagging or editing keywords
+    Pixels,
+}
+
+/// what the percent of the progress of hashing counts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ProgressUnit {
+    #[default]
+    Files,
+    Bytes,
+}
+
+pub fn default_retries() -> u32 {
+    2
+}
+
+/// Bump whenever decoding or hashing changes in a way that changes hashes, cached and stored hashes
+/// of other versions are then dropped and recomputed, they'd group files unlike fresh ones.
+pub const HASH_VERSION: u32 = 1;
+
+/// cached hashes are namespaced by everything that affects them
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheKey {
+    hash_type: HashType,
+    hash_size: HashSize,
+    version: u32,
+    /// empty for content addressed entries
+    path: PathBuf,
+    /// SHA-256 of the file for content addressed entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    /// hashed from the embedded preview when there was one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    preview: bool,
+}
+
+impl CacheKey {
+    fn new(hash_type: HashType, hash_size: HashSize, path: PathBuf) -> Self {
+        Self { hash_type, hash_size, version: HASH_VERSION, path, checksum: None, preview: false }
+    }
+
+    fn content(hash_type: HashType, hash_size: HashSize, checksum: String) -> Self {
+        Self { hash_type, hash_size, version: HASH_VERSION, path: PathBuf::new(), checksum: Some(checksum), preview: false }
+    }
+}
+
+/// hash as stored in the cache, along with the file stamp it was computed for
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CacheEntry {
+    hash: String,
+    // entries written before stamps were tracked never match and get recomputed
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    modified: u64,
+}
+
+impl CacheEntry {
+    fn new(stamp: FileStamp, hash: &ImageHash) -> Self {
+        Self { hash: hash.to_base64(), size: stamp.size, modified: stamp.modified }
+    }
+
+    /// the hash, unless the file changed since it was computed
+    fn hash(&self, stamp: Option<FileStamp>) -> Option<ImageHash> {
+        if stamp.is_some_and(|stamp| self.size != stamp.size || self.modified != stamp.modified) {
+            return None;
+        }
+        ImageHash::from_base64(&self.hash).ok()
+    }
+}
+
+/// pre-computes hashes into the cache, without grouping
+#[derive(Debug, serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmRequest {
+    #[param(value_type = String)]
     pub path: PathBuf,
     pub hash_type: HashType,
-    pub hash_size: u32,
+    #[serde(default)]
+    pub hash_size: HashSize,
 }
 
-type CacheKey = (HashType, u32, PathBuf);
+#[derive(Debug, Clone, Default, serde::Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmStatus {
+    running: bool,
+    #[schema(value_type = Option<String>)]
+    path: Option<PathBuf>,
+    total: usize,
+    /// computed by this run
+    hashed: usize,
+    /// already in the cache
+    cached: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    #[default]
+    Listing,
+    Hashing,
+    Grouping,
+}
+
+/// what the coarse pass of an analysis found
+struct CoarseBuckets {
+    /// of the candidates
+    buckets: HashMap<PathBuf, usize>,
+    hashed: usize,
+    deferred: usize,
+    corrupted: Vec<CorruptedFile>,
+    concurrency: Vec<ConcurrencyAdjustment>,
+}
+
+/// where a running analysis is at
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    pub phase: Phase,
+    /// 0..=100, of hashing the files or the bytes by `unit`, then of the shards matched while grouping
+    pub percent: usize,
+    pub unit: ProgressUnit,
+    pub files: usize,
+    pub total_files: usize,
+    pub bytes: u64,
+    pub total_bytes: u64,
+    /// remaining seconds of hashing, estimated from the bytes done so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta: Option<u64>,
+    /// segments of the hashes matched one after another while grouping, and the one being matched from 1
+    pub segment: usize,
+    pub segments: usize,
+    /// shards of the current segment matched, and how many it has
+    pub shards: usize,
+    pub total_shards: usize,
+}
+
+impl Progress {
+    pub(crate) fn hashing(unit: ProgressUnit, files: usize, total_files: usize, bytes: u64, total_bytes: u64, elapsed: Duration) -> Self {
+        let percent = match unit {
+            ProgressUnit::Files => (files * 100).checked_div(total_files).unwrap_or(100),
+            ProgressUnit::Bytes => (bytes.saturating_mul(100).checked_div(total_bytes).unwrap_or(100)) as usize,
+        };
+        let eta = (bytes > 0).then(|| {
+            let remaining = total_bytes.saturating_sub(bytes) as f64 / bytes as f64;
+            (elapsed.as_secs_f64() * remaining).round() as u64
+        });
+        Self { phase: Phase::Hashing, percent, unit, files, total_files, bytes, total_bytes, eta, ..Self::default() }
+    }
+}
+
+/// how often paused warming checks whether analyses are done
+const WARM_PAUSE: Duration = Duration::from_secs(1);
+
+/// counts a running analysis while alive
+struct ActiveAnalysis<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveAnalysis<'a> {
+    fn new(active: &'a AtomicUsize) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(active)
+    }
+}
+
+impl Drop for ActiveAnalysis<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 pub struct Analyzer {
-    cache: Cache<CacheKey, ImageHash>,
+    cache: Cache<CacheKey, CacheEntry>,
+    roots: Arc<Roots>,
+    sandbox: Arc<Sandbox>,
+    /// reads paths of other schemes than local ones
+    remotes: Remotes,
+    /// other machines hashing for analyses
+    workers: Workers,
+    /// suggest which copy of each group to keep
+    keep_rules: KeepRules,
+    /// bounds the decoded images in memory, unbounded when `None`
+    memory: Option<MemoryBudget>,
+    /// kept out of the groups
+    ignored: Arc<IgnoreList>,
+    index: RwLock<Option<SearchIndex>>,
+    /// where the index is kept between restarts, only in memory when `None`
+    index_file: Option<PathBuf>,
+    /// analyses in progress, warming waits for them
+    active: AtomicUsize,
+    warming: Mutex<WarmStatus>,
+    /// of the screenshots matched by their text
+    #[cfg(feature = "ocr")]
+    texts: crate::ocr::TextCache,
+}
+
+#[derive(Debug, serde::Serialize, ToSchema)]
+pub struct SearchMatch {
+    file: FileInfo,
+    distance: u32,
+}
+
+/// the files of the index within `max_dist` of the hash, closest first
+fn closest(index: &SearchIndex, hash: &ImageHash, max_dist: u32, limit: usize) -> Vec<SearchMatch> {
+    let mut matches: Vec<SearchMatch> = index.tree
+        .find(hash, max_dist)
+        .into_iter()
+        .map(|(distance, file)| SearchMatch { file: file.clone(), distance })
+        .collect();
+
+    matches.sort_by(|a, b| a.distance.cmp(&b.distance).then_with(|| a.file.path.cmp(&b.file.path)));
+    matches.truncate(limit);
+    matches
 }
 
 impl Analyzer {
-    pub fn new() -> Self {
-        Self { cache: Cache::new() }
+    pub fn new(cache: Cache<CacheKey, CacheEntry>, roots: Arc<Roots>, sandbox: Arc<Sandbox>) -> Self {
+        Self {
+            cache,
+            roots,
+            sandbox,
+            remotes: Remotes::default(),
+            keep_rules: KeepRules::default(),
+            memory: None,
+            ignored: Arc::default(),
+            workers: Workers::default(),
+            index: RwLock::new(None),
+            index_file: None,
+            active: AtomicUsize::new(0),
+            warming: Mutex::new(WarmStatus::default()),
+            #[cfg(feature = "ocr")]
+            texts: crate::ocr::TextCache::default(),
+        }
     }
 
-    fn make_hasher(req: &AnalyzeRequest) -> Hasher {
-        let (hash_alg, dct) = match req.hash_type {
-            HashType::AHash => (HashAlg::Mean, false),
-            HashType::PHash => (HashAlg::Mean, true),
-            HashType::DHash => (HashAlg::Gradient, false),
-        };
+    pub(crate) fn with_remotes(self, remotes: Remotes) -> Self {
+        Self { remotes, ..self }
+    }
+
+    pub(crate) fn with_keep_rules(self, keep_rules: KeepRules) -> Self {
+        Self { keep_rules, ..self }
+    }
 
-        let mut config = HasherConfig::new()
-            .hash_size(req.hash_size, req.hash_size)
-            .hash_alg(hash_alg);
+    pub(crate) fn with_ignored(self, ignored: Arc<IgnoreList>) -> Self {
+        Self { ignored, ..self }
+    }
 
-        if dct {
-            config = config.preproc_dct();
+    /// keeps the search index in the file, starting with the one kept there
+    pub(crate) fn with_index_file(self, path: PathBuf) -> Self {
+        let index = match SearchIndex::load(&path) {
+            Ok(index) => index,
+            Err(err) => {
+                tracing::warn!(path = path.to_str(), "unable to load the search index, searched after the next analysis: {:#}", err);
+                None
+            }
+        };
+        if let Some(index) = &index {
+            tracing::info!(files = index.tree.len(), "search index loaded");
         }
+        Self { index: RwLock::new(index), index_file: Some(path), ..self }
+    }
 
-        config.to_hasher()
+    /// in bytes
+    pub(crate) fn with_memory_budget(self, budget: Option<u64>) -> Self {
+        Self { memory: budget.map(MemoryBudget::new), ..self }
+    }
+
+    pub(crate) fn workers(&self) -> &Workers {
+        &self.workers
+    }
+
+    /// `None` for local paths
+    pub(crate) fn remote(&self, path: &Path) -> Result<Option<Arc<dyn Storage>>> {
+        Ok(self.remotes.get(path)?)
+    }
+
+    /// the storage `path` is read from
+    pub(crate) fn storage(&self, path: &Path) -> Result<Arc<dyn Storage>> {
+        Ok(self.remote(path)?.unwrap_or_else(|| Arc::new(Local(self.sandbox.clone()))))
+    }
+
+    /// the storage of the request, reading into archives when it asks to
+    fn request_storage(&self, req: &AnalyzeRequest) -> Result<Arc<dyn Storage>> {
+        Ok(match self.remote(&req.path)? {
+            Some(storage) => storage,
+            None if req.archives => Arc::new(self.archives()),
+            None => Arc::new(Local(self.sandbox.clone())),
+        })
+    }
+
+    /// the local folders and archives, reading archive entries
+    pub(crate) fn archives(&self) -> Archives {
+        Archives(Local(self.sandbox.clone()))
+    }
+
+    /// the images below a folder, local or remote
+    pub fn scan(&self, dir: &Path) -> Result<Listing> {
+        self.storage(dir)?.scan(dir)
+    }
+
+    fn cached(&self, key: CacheKey, stamp: FileStamp) -> Result<Option<ImageHash>> {
+        // the same content has the same hash wherever and whenever it was stored
+        let stamp = key.checksum.is_none().then_some(stamp);
+        Ok(self.cache.get_valid(key, move |entry| entry.hash(stamp).is_some())?.and_then(|entry| entry.hash(stamp)))
+    }
+
+    fn store(&self, key: CacheKey, stamp: FileStamp, hash: &ImageHash) -> Result<()> {
+        self.cache.set(key, CacheEntry::new(stamp, hash))
     }
 
     fn cache_key(req: &AnalyzeRequest, file_path: PathBuf) -> CacheKey {
-        (req.hash_type, req.hash_size, file_path)
+        CacheKey::new(req.hash_type, req.hash_size, file_path)
     }
 
-    fn compute_hash(&self, req: &AnalyzeRequest, hasher: &Hasher, file: FileInfo) -> Option<(FileInfo, ImageHash)> {
-        let key = Self::cache_key(req, file.path.clone());
-        if let Ok(Some(hash)) = self.cache.get(key) {
-            Some((file, hash))
-        } else {
-            let path = file.path.to_str();
-            tracing::info!(path, "analyzing");
-            match image::open(&file.path) {
-                Ok(image) => {
-                    let hash = hasher.hash_image(&image);
-                    Some((file, hash))
+    pub fn cache_migrations(&self) -> Vec<crate::schema::Migration> {
+        self.cache.migrations()
+    }
+
+    pub fn cache_stats(&self) -> Result<CacheStats> {
+        self.cache.stats()
+    }
+
+    pub fn ping_cache(&self) -> Result<()> {
+        self.cache.ping()
+    }
+
+    pub fn flush_cache(&self) -> Result<()> {
+        self.cache.flush()
+    }
+
+    pub fn prune_cache(&self, older_than: Option<Duration>) -> Result<usize> {
+        self.cache.prune(older_than)
+    }
+
+    pub fn export_cache(&self) -> Result<Vec<u8>> {
+        self.cache.export()
+    }
+
+    /// imports hashes computed on another machine, paths under `from`
+    /// are moved to `to` when the library is mounted elsewhere
+    pub fn import_cache(&self, dump: &[u8], remap: Option<(PathBuf, PathBuf)>) -> Result<usize> {
+        self.cache.import(dump, |mut key: CacheKey| {
+            if let Some((from, to)) = &remap {
+                if let Ok(rest) = key.path.strip_prefix(from) {
+                    key.path = to.join(rest);
+                }
+            }
+            key
+        })
+    }
+
+    /// hashes a single file, going through the cache
+    pub fn hash_file(&self, hash_type: HashType, hash_size: HashSize, path: &Path) -> Result<ImageHash> {
+        let key = CacheKey::new(hash_type, hash_size, path.to_owned());
+        let stamp = FileStamp::of(path)?;
+        if let Some(hash) = self.cached(key.clone(), stamp)? {
+            return Ok(hash);
+        }
+
+        let (image, _) = open_image(path, DECODE_SIZE)?;
+        let hash = hasher::make(hash_type, hash_size).hash(&image);
+        self.store(key, stamp, &hash)?;
+        Ok(hash)
+    }
+
+    /// Decodes the file at most once per run, the fine pass takes the image the coarse pass decoded from the memo
+    /// while it's kept there. The truncation check reads raw bytes.
+    fn compute_hash(
+        &self,
+        req: &AnalyzeRequest,
+        source: &Source,
+        hasher: &dyn ImageHasher,
+        throttle: &Throttle,
+        deadline: Option<Instant>,
+        file: FileInfo,
+    ) -> HashOutcome {
+        let key = match (source.cache_key(req, &file), req.cache_mode) {
+            (Some(key), _) => key,
+            (None, CacheMode::Path) => Self::cache_key(req, file.path.clone()),
+            (None, CacheMode::Content) => {
+                let permit = throttle.acquire();
+                let started = Instant::now();
+                let checksum = source.clock.time(Step::CacheLookup, || source.storage.checksum(&file.path));
+                permit.done(started.elapsed());
+                match checksum {
+                    Ok(checksum) => CacheKey::content(req.hash_type, req.hash_size, checksum),
+                    Err(err) => return HashOutcome::unreadable(file, err),
+                }
+            }
+        };
+        let key = CacheKey { preview: req.fast, ..key };
+        if let Ok(Some(hash)) = source.clock.time(Step::CacheLookup, || self.cached(key.clone(), file.stamp())) {
+            return HashOutcome::Hashed(file, hash);
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return HashOutcome::Deferred;
+        }
+
+        let path = file.path.to_str();
+        tracing::info!(path, "analyzing");
+        // previews are as quick to decode again, and aren't the image the checksums want
+        let memoized = match req.fast {
+            true => None,
+            false => source.memo.get(&file.path, file.stamp()).map(|(image, _)| image),
+        };
+        // held until the image is hashed, before the throttle so waiting for memory isn't taken for slow reads
+        let _memory = match memoized {
+            Some(_) => None,
+            None => source.memo.reserve(|| source.storage.decoded_size(&file.path, DECODE_SIZE).unwrap_or(file.size.saturating_mul(UNKNOWN_DECODE_RATIO))),
+        };
+        let opened = match memoized {
+            Some(image) => Ok((image, Ok(false))),
+            None => {
+                let permit = throttle.acquire();
+                let started = Instant::now();
+                let opened = source.clock.time(Step::Decode, || {
+                    let preview = req.fast.then(|| source.storage.preview(&file.path, DECODE_SIZE)).flatten();
+                    preview.map_or_else(|| source.storage.open(&file.path, DECODE_SIZE), Ok)
+                });
+                permit.done(started.elapsed());
+                opened.map(|(image, truncated)| (Arc::new(image), truncated))
+            }
+        };
+
+        match opened {
+            Ok((image, truncated)) => match truncated {
+                Ok(false) => {
+                    if !req.fast {
+                        // images are only scaled down to a side of `DECODE_SIZE`, smaller ones have the size of the file
+                        let full = image.width().max(image.height()) < DECODE_SIZE;
+                        source.memo.insert(file.path.clone(), file.stamp(), image.clone(), full);
+                    }
+                    let hash = source.clock.time(Step::Hash, || hasher.hash(&image));
+                    metrics().files_hashed.inc();
+                    // cached right away rather than at the end of the run, so a crashed
+                    // or interrupted analysis resumes from where it stopped when resubmitted
+                    if let Err(err) = self.store(key, file.stamp(), &hash) {
+                        tracing::error!(path, "unable to cache the hash: {:?}", err);
+                    }
+                    HashOutcome::Hashed(file, hash)
+                }
+                Ok(true) => {
+                    tracing::warn!(path, "truncated image");
+                    let error = "truncated image data".to_owned();
+                    HashOutcome::Corrupted(CorruptedFile { path: file.path, error })
                 }
                 Err(err) => {
-                    tracing::error!(path, "unable to open the image: {:?}", err);
-                    None
+                    tracing::error!(path, "unable to read the image: {:?}", err);
+                    HashOutcome::unreadable(file, err)
                 }
+            },
+            Err(ImageError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                tracing::warn!(path, "truncated image");
+                let error = "truncated image data".to_owned();
+                HashOutcome::Corrupted(CorruptedFile { path: file.path, error })
+            }
+            Err(ImageError::IoError(err)) => {
+                tracing::error!(path, "unable to open the image: {:?}", err);
+                HashOutcome::unreadable(file, err)
+            }
+            Err(err) => {
+                tracing::warn!(path, "unable to decode the image: {:?}", err);
+                HashOutcome::Corrupted(CorruptedFile { path: file.path, error: err.to_string() })
             }
         }
     }
 
-    fn compute_hashes(&self, req: &AnalyzeRequest, tx: watch::Sender<usize>) -> Result<Hashes> {
-        let files = list_dir(&req.path)?;
-        let hasher = Self::make_hasher(req);
+    /// Hashes the files without cached hashes on the workers, in batches. Their hashes are cached, `hash_one`
+    /// then finds them there, and hashes what workers couldn't and what's left when they are gone.
+    fn share_hashing(
+        &self,
+        req: &AnalyzeRequest,
+        files: Vec<FileInfo>,
+        pool: &rayon::ThreadPool,
+        hash_one: &(impl Fn(FileInfo) -> HashOutcome + Sync),
+        cancel: &CancelToken,
+    ) -> Vec<HashOutcome> {
+        let (cached, missing): (Vec<_>, Vec<_>) = files
+            .into_iter()
+            .partition(|file| matches!(self.cached(Self::cache_key(req, file.path.clone()), file.stamp()), Ok(Some(_))));
+        let mut outcomes: Vec<HashOutcome> = pool.install(|| cached.into_par_iter().map(hash_one).collect());
+
+        let mut files: HashMap<Uuid, Vec<FileInfo>> = HashMap::new();
+        let batches = missing
+            .chunks(workers::BATCH_FILES)
+            .map(|chunk| {
+                let batch = Batch::new(req.hash_type, req.hash_size, chunk.iter().map(|file| file.path.clone()).collect());
+                files.insert(batch.id, chunk.to_vec());
+                batch
+            })
+            .collect();
+        self.workers.share(batches, cancel, |batch, answer| {
+            let files = files.remove(&batch.id).unwrap_or_default();
+            let by_path: HashMap<PathBuf, HashedFile> = answer.unwrap_or_default().into_iter().map(|file| (file.path.clone(), file)).collect();
+            for file in &files {
+                let Some(hash) = by_path.get(&file.path).and_then(|hashed| hashed.hash(req.hash_size)) else {
+                    continue;
+                };
+                metrics().files_hashed.inc();
+                if let Err(err) = self.store(Self::cache_key(req, file.path.clone()), file.stamp(), &hash) {
+                    tracing::error!(path = file.path.to_str(), "unable to cache the hash of a worker: {:?}", err);
+                }
+            }
+            outcomes.extend(pool.install(|| files.into_par_iter().map(hash_one).collect::<Vec<_>>()));
+        });
+        outcomes
+    }
+
+    fn compute_hashes(
+        &self,
+        req: &AnalyzeRequest,
+        source: &Source,
+        files: Vec<FileInfo>,
+        errors: &mut Vec<FileError>,
+        tx: &watch::Sender<Progress>,
+        cancel: &CancelToken,
+    ) -> Result<(Hashes, Vec<CorruptedFile>, usize, Vec<ConcurrencyAdjustment>)> {
+        let hasher = hasher::make(req.hash_type, req.hash_size);
+        let started = Instant::now();
+        let deadline = req.max_minutes.map(|m| Instant::now() + Duration::from_secs(m * 60));
         let total = files.len();
-        let iter = files.into_par_iter();
+        let total_bytes = files.iter().map(|file| file.size).sum();
         let counter = AtomicUsize::new(0);
+        let bytes = AtomicU64::new(0);
+        tx.send_replace(Progress::hashing(req.progress_unit, 0, total, 0, total_bytes, Duration::ZERO));
+
+        // more threads than cores, so reads from slow storage overlap,
+        // the throttle decides how many of them actually touch the disk
+        let throttle = Throttle::new(MIN_WORKERS, rayon::current_num_threads() * 2);
+        let pool = ThreadPoolBuilder::new().num_threads(throttle.max()).build()?;
 
-        let result = iter.filter_map(|file| {
-            let prev = counter.fetch_add(1, Ordering::Relaxed);
-            let progress = prev * 100 / total;
-            if tx.send(progress).is_err() {
-                tracing::error!(path = file.path.to_str(), "unable to report progress");
+        // workers log into the task of the caller
+        let span = tracing::Span::current();
+        let hash_one = |file: FileInfo| {
+            let _span = span.enter();
+            // the rest is skipped, the run fails as cancelled below
+            if cancel.is_cancelled() {
+                return HashOutcome::Deferred;
             }
 
-            self.compute_hash(req, &hasher, file)
-        }).collect();
+            let size = file.size;
+            let outcome = self.compute_hash(req, source, &*hasher, &throttle, deadline, file);
+            let done = counter.fetch_add(1, Ordering::Relaxed) + 1;
+            let done_bytes = bytes.fetch_add(size, Ordering::Relaxed) + size;
+            // workers finish out of order, never go backwards
+            tx.send_if_modified(|progress| {
+                let newer = done > progress.files;
+                if newer {
+                    let percent = progress.percent;
+                    *progress = Progress::hashing(req.progress_unit, done, total, done_bytes, total_bytes, started.elapsed());
+                    // the bytes of files counted earlier may be added later
+                    progress.percent = progress.percent.max(percent);
+                }
+                newer
+            });
+            outcome
+        };
+        // workers read the same paths, they can't help with remote storage or content addressed caching,
+        // decode the images in full, and only know the built-in hashes
+        let shared = self.workers.active()
+            && !matches!(req.hash_type, HashType::Custom(_))
+            && req.cache_mode == CacheMode::Path
+            && !req.fast
+            && source.tags.is_empty()
+            && matches!(self.remote(&req.path), Ok(None));
+        let outcomes = if shared {
+            self.share_hashing(req, files, &pool, &hash_one, cancel)
+        } else {
+            pool.install(|| files.into_par_iter().map(&hash_one).collect())
+        };
+        if cancel.is_cancelled() {
+            let error = cancel.error();
+            tracing::info!("analysis stopped: {}", error);
+            return Err(error);
+        }
+
+        let mut hashes = Vec::new();
+        let mut corrupted = Vec::new();
+        let mut deferred = 0;
+        let mut failed = Vec::new();
+        let mut sort_outcomes = |outcomes: Vec<HashOutcome>, failed: &mut Vec<(FileInfo, FileError)>| {
+            for outcome in outcomes {
+                match outcome {
+                    HashOutcome::Hashed(file, hash) => hashes.push((file, hash)),
+                    HashOutcome::Corrupted(file) => corrupted.push(file),
+                    HashOutcome::Unreadable(file, error) => failed.push((file, error)),
+                    HashOutcome::Deferred => deferred += 1,
+                }
+            }
+        };
+        sort_outcomes(outcomes, &mut failed);
+
+        // I/O errors may be transient
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1..=req.retries.min(MAX_RETRIES) {
+            if failed.is_empty() || cancel.is_cancelled() {
+                break;
+            }
+
+            tracing::info!(attempt, files = failed.len(), "retrying unreadable files in {:?}", backoff);
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+
+            let retried = std::mem::take(&mut failed);
+            let outcomes: Vec<HashOutcome> = pool.install(|| retried.into_par_iter().map(|(file, _)| {
+                let _span = span.enter();
+                self.compute_hash(req, source, &*hasher, &throttle, deadline, file)
+            }).collect());
+            sort_outcomes(outcomes, &mut failed);
+        }
+        errors.extend(failed.into_iter().map(|(_, error)| error));
+
+        if deferred > 0 {
+            tracing::info!(deferred, "time limit reached, remaining files deferred");
+        }
+
+        Ok((hashes, corrupted, deferred, throttle.adjustments()))
+    }
+
+    fn update_index(&self, req: &AnalyzeRequest, hashes: &Hashes) {
+        let index = SearchIndex::new(req.hash_type, req.hash_size, hashes);
+        tracing::info!(files = index.tree.len(), "search index updated");
+        if let Some(path) = &self.index_file {
+            if let Err(err) = index.save(path) {
+                tracing::error!(path = path.to_str(), "unable to keep the search index: {:#}", err);
+            }
+        }
+        *self.index.write().unwrap() = Some(index);
+    }
+
+    /// files of the last analysis most similar to the image, closest first,
+    /// `None` when nothing has been analyzed yet
+    pub fn search(&self, path: &Path, max_dist: u32, limit: usize) -> Result<Option<Vec<SearchMatch>>> {
+        // hashed without the lock, so the index isn't held up while the file is decoded
+        let mut kind = match self.index.read().unwrap().as_ref() {
+            Some(index) => (index.hash_type, index.hash_size),
+            None => return Ok(None),
+        };
+        loop {
+            let hash = self.hash_file(kind.0, kind.1, path)?;
+            let index = self.index.read().unwrap();
+            let Some(index) = index.as_ref() else {
+                return Ok(None);
+            };
+            // rebuilt with another hash in the meantime
+            if (index.hash_type, index.hash_size) == kind {
+                return Ok(Some(closest(index, &hash, max_dist, limit)));
+            }
+            kind = (index.hash_type, index.hash_size);
+        }
+    }
+
+    /// like `search`, for an image which isn't in a library, e.g. one uploaded, nothing is cached of it
+    pub fn search_image(&self, data: &[u8], max_dist: u32, limit: usize) -> Result<Option<Vec<SearchMatch>>> {
+        // decoded before taking the lock, so the index isn't held up by the upload
+        let (image, _) = storage::decode(data, DECODE_SIZE)?;
+        let index = self.index.read().unwrap();
+        let Some(index) = index.as_ref() else {
+            return Ok(None);
+        };
+
+        let hash = hasher::make(index.hash_type, index.hash_size).hash(&image);
+        Ok(Some(closest(index, &hash, max_dist, limit)))
+    }
+
+    pub fn warm_status(&self) -> WarmStatus {
+        self.warming.lock().unwrap().clone()
+    }
 
-        let progress = counter.into_inner() * 100 / total;
-        tx.send(progress)?;
+    /// Hashes a folder into the cache on a background thread, one file at a time
+    /// and pausing while analyses run. `false` if warming is already running.
+    pub fn start_warming(self: &Arc<Self>, req: WarmRequest) -> bool {
+        {
+            let mut status = self.warming.lock().unwrap();
+            if status.running {
+                return false;
+            }
+            *status = WarmStatus { running: true, path: Some(req.path.clone()), ..Default::default() };
+        }
 
-        Ok(result)
+        let engine = self.clone();
+        thread::spawn(move || {
+            if let Err(err) = engine.warm(&req) {
+                tracing::error!(path = req.path.to_str(), "cache warming failed: {:?}", err);
+            }
+            engine.warming.lock().unwrap().running = false;
+        });
+        true
     }
 
-    fn update_cache(&self, req: &AnalyzeRequest, hashes: Hashes) -> Result<()> {
-        for (file, hash) in hashes {
-            let key = Self::cache_key(req, file.path);
-            self.cache.set(key, hash)?;
+    fn warm(&self, req: &WarmRequest) -> Result<()> {
+        let files = scan_dir(&req.path, &self.sandbox)?.files;
+        self.warming.lock().unwrap().total = files.len();
+        let hasher = hasher::make(req.hash_type, req.hash_size);
+        tracing::info!(path = req.path.to_str(), files = files.len(), "cache warming started");
+
+        for file in files {
+            while self.active.load(Ordering::SeqCst) > 0 {
+                thread::sleep(WARM_PAUSE);
+            }
+
+            let key = CacheKey::new(req.hash_type, req.hash_size, file.path.clone());
+            let stamp = file.stamp();
+            if self.cached(key.clone(), stamp)?.is_some() {
+                self.warming.lock().unwrap().cached += 1;
+                continue;
+            }
+
+            match open_image(&file.path, DECODE_SIZE) {
+                Ok((image, _)) => {
+                    self.store(key, stamp, &hasher.hash(&image))?;
+                    self.warming.lock().unwrap().hashed += 1;
+                }
+                Err(err) => {
+                    // reported properly by the analysis
+                    tracing::debug!(path = file.path.to_str(), "unable to warm: {:?}", err);
+                    self.warming.lock().unwrap().failed += 1;
+                }
+            }
         }
 
+        tracing::info!(path = req.path.to_str(), "cache warming completed");
         Ok(())
     }
 
-    pub fn analyze(&self, req: &AnalyzeRequest, tx: watch::Sender<usize>) -> Result<Groups> {
-        let hashes = self.compute_hashes(req, tx)?;
-        let result = create_groups(&hashes, req.dist);
-        self.update_cache(req, hashes)?;
-        Ok(result)
+    /// as reported, with the distances between their files
+    fn report_groups(&self, groups: Groups, hashes: &Hashes) -> Vec<Group> {
+        let by_path: HashMap<&Path, &ImageHash> = hashes.iter().map(|(file, hash)| (file.path.as_path(), hash)).collect();
+        groups
+            .into_iter()
+            .map(|files| {
+                let hashes: Vec<_> = files.iter().map(|file| by_path[file.path.as_path()]).collect();
+                Group::new(files, &hashes, &self.roots, &self.keep_rules)
+            })
+            .collect()
+    }
+
+    /// adds the matched pairs of indices into `hashes` to the groups, those only in `extra` matched by text
+    fn add_edges(groups: &mut [Group], hashes: &Hashes, matched: &[(usize, usize)], extra: &[(usize, usize)]) {
+        let mut positions: HashMap<&Path, (usize, usize)> = HashMap::new();
+        for (g, group) in groups.iter().enumerate() {
+            for (i, file) in group.files.iter().enumerate() {
+                positions.insert(&file.path, (g, i));
+            }
+        }
+        let mut edges = vec![Vec::new(); groups.len()];
+        let hashed: HashSet<(usize, usize)> = matched.iter().map(|&(i, j)| (i.min(j), i.max(j))).collect();
+        let pairs = matched
+            .iter()
+            .map(|&pair| (pair, false))
+            .chain(extra.iter().filter(|&&(i, j)| !hashed.contains(&(i.min(j), i.max(j)))).map(|&pair| (pair, true)));
+        for ((i, j), text) in pairs {
+            let (Some(&(group, from)), Some(&(_, to))) = (positions.get(hashes[i].0.path.as_path()), positions.get(hashes[j].0.path.as_path())) else {
+                continue;
+            };
+            let (from, to) = (from.min(to), from.max(to));
+            let distance = hashes[i].1.dist(&hashes[j].1);
+            let exact = groups[group].identical.iter().any(|same| same.contains(&from) && same.contains(&to));
+            edges[group].push(Edge { from, to, distance, text, exact });
+        }
+        for (group, mut edges) in groups.iter_mut().zip(edges) {
+            edges.sort_by_key(|edge| (edge.from, edge.to));
+            group.edges = edges;
+        }
+    }
+
+    fn find_identical(groups: &mut [Group], by: ExactBy, checksum: &(impl Fn(&FileInfo) -> Result<String> + Sync)) {
+        groups.par_iter_mut().for_each(|group| group.find_identical(by, checksum));
+    }
+
+    /// Of the pixels at full size, those hashing decoded at full size already are taken from the memo,
+    /// the others are decoded within the memory budget.
+    fn pixel_checksum(storage: &dyn Storage, memo: &DecodeMemo, file: &FileInfo) -> Result<String> {
+        if let Some((image, true)) = memo.get(&file.path, file.stamp()) {
+            return Ok(storage::pixel_checksum(&image));
+        }
+        let _memory = memo.reserve(|| storage.decoded_size(&file.path, u32::MAX).unwrap_or(file.size.saturating_mul(UNKNOWN_DECODE_RATIO)));
+        let (image, _) = storage.open(&file.path, u32::MAX)?;
+        Ok(storage::pixel_checksum(&image))
+    }
+
+    /// groups local files hashed elsewhere, as an analysis without OCR would
+    pub(crate) fn group(&self, hashes: &Hashes, dist: u32) -> Vec<Group> {
+        let hashes = self.ignored.kept(hashes);
+        let hashes = hashes.as_ref();
+        let mut groups = self.report_groups(create_groups(hashes, dist, &[], &self.ignored.pairs(hashes), &[], None, None), hashes);
+        let local = Local(self.sandbox.clone());
+        Self::find_identical(&mut groups, ExactBy::Bytes, &|file: &FileInfo| local.checksum(&file.path));
+        groups
+    }
+
+    pub fn analyze(&self, req: &AnalyzeRequest, tx: watch::Sender<Progress>, cancel: &CancelToken) -> Result<AnalyzeResult> {
+        self.analyze_since(req, Hashes::new(), tx, cancel)
+    }
+
+    /// as `analyze`, the hashes of an earlier run are taken for the files of the same size and mtime,
+    /// the others are hashed
+    pub(crate) fn analyze_since(&self, req: &AnalyzeRequest, earlier: Hashes, tx: watch::Sender<Progress>, cancel: &CancelToken) -> Result<AnalyzeResult> {
+        if cancel.is_cancelled() {
+            return Err(cancel.error());
+        }
+        let _active = ActiveAnalysis::new(&self.active);
+        let started = Instant::now();
+        let storage = self.request_storage(req)?;
+        let listing = match &req.files {
+            Some(paths) => list_files(paths, &self.sandbox),
+            None => storage.scan(&req.path)?,
+        };
+        let Listing { mut files, mut skipped, mut errors, tags, .. } = listing;
+        let (ignored, kept): (Vec<_>, Vec<_>) = files.into_iter().partition(|file| self.ignored.excludes(&file.path));
+        if !ignored.is_empty() {
+            skipped.extend(ignored.into_iter().map(|file| SkippedFile::new(file.path, SkipReason::Ignored)));
+            skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        files = kept;
+        let source = Source { storage: storage.as_ref(), tags, clock: StepClock::default(), memo: DecodeMemo::new(self.memory.as_ref()) };
+        for file in &mut files {
+            file.storage_class = self.roots.classify(&file.path);
+        }
+        let listing = started.elapsed();
+        tracing::info!(files = files.len(), skipped = skipped.len(), errors = errors.len(), "folder scanned");
+        let total = files.len();
+        let (files, coarse) = match req.coarse_request() {
+            Some(coarse_req) => {
+                let (candidates, coarse) = self.coarse_pass(&coarse_req, &source, files, &mut errors, &tx, cancel)?;
+                (candidates, Some(coarse))
+            }
+            None => (files, None),
+        };
+        let (files, reused) = Self::reuse_hashes(files, earlier);
+        if !reused.is_empty() {
+            tracing::info!(reused = reused.len(), changed = files.len(), "hashes of the latest run taken over");
+        }
+        let (mut hashes, mut corrupted, deferred, mut concurrency) = self.compute_hashes(req, &source, files, &mut errors, &tx, cancel)?;
+        let reused_count = reused.len();
+        if reused_count > 0 {
+            // in listing order, as a full analysis has them
+            hashes.extend(reused);
+            hashes.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+        }
+        tx.send_modify(|progress| {
+            progress.phase = Phase::Grouping;
+            progress.percent = 0;
+            progress.eta = None;
+        });
+        let grouping = Instant::now();
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut coverage = Coverage { hashed: hashes.len(), deferred, total, reused: reused_count, refined: None };
+        let mut buckets = Vec::new();
+        if let Some(coarse) = coarse {
+            coverage = Coverage { hashed: coarse.hashed, deferred: coarse.deferred + deferred, refined: Some(hashes.len()), ..coverage };
+            buckets = hashes.iter().map(|(file, _)| coarse.buckets[&file.path]).collect();
+            corrupted.extend(coarse.corrupted);
+            corrupted.sort_by(|a, b| a.path.cmp(&b.path));
+            concurrency.splice(0..0, coarse.concurrency);
+        }
+        #[cfg(feature = "ocr")]
+        // OCR reads local files, remote ones are grouped by their hashes only
+        let extra = if req.ocr && self.remote(&req.path)?.is_none() {
+            let paths = hashes.par_iter().enumerate().map(|(i, (file, _))| (i, file.path.as_path()));
+            crate::ocr::similar_pairs(paths, crate::ocr::SIMILARITY, &self.texts)
+        } else {
+            Vec::new()
+        };
+        #[cfg(not(feature = "ocr"))]
+        let extra = Vec::new();
+        // of the coarse hashes of every file after a coarse pass
+        if buckets.is_empty() {
+            self.update_index(req, &hashes);
+        }
+        let (groups, reclaimable, stats) = self.finish_groups(req, &source, &hashes, &extra, &buckets, Some(&tx))?;
+        let timings = source.clock.timings(listing, grouping.elapsed(), started.elapsed());
+        tracing::info!(?timings, "analysis timed");
+        Ok(AnalyzeResult { groups, skipped, corrupted, errors, reclaimable, stats, coverage, concurrency, timings, hashes })
+    }
+
+    /// the candidates among the files, those of some bucket
+    fn coarse_pass(
+        &self,
+        req: &AnalyzeRequest,
+        source: &Source,
+        files: Vec<FileInfo>,
+        errors: &mut Vec<FileError>,
+        tx: &watch::Sender<Progress>,
+        cancel: &CancelToken,
+    ) -> Result<(Vec<FileInfo>, CoarseBuckets)> {
+        let (hashes, corrupted, deferred, concurrency) = self.compute_hashes(req, source, files.clone(), errors, tx, cancel)?;
+        // ignored pairs are kept apart by the fine pass
+        let groups = create_groups(&hashes, req.dist, &[], &HashSet::new(), &[], None, None);
+        let buckets: HashMap<PathBuf, usize> = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(bucket, files)| files.iter().map(move |file| (file.path.clone(), bucket)))
+            .collect();
+        tracing::info!(files = hashes.len(), buckets = groups.len(), candidates = buckets.len(), "coarse pass done");
+        self.update_index(req, &hashes);
+        let candidates = files.into_iter().filter(|file| buckets.contains_key(&file.path)).collect();
+        Ok((candidates, CoarseBuckets { buckets, hashed: hashes.len(), deferred, corrupted, concurrency }))
+    }
+
+    /// the files which weren't hashed the way they are now, and the earlier hashes of the others
+    fn reuse_hashes(files: Vec<FileInfo>, earlier: Hashes) -> (Vec<FileInfo>, Hashes) {
+        if earlier.is_empty() {
+            return (files, Hashes::new());
+        }
+        let mut earlier: HashMap<PathBuf, (FileStamp, ImageHash)> = earlier.into_iter().map(|(file, hash)| (file.path.clone(), (file.stamp(), hash))).collect();
+        let mut changed = Vec::new();
+        let mut reused = Hashes::new();
+        for file in files {
+            match earlier.remove(&file.path) {
+                Some((stamp, hash)) if stamp == file.stamp() => reused.push((file, hash)),
+                _ => changed.push(file),
+            }
+        }
+        (changed, reused)
+    }
+
+    /// the groups of the hashes, with what results say about them
+    fn finish_groups(
+        &self,
+        req: &AnalyzeRequest,
+        source: &Source,
+        hashes: &Hashes,
+        extra: &[(usize, usize)],
+        buckets: &[usize],
+        progress: Option<&watch::Sender<Progress>>,
+    ) -> Result<(Vec<Group>, Vec<ClassSavings>, DuplicateStats)> {
+        let mut matched = Vec::new();
+        let groups = create_groups(hashes, req.dist, extra, &self.ignored.pairs(hashes), buckets, req.edges.then_some(&mut matched), progress);
+        let reclaimable = report::reclaimable_by_class(&groups);
+        let stats = report::duplicate_stats(&groups);
+        let mut groups = self.report_groups(groups, hashes);
+        // remote files would be downloaded again
+        if self.remote(&req.path)?.is_none() {
+            let checksum = |file: &FileInfo| match req.exact_by {
+                ExactBy::Bytes => source.storage.checksum(&file.path),
+                ExactBy::Pixels => Self::pixel_checksum(source.storage, &source.memo, file),
+            };
+            Self::find_identical(&mut groups, req.exact_by, &checksum);
+        }
+        if req.edges {
+            Self::add_edges(&mut groups, hashes, &matched, extra);
+        }
+        Ok((groups, reclaimable, stats))
+    }
+
+    /// groups the hashes of an earlier analysis again, e.g. with another `dist`, no image is read,
+    /// OCR matches aren't looked for again
+    pub(crate) fn regroup(&self, req: &AnalyzeRequest, mut hashes: Hashes, earlier: &AnalyzeResult) -> Result<AnalyzeResult> {
+        let storage = self.request_storage(req)?;
+        // ignored since
+        hashes.retain(|(file, _)| !self.ignored.excludes(&file.path));
+        let started = Instant::now();
+        let source = Source { storage: storage.as_ref(), tags: HashMap::new(), clock: StepClock::default(), memo: DecodeMemo::new(self.memory.as_ref()) };
+        let (groups, reclaimable, stats) = self.finish_groups(req, &source, &hashes, &[], &[], None)?;
+        let grouping = started.elapsed();
+        Ok(AnalyzeResult {
+            groups,
+            skipped: earlier.skipped.clone(),
+            corrupted: earlier.corrupted.clone(),
+            errors: earlier.errors.clone(),
+            reclaimable,
+            stats,
+            coverage: earlier.coverage.clone(),
+            concurrency: Vec::new(),
+            // nothing is listed or hashed again
+            timings: StepClock::default().timings(Duration::ZERO, grouping, grouping),
+            hashes,
+        })
+    }
+
+    /// a result of groups found by another tool, files which can't be read or are outside the libraries are errors
+    pub fn import(&self, groups: Vec<Vec<PathBuf>>) -> AnalyzeResult {
+        let mut errors = Vec::new();
+        let mut total = 0;
+        let groups: Groups = groups
+            .into_iter()
+            .map(|group| {
+                total += group.len();
+                let mut files = Vec::with_capacity(group.len());
+                for path in group {
+                    let file = match self.sandbox.check(&path) {
+                        Err(Denied::Outside) => Err(eyre::eyre!("outside of the libraries")),
+                        // missing files fail to be read below
+                        Ok(()) | Err(Denied::NotFound) => FileInfo::from_path(path.clone()),
+                    };
+                    match file {
+                        Ok(file) => files.push(FileInfo { storage_class: self.roots.classify(&file.path), ..file }),
+                        Err(err) => errors.push(FileError::new(path, format!("{:#}", err))),
+                    }
+                }
+                files
+            })
+            .filter(|files| files.len() > 1)
+            .collect();
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        let hashed = groups.iter().map(Vec::len).sum();
+        let reclaimable = report::reclaimable_by_class(&groups);
+        let stats = report::duplicate_stats(&groups);
+        // no hashes, so no distances either
+        let mut groups: Vec<_> = groups.into_iter().map(|files| Group::new(files, &[], &self.roots, &self.keep_rules)).collect();
+        let local = Local(self.sandbox.clone());
+        Self::find_identical(&mut groups, ExactBy::Bytes, &|file: &FileInfo| local.checksum(&file.path));
+        let coverage = Coverage { hashed, deferred: 0, total, reused: 0, refined: None };
+        AnalyzeResult { groups, skipped: Vec::new(), corrupted: Vec::new(), errors, reclaimable, stats, coverage, concurrency: Vec::new(), timings: Timings::default(), hashes: Vec::new() }
     }
 }
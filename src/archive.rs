@@ -0,0 +1,254 @@
+//! Images inside ZIP archives, e.g. exported albums and `.cbz` comics, named `<archive>!<entry>` like
+//! `/photos/album.zip!2019/beach.jpg`. Only stored and deflated entries are read, ZIP64 and nested archives aren't.
+//! Entries are hashed and grouped as any file, but nothing acts on them: the archive is left as it is.
+
+use eyre::{bail, eyre, Result};
+use flate2::{read::DeflateDecoder, Crc};
+use image::{DynamicImage, ImageFormat, ImageResult};
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::analyzer::{FileError, FileInfo, Listing, SkipReason, SkippedFile};
+use crate::storage::{self, Local, Storage};
+
+const SEPARATOR: char = '!';
+const EXTENSIONS: [&str; 2] = ["zip", "cbz"];
+
+pub(crate) const END_SIGNATURE: u32 = 0x0605_4b50;
+pub(crate) const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+pub(crate) const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+const END_LEN: usize = 22;
+const MAX_COMMENT_LEN: usize = u16::MAX as usize;
+
+pub(crate) const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// entries larger than this are left out, rather than inflated into memory
+const MAX_ENTRY_SIZE: u64 = 512 * 1024 * 1024;
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| EXTENSIONS.iter().any(|known| extension.eq_ignore_ascii_case(known)))
+}
+
+/// the archive and the name of the entry, `None` for other paths
+pub fn split(path: &Path) -> Option<(PathBuf, String)> {
+    let path = path.to_str()?;
+    path.match_indices(SEPARATOR).find_map(|(at, _)| {
+        let archive = Path::new(&path[..at]);
+        is_archive(archive).then(|| (archive.to_owned(), path[at + 1..].to_owned()))
+    })
+}
+
+pub fn is_entry(path: &Path) -> bool {
+    split(path).is_some()
+}
+
+pub fn entry_path(archive: &Path, name: &str) -> PathBuf {
+    let mut path = OsString::from(archive);
+    path.push(SEPARATOR.to_string());
+    path.push(name);
+    PathBuf::from(path)
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16> {
+    let bytes = data.get(at..at + 2).ok_or_else(|| eyre!("cut short"))?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32> {
+    let bytes = data.get(at..at + 4).ok_or_else(|| eyre!("cut short"))?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// a file of the central directory
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    method: u16,
+    encrypted: bool,
+    crc: u32,
+    compressed: u64,
+    /// uncompressed
+    pub size: u64,
+    /// of the local header
+    offset: u64,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    fn readable(&self) -> bool {
+        !self.encrypted && matches!(self.method, STORED | DEFLATED) && self.size <= MAX_ENTRY_SIZE
+    }
+
+    /// dot files and the resource forks macOS adds, as they are when hidden
+    fn is_hidden(&self) -> bool {
+        self.name.split('/').any(|name| name.starts_with('.') || name == "__MACOSX")
+    }
+}
+
+/// the files of the archive, from its central directory
+pub fn entries(archive: &Path) -> Result<Vec<Entry>> {
+    let mut file = File::open(archive)?;
+    let len = file.metadata()?.len();
+    let tail_len = len.min((END_LEN + MAX_COMMENT_LEN) as u64);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let Some(end) = (0..tail.len().saturating_sub(END_LEN - 1)).rev().find(|&at| u32_at(&tail, at).is_ok_and(|sig| sig == END_SIGNATURE)) else {
+        bail!("not a ZIP archive");
+    };
+    let count = u16_at(&tail, end + 10)?;
+    let (dir_len, dir_offset) = (u32_at(&tail, end + 12)?, u32_at(&tail, end + 16)?);
+    if count == u16::MAX || dir_len == u32::MAX || dir_offset == u32::MAX {
+        bail!("ZIP64 archives aren't supported");
+    }
+
+    let mut dir = vec![0; dir_len as usize];
+    file.seek(SeekFrom::Start(dir_offset as u64))?;
+    file.read_exact(&mut dir)?;
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut at = 0;
+    for _ in 0..count {
+        if u32_at(&dir, at)? != CENTRAL_SIGNATURE {
+            bail!("broken central directory");
+        }
+        let flags = u16_at(&dir, at + 8)?;
+        let name_len = u16_at(&dir, at + 28)? as usize;
+        let extra_len = u16_at(&dir, at + 30)? as usize;
+        let comment_len = u16_at(&dir, at + 32)? as usize;
+        let name = dir.get(at + 46..at + 46 + name_len).ok_or_else(|| eyre!("cut short"))?;
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(&dir, at + 10)?,
+            encrypted: flags & 1 != 0,
+            crc: u32_at(&dir, at + 16)?,
+            compressed: u32_at(&dir, at + 20)? as u64,
+            size: u32_at(&dir, at + 24)? as u64,
+            offset: u32_at(&dir, at + 42)? as u64,
+        });
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// the content of the entry, checked against its CRC
+pub fn read(archive: &Path, entry: &Entry) -> Result<Vec<u8>> {
+    if !entry.readable() {
+        bail!("unsupported entry {}", entry.name);
+    }
+    let mut file = File::open(archive)?;
+    let mut header = [0; 30];
+    file.seek(SeekFrom::Start(entry.offset))?;
+    file.read_exact(&mut header)?;
+    if u32_at(&header, 0)? != LOCAL_SIGNATURE {
+        bail!("broken local header of {}", entry.name);
+    }
+    let skip = u16_at(&header, 26)? as i64 + u16_at(&header, 28)? as i64;
+    file.seek(SeekFrom::Current(skip))?;
+
+    let compressed = file.take(entry.compressed);
+    let mut data = Vec::with_capacity(entry.size as usize);
+    match entry.method {
+        STORED => compressed.take(entry.size).read_to_end(&mut data)?,
+        _ => DeflateDecoder::new(compressed).take(entry.size).read_to_end(&mut data)?,
+    };
+    let mut crc = Crc::new();
+    crc.update(&data);
+    if data.len() as u64 != entry.size || crc.sum() != entry.crc {
+        bail!("{} is cut short or corrupted", entry.name);
+    }
+    Ok(data)
+}
+
+/// the images of the archive, with the times of the archive itself
+fn list(archive: &Path) -> Result<Listing> {
+    let info = FileInfo::from_path(archive.to_owned())?;
+    let mut listing = Listing::default();
+    for entry in entries(archive)?.into_iter().filter(|entry| !entry.is_dir()) {
+        let path = entry_path(archive, &entry.name);
+        // by the extension, sniffing the content would inflate every entry
+        match ImageFormat::from_path(&entry.name) {
+            _ if entry.is_hidden() => listing.skipped.push(SkippedFile::new(path, SkipReason::Hidden)),
+            Ok(format) if entry.readable() => {
+                listing.files.push(FileInfo { path, size: entry.size, ..info.clone() });
+                *listing.formats.entry(format!("{:?}", format).to_lowercase()).or_default() += 1;
+            }
+            _ => listing.skipped.push(SkippedFile::new(path, SkipReason::Unsupported)),
+        }
+    }
+    Ok(listing)
+}
+
+/// local folders with the images in their archives
+pub struct Archives(pub Local);
+
+impl Archives {
+    fn read_entry(&self, archive: &Path, name: &str) -> Result<Vec<u8>> {
+        let entries = entries(archive)?;
+        let Some(entry) = entries.iter().find(|entry| entry.name == name) else {
+            bail!("no {} in {}", name, archive.display());
+        };
+        read(archive, entry)
+    }
+}
+
+impl Storage for Archives {
+    fn scan(&self, dir: &Path) -> Result<Listing> {
+        let mut listing = self.0.scan(dir)?;
+        let (archives, skipped): (Vec<_>, Vec<_>) = listing
+            .skipped
+            .into_iter()
+            .partition(|file| matches!(file.reason(), SkipReason::Unsupported) && is_archive(file.path()));
+        listing.skipped = skipped;
+        for archive in archives {
+            match list(archive.path()) {
+                Ok(found) => listing = listing.merge(found),
+                Err(err) => listing.errors.push(FileError::new(archive.path().to_owned(), format!("{:#}", err))),
+            }
+        }
+        listing.sort();
+        Ok(listing)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        match split(path) {
+            Some((archive, name)) => self.read_entry(&archive, &name),
+            None => self.0.read(path),
+        }
+    }
+
+    fn checksum(&self, path: &Path) -> Result<String> {
+        match is_entry(path) {
+            true => Ok(sha256::digest(self.read(path)?.as_slice())),
+            false => self.0.checksum(path),
+        }
+    }
+
+    fn open(&self, path: &Path, size: u32) -> ImageResult<(DynamicImage, io::Result<bool>)> {
+        match is_entry(path) {
+            true => storage::decode(&self.read(path).map_err(|err| io::Error::other(format!("{:#}", err)))?, size),
+            false => self.0.open(path, size),
+        }
+    }
+
+    fn decoded_size(&self, path: &Path, size: u32) -> Option<u64> {
+        match is_entry(path) {
+            true => None,
+            false => self.0.decoded_size(path, size),
+        }
+    }
+
+    fn preview(&self, path: &Path, size: u32) -> Option<(DynamicImage, io::Result<bool>)> {
+        match is_entry(path) {
+            true => None,
+            false => self.0.preview(path, size),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! The built client, from `--static-dir` or compiled into the binary with the `embed` feature.
+//! Debug builds read the embedded files from `client/dist` on each request, so they stay in sync.
+
+#[cfg(feature = "embed")]
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use axum::{routing::get_service, Router};
+use std::path::PathBuf;
+use tower_http::services::{ServeDir, ServeFile};
+
+#[cfg(feature = "embed")]
+use crate::conditional;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assets {
+    Dir(PathBuf),
+    #[cfg(feature = "embed")]
+    Embedded,
+}
+
+/// the binary's own copy when it has one
+impl Default for Assets {
+    fn default() -> Self {
+        #[cfg(feature = "embed")]
+        return Self::Embedded;
+        #[cfg(not(feature = "embed"))]
+        Self::Dir(PathBuf::from("client/dist"))
+    }
+}
+
+#[cfg(feature = "embed")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "client/dist"]
+struct Client;
+
+#[cfg(feature = "embed")]
+fn serve_embedded(path: &str, headers: &HeaderMap) -> Response {
+    let Some(file) = Client::get(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let hash: String = file.metadata.sha256_hash().iter().map(|byte| format!("{:02x}", byte)).collect();
+    let etag = conditional::tag(&hash);
+    if conditional::matches(headers, &etag) {
+        return conditional::not_modified(etag);
+    }
+    let content_type = HeaderValue::from_str(file.metadata.mimetype()).unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    ([(header::CONTENT_TYPE, content_type), (header::ETAG, etag)], file.data).into_response()
+}
+
+impl Assets {
+    /// the client, it holds no library data
+    pub fn routes<S>(&self) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        match self {
+            Self::Dir(dir) => Router::new()
+                .route("/", get_service(ServeFile::new(dir.join("index.html"))))
+                .nest_service("/static", ServeDir::new(dir))
+                .nest_service("/assets", ServeDir::new(dir.join("assets"))),
+            #[cfg(feature = "embed")]
+            Self::Embedded => Router::new()
+                .route("/", get(|headers: HeaderMap| async move { serve_embedded("index.html", &headers) }))
+                .route(
+                    "/static/*path",
+                    get(|Path(path): Path<String>, headers: HeaderMap| async move { serve_embedded(&path, &headers) }),
+                )
+                .route(
+                    "/assets/*path",
+                    get(|Path(path): Path<String>, headers: HeaderMap| async move {
+                        serve_embedded(&format!("assets/{}", path), &headers)
+                    }),
+                ),
+        }
+    }
+}
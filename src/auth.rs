@@ -0,0 +1,75 @@
+//! Optional credentials every request has to carry, on top of tenant API keys.
+//! Share links carry their own token instead, let through when it is a known one and limited by the share guard.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::error::{ErrorBody, ErrorCode};
+
+/// whether a share token was minted and hasn't expired
+pub type ShareCheck = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// `Authorization: Bearer <token>`
+    Token(String),
+    /// HTTP basic auth, the browser asks for it
+    Basic { user: String, password: String },
+}
+
+/// equal length inputs take the same time whichever bytes differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl Auth {
+    /// the `Authorization` header sent by a permitted client
+    fn expected(&self) -> String {
+        match self {
+            Self::Token(token) => format!("Bearer {}", token),
+            Self::Basic { user, password } => format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password))),
+        }
+    }
+
+    /// whether the value of an `Authorization` header is the expected one
+    pub fn permits_header(&self, header: &[u8]) -> bool {
+        constant_time_eq(header, self.expected().as_bytes())
+    }
+
+    fn permits<B>(&self, request: &Request<B>, query: &HashMap<String, String>) -> bool {
+        let header = request.headers().get(header::AUTHORIZATION).map(HeaderValue::as_bytes);
+        if header.is_some_and(|header| self.permits_header(header)) {
+            return true;
+        }
+        // browsers can't set headers on image loads and websockets, hence the query parameter
+        match (self, query.get("accessToken")) {
+            (Self::Token(token), Some(given)) => constant_time_eq(given.as_bytes(), token.as_bytes()),
+            _ => false,
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        match self {
+            Self::Token(_) => "Bearer",
+            Self::Basic { .. } => "Basic realm=\"image-analyzer\"",
+        }
+    }
+}
+
+pub async fn guard<B>(State((auth, shares)): State<(Arc<Auth>, ShareCheck)>, request: Request<B>, next: Next<B>) -> Response {
+    let query = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .map(|Query(query)| query)
+        .unwrap_or_default();
+
+    if query.get("token").is_some_and(|token| shares(token)) || auth.permits(&request, &query) {
+        return next.run(request).await;
+    }
+    let body = ErrorBody::new(ErrorCode::Unauthorized, "missing or wrong credentials");
+    ([(header::WWW_AUTHENTICATE, auth.challenge())], body).into_response()
+}
@@ -0,0 +1,154 @@
+//! Rules resolving the groups of watched folders on their own, after each scan changing them, e.g. deleting
+//! the copies under `Downloads` of originals under `Photos`. They are the `[[auto-resolve]]` tables of the config file,
+//! the first one with actions for a group is applied to it. Protected copies are left alone, as by any plan.
+
+use serde::Deserialize;
+use std::{
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::analyzer::{FileInfo, Group};
+use crate::archive;
+use crate::protect::Protected;
+use crate::resolve::{Action, Plan, PlannedAction, PlannedGroup};
+use crate::tasks::to_millis;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// `*` and `?` within a name
+fn matches_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_name(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && matches_name(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches_name(rest, &name[1..]),
+    }
+}
+
+/// `**` for any number of folders
+fn matches_names(pattern: &[&str], names: &[String]) -> bool {
+    match pattern.split_first() {
+        None => names.is_empty(),
+        Some((&"**", rest)) => (0..=names.len()).any(|skip| matches_names(rest, &names[skip..])),
+        Some((first, rest)) => names
+            .split_first()
+            .is_some_and(|(name, left)| matches_name(first.as_bytes(), name.as_bytes()) && matches_names(rest, left)),
+    }
+}
+
+/// matches the whole path, e.g. `/photos/**/Downloads/*.jpg`
+fn matches_glob(pattern: &str, path: &Path) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|name| !name.is_empty()).collect();
+    let names: Vec<String> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    matches_names(&pattern, &names)
+}
+
+/// the files a rule applies to, any file when empty
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FileFilter {
+    /// glob on the whole path, `*` and `?` within a name and `**` for any number of folders
+    pub path: Option<String>,
+    /// by the file date
+    pub older_than_days: Option<u64>,
+    /// extensions, in any case
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// width times height, read from the headers
+    pub min_pixels: Option<u64>,
+    pub max_pixels: Option<u64>,
+}
+
+impl FileFilter {
+    fn matches(&self, file: &FileInfo, now: u64) -> bool {
+        if self.path.as_ref().is_some_and(|pattern| !matches_glob(pattern, &file.path)) {
+            return false;
+        }
+        if self.older_than_days.is_some_and(|days| now.saturating_sub(file.date) < days.saturating_mul(DAY_MS)) {
+            return false;
+        }
+        if !self.formats.is_empty() {
+            let extension = file.path.extension().map(|extension| extension.to_string_lossy());
+            if !extension.is_some_and(|extension| self.formats.iter().any(|format| format.eq_ignore_ascii_case(&extension))) {
+                return false;
+            }
+        }
+        if self.min_pixels.is_some() || self.max_pixels.is_some() {
+            let Ok((width, height)) = image::image_dimensions(&file.path) else {
+                return false;
+            };
+            let pixels = width as u64 * height as u64;
+            if self.min_pixels.is_some_and(|min| pixels < min) || self.max_pixels.is_some_and(|max| pixels > max) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AutoRule {
+    /// in the logs
+    pub name: String,
+    /// the copies acted on
+    #[serde(default)]
+    pub copies: FileFilter,
+    /// the copy to keep, the group is left alone without one, the suggested copy wins when it matches
+    #[serde(default)]
+    pub keep: FileFilter,
+    /// only the copies with the same content as the kept one
+    #[serde(default)]
+    pub exact_only: bool,
+    /// `action = "delete"`, `"quarantine"` or `"move"` with a `target`, as in plans
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+impl AutoRule {
+    fn plan(&self, group: &Group, protected: &Protected, now: u64) -> Option<PlannedGroup> {
+        let files = group.files();
+        let suggested = group.suggestion().and_then(|suggestion| files.iter().position(|file| file.path == suggestion.keep));
+        let kept = suggested
+            .filter(|&i| self.keep.matches(&files[i], now))
+            .or_else(|| files.iter().position(|file| self.keep.matches(file, now)))?;
+        let same = group.identical().iter().find(|same| same.contains(&kept));
+        let actions: Vec<_> = files
+            .iter()
+            .enumerate()
+            .filter(|&(i, file)| {
+                i != kept
+                    && (!self.exact_only || same.is_some_and(|same| same.contains(&i)))
+                    && !protected.covers(&file.path)
+                    && !archive::is_entry(&file.path)
+                    && self.copies.matches(file, now)
+            })
+            .map(|(_, file)| PlannedAction { path: file.path.clone(), action: self.action.clone() })
+            .collect();
+        (!actions.is_empty()).then(|| PlannedGroup { keep: files[kept].path.clone(), actions })
+    }
+}
+
+/// the groups some rule has actions for, by the first of them
+pub fn plan(rules: &[AutoRule], groups: &[Group], protected: &Protected) -> Plan {
+    let now = to_millis(SystemTime::now());
+    let groups = groups
+        .iter()
+        .filter_map(|group| {
+            rules.iter().find_map(|rule| {
+                let planned = rule.plan(group, protected, now)?;
+                let paths: Vec<&PathBuf> = planned.actions.iter().map(|action| &action.path).collect();
+                tracing::info!(rule = rule.name, keep = planned.keep.to_str(), ?paths, "auto-resolving group");
+                Some(planned)
+            })
+        })
+        .collect();
+    Plan { groups }
+}
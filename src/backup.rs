@@ -0,0 +1,51 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+use utoipa::ToSchema;
+
+/// exclude file flavours of common backup tools
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExcludeFormat {
+    /// `restic backup --exclude-file`
+    Restic,
+    /// `borg create --exclude-from`
+    Borg,
+    /// `rsync --exclude-from`
+    Rsync,
+}
+
+fn escape_glob(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Exclude list for files that don't need to be backed up, one pattern per line.
+/// Expects absolute paths.
+pub fn exclude_list(format: ExcludeFormat, files: &[PathBuf]) -> String {
+    let mut list = String::new();
+
+    for file in files {
+        let pattern = match format {
+            ExcludeFormat::Restic => escape_glob(&file.to_string_lossy()),
+            // literal full path match, no escaping needed
+            ExcludeFormat::Borg => format!("pf:{}", file.to_string_lossy()),
+            // rsync anchors patterns at the transfer root, which is unknown here,
+            // but names of removed files are unique so matching the name is enough
+            ExcludeFormat::Rsync => match file.file_name() {
+                Some(name) => escape_glob(&name.to_string_lossy()),
+                None => continue,
+            },
+        };
+
+        list.push_str(&pattern);
+        list.push('\n');
+    }
+
+    list
+}
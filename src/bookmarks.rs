@@ -0,0 +1,128 @@
+//! Folders saved to be scanned again without typing their path, kept in `bookmarks.json`.
+//! They are shared by every session, like the roots, the runs listed with them are those the session sees.
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::runs::RunSummary;
+use crate::schema;
+
+/// schema version of the bookmarks file
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmark {
+    pub id: Uuid,
+    pub name: String,
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+}
+
+/// a bookmark to add, or the new path and name of one
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkRequest {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    /// the name of the folder when not given
+    pub name: Option<String>,
+}
+
+impl BookmarkRequest {
+    fn name(&self) -> String {
+        match (&self.name, self.path.file_name()) {
+            (Some(name), _) => name.clone(),
+            (None, Some(name)) => name.to_string_lossy().into_owned(),
+            (None, None) => self.path.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+/// a bookmark as listed, with the latest run of its folder
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkStatus {
+    #[serde(flatten)]
+    pub bookmark: Bookmark,
+    /// `None` when the folder wasn't analyzed yet
+    pub last_run: Option<RunSummary>,
+}
+
+#[derive(Debug)]
+pub struct Bookmarks {
+    file: PathBuf,
+    bookmarks: RwLock<Vec<Bookmark>>,
+}
+
+impl Bookmarks {
+    pub fn open<T>(file: T) -> Result<Self>
+    where
+        PathBuf: From<T>
+    {
+        let file = PathBuf::from(file);
+        let bookmarks = match file.exists() {
+            true => schema::decode(&fs::read(&file)?, VERSION, schema::unversioned_to_v1)?.0,
+            false => Vec::new(),
+        };
+        Ok(Self { file, bookmarks: RwLock::new(bookmarks) })
+    }
+
+    fn save(&self, bookmarks: &[Bookmark]) -> Result<()> {
+        fs::write(&self.file, schema::encode_pretty(&bookmarks, VERSION)?)?;
+        Ok(())
+    }
+
+    /// in the order they were added
+    pub fn list(&self) -> Vec<Bookmark> {
+        self.bookmarks.read().unwrap().clone()
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Bookmark> {
+        self.bookmarks.read().unwrap().iter().find(|bookmark| bookmark.id == id).cloned()
+    }
+
+    /// whether another bookmark than `except` is of the folder
+    pub fn contains(&self, path: &Path, except: Option<Uuid>) -> bool {
+        self.bookmarks.read().unwrap().iter().any(|bookmark| bookmark.path == path && Some(bookmark.id) != except)
+    }
+
+    pub fn add(&self, request: &BookmarkRequest) -> Result<Bookmark> {
+        let bookmark = Bookmark { id: Uuid::new_v4(), name: request.name(), path: request.path.clone() };
+        let mut bookmarks = self.bookmarks.write().unwrap();
+        bookmarks.push(bookmark.clone());
+        self.save(&bookmarks)?;
+        Ok(bookmark)
+    }
+
+    /// `None` if there is no such bookmark
+    pub fn update(&self, id: Uuid, request: &BookmarkRequest) -> Result<Option<Bookmark>> {
+        let mut bookmarks = self.bookmarks.write().unwrap();
+        let Some(bookmark) = bookmarks.iter_mut().find(|bookmark| bookmark.id == id) else {
+            return Ok(None);
+        };
+        *bookmark = Bookmark { id, name: request.name(), path: request.path.clone() };
+        let bookmark = bookmark.clone();
+        self.save(&bookmarks)?;
+        Ok(Some(bookmark))
+    }
+
+    /// `false` if there was no such bookmark
+    pub fn remove(&self, id: Uuid) -> Result<bool> {
+        let mut bookmarks = self.bookmarks.write().unwrap();
+        let len = bookmarks.len();
+        bookmarks.retain(|bookmark| bookmark.id != id);
+        if bookmarks.len() == len {
+            return Ok(false);
+        }
+        self.save(&bookmarks)?;
+        Ok(true)
+    }
+}
@@ -1,58 +1,503 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::Debug,
     hash::Hash,
+    io::Write,
+    path::{Path, PathBuf},
     sync::mpsc,
     thread,
+    time::{Duration, SystemTime},
 };
-use eyre::Result;
+use eyre::{bail, Result};
+use utoipa::ToSchema;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Serialize, de::DeserializeOwned};
 use tokio::sync::oneshot;
 
-#[derive(Debug)]
+use crate::metrics::metrics;
+use crate::schema::Migration;
+
+/// schema version of the cache database
+const VERSION: u32 = 2;
+/// the format of the entries written before they had one
+const FIRST_FORMAT: u32 = 1;
+
+/// tells whether a cached value still holds, e.g. for a file which may have changed since
+type Validator<V> = Box<dyn FnOnce(&V) -> bool + Send>;
+
 enum CacheCommand<K, V> {
-    Get(K, oneshot::Sender<Option<V>>),
+    Get(K, Option<Validator<V>>, oneshot::Sender<Option<V>>),
     Set(K, V),
+    Stats(oneshot::Sender<Result<CacheStats>>),
+    /// drops entries created more than that many seconds ago, all of them when `None`
+    Prune(Option<u64>, oneshot::Sender<Result<usize>>),
+    Export(oneshot::Sender<Result<Vec<Row>>>),
+    Import(Vec<Row>, oneshot::Sender<Result<usize>>),
+    /// commits pending writes
+    Flush(oneshot::Sender<Result<()>>),
+    Ping(oneshot::Sender<Result<()>>),
+}
+
+/// serialized key, serialized value, creation time and format
+type Row = (String, String, i64, u32);
+
+fn first_format() -> u32 {
+    FIRST_FORMAT
+}
+
+/// line of an exported cache, JSON lines keep big dumps streamable
+#[derive(serde::Deserialize)]
+struct ExportedEntry<K, V> {
+    key: K,
+    value: V,
+    created: i64,
+    #[serde(default = "first_format")]
+    format: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counters {
+    hits: u64,
+    misses: u64,
+    stale: u64,
+}
+
+/// bounds of the in-memory part of the cache, the database itself isn't limited
+#[derive(Debug, Clone, Copy)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    /// approximated by the serialized size of keys and values
+    pub max_bytes: usize,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self { max_entries: 100_000, max_bytes: 64 << 20 }
+    }
+}
+
+impl CacheLimits {
+    /// defaults overridden by `CACHE_MAX_ENTRIES` and `CACHE_MAX_BYTES`
+    pub fn from_env() -> Result<Self> {
+        let mut limits = Self::default();
+        if let Ok(max_entries) = std::env::var("CACHE_MAX_ENTRIES") {
+            limits.max_entries = max_entries.parse()?;
+        }
+        if let Ok(max_bytes) = std::env::var("CACHE_MAX_BYTES") {
+            limits.max_bytes = max_bytes.parse()?;
+        }
+        Ok(limits)
+    }
 }
 
-fn task_cache<K, V>(commands: mpsc::Receiver<CacheCommand<K, V>>)
+/// least recently used entries are evicted first
+struct Lru<K, V> {
+    entries: HashMap<K, (V, usize, u64)>,
+    /// access tick to key, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    bytes: usize,
+    limits: CacheLimits,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Lru<K, V> {
+    fn new(limits: CacheLimits) -> Self {
+        Self { entries: HashMap::new(), order: BTreeMap::new(), tick: 0, bytes: 0, limits }
+    }
+
+    fn touch(&mut self, key: &K) -> Option<&V> {
+        self.tick += 1;
+        let (_, _, tick) = self.entries.get_mut(key)?;
+        self.order.remove(tick);
+        *tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.entries.get(key).map(|(val, _, _)| val)
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.touch(key).cloned()
+    }
+
+    fn insert(&mut self, key: K, val: V, size: usize) {
+        self.tick += 1;
+        if let Some((_, old_size, old_tick)) = self.entries.insert(key.clone(), (val, size, self.tick)) {
+            self.bytes -= old_size;
+            self.order.remove(&old_tick);
+        }
+        self.bytes += size;
+        self.order.insert(self.tick, key);
+
+        while self.entries.len() > self.limits.max_entries || self.bytes > self.limits.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.entries.remove(&oldest) {
+                self.bytes -= size;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+}
+
+#[derive(Debug, serde::Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub entries: u64,
+    /// entries held in memory and their approximate size
+    pub memory_entries: usize,
+    pub memory_bytes: usize,
+    /// lookups since startup
+    pub hits: u64,
+    pub misses: u64,
+    /// found, but for a file which changed since, they are hashed again as misses are
+    pub stale: u64,
+    /// of the hits among all lookups
+    pub hit_rate: f64,
+    /// size of the database in bytes
+    pub disk_size: u64,
+    /// age of the oldest entry in seconds
+    pub oldest_age: Option<u64>,
+}
+
+/// SQLite table behind the in-memory map, keys and values are stored as JSON
+struct Store {
+    db: Connection,
+    path: PathBuf,
+    /// of the values written and read, those of other formats are dropped on open
+    format: u32,
+    /// writes not yet committed, flushed in a single transaction
+    pending: Vec<(String, String)>,
+}
+
+impl Store {
+    fn open(path: &Path, format: u32) -> Result<(Self, Vec<Migration>)> {
+        let mut db = Connection::open(path)?;
+        let mut migrations: Vec<_> = Self::migrate(&mut db)?.into_iter().collect();
+        migrations.extend(Self::invalidate(&db, format)?);
+        Ok((Self { db, path: path.to_owned(), format, pending: Vec::new() }, migrations))
+    }
+
+    /// drops the entries of other formats, e.g. hashes computed another way by an older version
+    fn invalidate(db: &Connection, format: u32) -> Result<Option<Migration>> {
+        let oldest: Option<u32> = db.query_row("SELECT MIN(format) FROM cache WHERE format != ?1", [format], |row| row.get(0))?;
+        let Some(oldest) = oldest else {
+            return Ok(None);
+        };
+        let removed = db.execute("DELETE FROM cache WHERE format != ?1", [format])?;
+        tracing::info!(removed, "cache entries of format {} dropped for format {}", oldest, format);
+        Ok(Some(Migration::new("cache entries", oldest, format, removed)))
+    }
+
+    /// brings the database to the current schema version, kept in `user_version`
+    fn migrate(db: &mut Connection) -> Result<Option<Migration>> {
+        let found: u32 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if found > VERSION {
+            bail!("cache schema version {} is newer than the supported {}", found, VERSION);
+        }
+        if found == VERSION {
+            return Ok(None);
+        }
+
+        let tx = db.transaction()?;
+        for version in found..VERSION {
+            match version {
+                // unversioned databases already have the table
+                0 => tx.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS cache (
+                        key TEXT PRIMARY KEY,
+                        value TEXT NOT NULL,
+                        created INTEGER NOT NULL
+                    )"
+                )?,
+                // entries written so far are of the first format
+                1 => tx.execute_batch(&format!("ALTER TABLE cache ADD COLUMN format INTEGER NOT NULL DEFAULT {}", FIRST_FORMAT))?,
+                _ => bail!("no cache migration from version {}", version),
+            }
+        }
+        tx.pragma_update(None, "user_version", VERSION)?;
+        let items: i64 = tx.query_row("SELECT COUNT(*) FROM cache", [], |row| row.get(0))?;
+        tx.commit()?;
+
+        Ok(Some(Migration::new("cache", found, VERSION, items as usize)))
+    }
+
+    /// the value along with its serialized size
+    fn get<K: Serialize, V: DeserializeOwned>(&self, key: &K) -> Result<Option<(V, usize)>> {
+        let key = serde_json::to_string(key)?;
+        let value: Option<String> = self.db
+            .query_row("SELECT value FROM cache WHERE key = ?1 AND format = ?2", rusqlite::params![key, self.format], |row| row.get(0))
+            .optional()?;
+
+        match value {
+            Some(value) => Ok(Some((serde_json::from_str(&value)?, key.len() + value.len()))),
+            None => Ok(None),
+        }
+    }
+
+    /// returns the serialized size of the entry
+    fn set<K: Serialize, V: Serialize>(&mut self, key: &K, val: &V) -> Result<usize> {
+        let key = serde_json::to_string(key)?;
+        let val = serde_json::to_string(val)?;
+        let size = key.len() + val.len();
+        self.pending.push((key, val));
+        Ok(size)
+    }
+
+    fn stats<K, V>(&self, counters: Counters, memory: &Lru<K, V>) -> Result<CacheStats> {
+        let (entries, oldest): (i64, Option<i64>) = self.db
+            .query_row("SELECT COUNT(*), MIN(created) FROM cache", [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let now = now_secs()?;
+        let lookups = counters.hits + counters.misses + counters.stale;
+        let hit_rate = if lookups == 0 { 0.0 } else { counters.hits as f64 / lookups as f64 };
+
+        // write-ahead log and journal live next to the database when present
+        let mut disk_size = 0;
+        for suffix in ["", "-wal", "-journal"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            if let Ok(metadata) = std::fs::metadata(path) {
+                disk_size += metadata.len();
+            }
+        }
+
+        Ok(CacheStats {
+            entries: entries as u64,
+            memory_entries: memory.entries.len(),
+            memory_bytes: memory.bytes,
+            hits: counters.hits,
+            misses: counters.misses,
+            stale: counters.stale,
+            hit_rate,
+            disk_size,
+            oldest_age: oldest.map(|created| now.saturating_sub(created) as u64),
+        })
+    }
+
+    fn prune(&mut self, older_than: Option<u64>) -> Result<usize> {
+        self.flush()?;
+        let removed = match older_than {
+            Some(age) => {
+                let cutoff = now_secs()?.saturating_sub(age as i64);
+                self.db.execute("DELETE FROM cache WHERE created < ?1", [cutoff])?
+            }
+            None => self.db.execute("DELETE FROM cache", [])?,
+        };
+        Ok(removed)
+    }
+
+    fn export(&mut self) -> Result<Vec<Row>> {
+        self.flush()?;
+        let mut select = self.db.prepare("SELECT key, value, created, format FROM cache ORDER BY key")?;
+        let rows = select
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(rows)
+    }
+
+    /// existing entries with the same keys are replaced, those of other formats are left out
+    fn import(&mut self, rows: Vec<Row>) -> Result<usize> {
+        self.flush()?;
+        let (rows, other): (Vec<_>, Vec<_>) = rows.into_iter().partition(|(_, _, _, format)| *format == self.format);
+        if !other.is_empty() {
+            tracing::warn!(entries = other.len(), "imported entries of another format left out");
+        }
+        let count = rows.len();
+        let tx = self.db.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO cache (key, value, created, format) VALUES (?1, ?2, ?3, ?4)"
+            )?;
+            for row in rows {
+                insert.execute(row)?;
+            }
+        }
+        tx.commit()?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let created = now_secs()?;
+        let tx = self.db.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO cache (key, value, created, format) VALUES (?1, ?2, ?3, ?4)"
+            )?;
+            for (key, val) in self.pending.drain(..) {
+                insert.execute((key, val, created, self.format))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn now_secs() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64)
+}
+
+fn handle_command<K, V>(
+    cache: &mut Lru<K, V>,
+    store: &mut Store,
+    counters: &mut Counters,
+    command: CacheCommand<K, V>,
+)
 where
-    K: Eq + Hash + Debug,
-    V: Clone,
+    K: Eq + Hash + Clone + Debug + Serialize,
+    V: Clone + Serialize + DeserializeOwned,
 {
-    let mut cache: HashMap<K, V> = HashMap::new();
+    match command {
+        CacheCommand::Get(key, valid, tx) => {
+            let mut val = cache.get(&key);
+            if val.is_none() {
+                match store.get::<K, V>(&key) {
+                    Ok(Some((found, size))) => {
+                        cache.insert(key.clone(), found.clone(), size);
+                        val = Some(found);
+                    }
+                    Ok(None) => {}
+                    Err(err) => tracing::error!("unable to read cached data for key {:?}: {:?}", key, err),
+                }
+            }
 
-    for command in commands {
-        match command {
-            CacheCommand::Get(key, tx) => {
-                if tx.send(cache.get(&key).cloned()).is_err() {
-                    tracing::error!("unable to send cached data for key {:?}", key);
+            let valid = match (&val, valid) {
+                (Some(found), Some(valid)) => valid(found),
+                _ => true,
+            };
+            let result = match (val.is_some(), valid) {
+                (true, true) => {
+                    counters.hits += 1;
+                    "hit"
+                }
+                (true, false) => {
+                    val = None;
+                    counters.stale += 1;
+                    "stale"
+                }
+                (false, _) => {
+                    counters.misses += 1;
+                    "miss"
                 }
+            };
+            metrics().cache_lookups.with_label_values(&[result]).inc();
+
+            if tx.send(val).is_err() {
+                tracing::error!("unable to send cached data for key {:?}", key);
             }
-            CacheCommand::Set(key, val) => {
-                cache.insert(key, val);
+        }
+        CacheCommand::Set(key, val) => {
+            match store.set(&key, &val) {
+                Ok(size) => cache.insert(key, val, size),
+                Err(err) => tracing::error!("unable to store cached data for key {:?}: {:?}", key, err),
             }
         }
+        CacheCommand::Ping(tx) => {
+            let pong = store.db.query_row("SELECT 1", [], |_| Ok(())).map_err(eyre::Report::from);
+            if tx.send(pong).is_err() {
+                tracing::error!("unable to answer a cache ping");
+            }
+        }
+        CacheCommand::Stats(tx) => {
+            // count what's still pending as well
+            let stats = store.flush().and_then(|_| store.stats(*counters, cache));
+            if tx.send(stats).is_err() {
+                tracing::error!("unable to send cache stats");
+            }
+        }
+        CacheCommand::Prune(older_than, tx) => {
+            // the in-memory map doesn't track ages, it's refilled from the store on demand
+            cache.clear();
+            let removed = store.prune(older_than);
+            if tx.send(removed).is_err() {
+                tracing::error!("unable to send pruned entry count");
+            }
+        }
+        CacheCommand::Export(tx) => {
+            if tx.send(store.export()).is_err() {
+                tracing::error!("unable to send exported cache");
+            }
+        }
+        CacheCommand::Import(rows, tx) => {
+            // imported values may replace ones held in memory
+            cache.clear();
+            if tx.send(store.import(rows)).is_err() {
+                tracing::error!("unable to send imported entry count");
+            }
+        }
+        CacheCommand::Flush(tx) => {
+            if tx.send(store.flush()).is_err() {
+                tracing::error!("unable to confirm the cache flush");
+            }
+        }
+    }
+}
+
+fn task_cache<K, V>(commands: mpsc::Receiver<CacheCommand<K, V>>, mut store: Store, limits: CacheLimits)
+where
+    K: Eq + Hash + Clone + Debug + Serialize,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    let mut cache = Lru::new(limits);
+    let mut counters = Counters::default();
+
+    for command in &commands {
+        handle_command(&mut cache, &mut store, &mut counters, command);
+
+        // batch everything queued up meanwhile into one transaction,
+        // committed before waiting for more so little is lost on a crash
+        while let Ok(command) = commands.try_recv() {
+            handle_command(&mut cache, &mut store, &mut counters, command);
+        }
+
+        if let Err(err) = store.flush() {
+            tracing::error!("unable to flush the cache: {:?}", err);
+        }
     }
 }
 
 pub struct Cache<K, V> {
     commands: mpsc::Sender<CacheCommand<K, V>>,
+    migrations: Vec<Migration>,
 }
 
 impl<K, V> Cache<K, V>
 where
-    K: Eq + Hash + Debug + Send + 'static,
-    V: Clone + Send + 'static,
+    K: Eq + Hash + Clone + Debug + Serialize + Send + 'static,
+    V: Clone + Serialize + DeserializeOwned + Send + 'static,
 {
-    pub fn new() -> Self {
+    /// Cache persisted into an SQLite database, survives restarts, recently used entries are kept in memory
+    /// within the limits. Values are of `format`, bumped when their meaning changes, entries of other formats are
+    /// dropped rather than taken for ones of this format.
+    pub fn open(path: &Path, limits: CacheLimits, format: u32) -> Result<Self> {
+        let (store, migrations) = Store::open(path, format)?;
         let (tx, rx) = mpsc::channel();
-        thread::spawn(|| task_cache(rx));
-        Self { commands: tx }
+        thread::spawn(move || task_cache(rx, store, limits));
+        Ok(Self { commands: tx, migrations })
+    }
+
+    /// upgrades of the database and entries dropped on open
+    pub fn migrations(&self) -> Vec<Migration> {
+        self.migrations.clone()
     }
 
     pub fn get(&self, key: K) -> Result<Option<V>> {
         let (tx, rx) = oneshot::channel();
-        self.commands.send(CacheCommand::Get(key, tx)).unwrap();
+        self.commands.send(CacheCommand::Get(key, None, tx)).unwrap();
+        Ok(rx.blocking_recv()?)
+    }
+
+    /// as `get`, values `valid` rejects aren't returned and are counted as stale rather than as hits
+    pub fn get_valid(&self, key: K, valid: impl FnOnce(&V) -> bool + Send + 'static) -> Result<Option<V>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(CacheCommand::Get(key, Some(Box::new(valid)), tx)).unwrap();
         Ok(rx.blocking_recv()?)
     }
 
@@ -60,4 +505,63 @@ where
         self.commands.send(CacheCommand::Set(key, val)).unwrap();
         Ok(())
     }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(CacheCommand::Stats(tx)).unwrap();
+        rx.blocking_recv()?
+    }
+
+    /// the database answers a query, fails rather than panics when the cache thread is gone
+    pub fn ping(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(CacheCommand::Ping(tx)).map_err(|_| eyre::eyre!("the cache thread stopped"))?;
+        rx.blocking_recv()?
+    }
+
+    /// removes entries older than the given age, or everything
+    pub fn prune(&self, older_than: Option<Duration>) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        let older_than = older_than.map(|age| age.as_secs());
+        self.commands.send(CacheCommand::Prune(older_than, tx)).unwrap();
+        rx.blocking_recv()?
+    }
+
+    /// waits until everything set so far is on disk
+    pub fn flush(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(CacheCommand::Flush(tx)).unwrap();
+        rx.blocking_recv()?
+    }
+
+    /// all entries as JSON lines of `{"key", "value", "created", "format"}`
+    pub fn export(&self) -> Result<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(CacheCommand::Export(tx)).unwrap();
+
+        let mut dump = Vec::new();
+        for (key, val, created, format) in rx.blocking_recv()?? {
+            // keys and values are stored as JSON already
+            writeln!(dump, r#"{{"key":{},"value":{},"created":{},"format":{}}}"#, key, val, created, format)?;
+        }
+        Ok(dump)
+    }
+
+    /// loads an export, keys can be rewritten on the way e.g. to move paths
+    pub fn import(&self, dump: &[u8], map_key: impl Fn(K) -> K) -> Result<usize>
+    where
+        K: DeserializeOwned,
+    {
+        let mut rows = Vec::new();
+        for line in dump.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            let entry: ExportedEntry<K, V> = serde_json::from_slice(line)?;
+            let key = serde_json::to_string(&map_key(entry.key))?;
+            let val = serde_json::to_string(&entry.value)?;
+            rows.push((key, val, entry.created, entry.format));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(CacheCommand::Import(rows, tx)).unwrap();
+        rx.blocking_recv()?
+    }
 }
@@ -0,0 +1,97 @@
+use eyre::Result;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, Rgba, RgbaImage};
+use serde::Serialize;
+use std::{io::Cursor, path::Path};
+use utoipa::ToSchema;
+
+use crate::analyzer::{self, Analyzer, HashSize, HashType};
+use crate::metadata::{self, ImageMetadata};
+
+/// longest side of the generated diff image
+const DIFF_SIZE: u32 = 1024;
+/// pixels are compared in blocks, so compression noise doesn't light up
+const BLOCK: u32 = 8;
+/// mean per-channel difference of a block to consider it changed
+const THRESHOLD: u32 = 24;
+
+fn block_diff(left: &DynamicImage, right: &DynamicImage, bx: u32, by: u32) -> u32 {
+    let (width, height) = left.dimensions();
+    let mut total = 0;
+    let mut count = 0;
+
+    for y in by..(by + BLOCK).min(height) {
+        for x in bx..(bx + BLOCK).min(width) {
+            let a = left.get_pixel(x, y);
+            let b = right.get_pixel(x, y);
+            total += a.0[..3]
+                .iter()
+                .zip(&b.0[..3])
+                .map(|(a, b)| a.abs_diff(*b) as u32)
+                .max()
+                .unwrap_or(0);
+            count += 1;
+        }
+    }
+
+    total / count.max(1)
+}
+
+/// Renders the left image dimmed, with blocks differing from the right image
+/// highlighted in red. The right image is stretched to the left one's size.
+pub fn diff_image(left: &Path, right: &Path) -> Result<Vec<u8>> {
+    let (left, _) = analyzer::open_image(left, DIFF_SIZE)?;
+    let (width, height) = left.dimensions();
+    let (right, _) = analyzer::open_image(right, DIFF_SIZE)?;
+    let right = right.resize_exact(width, height, image::imageops::FilterType::Triangle);
+
+    let mut output = RgbaImage::new(width, height);
+
+    for by in (0..height).step_by(BLOCK as usize) {
+        for bx in (0..width).step_by(BLOCK as usize) {
+            let changed = block_diff(&left, &right, bx, by) > THRESHOLD;
+
+            for y in by..(by + BLOCK).min(height) {
+                for x in bx..(bx + BLOCK).min(width) {
+                    let [r, g, b, _] = left.get_pixel(x, y).0;
+                    let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+                    let pixel = if changed {
+                        Rgba([(128 + luma / 2) as u8, (luma / 3) as u8, (luma / 3) as u8, 255])
+                    } else {
+                        let dim = (luma / 2) as u8;
+                        Rgba([dim, dim, dim, 255])
+                    };
+                    output.put_pixel(x, y, pixel);
+                }
+            }
+        }
+    }
+
+    let mut content = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba8(output).write_to(&mut content, ImageOutputFormat::Png)?;
+    Ok(content.into_inner())
+}
+
+/// two files side by side, e.g. a spot check outside of any group
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Comparison {
+    /// the same content byte for byte
+    pub identical: bool,
+    /// between their perceptual hashes
+    pub distance: u32,
+    pub a: ImageMetadata,
+    pub b: ImageMetadata,
+}
+
+/// files of other sizes aren't read in full for the checksums
+pub fn compare_files(engine: &Analyzer, hash_type: HashType, hash_size: HashSize, a: &Path, b: &Path) -> Result<Comparison> {
+    let read = |path: &Path| -> Result<_> {
+        let mut meta = metadata::read_metadata(path)?;
+        let hash = engine.hash_file(hash_type, hash_size, path)?;
+        meta.hash = Some(hash.to_base64());
+        Ok((meta, hash))
+    };
+    let ((a, hash_a), (b, hash_b)) = (read(a)?, read(b)?);
+    let identical = a.size == b.size && sha256::try_digest(&a.path)? == sha256::try_digest(&b.path)?;
+    Ok(Comparison { identical, distance: hash_a.dist(&hash_b), a, b })
+}
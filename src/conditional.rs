@@ -0,0 +1,45 @@
+//! Conditional GETs of images, so clients revalidate their copies instead of downloading them again.
+//! Tags derive from the path, size and mtime of a file, it isn't read for them.
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use eyre::Result;
+use std::{fs::Metadata, path::Path, time::SystemTime};
+
+/// `variant` tells apart renditions of the same file, e.g. thumbnail sizes
+pub fn file_tag(path: &Path, metadata: &Metadata, variant: &str) -> Result<HeaderValue> {
+    let mtime = metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH)?;
+    let key = format!("{}|{}|{}|{}", path.display(), metadata.len(), mtime.as_nanos(), variant);
+    Ok(tag(&sha256::digest(key)))
+}
+
+/// the hex digest quoted, as in `ETag` headers
+pub fn tag(digest: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", digest)).expect("hex digests are valid header values")
+}
+
+/// whether `If-None-Match` lists the tag, or is `*`
+pub fn matches(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let etag = etag.to_str().unwrap_or_default();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        // weak comparison, `W/` tags match their strong ones
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+pub fn not_modified(etag: HeaderValue) -> Response {
+    (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response()
+}
+
+/// `If-Modified-Since` is ignored next to `If-None-Match`, the tag tells more than the date
+pub fn prefer_tag<T>(request: &mut Request<T>) {
+    if request.headers().contains_key(header::IF_NONE_MATCH) {
+        request.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+}
@@ -0,0 +1,567 @@
+//! Server settings from the command line and an optional TOML file,
+//! settings given on the command line win over the ones in the file.
+
+use axum::http::HeaderValue;
+use clap::{Args, Parser, Subcommand};
+use eyre::Result;
+use serde::Deserialize;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::analyzer::{HashSize, HashType};
+use crate::assets::Assets;
+use crate::auth::Auth;
+use crate::autoresolve::AutoRule;
+use crate::import::ImportFormat;
+use crate::logging::{LogFormat, LogOptions};
+use crate::protect::Protected;
+use crate::resolve::{KeepRule, KeepRules};
+use crate::roots::StorageClass;
+use crate::s3::S3Config;
+use crate::sftp::SftpConfig;
+use crate::watch::WatchOptions;
+use crate::webdav::WebDavConfig;
+use url::Url;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Finds duplicate and similar images in folders.")]
+pub struct Cli {
+    /// TOML file with the same settings as the options, e.g. `port = 8080`
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub settings: Settings,
+    /// runs the server without one
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// writes the synthetic test images, for reproducing bugs
+    GenFixtures { dir: PathBuf },
+    /// dumps the hash cache for another machine
+    ExportCache { file: PathBuf },
+    /// loads a dump, moving paths under `from` to `to`
+    ImportCache {
+        file: PathBuf,
+        #[arg(requires = "to")]
+        from: Option<PathBuf>,
+        to: Option<PathBuf>,
+    },
+    /// stores the duplicate report of fdupes, jdupes or czkawka as a task, listed from the next start of the server
+    ImportReport {
+        file: PathBuf,
+        /// `fdupes`, `jdupes` or `czkawka`
+        #[arg(long, value_parser = parse_import_format)]
+        format: ImportFormat,
+    },
+    /// hashes batches of files for the server at `coordinator`, until stopped
+    Worker {
+        coordinator: Url,
+        /// how the server lists it [default: random]
+        #[arg(long)]
+        name: Option<String>,
+        /// `auth-token` of the server
+        #[arg(long)]
+        token: Option<String>,
+        /// `worker-token` of the server
+        #[arg(long)]
+        worker_token: String,
+        /// paths of the server under that folder are read below `to`, when the library is mounted elsewhere
+        #[arg(long, requires = "to")]
+        from: Option<PathBuf>,
+        #[arg(long, requires = "from")]
+        to: Option<PathBuf>,
+    },
+    /// analyzes a folder without starting the server and prints the groups
+    Analyze {
+        /// the folder of the listed files [default: their common folder]
+        #[arg(required_unless_present = "files_from")]
+        path: Option<PathBuf>,
+        /// hashes the files listed in it, a path per line, `-` for stdin, rather than those found in the folder
+        #[arg(long, value_name = "FILE")]
+        files_from: Option<PathBuf>,
+        /// hash distance up to which images are grouped
+        #[arg(long, default_value_t = 5)]
+        dist: u32,
+        /// `DHash`, `AHash` or `PHash`
+        #[arg(long, default_value = "DHash", value_parser = parse_hash_type)]
+        hash_type: HashType,
+        /// 8, 16 or 32
+        #[arg(long, default_value = "8", value_parser = parse_hash_size)]
+        hash_size: HashSize,
+        /// prints the whole result as JSON instead of the paths of a group per paragraph
+        #[arg(long)]
+        json: bool,
+        /// hashes the previews embedded in camera JPEGs instead of decoding the images
+        #[arg(long)]
+        fast: bool,
+        /// the progress bar counts bytes rather than files, for folders of a few huge TIFFs among small JPEGs
+        #[arg(long)]
+        byte_progress: bool,
+        /// copies count as exact when their decoded pixels are the same, whatever their EXIF or XMP tags
+        #[arg(long)]
+        pixel_exact: bool,
+    },
+    /// terminal UI going through the groups of an exported result
+    #[cfg(feature = "tui")]
+    Review { file: Option<String> },
+}
+
+/// the names of the HTTP API
+fn parse_hash_type(name: &str) -> Result<HashType, String> {
+    serde_json::from_value(name.into()).map_err(|_| format!("unknown hash type {}, expected DHash, AHash, PHash or a registered one", name))
+}
+
+fn parse_import_format(name: &str) -> Result<ImportFormat, String> {
+    serde_json::from_value(name.into()).map_err(|_| format!("unknown format {}, expected fdupes, jdupes or czkawka", name))
+}
+
+fn parse_keep_rule(name: &str) -> Result<KeepRule, String> {
+    serde_json::from_value(name.into()).map_err(|_| {
+        format!("unknown keep rule {}, expected newest, oldest, largest, highestResolution, preferredFolder, preferredStorage or preferRaw", name)
+    })
+}
+
+fn parse_storage_class(name: &str) -> Result<StorageClass, String> {
+    serde_json::from_value(name.into()).map_err(|_| format!("unknown storage class {}, expected ssd, hdd, nas or cloudSync", name))
+}
+
+fn parse_log_format(name: &str) -> Result<LogFormat, String> {
+    serde_json::from_value(name.into()).map_err(|_| format!("unknown log format {}, expected text or json", name))
+}
+
+#[cfg(unix)]
+fn parse_socket_mode(mode: &str) -> Result<u32, String> {
+    let parsed = u32::from_str_radix(mode, 8).ok().filter(|mode| *mode <= 0o777);
+    parsed.ok_or_else(|| format!("invalid mode {}, expected octal permissions like 660", mode))
+}
+
+fn parse_hash_size(size: &str) -> Result<HashSize, String> {
+    size.parse::<u32>().map_err(|err| err.to_string())?.try_into()
+}
+
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    /// address to listen on [default: 0.0.0.0]
+    #[arg(long)]
+    bind: Option<IpAddr>,
+    /// port to listen on [default: 3000]
+    #[arg(long)]
+    port: Option<u16>,
+    /// Unix socket to listen on for a reverse proxy, instead of TCP unless `bind` or `port` is set too
+    #[cfg(unix)]
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// permissions of the socket in octal, `660` for a proxy in the group of the server, `0o660` in the config file
+    /// [default: by the umask]
+    #[cfg(unix)]
+    #[arg(long, value_parser = parse_socket_mode)]
+    socket_mode: Option<u32>,
+    /// the built client [default: client/dist, or the one in the binary with `embed`]
+    #[arg(long)]
+    static_dir: Option<PathBuf>,
+    /// roots, hash cache, tasks and removed files [default: .]
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// analyses running at once, overrides `TASK_CONCURRENCY`
+    #[arg(long)]
+    task_concurrency: Option<usize>,
+    /// threads hashing and grouping images [default: one per CPU]
+    #[arg(long)]
+    hash_threads: Option<usize>,
+    /// MB of decoded images held at once, so large scans fit small machines [default: unbounded]
+    #[arg(long)]
+    memory_budget: Option<u64>,
+    /// analyses a client may start per minute, by profile and searches by upload included [default: 20]
+    #[arg(long)]
+    analyze_per_minute: Option<u32>,
+    /// thumbnails and previews a client may load per minute [default: 1200]
+    #[arg(long)]
+    thumbnails_per_minute: Option<u32>,
+    /// PEM certificate chain, serves HTTPS together with `tls-key`
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// every request needs `Authorization: Bearer <token>`, better kept in the config file
+    #[arg(long, conflicts_with = "auth_user")]
+    auth_token: Option<String>,
+    /// basic auth user, together with `auth-password`
+    #[arg(long, requires = "auth_password")]
+    auth_user: Option<String>,
+    /// basic auth password, better kept in the config file
+    #[arg(long, requires = "auth_user")]
+    auth_password: Option<String>,
+    /// frontend origin allowed to call the API from another host or port, repeated for several, `*` for any
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    #[serde(default)]
+    cors_origins: Vec<String>,
+    /// port of the gRPC service, on the same address, none without it
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_port: Option<u16>,
+    /// URL POSTed to when an analysis finishes, unless it names a `callback` of its own
+    #[arg(long)]
+    webhook: Option<Url>,
+    /// host analyses may name a `callback` on, repeated for several, only the `webhook` itself without any
+    #[arg(long = "webhook-host", value_name = "HOST")]
+    #[serde(default)]
+    webhook_hosts: Vec<String>,
+    /// requests with `X-Admin-Token: <token>` see the tasks of every session, better kept in the config file
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// workers send it as `X-Worker-Token: <token>`, the worker endpoints are only served with one
+    #[arg(long)]
+    worker_token: Option<String>,
+    /// S3-compatible service `s3://bucket/prefix` paths are read from, e.g. `https://s3.eu-west-1.amazonaws.com`
+    #[arg(long, requires_all = ["s3_access_key", "s3_secret_key"])]
+    s3_endpoint: Option<Url>,
+    /// region requests are signed for [default: us-east-1]
+    #[arg(long)]
+    s3_region: Option<String>,
+    #[arg(long)]
+    s3_access_key: Option<String>,
+    /// better kept in the config file
+    #[arg(long)]
+    s3_secret_key: Option<String>,
+    /// WebDAV share `webdav://folder` paths are read from, e.g. `https://nas.local/dav/photos`
+    #[arg(long)]
+    webdav_endpoint: Option<Url>,
+    /// basic auth user of the share, together with `webdav-password`
+    #[arg(long, requires = "webdav_password")]
+    webdav_user: Option<String>,
+    /// better kept in the config file
+    #[arg(long, requires = "webdav_user")]
+    webdav_password: Option<String>,
+    /// SSH server and folder `sftp://folder` paths are read from, e.g. `sftp://nas.local:22/srv/photos`
+    #[arg(long, requires = "sftp_user")]
+    sftp_endpoint: Option<Url>,
+    /// together with either `sftp-password` or `sftp-key`
+    #[arg(long)]
+    sftp_user: Option<String>,
+    /// better kept in the config file
+    #[arg(long, conflicts_with = "sftp_key")]
+    sftp_password: Option<String>,
+    /// unencrypted OpenSSH private key file
+    #[arg(long)]
+    sftp_key: Option<PathBuf>,
+    /// public key of the server, `ssh-ed25519 AAAA...`, looked up in `~/.ssh/known_hosts` without it
+    #[arg(long)]
+    sftp_host_key: Option<String>,
+    /// folder kept analyzed, its groups are served by `/watch/groups`, repeated for several
+    #[arg(long = "watch", value_name = "DIR")]
+    #[serde(default)]
+    watch: Vec<PathBuf>,
+    /// seconds between scans of the watched folders on network shares, the others are scanned on changes [default: 60]
+    #[arg(long)]
+    watch_interval: Option<u64>,
+    /// hash distance up to which images of watched folders are grouped [default: 5]
+    #[arg(long)]
+    watch_dist: Option<u32>,
+    /// picks the copy of each group suggested to keep, repeated for the rules breaking its ties [default: largest]
+    #[arg(long = "keep", value_name = "RULE", value_parser = parse_keep_rule)]
+    #[serde(default)]
+    keep: Vec<KeepRule>,
+    /// folder the `preferredFolder` rule keeps copies in, repeated for several, the first ones win
+    #[arg(long = "prefer", value_name = "DIR")]
+    #[serde(default)]
+    prefer: Vec<PathBuf>,
+    /// storage class of roots the `preferredStorage` rule keeps copies on, repeated for several, the first ones win
+    #[arg(long = "prefer-storage", value_name = "CLASS", value_parser = parse_storage_class)]
+    #[serde(default)]
+    prefer_storage: Vec<StorageClass>,
+    /// folder nothing is deleted or moved out of, its copies are the ones suggested, repeated for several
+    #[arg(long = "protect", value_name = "DIR")]
+    #[serde(default)]
+    protect: Vec<PathBuf>,
+    /// `[[auto-resolve]]` tables resolving the groups of watched folders after their scans, in the config file only
+    #[arg(skip)]
+    #[serde(default)]
+    auto_resolve: Vec<AutoRule>,
+    /// serves `/desktop/open` and `/desktop/reveal`, opening files on this machine for browsers running on it
+    #[arg(long)]
+    #[serde(default)]
+    desktop: bool,
+    /// file logged to instead of stdout, rotated once it grows past `log-max-size`
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// MB of a log file before it is rotated [default: 10]
+    #[arg(long)]
+    log_max_size: Option<u64>,
+    /// rotated log files kept, `<log-file>.1` being the latest [default: 5]
+    #[arg(long)]
+    log_keep: Option<usize>,
+    /// `text`, or `json` for a JSON object per line with the fields of the request and the task [default: text]
+    #[arg(long, value_parser = parse_log_format)]
+    log_format: Option<LogFormat>,
+    /// folder the server may access, repeated for several, anything goes without one
+    #[arg(long = "library", value_name = "DIR")]
+    #[serde(default)]
+    libraries: Vec<PathBuf>,
+}
+
+impl Settings {
+    /// the settings of `self`, those it lacks from `other`
+    fn or(self, other: Self) -> Self {
+        Self {
+            bind: self.bind.or(other.bind),
+            port: self.port.or(other.port),
+            #[cfg(unix)]
+            socket: self.socket.or(other.socket),
+            #[cfg(unix)]
+            socket_mode: self.socket_mode.or(other.socket_mode),
+            static_dir: self.static_dir.or(other.static_dir),
+            data_dir: self.data_dir.or(other.data_dir),
+            task_concurrency: self.task_concurrency.or(other.task_concurrency),
+            hash_threads: self.hash_threads.or(other.hash_threads),
+            memory_budget: self.memory_budget.or(other.memory_budget),
+            analyze_per_minute: self.analyze_per_minute.or(other.analyze_per_minute),
+            thumbnails_per_minute: self.thumbnails_per_minute.or(other.thumbnails_per_minute),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            auth_token: self.auth_token.or(other.auth_token),
+            auth_user: self.auth_user.or(other.auth_user),
+            auth_password: self.auth_password.or(other.auth_password),
+            cors_origins: if self.cors_origins.is_empty() { other.cors_origins } else { self.cors_origins },
+            #[cfg(feature = "grpc")]
+            grpc_port: self.grpc_port.or(other.grpc_port),
+            webhook: self.webhook.or(other.webhook),
+            webhook_hosts: if self.webhook_hosts.is_empty() { other.webhook_hosts } else { self.webhook_hosts },
+            admin_token: self.admin_token.or(other.admin_token),
+            worker_token: self.worker_token.or(other.worker_token),
+            s3_endpoint: self.s3_endpoint.or(other.s3_endpoint),
+            s3_region: self.s3_region.or(other.s3_region),
+            s3_access_key: self.s3_access_key.or(other.s3_access_key),
+            s3_secret_key: self.s3_secret_key.or(other.s3_secret_key),
+            webdav_endpoint: self.webdav_endpoint.or(other.webdav_endpoint),
+            webdav_user: self.webdav_user.or(other.webdav_user),
+            webdav_password: self.webdav_password.or(other.webdav_password),
+            sftp_endpoint: self.sftp_endpoint.or(other.sftp_endpoint),
+            sftp_user: self.sftp_user.or(other.sftp_user),
+            sftp_password: self.sftp_password.or(other.sftp_password),
+            sftp_key: self.sftp_key.or(other.sftp_key),
+            sftp_host_key: self.sftp_host_key.or(other.sftp_host_key),
+            watch: if self.watch.is_empty() { other.watch } else { self.watch },
+            watch_interval: self.watch_interval.or(other.watch_interval),
+            watch_dist: self.watch_dist.or(other.watch_dist),
+            keep: if self.keep.is_empty() { other.keep } else { self.keep },
+            prefer: if self.prefer.is_empty() { other.prefer } else { self.prefer },
+            prefer_storage: if self.prefer_storage.is_empty() { other.prefer_storage } else { self.prefer_storage },
+            protect: if self.protect.is_empty() { other.protect } else { self.protect },
+            auto_resolve: if self.auto_resolve.is_empty() { other.auto_resolve } else { self.auto_resolve },
+            desktop: self.desktop || other.desktop,
+            log_file: self.log_file.or(other.log_file),
+            log_max_size: self.log_max_size.or(other.log_max_size),
+            log_keep: self.log_keep.or(other.log_keep),
+            log_format: self.log_format.or(other.log_format),
+            libraries: if self.libraries.is_empty() { other.libraries } else { self.libraries },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub addr: SocketAddr,
+    /// whether `addr` is listened on, not when only the socket is
+    pub tcp: bool,
+    /// listened on as well as or instead of `addr`
+    #[cfg(unix)]
+    pub socket: Option<PathBuf>,
+    /// of the socket, by the umask when `None`
+    #[cfg(unix)]
+    pub socket_mode: Option<u32>,
+    pub assets: Assets,
+    pub data_dir: PathBuf,
+    pub task_concurrency: Option<usize>,
+    pub hash_threads: Option<usize>,
+    /// in bytes, unbounded when `None`
+    pub memory_budget: Option<u64>,
+    pub analyze_per_minute: u32,
+    pub thumbnails_per_minute: u32,
+    /// plain HTTP when `None`
+    pub tls: Option<TlsFiles>,
+    /// open to anyone who can reach it when `None`
+    pub auth: Option<Auth>,
+    /// same origin only when empty
+    pub cors_origins: Vec<HeaderValue>,
+    #[cfg(feature = "grpc")]
+    pub grpc_addr: Option<SocketAddr>,
+    pub webhook: Option<Url>,
+    /// callbacks are accepted on, besides the `webhook`
+    pub webhook_hosts: Vec<String>,
+    /// nobody sees the tasks of other sessions when `None`
+    pub admin_token: Option<String>,
+    /// the worker endpoints aren't served when `None`
+    pub worker_token: Option<String>,
+    /// `s3://` paths can't be analyzed when `None`
+    pub s3: Option<S3Config>,
+    /// `webdav://` paths can't be analyzed when `None`
+    pub webdav: Option<WebDavConfig>,
+    /// `sftp://` paths can't be analyzed when `None`
+    pub sftp: Option<SftpConfig>,
+    /// folders kept analyzed, none when empty
+    pub watch: Vec<PathBuf>,
+    pub watch_options: WatchOptions,
+    /// applied to the groups of the watched folders
+    pub auto_resolve: Vec<AutoRule>,
+    pub keep_rules: KeepRules,
+    /// `None` when any folder may be accessed
+    pub libraries: Option<Vec<PathBuf>>,
+    pub desktop: bool,
+    pub log: LogOptions,
+}
+
+impl Config {
+    pub fn new(cli_settings: Settings, file: Option<&PathBuf>) -> Result<Self> {
+        let file_settings = match file {
+            Some(file) => toml::from_str(&fs::read_to_string(file)?)?,
+            None => Settings::default(),
+        };
+        let settings = cli_settings.or(file_settings);
+
+        eyre::ensure!(settings.task_concurrency != Some(0), "task concurrency must be at least 1");
+        eyre::ensure!(settings.hash_threads != Some(0), "hash threads must be at least 1");
+        eyre::ensure!(settings.memory_budget != Some(0), "memory budget must be at least 1 MB");
+        let analyze_per_minute = settings.analyze_per_minute.unwrap_or(20);
+        let thumbnails_per_minute = settings.thumbnails_per_minute.unwrap_or(1200);
+        eyre::ensure!(analyze_per_minute > 0 && thumbnails_per_minute > 0, "rate limits must be at least 1 per minute");
+        let tls = match (settings.tls_cert, settings.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+            (None, None) => None,
+            _ => eyre::bail!("TLS needs both a certificate and a key"),
+        };
+        let auth = match (settings.auth_token, settings.auth_user, settings.auth_password) {
+            (Some(token), None, None) => Some(Auth::Token(token)),
+            (None, Some(user), Some(password)) => Some(Auth::Basic { user, password }),
+            (None, None, None) => None,
+            _ => eyre::bail!("auth needs either a token or both a user and a password"),
+        };
+        eyre::ensure!(settings.admin_token.as_deref() != Some(""), "admin token must not be empty");
+        eyre::ensure!(settings.worker_token.as_deref() != Some(""), "worker token must not be empty");
+        let s3 = match (settings.s3_endpoint, settings.s3_access_key, settings.s3_secret_key) {
+            (Some(endpoint), Some(access_key), Some(secret_key)) => {
+                let region = settings.s3_region.unwrap_or_else(|| "us-east-1".to_owned());
+                Some(S3Config { endpoint, region, access_key, secret_key })
+            }
+            (None, None, None) => None,
+            _ => eyre::bail!("S3 needs an endpoint, an access key and a secret key"),
+        };
+        let webdav = match (settings.webdav_endpoint, settings.webdav_user, settings.webdav_password) {
+            (Some(endpoint), user, password) if user.is_some() == password.is_some() => Some(WebDavConfig { endpoint, user, password }),
+            (None, None, None) => None,
+            _ => eyre::bail!("WebDAV needs an endpoint, and either both a user and a password or neither"),
+        };
+        let sftp = match (settings.sftp_endpoint, settings.sftp_user, settings.sftp_password, settings.sftp_key) {
+            (Some(endpoint), Some(user), password, key) if password.is_some() != key.is_some() => {
+                eyre::ensure!(endpoint.scheme() == "sftp" && endpoint.host_str().is_some(), "the SFTP endpoint must be an sftp://host/folder URL");
+                Some(SftpConfig { endpoint, user, password, key, host_key: settings.sftp_host_key })
+            }
+            (None, None, None, None) => None,
+            _ => eyre::bail!("SFTP needs an endpoint, a user and either a password or a key"),
+        };
+        eyre::ensure!(settings.watch_interval != Some(0), "watch interval must be at least 1 second");
+        let watch_options = WatchOptions {
+            interval: Duration::from_secs(settings.watch_interval.unwrap_or(60)),
+            dist: settings.watch_dist.unwrap_or(5),
+            ..WatchOptions::default()
+        };
+        for folder in &settings.watch {
+            let in_library = settings.libraries.iter().any(|library| folder.starts_with(library));
+            eyre::ensure!(settings.libraries.is_empty() || in_library, "watched folder {} is outside the libraries", folder.display());
+        }
+        eyre::ensure!(settings.auto_resolve.is_empty() || !settings.watch.is_empty(), "auto-resolve rules apply to watched folders, none is watched");
+        let preferred = settings.keep.contains(&KeepRule::PreferredFolder);
+        eyre::ensure!(preferred || settings.prefer.is_empty(), "preferred folders need the preferredFolder keep rule");
+        eyre::ensure!(!preferred || !settings.prefer.is_empty(), "the preferredFolder keep rule needs preferred folders");
+        let storage = settings.keep.contains(&KeepRule::PreferredStorage);
+        eyre::ensure!(storage || settings.prefer_storage.is_empty(), "preferred storage classes need the preferredStorage keep rule");
+        eyre::ensure!(!storage || !settings.prefer_storage.is_empty(), "the preferredStorage keep rule needs preferred storage classes");
+        eyre::ensure!(!settings.keep.contains(&KeepRule::Protected), "the protected keep rule always comes first, it can't be configured");
+        for folder in &settings.protect {
+            let in_library = settings.libraries.iter().any(|library| folder.starts_with(library));
+            eyre::ensure!(settings.libraries.is_empty() || in_library, "protected folder {} is outside the libraries", folder.display());
+        }
+        let mut keep_rules = match settings.keep.is_empty() {
+            true => KeepRules::default(),
+            false => KeepRules { rules: settings.keep, preferred: settings.prefer, storage: settings.prefer_storage, ..KeepRules::default() },
+        };
+        // the server adds those of the API once it opens the data directory
+        keep_rules.protected = Arc::new(Protected::new(settings.protect));
+        if let Some(Auth::Token(token)) = &auth {
+            eyre::ensure!(!token.is_empty(), "auth token must not be empty");
+        }
+        let cors_origins = settings
+            .cors_origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin).map_err(|_| eyre::eyre!("invalid CORS origin {:?}", origin)))
+            .collect::<Result<Vec<_>>>()?;
+        eyre::ensure!(settings.log_max_size != Some(0), "log files must be allowed at least 1 MB");
+        let default_log = LogOptions::default();
+        let log = LogOptions {
+            file: settings.log_file,
+            max_size: settings.log_max_size.map_or(default_log.max_size, |mb| mb.saturating_mul(1024 * 1024)),
+            keep: settings.log_keep.unwrap_or(default_log.keep),
+            format: settings.log_format.unwrap_or_default(),
+        };
+        #[cfg(unix)]
+        eyre::ensure!(settings.socket.is_some() || settings.socket_mode.is_none(), "the socket mode needs a socket");
+        #[cfg(unix)]
+        let tcp = settings.socket.is_none() || settings.bind.is_some() || settings.port.is_some();
+        #[cfg(not(unix))]
+        let tcp = true;
+        let addr = SocketAddr::new(settings.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), settings.port.unwrap_or(3000));
+        #[cfg(feature = "grpc")]
+        let grpc_addr = settings.grpc_port.map(|port| SocketAddr::new(addr.ip(), port));
+        #[cfg(feature = "grpc")]
+        eyre::ensure!(grpc_addr != Some(addr), "the gRPC service needs a port of its own");
+        Ok(Self {
+            addr,
+            tcp,
+            #[cfg(unix)]
+            socket: settings.socket,
+            #[cfg(unix)]
+            socket_mode: settings.socket_mode,
+            assets: settings.static_dir.map_or_else(Assets::default, Assets::Dir),
+            data_dir: settings.data_dir.unwrap_or_else(|| PathBuf::from(".")),
+            task_concurrency: settings.task_concurrency,
+            hash_threads: settings.hash_threads,
+            memory_budget: settings.memory_budget.map(|mb| mb.saturating_mul(1024 * 1024)),
+            analyze_per_minute,
+            thumbnails_per_minute,
+            tls,
+            auth,
+            cors_origins,
+            #[cfg(feature = "grpc")]
+            grpc_addr,
+            webhook: settings.webhook,
+            webhook_hosts: settings.webhook_hosts,
+            admin_token: settings.admin_token,
+            worker_token: settings.worker_token,
+            s3,
+            webdav,
+            sftp,
+            watch: settings.watch,
+            watch_options,
+            auto_resolve: settings.auto_resolve,
+            keep_rules,
+            libraries: Some(settings.libraries).filter(|libraries| !libraries.is_empty()),
+            desktop: settings.desktop,
+            log,
+        })
+    }
+}
@@ -0,0 +1,58 @@
+//! Files opened on the machine of the server, in their default viewer or selected in the file manager, for browsers
+//! running on that machine. The commands are those of the platform the server runs on: `open` on macOS,
+//! `explorer` on Windows, `xdg-open` and the file manager of the desktop over D-Bus elsewhere.
+
+use eyre::{bail, Result};
+use std::{path::Path, process::Command};
+
+/// started without waiting for it, viewers keep running
+fn spawn(mut command: Command) -> Result<()> {
+    let mut child = command.spawn()?;
+    // reaped once closed
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// in the default application for its type
+pub fn open(path: &Path) -> Result<()> {
+    let mut command;
+    if cfg!(target_os = "macos") {
+        command = Command::new("open");
+    } else if cfg!(windows) {
+        // not through `cmd /C start`, which would run what the path holds after a `&`
+        command = Command::new("explorer");
+    } else {
+        command = Command::new("xdg-open");
+    }
+    command.arg(path);
+    spawn(command)
+}
+
+/// selected in Finder, Explorer or the file manager of the desktop, its folder opened by those which can't select
+pub fn reveal(path: &Path) -> Result<()> {
+    if cfg!(target_os = "macos") {
+        let mut command = Command::new("open");
+        command.arg("-R").arg(path);
+        return spawn(command);
+    }
+    if cfg!(windows) {
+        let mut command = Command::new("explorer");
+        command.arg(format!("/select,{}", path.display()));
+        return spawn(command);
+    }
+
+    let Ok(uri) = url::Url::from_file_path(path) else {
+        bail!("not an absolute path");
+    };
+    let shown = Command::new("dbus-send")
+        .args(["--session", "--dest=org.freedesktop.FileManager1", "--type=method_call", "/org/freedesktop/FileManager1"])
+        .arg("org.freedesktop.FileManager1.ShowItems")
+        .arg(format!("array:string:{}", uri))
+        .arg("string:")
+        .status();
+    match (shown, path.parent()) {
+        (Ok(status), _) if status.success() => Ok(()),
+        (_, Some(dir)) => open(dir),
+        (_, None) => bail!("no folder to open"),
+    }
+}
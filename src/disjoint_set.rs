@@ -1,12 +1,51 @@
+//! Union-find over any hashable values, what groups are made of: sets are joined by rank and
+//! paths compressed on lookups, while each set is represented by its earliest inserted value,
+//! so sets and their members come out in insertion order whatever order the unions came in.
+
 use std::hash::Hash;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::mem;
 
+/// Sets grown one value and one union at a time, they can be read at any point,
+/// only the sets changed since the last read when asked for.
 #[derive(Debug)]
 pub struct DisjointSet<T> {
     parents: Vec<usize>,
+    /// upper bound of the height of the tree below, by key
+    ranks: Vec<u8>,
     values: HashMap<T, usize>,
+    /// by key, in insertion order
+    items: Vec<T>,
+    /// keys of each set, kept by its root, empty for the others
+    members: Vec<Vec<usize>>,
+    /// the earliest key of each set, kept by its root
+    first: Vec<usize>,
+    /// earliest keys of the sets changed since `take_changed`
+    changed: BTreeSet<usize>,
+    sets: usize,
+}
+
+/// the values of a set, in insertion order
+#[derive(Debug, Clone)]
+pub struct Members<'a, T> {
+    items: &'a [T],
+    keys: std::vec::IntoIter<usize>,
+}
+
+impl<'a, T> Iterator for Members<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.keys.next().map(|key| &self.items[key])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.keys.size_hint()
+    }
 }
 
+impl<T> ExactSizeIterator for Members<'_, T> {}
+
 fn find_parent(parents: &mut [usize], key: usize) -> usize {
     let mut k = key;
     let mut p = parents[key];
@@ -21,49 +60,162 @@ fn find_parent(parents: &mut [usize], key: usize) -> usize {
     p
 }
 
-impl<T: Eq + Hash> DisjointSet<T> {
+/// without compressing the path, for lookups through `&self`
+fn find_root(parents: &[usize], mut key: usize) -> usize {
+    while parents[key] != key {
+        key = parents[key];
+    }
+    key
+}
+
+impl<T: Eq + Hash + Clone> Default for DisjointSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Eq + Hash + Clone> DisjointSet<T> {
     pub fn new() -> Self {
         DisjointSet {
             parents: Vec::new(),
+            ranks: Vec::new(),
             values: HashMap::new(),
+            items: Vec::new(),
+            members: Vec::new(),
+            first: Vec::new(),
+            changed: BTreeSet::new(),
+            sets: 0,
         }
     }
 
+    /// values inserted so far
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn set_count(&self) -> usize {
+        self.sets
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.values.contains_key(value)
+    }
+
+    /// a set of its own, values inserted before keep their key and set
     pub fn insert(&mut self, value: T) -> usize {
-        let key = self.values.len();
+        if let Some(&key) = self.values.get(&value) {
+            return key;
+        }
+        let key = self.items.len();
         self.parents.push(key);
-        self.values.insert(value, key);
+        self.ranks.push(0);
+        self.values.insert(value.clone(), key);
+        self.items.push(value);
+        self.members.push(vec![key]);
+        self.first.push(key);
+        self.changed.insert(key);
+        self.sets += 1;
         key
     }
 
-    pub fn find(&mut self, value: &T) -> usize {
-        let key = self.values[value];
-        find_parent(&mut self.parents, key)
+    /// the key of the earliest inserted value of the set, `None` for values never inserted
+    pub fn find(&mut self, value: &T) -> Option<usize> {
+        let key = *self.values.get(value)?;
+        Some(self.first[find_parent(&mut self.parents, key)])
+    }
+
+    /// `false` unless both were inserted
+    pub fn same_set(&mut self, va: &T, vb: &T) -> bool {
+        matches!((self.find(va), self.find(vb)), (Some(a), Some(b)) if a == b)
     }
 
-    pub fn union(&mut self, va: &T, vb: &T) {
-        let pa = self.find(va);
-        let pb = self.find(vb);
+    /// `Some(false)` if they were in the same set already, `None` unless both were inserted
+    pub fn union(&mut self, va: &T, vb: &T) -> Option<bool> {
+        let (&ka, &kb) = (self.values.get(va)?, self.values.get(vb)?);
+        let pa = find_parent(&mut self.parents, ka);
+        let pb = find_parent(&mut self.parents, kb);
 
         if pa == pb {
-            return;
+            return Some(false);
         }
 
-        self.parents[pa] = pb;
-    }
+        let (root, child) = if self.ranks[pa] < self.ranks[pb] { (pb, pa) } else { (pa, pb) };
+        self.parents[child] = root;
+        if self.ranks[root] == self.ranks[child] {
+            self.ranks[root] += 1;
+        }
 
-    pub fn into_vec(self) -> Vec<Vec<T>> {
-        let mut groups = HashMap::new();
-        let mut parents = self.parents;
-
-        for (v, k) in self.values.into_iter() {
-            let p = find_parent(&mut parents, k);
-            let vs = groups
-                .entry(p)
-                .or_insert(Vec::new());
-            vs.push(v);
+        // the smaller list is moved, so each key moves a logarithmic number of times
+        let mut moved = mem::take(&mut self.members[child]);
+        if moved.len() > self.members[root].len() {
+            mem::swap(&mut moved, &mut self.members[root]);
         }
+        self.members[root].extend(moved);
+
+        let (kept, gone) = (self.first[root].min(self.first[child]), self.first[root].max(self.first[child]));
+        self.first[root] = kept;
+        self.changed.remove(&gone);
+        self.changed.insert(kept);
+        self.sets -= 1;
+        Some(true)
+    }
+
+    fn members_of(&self, root: usize) -> Members<'_, T> {
+        let mut keys = self.members[root].clone();
+        keys.sort_unstable();
+        Members { items: &self.items, keys: keys.into_iter() }
+    }
 
-        groups.into_values().collect()
+    /// `None` for values never inserted
+    pub fn members(&self, value: &T) -> Option<Members<'_, T>> {
+        let key = *self.values.get(value)?;
+        Some(self.members_of(find_root(&self.parents, key)))
+    }
+
+    /// the number of values in the set of the value, `None` for values never inserted
+    pub fn set_len(&self, value: &T) -> Option<usize> {
+        let key = *self.values.get(value)?;
+        Some(self.members[find_root(&self.parents, key)].len())
+    }
+
+    /// sets in insertion order of their representatives
+    pub fn iter(&self) -> impl Iterator<Item = Members<'_, T>> {
+        let mut roots: Vec<usize> = (0..self.items.len()).filter(|&key| !self.members[key].is_empty()).collect();
+        roots.sort_unstable_by_key(|&root| self.first[root]);
+        roots.into_iter().map(|root| self.members_of(root))
+    }
+
+    /// as `iter`, with copies of the values
+    pub fn sets(&self) -> Vec<Vec<T>> {
+        self.iter().map(|members| members.cloned().collect()).collect()
+    }
+
+    /// the sets which got values or were merged since the last call, in the order of `sets`,
+    /// the sets they were merged from aren't reported on their own anymore
+    pub fn take_changed(&mut self) -> Vec<Vec<T>> {
+        mem::take(&mut self.changed)
+            .into_iter()
+            .map(|first| self.members_of(find_root(&self.parents, first)).cloned().collect())
+            .collect()
+    }
+
+    /// as `sets`, without copying the values
+    pub fn into_vec(self) -> Vec<Vec<T>> {
+        let mut roots: Vec<usize> = (0..self.items.len()).filter(|&key| !self.members[key].is_empty()).collect();
+        roots.sort_unstable_by_key(|&root| self.first[root]);
+        let mut items: Vec<Option<T>> = self.items.into_iter().map(Some).collect();
+        let mut members = self.members;
+        roots
+            .into_iter()
+            .map(|root| {
+                let mut keys = mem::take(&mut members[root]);
+                keys.sort_unstable();
+                keys.into_iter().filter_map(|key| items[key].take()).collect()
+            })
+            .collect()
     }
 }
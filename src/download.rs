@@ -0,0 +1,164 @@
+//! Selected files, e.g. the members of a group, as one ZIP archive to compare them offline or back them up before
+//! removing some. Entries are stored as they are, images are compressed already, and written one after the other so
+//! only one file is held in memory. Archives stay below 4 GiB and 65535 entries, ZIP64 isn't written.
+
+use axum::body::Bytes;
+use eyre::{ensure, eyre, Result};
+use flate2::Crc;
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::archive::{CENTRAL_SIGNATURE, END_SIGNATURE, LOCAL_SIGNATURE, STORED};
+use crate::s3;
+use crate::storage::Storage;
+
+/// entries of an archive without ZIP64
+pub const MAX_ENTRIES: usize = u16::MAX as usize;
+
+/// 2.0, the first to know folders
+const VERSION: u16 = 20;
+/// names are UTF-8
+const UTF8_NAMES: u16 = 1 << 11;
+
+/// the folders shared by all the paths
+fn common_dir(paths: &[PathBuf]) -> PathBuf {
+    let mut dirs = paths.iter().map(|path| path.parent().unwrap_or(Path::new("")));
+    let Some(first) = dirs.next() else {
+        return PathBuf::new();
+    };
+    let mut common: Vec<Component> = first.components().collect();
+    for dir in dirs {
+        let shared = common.iter().zip(dir.components()).take_while(|(a, b)| *a == b).count();
+        common.truncate(shared);
+    }
+    common.iter().collect()
+}
+
+/// the paths below the folders they share, with `/` as ZIP names have
+pub fn entry_names(paths: &[PathBuf]) -> Vec<String> {
+    let common = common_dir(paths);
+    paths
+        .iter()
+        .map(|path| {
+            let rest = path.strip_prefix(&common).unwrap_or(path);
+            let parts: Vec<_> = rest
+                .components()
+                .filter_map(|part| match part {
+                    Component::Normal(part) => Some(part.to_string_lossy()),
+                    _ => None,
+                })
+                .collect();
+            parts.join("/")
+        })
+        .collect()
+}
+
+/// the MS-DOS time and date of the entries, 1980 at the earliest
+fn dos_time(time: Option<SystemTime>) -> (u16, u16) {
+    let secs = time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map_or(0, |since| since.as_secs());
+    let (year, month, day) = s3::civil_from_days((secs / 86_400) as i64);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = secs % 86_400;
+    let dos_time = ((time / 3600) << 11) | ((time / 60 % 60) << 5) | (time % 60 / 2);
+    let dos_date = (((year - 1980).min(127) as u32) << 9) | (month << 5) | day;
+    (dos_time as u16, dos_date as u16)
+}
+
+/// the local headers and data of the entries in turn, then the central directory
+#[derive(Default)]
+struct ZipWriter {
+    central: Vec<u8>,
+    offset: u32,
+    entries: u16,
+}
+
+impl ZipWriter {
+    /// the header and the data of the entry
+    fn entry(&mut self, name: &str, data: &[u8], modified: Option<SystemTime>) -> Result<Vec<u8>> {
+        ensure!((self.entries as usize) < MAX_ENTRIES, "more than {} files", MAX_ENTRIES);
+        let size = u32::try_from(data.len()).map_err(|_| eyre!("{} is larger than 4 GiB", name))?;
+        let name_len = u16::try_from(name.len())?;
+        let mut crc = Crc::new();
+        crc.update(data);
+        let (time, date) = dos_time(modified);
+
+        let mut local = Vec::with_capacity(30 + name.len() + data.len());
+        local.extend(LOCAL_SIGNATURE.to_le_bytes());
+        for field in [VERSION, UTF8_NAMES, STORED, time, date] {
+            local.extend(field.to_le_bytes());
+        }
+        for field in [crc.sum(), size, size] {
+            local.extend(field.to_le_bytes());
+        }
+        local.extend(name_len.to_le_bytes());
+        local.extend(0u16.to_le_bytes());
+        local.extend(name.as_bytes());
+        local.extend(data);
+
+        self.central.extend(CENTRAL_SIGNATURE.to_le_bytes());
+        for field in [VERSION, VERSION, UTF8_NAMES, STORED, time, date] {
+            self.central.extend(field.to_le_bytes());
+        }
+        for field in [crc.sum(), size, size] {
+            self.central.extend(field.to_le_bytes());
+        }
+        // the name, no extra field, comment, disk number nor internal attributes
+        for field in [name_len, 0, 0, 0, 0] {
+            self.central.extend(field.to_le_bytes());
+        }
+        self.central.extend(0u32.to_le_bytes());
+        self.central.extend(self.offset.to_le_bytes());
+        self.central.extend(name.as_bytes());
+
+        let len = u32::try_from(local.len()).ok();
+        self.offset = len.and_then(|len| self.offset.checked_add(len)).ok_or_else(|| eyre!("the archive would grow past 4 GiB"))?;
+        self.entries += 1;
+        Ok(local)
+    }
+
+    /// the central directory and its end
+    fn finish(self) -> Result<Vec<u8>> {
+        let size = u32::try_from(self.central.len())?;
+        ensure!(self.offset.checked_add(size).is_some(), "the archive would grow past 4 GiB");
+        let mut end = self.central;
+        end.extend(END_SIGNATURE.to_le_bytes());
+        for field in [0, 0, self.entries, self.entries] {
+            end.extend(field.to_le_bytes());
+        }
+        end.extend(size.to_le_bytes());
+        end.extend(self.offset.to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+        Ok(end)
+    }
+}
+
+/// The archive of the files, a chunk per file given to `send` as it's read, which is false once the client is gone.
+/// A file which can't be read ends the archive with an error, the download fails rather than lacking the file.
+pub fn write(files: &[(Arc<dyn Storage>, PathBuf)], mut send: impl FnMut(io::Result<Bytes>) -> bool) {
+    let paths: Vec<PathBuf> = files.iter().map(|(_, path)| path.clone()).collect();
+    let mut zip = ZipWriter::default();
+    let chunks = files.iter().zip(entry_names(&paths)).map(|((storage, path), name)| {
+        let data = storage.read(path)?;
+        // remote files and archive entries have no local mtime
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        zip.entry(&name, &data, modified)
+    });
+    for chunk in chunks {
+        let chunk = chunk.map(Bytes::from).map_err(|err| {
+            tracing::error!("unable to add a file to the download: {:#}", err);
+            io::Error::other(format!("{:#}", err))
+        });
+        let failed = chunk.is_err();
+        if !send(chunk) || failed {
+            return;
+        }
+    }
+    let end = zip.finish().map(Bytes::from).map_err(|err| io::Error::other(format!("{:#}", err)));
+    send(end);
+}
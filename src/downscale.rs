@@ -0,0 +1,205 @@
+//! PNGs and TIFFs scaled down while decoding, as JPEGs are: rows are read a strip or tile at a time
+//! and averaged into boxes of `factor` x `factor` pixels, so the pixels of huge scans are never all in memory.
+//! Interlaced PNGs, planar TIFFs and samples other than 8 and 16 bit integers are left to the `image` decoders.
+
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, ImageBuffer, ImageError, ImageFormat, ImageResult,
+};
+use std::io::{BufRead, Read, Seek};
+use tiff::{
+    decoder::{ChunkType, Decoder as TiffDecoder, DecodingResult},
+    tags::Tag,
+    ColorType,
+};
+
+/// pixels of each side averaged into one, the image keeping at least `size` on both sides,
+/// the sums of 8 bit samples fit in a `u32` up to 4096 x 4096 boxes
+pub(crate) fn factor((width, height): (u32, u32), size: u32) -> u32 {
+    (width / size.max(1)).min(height / size.max(1)).clamp(1, 4096)
+}
+
+/// the image scaled down by `factor` at least to `size` on its shorter side, `None` when it's too small
+/// to be scaled or laid out in a way only the `image` decoders read, the reader is then left anywhere
+pub(crate) fn decode<R: BufRead + Seek>(reader: R, format: ImageFormat, size: u32) -> ImageResult<Option<DynamicImage>> {
+    match format {
+        ImageFormat::Png => decode_png(reader, size),
+        ImageFormat::Tiff => decode_tiff(reader, size),
+        _ => Ok(None),
+    }
+}
+
+/// whether `decode` reads the image, from its header only
+pub(crate) fn scales<R: BufRead + Seek>(reader: R, format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Png => matches!(png_reader(reader), Ok(Some(_))),
+        ImageFormat::Tiff => matches!(tiff_decoder(reader), Ok(Some(_))),
+        _ => false,
+    }
+}
+
+fn png_error(err: png::DecodingError) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Png), err))
+}
+
+fn tiff_error(err: tiff::TiffError) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Tiff), err))
+}
+
+/// with palettes and bit depths below 8 expanded, `None` when interlaced
+fn png_reader<R: BufRead + Seek>(reader: R) -> ImageResult<Option<png::Reader<R>>> {
+    let mut decoder = png::Decoder::new(reader);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let reader = decoder.read_info().map_err(png_error)?;
+    Ok((!reader.info().interlaced).then_some(reader))
+}
+
+fn decode_png<R: BufRead + Seek>(reader: R, size: u32) -> ImageResult<Option<DynamicImage>> {
+    let Some(mut reader) = png_reader(reader)? else {
+        return Ok(None);
+    };
+    let (width, height) = reader.info().size();
+    let factor = factor((width, height), size);
+    if factor < 2 {
+        return Ok(None);
+    }
+    let (color, depth) = reader.output_color_type();
+    let mut boxes = Boxes::new((width, height), factor, color.samples());
+    let mut y = 0;
+    while let Some(row) = reader.next_row().map_err(png_error)? {
+        match depth {
+            // big endian, the high byte first
+            png::BitDepth::Sixteen => boxes.add(y, 0, row.data().chunks_exact(2).map(|sample| sample[0])),
+            _ => boxes.add(y, 0, row.data().iter().copied()),
+        }
+        y += 1;
+        boxes.flush(y);
+    }
+    Ok(boxes.finish())
+}
+
+/// with the samples per pixel, `None` unless chunky gray or RGB, with or without alpha, of 8 or 16 bits
+fn tiff_decoder<R: Read + Seek>(reader: R) -> ImageResult<Option<(TiffDecoder<R>, usize)>> {
+    let mut decoder = TiffDecoder::new(reader).map_err(tiff_error)?;
+    let channels = match decoder.colortype().map_err(tiff_error)? {
+        ColorType::Gray(8 | 16) => 1,
+        ColorType::GrayA(8 | 16) => 2,
+        ColorType::RGB(8 | 16) => 3,
+        ColorType::RGBA(8 | 16) => 4,
+        _ => return Ok(None),
+    };
+    // 2 for a plane of each sample
+    if decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration).map_err(tiff_error)?.unwrap_or(1) != 1 {
+        return Ok(None);
+    }
+    Ok(Some((decoder, channels)))
+}
+
+fn decode_tiff<R: Read + Seek>(reader: R, size: u32) -> ImageResult<Option<DynamicImage>> {
+    let Some((mut decoder, channels)) = tiff_decoder(reader)? else {
+        return Ok(None);
+    };
+    let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+    let factor = factor((width, height), size);
+    if factor < 2 {
+        return Ok(None);
+    }
+    let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+    // chunks go left to right, then top to bottom, a strip spans the width
+    let (across, chunks) = match decoder.get_chunk_type() {
+        ChunkType::Strip => (1, decoder.strip_count().map_err(tiff_error)?),
+        ChunkType::Tile => (width.div_ceil(chunk_width.max(1)), decoder.tile_count().map_err(tiff_error)?),
+    };
+    let mut boxes = Boxes::new((width, height), factor, channels);
+    for chunk in 0..chunks {
+        let (data_width, data_height) = decoder.chunk_data_dimensions(chunk);
+        let (x, y) = ((chunk % across) * chunk_width, (chunk / across) * chunk_height);
+        let row_len = data_width as usize * channels;
+        match decoder.read_chunk(chunk).map_err(tiff_error)? {
+            DecodingResult::U8(data) => {
+                for (i, row) in data.chunks_exact(row_len).take(data_height as usize).enumerate() {
+                    boxes.add(y + i as u32, x, row.iter().copied());
+                }
+            }
+            DecodingResult::U16(data) => {
+                for (i, row) in data.chunks_exact(row_len).take(data_height as usize).enumerate() {
+                    boxes.add(y + i as u32, x, row.iter().map(|sample| (sample >> 8) as u8));
+                }
+            }
+            _ => return Ok(None),
+        }
+        if chunk % across == across - 1 {
+            boxes.flush(y + data_height);
+        }
+    }
+    Ok(boxes.finish())
+}
+
+/// Sums of the samples of each box, for the output rows the rows read so far fall into,
+/// the rows already complete are averaged into `pixels`.
+struct Boxes {
+    width: u32,
+    height: u32,
+    factor: u32,
+    channels: usize,
+    /// samples of an output row
+    row_len: usize,
+    /// the output row the sums start at
+    first: u32,
+    sums: Vec<u32>,
+    pixels: Vec<u8>,
+}
+
+impl Boxes {
+    fn new((width, height): (u32, u32), factor: u32, channels: usize) -> Self {
+        let row_len = width.div_ceil(factor) as usize * channels;
+        Self { width, height, factor, channels, row_len, first: 0, sums: Vec::new(), pixels: Vec::new() }
+    }
+
+    /// 8 bit samples of row `y` from pixel `x` on
+    fn add(&mut self, y: u32, x: u32, samples: impl Iterator<Item = u8>) {
+        let start = (y / self.factor - self.first) as usize * self.row_len;
+        if self.sums.len() < start + self.row_len {
+            self.sums.resize(start + self.row_len, 0);
+        }
+        let (factor, channels) = (self.factor as usize, self.channels);
+        let row = &mut self.sums[start..start + self.row_len];
+        for (i, sample) in samples.enumerate() {
+            let column = (x as usize + i / channels) / factor;
+            // rows longer than the width, of malformed files
+            if let Some(sum) = row.get_mut(column * channels + i % channels) {
+                *sum += sample as u32;
+            }
+        }
+    }
+
+    /// averages the output rows that the first `rows` rows cover, the last one once all rows are read
+    fn flush(&mut self, rows: u32) {
+        let complete = if rows >= self.height { self.height.div_ceil(self.factor) } else { rows / self.factor };
+        while self.first < complete {
+            let box_height = self.factor.min(self.height - self.first * self.factor);
+            let done = self.row_len.min(self.sums.len());
+            let mut sums = self.sums.drain(..done).chain(std::iter::repeat(0));
+            for column in 0..self.row_len / self.channels {
+                let box_width = self.factor.min(self.width - column as u32 * self.factor);
+                let count = box_width * box_height;
+                for sum in sums.by_ref().take(self.channels) {
+                    self.pixels.push(((sum + count / 2) / count) as u8);
+                }
+            }
+            self.first += 1;
+        }
+    }
+
+    fn finish(mut self) -> Option<DynamicImage> {
+        self.flush(self.height);
+        let (width, height) = (self.width.div_ceil(self.factor), self.height.div_ceil(self.factor));
+        match self.channels {
+            1 => ImageBuffer::from_raw(width, height, self.pixels).map(DynamicImage::ImageLuma8),
+            2 => ImageBuffer::from_raw(width, height, self.pixels).map(DynamicImage::ImageLumaA8),
+            3 => ImageBuffer::from_raw(width, height, self.pixels).map(DynamicImage::ImageRgb8),
+            4 => ImageBuffer::from_raw(width, height, self.pixels).map(DynamicImage::ImageRgba8),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! The JSON body of error responses, with a code clients can tell errors apart by.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use eyre::Report;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    /// outside of the libraries, or not allowed with a share link
+    Forbidden,
+    /// the file system refused
+    PermissionDenied,
+    NotFound,
+    /// e.g. a task which did not complete
+    Conflict,
+    /// in safe mode, the journal has to be reviewed first
+    Locked,
+    TooManyRequests,
+    /// shutting down
+    Unavailable,
+    /// a bug or a failure of the server itself
+    #[default]
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden | Self::PermissionDenied => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::Locked => StatusCode::LOCKED,
+            Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn of_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest,
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::FORBIDDEN => Self::Forbidden,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::CONFLICT => Self::Conflict,
+            StatusCode::LOCKED => Self::Locked,
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequests,
+            StatusCode::SERVICE_UNAVAILABLE => Self::Unavailable,
+            _ => Self::Internal,
+        }
+    }
+
+    fn of_io(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// an IO error about a file, so the response can tell which
+#[derive(Debug)]
+pub struct PathError {
+    path: PathBuf,
+    source: io::Error,
+}
+
+impl PathError {
+    pub fn new(path: &Path, source: io::Error) -> Self {
+        Self { path: path.to_owned(), source }
+    }
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.source)
+    }
+}
+
+impl std::error::Error for PathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// all that is told about internal errors
+const INTERNAL_MESSAGE: &str = "internal server error";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    /// the file it is about, if it is about one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub path: Option<PathBuf>,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), path: None }
+    }
+
+    pub fn of_status(status: StatusCode) -> Self {
+        Self::new(ErrorCode::of_status(status), status.canonical_reason().unwrap_or("error").to_lowercase())
+    }
+
+    /// Classified by the first IO error of the chain, anything else is internal.
+    /// The chain of internal errors is logged rather than sent, it may tell about the server.
+    pub fn of_report(err: &Report) -> Self {
+        let mut body = Self::new(ErrorCode::Internal, format!("{:#}", err));
+        for cause in err.chain() {
+            if let Some(stored) = cause.downcast_ref::<ErrorBody>() {
+                return stored.clone();
+            }
+            if let Some(err) = cause.downcast_ref::<PathError>() {
+                body.code = ErrorCode::of_io(&err.source);
+                body.path = Some(err.path.clone());
+                break;
+            }
+            if let Some(err) = cause.downcast_ref::<io::Error>() {
+                body.code = ErrorCode::of_io(err);
+                break;
+            }
+        }
+        if body.code == ErrorCode::Internal {
+            tracing::error!("internal error: {:?}", err);
+            body.message = INTERNAL_MESSAGE.to_owned();
+        }
+        body
+    }
+}
+
+/// so a stored failure turns back into the same body
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ErrorBody {}
+
+impl IntoResponse for ErrorBody {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
@@ -0,0 +1,153 @@
+//! What happens on the server, for dashboards following every task in one subscription to `GET /events`:
+//! tasks submitted, their progress at each phase and tenth of the hashing, their outcome, and the file actions done.
+//! Subscribers falling behind miss events, they are told how many and list the tasks again.
+
+use eyre::Result;
+use serde::Serialize;
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::{broadcast, oneshot, watch};
+use uuid::Uuid;
+
+use crate::analyzer::{AnalyzeResult, Phase, Progress};
+use crate::history::Undoable;
+use crate::session::Session;
+use crate::webhook::Notification;
+
+/// events waiting for the slowest subscriber before it misses them
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerEvent {
+    #[serde(rename_all = "camelCase")]
+    Submitted {
+        task_id: Uuid,
+        path: PathBuf,
+        #[serde(skip)]
+        owner: Option<Uuid>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Progress {
+        task_id: Uuid,
+        progress: Progress,
+        #[serde(skip)]
+        owner: Option<Uuid>,
+    },
+    /// completed, failed, cancelled or timed out, as the webhook is told
+    Finished {
+        #[serde(flatten)]
+        notification: Notification,
+        #[serde(skip)]
+        owner: Option<Uuid>,
+    },
+    /// a batch of file actions, those `/undo` takes back
+    Files { actions: Vec<FileAction> },
+}
+
+/// done to a file, as it happened rather than as it is undone
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAction {
+    /// `trashed`, `moved`, `removed` into the bin of removed files or `linked` to the kept copy
+    action: &'static str,
+    /// where the file was
+    path: PathBuf,
+    /// where it was moved to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<PathBuf>,
+}
+
+impl From<&Undoable> for FileAction {
+    fn from(undoable: &Undoable) -> Self {
+        match undoable {
+            Undoable::Trashed { path } => Self { action: "trashed", path: path.clone(), to: None },
+            Undoable::Moved { from, to } => Self { action: "moved", path: to.clone(), to: Some(from.clone()) },
+            Undoable::Removed { path, .. } => Self { action: "removed", path: path.clone(), to: None },
+            Undoable::Linked { path } => Self { action: "linked", path: path.clone(), to: None },
+        }
+    }
+}
+
+impl ServerEvent {
+    pub fn files(actions: &[Undoable]) -> Self {
+        Self::Files { actions: actions.iter().map(FileAction::from).collect() }
+    }
+
+    /// the SSE event name, the `type` of the data
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Submitted { .. } => "submitted",
+            Self::Progress { .. } => "progress",
+            Self::Finished { .. } => "finished",
+            Self::Files { .. } => "files",
+        }
+    }
+
+    /// those of tasks by the sessions seeing the tasks, file actions by every session, like the history
+    pub fn seen_by(&self, session: &Session) -> bool {
+        match self {
+            Self::Submitted { owner, .. } | Self::Progress { owner, .. } | Self::Finished { owner, .. } => session.sees(*owner),
+            Self::Files { .. } => true,
+        }
+    }
+}
+
+/// the broadcast of the events of a server, or of a tenant
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<Arc<ServerEvent>>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub fn send(&self, event: ServerEvent) {
+        // fails without subscribers only
+        let _ = self.0.send(Arc::new(event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ServerEvent>> {
+        self.0.subscribe()
+    }
+
+    /// sends the milestones of the progress of a task and its outcome, in the background
+    pub fn follow(
+        &self,
+        task_id: Uuid,
+        path: PathBuf,
+        owner: Option<Uuid>,
+        mut progress: Option<watch::Receiver<Progress>>,
+        mut done: oneshot::Receiver<Arc<Result<AnalyzeResult>>>,
+    ) {
+        let events = self.clone();
+        tokio::spawn(async move {
+            let mut milestone: Option<(Phase, usize)> = None;
+            let finished = loop {
+                let Some(rx) = progress.as_mut() else {
+                    break done.await;
+                };
+                tokio::select! {
+                    result = &mut done => break result,
+                    changed = rx.changed() => {
+                        if changed.is_err() {
+                            progress = None;
+                            continue;
+                        }
+                        let current = *rx.borrow_and_update();
+                        let reached = (current.phase, current.percent / 10);
+                        if milestone != Some(reached) {
+                            milestone = Some(reached);
+                            events.send(ServerEvent::Progress { task_id, progress: current, owner });
+                        }
+                    }
+                }
+            };
+            // dropped from the manager in the meantime
+            if let Ok(result) = finished {
+                events.send(ServerEvent::Finished { notification: Notification::new(task_id, path, &result), owner });
+            }
+        });
+    }
+}
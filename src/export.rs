@@ -0,0 +1,211 @@
+//! Reports of the duplicate groups of an analysis, for spreadsheets and scripts, or as a page to send to someone
+//! without access to the server: the thumbnails are embedded, it opens in any browser from an email attachment.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::Result;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Write, path::Path};
+use utoipa::ToSchema;
+
+use crate::analyzer::{AnalyzeResult, Group};
+use crate::s3;
+
+/// of the thumbnails embedded in HTML reports, small enough to mail reports of thousands of files
+pub const THUMBNAIL_SIZE: u32 = 160;
+
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    /// one row per file
+    Csv,
+    /// a standalone page with the thumbnails and the space taken by the copies
+    Html,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::Html => "html",
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedFile<'a> {
+    path: &'a Path,
+    size: u64,
+    date: u64,
+    /// to the first file of the group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedGroup<'a> {
+    fingerprint: &'a str,
+    files: Vec<ExportedFile<'a>>,
+}
+
+fn exported_groups(result: &AnalyzeResult) -> Vec<ExportedGroup<'_>> {
+    result
+        .groups()
+        .iter()
+        .map(|group| ExportedGroup {
+            fingerprint: group.fingerprint(),
+            files: group
+                .files()
+                .iter()
+                .enumerate()
+                .map(|(i, file)| ExportedFile {
+                    path: &file.path,
+                    size: file.size,
+                    date: file.date,
+                    distance: group.distances().get(i).copied(),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// quoted when it has a separator, a quote or a line break
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// `1.5 MB`, in powers of 1024 as file managers show them
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
+
+/// `2024-01-31`, of a time in ms
+fn format_date(ms: u64) -> String {
+    let (year, month, day) = s3::civil_from_days((ms / 86_400_000) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse}td,th{padding:.2em 1em .2em 0;text-align:left}\
+section{border-top:1px solid #ddd;padding:1em 0}figure{display:inline-block;vertical-align:top;width:180px;margin:0 1em 1em 0}\
+img{max-width:160px;max-height:160px}figcaption{font-size:.8em;word-break:break-all}.keep{color:#080;font-weight:bold}";
+
+fn html_group(out: &mut String, n: usize, group: &Group, thumbnails: &HashMap<&Path, String>) -> std::fmt::Result {
+    let stats = group.stats();
+    let keep = group.suggestion().map(|suggestion| suggestion.keep.as_path());
+    writeln!(out, "<section><h2>Group {}</h2>", n)?;
+    writeln!(
+        out,
+        "<p>{} files, {} in all, {} freed keeping one</p>",
+        stats.files,
+        format_size(stats.total_bytes),
+        format_size(stats.reclaimable_bytes)
+    )?;
+    for (i, file) in group.files().iter().enumerate() {
+        let exact = group.identical().iter().any(|same| same.len() > 1 && same.contains(&i));
+        write!(out, "<figure>")?;
+        if let Some(data) = thumbnails.get(file.path.as_path()) {
+            write!(out, "<img src=\"data:image/jpeg;base64,{}\" alt=\"\">", data)?;
+        }
+        write!(out, "<figcaption>")?;
+        if keep == Some(file.path.as_path()) {
+            write!(out, "<span class=\"keep\">keep</span> ")?;
+        }
+        write!(out, "{}<br>{}, {}", escape_html(&file.path.to_string_lossy()), format_size(file.size), format_date(file.date))?;
+        if exact {
+            write!(out, ", exact copy")?;
+        }
+        writeln!(out, "</figcaption></figure>")?;
+    }
+    writeln!(out, "</section>")
+}
+
+/// the page, `thumbnail` gives the JPEG of a file shown above it, files without one are listed without
+pub fn html<F>(result: &AnalyzeResult, thumbnail: F) -> String
+where
+    F: Fn(&Path) -> Option<Vec<u8>> + Sync,
+{
+    let groups = result.groups();
+    let files: Vec<&Path> = groups.iter().flat_map(|group| group.files()).map(|file| file.path.as_path()).collect();
+    let thumbnails: HashMap<&Path, String> = files.par_iter().filter_map(|&path| Some((path, STANDARD.encode(thumbnail(path)?)))).collect();
+    let total: u64 = groups.iter().map(|group| group.stats().total_bytes).sum();
+    let reclaimable: u64 = groups.iter().map(|group| group.stats().reclaimable_bytes).sum();
+
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Duplicate photos</title>");
+    let _ = writeln!(out, "<style>{}</style></head><body>\n<h1>Duplicate photos</h1>", STYLE);
+    let _ = writeln!(
+        out,
+        "<table><tr><th>Groups</th><td>{}</td></tr><tr><th>Files</th><td>{}</td></tr>\
+<tr><th>Space they take</th><td>{}</td></tr><tr><th>Freed keeping one of each</th><td>{}</td></tr></table>",
+        groups.len(),
+        files.len(),
+        format_size(total),
+        format_size(reclaimable)
+    );
+    for (i, group) in groups.iter().enumerate() {
+        let _ = html_group(&mut out, i + 1, group, &thumbnails);
+    }
+    out.push_str("</body></html>\n");
+    out
+}
+
+/// `thumbnail` as for `html`, the other formats have none
+pub fn export<F>(format: ExportFormat, result: &AnalyzeResult, thumbnail: F) -> Result<Vec<u8>>
+where
+    F: Fn(&Path) -> Option<Vec<u8>> + Sync,
+{
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_vec_pretty(&exported_groups(result))?),
+        ExportFormat::Csv => {
+            let groups = exported_groups(result);
+            let mut csv = String::from("group,fingerprint,path,size,date,distance\n");
+            for (i, group) in groups.iter().enumerate() {
+                for file in &group.files {
+                    let distance = file.distance.map(|d| d.to_string()).unwrap_or_default();
+                    csv.push_str(&format!(
+                        "{},{},{},{},{},{}\n",
+                        i + 1,
+                        group.fingerprint,
+                        escape_csv(&file.path.to_string_lossy()),
+                        file.size,
+                        file.date,
+                        distance,
+                    ));
+                }
+            }
+            Ok(csv.into_bytes())
+        }
+        ExportFormat::Html => Ok(html(result, thumbnail).into_bytes()),
+    }
+}
@@ -0,0 +1,194 @@
+//! Actions on the files of duplicate groups, every file succeeds or fails on its own.
+
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Component, Path, PathBuf},
+};
+use utoipa::ToSchema;
+
+use crate::volumes;
+
+/// what happened to a single file of a batch
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FileOutcome {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    /// where the file ended up, if it was moved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    destination: Option<PathBuf>,
+    /// `None` when it went fine
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl FileOutcome {
+    pub fn new(path: PathBuf, result: Result<()>) -> Self {
+        Self::moved(path, result.map(|()| None))
+    }
+
+    pub fn moved(path: PathBuf, result: Result<Option<PathBuf>>) -> Self {
+        match result {
+            Ok(destination) => Self { path, destination, error: None },
+            Err(err) => {
+                tracing::warn!(path = path.to_str(), "file action failed: {}", err);
+                Self { path, destination: None, error: Some(err.to_string()) }
+            }
+        }
+    }
+}
+
+/// The closest existing folder of a target that may not exist yet,
+/// `None` for relative targets or ones with `..` which could escape it.
+pub fn existing_ancestor(target: &Path) -> Option<&Path> {
+    if !target.is_absolute() || target.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
+    target.ancestors().find(|dir| dir.is_dir())
+}
+
+/// how duplicates are made to share the data of the kept copy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum LinkMode {
+    /// one file under several names, edits show up in all of them
+    #[default]
+    Hardlink,
+    /// copy on write clone, on btrfs, XFS and APFS only
+    Reflink,
+}
+
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut buf_a = vec![0; 64 * 1024];
+    let mut buf_b = vec![0; 64 * 1024];
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Replaces the duplicate with a link to the kept copy once their content is verified
+/// to be the same. The link is made next to it first, so the duplicate is either
+/// replaced or left as it was.
+pub fn link_to(keep: &Path, path: &Path, mode: LinkMode) -> Result<()> {
+    if !path.is_file() {
+        bail!("not a file");
+    }
+    if volumes::canonicalize(keep)? == volumes::canonicalize(path)? {
+        bail!("the kept copy itself");
+    }
+    if !same_content(keep, path)? {
+        bail!("content differs from {}", keep.display());
+    }
+
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("not a file");
+    };
+    // hidden, so scans running meanwhile skip it
+    let tmp = dir.join(format!(".{}.{}.link", name.to_string_lossy(), uuid::Uuid::new_v4()));
+
+    tracing::info!(keep = keep.to_str(), path = path.to_str(), ?mode, "linking file");
+    let linked = match mode {
+        LinkMode::Hardlink => fs::hard_link(keep, &tmp),
+        LinkMode::Reflink => reflink_copy::reflink(keep, &tmp),
+    };
+    linked?;
+    if let Err(err) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Renames without ever replacing the destination, even one created since it was
+/// looked at: linking fails if it exists, then the old name is removed.
+fn rename_new(path: &Path, dest: &Path) -> io::Result<()> {
+    fs::hard_link(path, dest)?;
+    if let Err(err) = fs::remove_file(path) {
+        let _ = fs::remove_file(dest);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Gives a linked file data of its own again, a copy made next to it replaces
+/// the link, so the file is either unlinked or left as it was.
+pub fn unlink(path: &Path) -> Result<()> {
+    if !path.is_file() {
+        bail!("not a file");
+    }
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("not a file");
+    };
+    let tmp = dir.join(format!(".{}.{}.copy", name.to_string_lossy(), uuid::Uuid::new_v4()));
+
+    tracing::info!(path = path.to_str(), "unlinking file");
+    fs::copy(path, &tmp)?;
+    if let Err(err) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// moves the file into the folder, creating it as needed, never overwrites
+pub fn move_to(path: &Path, dir: &Path) -> Result<PathBuf> {
+    if !path.is_file() {
+        bail!("not a file");
+    }
+    let Some(name) = path.file_name() else {
+        bail!("not a file");
+    };
+    let dest = dir.join(name);
+
+    fs::create_dir_all(dir)?;
+    tracing::info!(src = path.to_str(), dest = dest.to_str(), "moving file");
+    match rename_new(path, &dest) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => bail!("{} already exists", dest.display()),
+        // e.g. to another disk or one without links, copy into a new file so nothing gets overwritten
+        Err(err) if matches!(err.kind(), io::ErrorKind::CrossesDevices | io::ErrorKind::Unsupported) => {
+            let mut dest_file = match File::options().write(true).create_new(true).open(&dest) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => bail!("{} already exists", dest.display()),
+                Err(err) => return Err(err.into()),
+            };
+            io::copy(&mut File::open(path)?, &mut dest_file)?;
+            dest_file.sync_all()?;
+            fs::remove_file(path)?;
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(dest)
+}
+
+/// moves the file into the system trash, or deletes it for good
+pub fn delete(path: &Path, permanent: bool) -> Result<()> {
+    if !path.is_file() {
+        bail!("not a file");
+    }
+
+    tracing::info!(path = path.to_str(), permanent, "deleting file");
+    if permanent {
+        fs::remove_file(path)?;
+    } else {
+        trash::delete(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+//! Synthetic image sets with known duplicates, used by the pipeline tests
+//! and by the hidden `gen-fixtures` command for reproducing grouping bugs.
+
+use eyre::Result;
+use image::{imageops, DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+const SIZE: u32 = 256;
+const ORIGINALS: u64 = 3;
+const UNRELATED: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FixtureKind {
+    Original,
+    ExactCopy,
+    Recompressed,
+    Resized,
+    Cropped,
+    /// with data after its end marker, as phones and editors write them
+    Padded,
+    /// perceptual hashes aren't rotation invariant, ends up on its own
+    Rotated,
+    Unrelated,
+    Truncated,
+    NotAnImage,
+}
+
+impl FixtureKind {
+    /// whether the analysis is expected to group it with its source
+    pub fn matches_source(self) -> bool {
+        matches!(self, Self::ExactCopy | Self::Recompressed | Self::Resized | Self::Cropped | Self::Padded)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Fixture {
+    pub path: PathBuf,
+    pub kind: FixtureKind,
+    /// the original the fixture is derived from
+    pub source: Option<PathBuf>,
+}
+
+/// tiny deterministic generator, fixtures must be the same on every run
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: u32) -> u32 {
+        self.0 = self.0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        ((self.0 >> 33) as u32) % bound
+    }
+}
+
+/// random rectangles over a gradient, distinct seeds give unrelated pictures
+fn pattern(seed: u64) -> RgbImage {
+    let mut rng = Lcg(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    let (r, g, b) = (rng.next(256), rng.next(256), rng.next(256));
+    let mut image = RgbImage::from_fn(SIZE, SIZE, |x, y| {
+        Rgb([
+            ((r + x) % 256) as u8,
+            ((g + y) % 256) as u8,
+            ((b + x / 2 + y / 2) % 256) as u8,
+        ])
+    });
+
+    for _ in 0..12 {
+        let (x0, y0) = (rng.next(SIZE), rng.next(SIZE));
+        let (w, h) = (16 + rng.next(SIZE / 2), 16 + rng.next(SIZE / 2));
+        let color = Rgb([rng.next(256) as u8, rng.next(256) as u8, rng.next(256) as u8]);
+        for y in y0..(y0 + h).min(SIZE) {
+            for x in x0..(x0 + w).min(SIZE) {
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    image
+}
+
+fn save(image: &RgbImage, path: &Path, format: ImageOutputFormat) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    DynamicImage::ImageRgb8(image.clone()).write_to(&mut writer, format)?;
+    Ok(())
+}
+
+/// writes the fixture set into the folder and returns what has been written
+pub fn generate(dir: &Path) -> Result<Vec<Fixture>> {
+    let originals = dir.join("originals");
+    let copies = dir.join("copies");
+    let unrelated = dir.join("unrelated");
+    for folder in [&originals, &copies, &unrelated] {
+        fs::create_dir_all(folder)?;
+    }
+
+    let mut fixtures = Vec::new();
+    let mut add = |path: PathBuf, kind, source: Option<&Path>| {
+        fixtures.push(Fixture { path, kind, source: source.map(Path::to_owned) });
+    };
+
+    for seed in 0..ORIGINALS {
+        let image = pattern(seed);
+        let original = originals.join(format!("photo-{}.png", seed));
+        save(&image, &original, ImageOutputFormat::Png)?;
+        add(original.clone(), FixtureKind::Original, None);
+
+        let copy = copies.join(format!("photo-{} (1).png", seed));
+        fs::copy(&original, &copy)?;
+        add(copy, FixtureKind::ExactCopy, Some(&original));
+
+        let recompressed = copies.join(format!("photo-{}.jpg", seed));
+        save(&image, &recompressed, ImageOutputFormat::Jpeg(50))?;
+        add(recompressed.clone(), FixtureKind::Recompressed, Some(&original));
+
+        let resized = copies.join(format!("photo-{}-small.png", seed));
+        let small = imageops::resize(&image, SIZE / 2, SIZE / 2, imageops::FilterType::Triangle);
+        save(&small, &resized, ImageOutputFormat::Png)?;
+        add(resized, FixtureKind::Resized, Some(&original));
+
+        let cropped = copies.join(format!("photo-{}-cropped.png", seed));
+        let border = SIZE / 32;
+        let crop = imageops::crop_imm(&image, border, border, SIZE - 2 * border, SIZE - 2 * border).to_image();
+        save(&crop, &cropped, ImageOutputFormat::Png)?;
+        add(cropped, FixtureKind::Cropped, Some(&original));
+
+        let rotated = copies.join(format!("photo-{}-rotated.png", seed));
+        save(&imageops::rotate90(&image), &rotated, ImageOutputFormat::Png)?;
+        add(rotated, FixtureKind::Rotated, Some(&original));
+
+        let content = fs::read(&recompressed)?;
+        let padded = copies.join(format!("photo-{}-padded.jpg", seed));
+        fs::write(&padded, [content.as_slice(), &[0; 4096]].concat())?;
+        add(padded, FixtureKind::Padded, Some(&original));
+
+        let truncated = copies.join(format!("photo-{}-truncated.jpg", seed));
+        fs::write(&truncated, &content[..content.len() / 2])?;
+        add(truncated, FixtureKind::Truncated, Some(&original));
+    }
+
+    for seed in 0..UNRELATED {
+        let path = unrelated.join(format!("other-{}.png", seed));
+        save(&pattern(ORIGINALS + seed), &path, ImageOutputFormat::Png)?;
+        add(path, FixtureKind::Unrelated, None);
+    }
+
+    let notes = dir.join("notes.txt");
+    fs::write(&notes, "not an image")?;
+    add(notes, FixtureKind::NotAnImage, None);
+
+    Ok(fixtures)
+}
+
+/// a JPEG of one pattern with the EXIF preview of another, as cameras write them
+#[cfg(test)]
+pub fn camera_jpeg(path: &Path, seed: u64, preview_seed: u64) -> Result<()> {
+    let encode = |image: &RgbImage| -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(image.clone()).write_to(&mut std::io::Cursor::new(&mut data), ImageOutputFormat::Jpeg(80))?;
+        Ok(data)
+    };
+    let main = encode(&pattern(seed))?;
+    let preview = encode(&imageops::resize(&pattern(preview_seed), SIZE / 4, SIZE / 4, imageops::FilterType::Triangle))?;
+
+    // little endian TIFF with an empty IFD0 and an IFD1 pointing at the preview, which follows it
+    let mut tiff = b"II\x2a\x00".to_vec();
+    tiff.extend(8u32.to_le_bytes());
+    tiff.extend(0u16.to_le_bytes());
+    tiff.extend(14u32.to_le_bytes());
+    tiff.extend(2u16.to_le_bytes());
+    for (tag, value) in [(0x0201u16, 44u32), (0x0202, preview.len() as u32)] {
+        tiff.extend(tag.to_le_bytes());
+        tiff.extend(4u16.to_le_bytes());
+        tiff.extend(1u32.to_le_bytes());
+        tiff.extend(value.to_le_bytes());
+    }
+    tiff.extend(0u32.to_le_bytes());
+    tiff.extend(&preview);
+
+    let mut data = vec![0xff, 0xd8, 0xff, 0xe1];
+    data.extend(((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    data.extend(b"Exif\0\0");
+    data.extend(tiff);
+    data.extend(&main[2..]);
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// a JPEG of the image with an EXIF orientation, e.g. 6 for one to be turned clockwise
+#[cfg(test)]
+pub fn oriented_jpeg(path: &Path, image: &RgbImage, orientation: u16) -> Result<()> {
+    let mut main = Vec::new();
+    DynamicImage::ImageRgb8(image.clone()).write_to(&mut std::io::Cursor::new(&mut main), ImageOutputFormat::Jpeg(90))?;
+
+    // little endian TIFF with the orientation as the only entry of IFD0
+    let mut tiff = b"II\x2a\x00".to_vec();
+    tiff.extend(8u32.to_le_bytes());
+    tiff.extend(1u16.to_le_bytes());
+    for field in [0x0112u16, 3] {
+        tiff.extend(field.to_le_bytes());
+    }
+    tiff.extend(1u32.to_le_bytes());
+    tiff.extend([orientation.to_le_bytes(), [0; 2]].concat());
+    tiff.extend(0u32.to_le_bytes());
+
+    let mut data = vec![0xff, 0xd8, 0xff, 0xe1];
+    data.extend(((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    data.extend(b"Exif\0\0");
+    data.extend(tiff);
+    data.extend(&main[2..]);
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// groups a correct analysis is expected to find
+pub fn expected_groups(fixtures: &[Fixture]) -> BTreeSet<BTreeSet<PathBuf>> {
+    fixtures
+        .iter()
+        .filter(|f| f.kind == FixtureKind::Original)
+        .map(|original| {
+            let mut group: BTreeSet<PathBuf> = fixtures
+                .iter()
+                .filter(|f| f.kind.matches_source() && f.source.as_ref() == Some(&original.path))
+                .map(|f| f.path.clone())
+                .collect();
+            group.insert(original.path.clone());
+            group
+        })
+        .collect()
+}
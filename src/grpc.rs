@@ -0,0 +1,318 @@
+//! The task API over gRPC, see `proto/image_analyzer.proto`.
+//! It shares the analyzer of the HTTP API, tasks submitted by either show up in both.
+
+// tonic's `Status` is the error of every handler
+#![allow(clippy::result_large_err)]
+
+use futures::{Stream, StreamExt};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::{TcpListenerStream, WatchStream};
+use tonic::{transport::Server, Code, Request, Response, Status};
+use uuid::Uuid;
+
+use crate::analyzer::{self, AnalyzeRequest, AnalyzeResult, CacheMode, CoarsePass, ExactBy, HashSize, HashType, Likeness, Phase, Progress, ProgressUnit};
+use crate::auth::Auth;
+use crate::error::{ErrorBody, ErrorCode};
+use crate::manager::{Cancelled, Priority, TaskResponse, TimedOut};
+use crate::ratelimit::Limiter;
+use crate::session::Session;
+use crate::server::{request_poll, request_progress, request_result, request_submit, request_task, AnalyzeResponse, AppError, AppState, TaskResult};
+
+pub mod proto {
+    tonic::include_proto!("image_analyzer");
+}
+
+use proto::{analyzer_server::AnalyzerServer, task_state::State};
+
+/// the gRPC code of the HTTP status the same error gets
+fn code(code: ErrorCode) -> Code {
+    match code {
+        ErrorCode::BadRequest => Code::InvalidArgument,
+        ErrorCode::Unauthorized => Code::Unauthenticated,
+        ErrorCode::Forbidden | ErrorCode::PermissionDenied => Code::PermissionDenied,
+        ErrorCode::NotFound => Code::NotFound,
+        ErrorCode::Conflict | ErrorCode::Locked => Code::FailedPrecondition,
+        ErrorCode::TooManyRequests => Code::ResourceExhausted,
+        ErrorCode::Unavailable => Code::Unavailable,
+        ErrorCode::Internal => Code::Internal,
+    }
+}
+
+/// as in the JSON error bodies
+fn code_name(code: ErrorCode) -> String {
+    match serde_json::to_value(code) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+fn status(err: AppError) -> Status {
+    let body = err.body();
+    Status::new(code(body.code), body.message)
+}
+
+fn task_id(request: Request<proto::TaskId>) -> Result<Uuid, Status> {
+    Uuid::parse_str(&request.into_inner().task_id).map_err(|err| Status::invalid_argument(err.to_string()))
+}
+
+/// gRPC clients have no session, they see the tasks submitted without one
+async fn check_task(state: &AppState, task_id: Uuid) -> Result<Uuid, Status> {
+    request_task(state, &Session::default(), task_id).await.map_err(status)?;
+    Ok(task_id)
+}
+
+fn hash_type(hash_type: proto::HashType) -> HashType {
+    match hash_type {
+        proto::HashType::Dhash => HashType::DHash,
+        proto::HashType::Ahash => HashType::AHash,
+        proto::HashType::Phash => HashType::PHash,
+    }
+}
+
+fn hash_size(size: u32) -> Result<HashSize, Status> {
+    match size {
+        0 => Ok(HashSize::default()),
+        size => HashSize::try_from(size).map_err(Status::invalid_argument),
+    }
+}
+
+fn analyze_request(req: proto::SubmitRequest) -> Result<AnalyzeRequest, Status> {
+    let hash_type = hash_type(req.hash_type());
+    let hash_size = hash_size(req.hash_size)?;
+    let coarse = match &req.coarse {
+        Some(coarse) => Some(CoarsePass { hash_type: self::hash_type(coarse.hash_type()), hash_size: self::hash_size(coarse.hash_size)?, dist: coarse.dist, fast: coarse.fast }),
+        None => None,
+    };
+    let cache_mode = match req.cache_mode() {
+        proto::CacheMode::Path => CacheMode::Path,
+        proto::CacheMode::Content => CacheMode::Content,
+    };
+    let progress_unit = match req.progress_unit() {
+        proto::ProgressUnit::Files => ProgressUnit::Files,
+        proto::ProgressUnit::Bytes => ProgressUnit::Bytes,
+    };
+    let exact_by = match req.exact_by() {
+        proto::ExactBy::Bytes => ExactBy::Bytes,
+        proto::ExactBy::Pixels => ExactBy::Pixels,
+    };
+    let priority = match req.priority() {
+        proto::Priority::Normal => Priority::Normal,
+        proto::Priority::Low => Priority::Low,
+        proto::Priority::High => Priority::High,
+    };
+    let callback = req
+        .callback
+        .as_deref()
+        .map(url::Url::parse)
+        .transpose()
+        .map_err(|err| Status::invalid_argument(format!("invalid callback: {}", err)))?;
+    Ok(AnalyzeRequest {
+        dist: req.dist,
+        path: req.path.into(),
+        hash_type,
+        hash_size,
+        max_minutes: req.max_minutes,
+        retries: req.retries.unwrap_or_else(analyzer::default_retries),
+        cache_mode,
+        timeout_minutes: req.timeout_minutes,
+        priority,
+        #[cfg(feature = "ocr")]
+        ocr: req.ocr,
+        edges: req.edges,
+        fast: req.fast,
+        incremental: req.incremental,
+        archives: req.archives,
+        coarse,
+        progress_unit,
+        exact_by,
+        files: (!req.files.is_empty()).then(|| req.files.into_iter().map(PathBuf::from).collect()),
+        callback,
+        owner: None,
+    })
+}
+
+fn progress(progress: Progress) -> proto::Progress {
+    let phase = match progress.phase {
+        Phase::Listing => proto::Phase::Listing,
+        Phase::Hashing => proto::Phase::Hashing,
+        Phase::Grouping => proto::Phase::Grouping,
+    };
+    let unit = match progress.unit {
+        ProgressUnit::Files => proto::ProgressUnit::Files,
+        ProgressUnit::Bytes => proto::ProgressUnit::Bytes,
+    };
+    proto::Progress {
+        phase: phase.into(),
+        percent: progress.percent as u32,
+        unit: unit.into(),
+        files: progress.files as u64,
+        total_files: progress.total_files as u64,
+        bytes: progress.bytes,
+        total_bytes: progress.total_bytes,
+        eta: progress.eta,
+        segment: progress.segment as u32,
+        segments: progress.segments as u32,
+        shards: progress.shards as u64,
+        total_shards: progress.total_shards as u64,
+    }
+}
+
+fn analyze_result(result: &AnalyzeResult) -> proto::AnalyzeResult {
+    let groups = result
+        .groups()
+        .iter()
+        .map(|group| proto::Group {
+            fingerprint: group.fingerprint().to_owned(),
+            files: group
+                .files()
+                .iter()
+                .map(|file| proto::FileInfo {
+                    path: file.path.to_string_lossy().into_owned(),
+                    size: file.size,
+                    date: file.date,
+                    modified: file.modified,
+                    hash: file.hash.clone().unwrap_or_default(),
+                    checksum: file.checksum.clone().unwrap_or_default(),
+                })
+                .collect(),
+            distances: group.distances().to_vec(),
+            nearest: group
+                .nearest()
+                .iter()
+                .map(|neighbor| proto::Neighbor { index: neighbor.index as u64, distance: neighbor.distance })
+                .collect(),
+            matrix: group.matrix().iter().map(|row| proto::DistanceRow { distances: row.clone() }).collect(),
+            representative: group.representative() as u64,
+            exact: group.likeness() == Some(Likeness::Exact),
+            identical: group.identical().iter().map(|same| proto::FileSet { indices: same.iter().map(|&i| i as u64).collect() }).collect(),
+            edges: group
+                .edges()
+                .iter()
+                .map(|edge| proto::Edge {
+                    from: edge.from as u64,
+                    to: edge.to as u64,
+                    distance: edge.distance,
+                    text: edge.text,
+                    exact: edge.exact,
+                })
+                .collect(),
+        })
+        .collect();
+    proto::AnalyzeResult { groups }
+}
+
+/// classified the same way as the HTTP polls
+fn task_state(resp: &TaskResponse<Progress, Arc<TaskResult>>) -> proto::TaskState {
+    let state = match AnalyzeResponse::new(resp) {
+        AnalyzeResponse::Queued { position } => State::Queued(proto::Queued { position: position as u64 }),
+        AnalyzeResponse::Pending { progress: p } => State::Pending(progress(p)),
+        AnalyzeResponse::Completed { data } => State::Completed(analyze_result(data)),
+        AnalyzeResponse::Failed { error, code, path } => State::Failed(proto::Failure {
+            code: code_name(code),
+            message: error,
+            path: path.map(|path| path.to_string_lossy().into_owned()),
+        }),
+        AnalyzeResponse::Cancelled => State::Cancelled(proto::Cancelled {}),
+        AnalyzeResponse::TimedOut => State::TimedOut(proto::TimedOut {}),
+    };
+    proto::TaskState { state: Some(state) }
+}
+
+pub struct Service {
+    state: Arc<AppState>,
+    /// submissions share the quota of `/analyze`, but not its buckets
+    submissions: Limiter,
+}
+
+type TaskStates = Pin<Box<dyn Stream<Item = Result<proto::TaskState, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl proto::analyzer_server::Analyzer for Service {
+    async fn submit(&self, request: Request<proto::SubmitRequest>) -> Result<Response<proto::TaskId>, Status> {
+        let client = request.remote_addr().map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
+        if let Err(wait) = self.submissions.acquire(client) {
+            tracing::warn!(client = %client, "rate limited");
+            let message = format!("too many requests, try again in {}s", wait.as_secs().max(1));
+            return Err(Status::resource_exhausted(message));
+        }
+
+        let req = analyze_request(request.into_inner())?;
+        let task_id = request_submit(&self.state, req).await.map_err(status)?;
+        Ok(Response::new(proto::TaskId { task_id: task_id.to_string() }))
+    }
+
+    async fn poll(&self, request: Request<proto::TaskId>) -> Result<Response<proto::TaskState>, Status> {
+        let task_id = check_task(&self.state, task_id(request)?).await?;
+        let resp = request_poll(&self.state, task_id).await.map_err(status)?;
+        Ok(Response::new(task_state(&resp)))
+    }
+
+    type SubscribeStream = TaskStates;
+
+    async fn subscribe(&self, request: Request<proto::TaskId>) -> Result<Response<TaskStates>, Status> {
+        let task_id = check_task(&self.state, task_id(request)?).await?;
+        let updates = request_progress(&self.state, task_id).await.map_err(status)?;
+        let result = request_result(&self.state, task_id).await.map_err(status)?;
+
+        // progress until the task is done, then its outcome
+        let finished = futures::stream::once(async move {
+            match result.await {
+                Ok(result) => Ok(task_state(&TaskResponse::Completed(result))),
+                Err(_) => Err(Status::not_found("task is gone")),
+            }
+        });
+        let stream = WatchStream::new(updates)
+            .map(|p| Ok(proto::TaskState { state: Some(State::Pending(progress(p))) }))
+            .chain(finished);
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn result(&self, request: Request<proto::TaskId>) -> Result<Response<proto::AnalyzeResult>, Status> {
+        let task_id = check_task(&self.state, task_id(request)?).await?;
+        let result = request_result(&self.state, task_id).await.map_err(status)?;
+        let result = result.await.map_err(|_| Status::not_found("task is gone"))?;
+        match &*result {
+            Ok(data) => Ok(Response::new(analyze_result(data))),
+            Err(err) if err.is::<Cancelled>() => Err(Status::cancelled("task cancelled")),
+            Err(err) if err.is::<TimedOut>() => Err(Status::deadline_exceeded("task timed out")),
+            Err(err) => {
+                let body = ErrorBody::of_report(err);
+                Err(Status::new(code(body.code), body.message))
+            }
+        }
+    }
+}
+
+/// checks the same credentials as the HTTP API, in the `authorization` metadata
+fn check_auth(auth: Option<Arc<Auth>>) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |request| match &auth {
+        Some(auth) => {
+            let header = request.metadata().get("authorization").map(|value| value.as_bytes());
+            if header.is_some_and(|header| auth.permits_header(header)) {
+                Ok(request)
+            } else {
+                Err(Status::unauthenticated("missing or wrong credentials"))
+            }
+        }
+        None => Ok(request),
+    }
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<AppState>,
+    auth: Option<Auth>,
+    analyze_per_minute: u32,
+) -> Result<(), tonic::transport::Error> {
+    let service = Service { state, submissions: Limiter::new(analyze_per_minute) };
+    Server::builder()
+        .add_service(AnalyzerServer::with_interceptor(service, check_auth(auth.map(Arc::new))))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
@@ -0,0 +1,88 @@
+//! Perceptual hashes of images. The built-in ones are those of `image_hasher`, others are plugged in with
+//! [`register_hasher`] under the name requests select them by as their `hashType`, e.g. a fingerprint of scanned documents.
+//! Hashes are compared bit by bit whatever made them, the more bits apart the less alike the images look.
+
+use eyre::{bail, Result};
+use image::DynamicImage;
+use image_hasher::{HashAlg, Hasher, HasherConfig};
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use crate::analyzer::{HashSize, HashType};
+
+pub use image_hasher::ImageHash;
+
+/// hashes the images of an analysis, made once for it by the factory of its hash type
+pub trait ImageHasher: Send + Sync {
+    /// of `size * size` bits at the size the hasher was made for, hashes of other lengths never match
+    fn hash(&self, image: &DynamicImage) -> ImageHash;
+}
+
+impl ImageHasher for Hasher {
+    fn hash(&self, image: &DynamicImage) -> ImageHash {
+        self.hash_image(image)
+    }
+}
+
+/// makes the hasher of a hash size
+type Factory = Arc<dyn Fn(HashSize) -> Box<dyn ImageHasher> + Send + Sync>;
+
+fn registry() -> &'static RwLock<HashMap<&'static str, Factory>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<&'static str, Factory>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// the name of a registered hash type
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub struct HasherName(&'static str);
+
+impl HasherName {
+    pub fn as_str(self) -> &'static str {
+        self.0
+    }
+
+    /// `None` unless a hasher was registered under it
+    pub(crate) fn lookup(name: &str) -> Option<Self> {
+        registry().read().unwrap().get_key_value(name).map(|(name, _)| Self(name))
+    }
+}
+
+/// Adds a hash type, selected by `name` from then on. Its hashes are cached apart from those of the others,
+/// by the name, so a hasher changing its hashes needs a new one. Fails for names already taken.
+pub fn register_hasher<F, H>(name: &'static str, make: F) -> Result<HashType>
+where
+    F: Fn(HashSize) -> H + Send + Sync + 'static,
+    H: ImageHasher + 'static,
+{
+    if HashType::builtin(name).is_some() {
+        bail!("{} is a built-in hash type", name);
+    }
+    let mut registry = registry().write().unwrap();
+    if registry.contains_key(name) {
+        bail!("a hasher is registered as {} already", name);
+    }
+    registry.insert(name, Arc::new(move |size| Box::new(make(size)) as Box<dyn ImageHasher>));
+    tracing::info!(name, "hasher registered");
+    Ok(HashType::Custom(HasherName(name)))
+}
+
+/// the hasher of an analysis
+pub(crate) fn make(hash_type: HashType, hash_size: HashSize) -> Box<dyn ImageHasher> {
+    let (hash_alg, dct) = match hash_type {
+        HashType::AHash => (HashAlg::Mean, false),
+        HashType::PHash => (HashAlg::Mean, true),
+        HashType::DHash => (HashAlg::Gradient, false),
+        HashType::Custom(name) => {
+            // names are only made by registering them
+            let make = registry().read().unwrap()[name.0].clone();
+            return make(hash_size);
+        }
+    };
+    let mut config = HasherConfig::new().hash_size(hash_size.get(), hash_size.get()).hash_alg(hash_alg);
+    if dct {
+        config = config.preproc_dct();
+    }
+    Box::new(config.to_hasher())
+}
@@ -0,0 +1,109 @@
+//! Analyses run from the command line, for cron jobs and shell pipelines.
+//! The groups go to stdout, the progress bar to stderr and only when it is a terminal.
+
+use eyre::Result;
+use std::{
+    fs,
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{sync::watch, task};
+
+use crate::analyzer::{AnalyzeRequest, AnalyzeResult, HashSize, HashType, Phase, Progress, ProgressUnit};
+use crate::manager::CancelToken;
+use crate::roots::Roots;
+
+const BAR_WIDTH: usize = 30;
+
+/// the defaults of the HTTP API for everything but the hashes
+pub fn request(path: PathBuf, dist: u32, hash_type: HashType, hash_size: HashSize) -> AnalyzeRequest {
+    AnalyzeRequest { dist, hash_type, hash_size, ..AnalyzeRequest::new(path) }
+}
+
+fn draw_progress(out: &mut impl Write, progress: &Progress) -> io::Result<()> {
+    match progress.phase {
+        Phase::Listing => write!(out, "\r\x1b[Klisting files..."),
+        Phase::Hashing => {
+            let done = progress.percent.min(100) * BAR_WIDTH / 100;
+            write!(out, "\r\x1b[K[{}{}] {:>3}% ", "#".repeat(done), "-".repeat(BAR_WIDTH - done), progress.percent)?;
+            match progress.unit {
+                ProgressUnit::Files => write!(out, "{}/{} files", progress.files, progress.total_files)?,
+                ProgressUnit::Bytes => write!(out, "{}/{} MB", progress.bytes / 1_000_000, progress.total_bytes / 1_000_000)?,
+            }
+            match progress.eta {
+                Some(eta) => write!(out, ", {}s left", eta),
+                None => Ok(()),
+            }
+        }
+        Phase::Grouping => write!(
+            out,
+            "\r\x1b[Kgrouping {} files... {:>3}% (segment {}/{}, {}/{} shards)",
+            progress.total_files, progress.percent, progress.segment, progress.segments, progress.shards, progress.total_shards
+        ),
+    }?;
+    out.flush()
+}
+
+/// the paths of each group on lines of their own, a blank line between groups
+pub fn write_groups(out: &mut impl Write, result: &AnalyzeResult, json: bool) -> Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *out, result)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    for (i, group) in result.groups().iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        for file in group.files() {
+            writeln!(out, "{}", file.path.display())?;
+        }
+    }
+    Ok(())
+}
+
+/// A path per line of the file, or of stdin for `-`, e.g. the output of `find`. Relative paths are taken from the
+/// current folder, blank lines are left out.
+pub fn read_list(source: &Path) -> Result<Vec<PathBuf>> {
+    let list = match source == Path::new("-") {
+        true => {
+            let mut list = String::new();
+            io::stdin().read_to_string(&mut list)?;
+            list
+        }
+        false => fs::read_to_string(source)?,
+    };
+    list.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(std::path::absolute(line)?))
+        .collect()
+}
+
+pub async fn analyze(data_dir: PathBuf, req: AnalyzeRequest, json: bool) -> Result<()> {
+    eyre::ensure!(req.path.is_dir(), "{} is not a folder", req.path.display());
+
+    let (tx, mut progress) = watch::channel(Progress::default());
+    // the cache blocks on its own thread, keep it off the runtime
+    let analysis = task::spawn_blocking(move || {
+        let roots = Arc::new(Roots::open(data_dir.join("roots.json"))?);
+        let engine = crate::server::open_engine(&data_dir, roots, Arc::default())?;
+        let result = engine.analyze(&req, tx, &CancelToken::default());
+        engine.flush_cache()?;
+        result
+    });
+
+    let bar = io::stderr().is_terminal();
+    // ends when the analysis drops the sender
+    while progress.changed().await.is_ok() {
+        if bar {
+            draw_progress(&mut io::stderr(), &progress.borrow_and_update())?;
+        }
+    }
+    if bar {
+        eprint!("\r\x1b[K");
+    }
+
+    let result = analysis.await??;
+    write_groups(&mut io::stdout().lock(), &result, json)
+}
@@ -0,0 +1,181 @@
+//! Recent batches of file actions, so the last one can be undone.
+//! Permanent deletes can't be taken back and are not recorded.
+
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use uuid::Uuid;
+
+use crate::files::{self, FileOutcome};
+use crate::remover::Remover;
+use crate::schema;
+use crate::tasks::to_millis;
+use crate::volumes;
+
+/// schema version of stored batches
+const VERSION: u32 = 1;
+
+/// batches kept, the oldest are forgotten first
+const MAX_BATCHES: usize = 50;
+
+/// an action and what is needed to take it back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Undoable {
+    /// into the system trash
+    Trashed { path: PathBuf },
+    Moved { from: PathBuf, to: PathBuf },
+    /// into the bin of removed files
+    Removed { id: String, path: PathBuf },
+    /// replaced with a link to the kept copy, undone with a copy of its own
+    Linked { path: PathBuf },
+}
+
+impl Undoable {
+    /// where the file was before the action
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Trashed { path } | Self::Removed { path, .. } | Self::Linked { path } => path,
+            Self::Moved { to, .. } => to,
+        }
+    }
+
+    pub fn undo(&self, remover: &Remover) -> Result<()> {
+        match self {
+            Self::Trashed { path } => restore_trashed(path),
+            Self::Moved { from, to } => {
+                let Some(dir) = to.parent() else {
+                    bail!("no folder to move {} back to", to.display());
+                };
+                files::move_to(from, dir)?;
+                Ok(())
+            }
+            Self::Removed { id, .. } => {
+                remover.restore(id)?;
+                Ok(())
+            }
+            Self::Linked { path } => files::unlink(path),
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "windows",
+    all(unix, not(target_os = "macos"), not(target_os = "ios"), not(target_os = "android"))
+))]
+fn restore_trashed(path: &Path) -> Result<()> {
+    // the trash keeps resolved paths
+    let resolved = path.parent().and_then(|dir| volumes::canonicalize(dir).ok()).zip(path.file_name());
+    let path = resolved.map_or_else(|| path.to_owned(), |(dir, name)| dir.join(name));
+    // the latest if it was trashed more than once
+    let item = trash::os_limited::list()?
+        .into_iter()
+        .filter(|item| item.original_path() == path)
+        .max_by_key(|item| item.time_deleted);
+    let Some(item) = item else {
+        bail!("no longer in the trash");
+    };
+    trash::os_limited::restore_all([item])?;
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "windows",
+    all(unix, not(target_os = "macos"), not(target_os = "ios"), not(target_os = "android"))
+)))]
+fn restore_trashed(_path: &Path) -> Result<()> {
+    bail!("restoring from the trash is not supported on this system, use the system trash instead")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Batch {
+    /// ms since the epoch
+    time: u64,
+    actions: Vec<Undoable>,
+}
+
+/// the time a batch file is named by
+fn batch_time(path: &Path) -> Option<u64> {
+    path.file_name()?.to_str()?.split('-').next()?.parse().ok()
+}
+
+/// one JSON file per batch, named so they sort by time
+#[derive(Debug)]
+pub struct History {
+    dir: PathBuf,
+    /// keeps concurrent undos from taking the same batch
+    lock: Mutex<()>,
+}
+
+impl History {
+    pub fn new<T>(dir: T) -> Self
+    where
+        PathBuf: From<T>
+    {
+        Self { dir: PathBuf::from(dir), lock: Mutex::new(()) }
+    }
+
+    fn batches(&self) -> Result<Vec<PathBuf>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut batches = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                batches.push(path);
+            }
+        }
+        batches.sort();
+        Ok(batches)
+    }
+
+    /// nothing is recorded for an empty batch
+    pub fn record(&self, actions: Vec<Undoable>) -> Result<()> {
+        if actions.is_empty() {
+            return Ok(());
+        }
+
+        let _lock = self.lock.lock().unwrap();
+        // batches of the same millisecond would sort by their random suffix
+        let last = self.batches()?.last().and_then(|path| batch_time(path));
+        let time = to_millis(SystemTime::now()).max(last.map_or(0, |last| last + 1));
+        let batch = Batch { time, actions };
+        fs::create_dir_all(&self.dir)?;
+        let name = format!("{:016}-{}.json", batch.time, Uuid::new_v4());
+        fs::write(self.dir.join(name), schema::encode(&batch, VERSION)?)?;
+
+        let batches = self.batches()?;
+        for path in &batches[..batches.len().saturating_sub(MAX_BATCHES)] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Takes back the last batch, its actions in reverse order, `None` without one.
+    /// Actions that fail are reported and the batch is forgotten all the same.
+    pub fn undo_last(&self, remover: &Remover) -> Result<Option<Vec<FileOutcome>>> {
+        let _lock = self.lock.lock().unwrap();
+        let Some(path) = self.batches()?.pop() else {
+            return Ok(None);
+        };
+        let (batch, _): (Batch, _) = schema::decode(&fs::read(&path)?, VERSION, schema::unversioned_to_v1)?;
+        fs::remove_file(&path)?;
+
+        let outcomes = batch
+            .actions
+            .iter()
+            .rev()
+            .map(|action| {
+                tracing::info!(path = action.path().to_str(), "undoing file action");
+                FileOutcome::new(action.path().to_owned(), action.undo(remover))
+            })
+            .collect();
+        Ok(Some(outcomes))
+    }
+}
@@ -0,0 +1,165 @@
+//! Files, folders and pairs of files never reported as duplicates, e.g. edited exports kept next to their originals,
+//! kept in `ignored.json` and applied by every analysis. Unlike the `ignored` review mark they leave the groups themselves.
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::analyzer::Hashes;
+use crate::schema;
+use crate::tasks::to_millis;
+
+/// schema version of the ignore list
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum IgnoreRule {
+    /// never in a group
+    File {
+        #[schema(value_type = String)]
+        path: PathBuf,
+    },
+    /// no file below it is in a group
+    Folder {
+        #[schema(value_type = String)]
+        path: PathBuf,
+    },
+    /// not matched with each other, both still are in one group when other files match them
+    Pair {
+        #[schema(value_type = String)]
+        a: PathBuf,
+        #[schema(value_type = String)]
+        b: PathBuf,
+    },
+}
+
+impl IgnoreRule {
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Self::File { path } | Self::Folder { path } => vec![path],
+            Self::Pair { a, b } => vec![a, b],
+        }
+    }
+
+    /// pairs the same whatever the order of their files
+    fn normalized(self) -> Self {
+        match self {
+            Self::Pair { a, b } if b < a => Self::Pair { a: b, b: a },
+            rule => rule,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Ignored {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub rule: IgnoreRule,
+    /// ms since the epoch
+    pub added: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct IgnoreList {
+    /// kept in memory only when `None`, e.g. for engines embedded without the server
+    file: Option<PathBuf>,
+    ignored: RwLock<Vec<Ignored>>,
+}
+
+impl IgnoreList {
+    pub fn open<T>(file: T) -> Result<Self>
+    where
+        PathBuf: From<T>
+    {
+        let file = PathBuf::from(file);
+        let ignored = match file.exists() {
+            true => schema::decode(&fs::read(&file)?, VERSION, schema::unversioned_to_v1)?.0,
+            false => Vec::new(),
+        };
+        Ok(Self { file: Some(file), ignored: RwLock::new(ignored) })
+    }
+
+    fn save(&self, ignored: &[Ignored]) -> Result<()> {
+        if let Some(file) = &self.file {
+            fs::write(file, schema::encode_pretty(&ignored, VERSION)?)?;
+        }
+        Ok(())
+    }
+
+    /// in the order they were added
+    pub fn list(&self) -> Vec<Ignored> {
+        self.ignored.read().unwrap().clone()
+    }
+
+    /// the entry of the rule when it was added before
+    pub fn add(&self, rule: IgnoreRule) -> Result<Ignored> {
+        let rule = rule.normalized();
+        let mut ignored = self.ignored.write().unwrap();
+        if let Some(entry) = ignored.iter().find(|entry| entry.rule == rule) {
+            return Ok(entry.clone());
+        }
+        let entry = Ignored { id: Uuid::new_v4(), rule, added: to_millis(SystemTime::now()) };
+        ignored.push(entry.clone());
+        self.save(&ignored)?;
+        Ok(entry)
+    }
+
+    /// `false` if there was no such entry
+    pub fn remove(&self, id: Uuid) -> Result<bool> {
+        let mut ignored = self.ignored.write().unwrap();
+        let len = ignored.len();
+        ignored.retain(|entry| entry.id != id);
+        if ignored.len() == len {
+            return Ok(false);
+        }
+        self.save(&ignored)?;
+        Ok(true)
+    }
+
+    /// whether the file is ignored itself or by a folder
+    pub fn excludes(&self, path: &Path) -> bool {
+        self.ignored.read().unwrap().iter().any(|entry| match &entry.rule {
+            IgnoreRule::File { path: file } => file == path,
+            IgnoreRule::Folder { path: folder } => path.starts_with(folder),
+            IgnoreRule::Pair { .. } => false,
+        })
+    }
+
+    /// the hashes of the files not excluded, copied only when some are
+    pub(crate) fn kept<'a>(&self, hashes: &'a Hashes) -> Cow<'a, Hashes> {
+        match hashes.iter().any(|(file, _)| self.excludes(&file.path)) {
+            true => Cow::Owned(hashes.iter().filter(|(file, _)| !self.excludes(&file.path)).cloned().collect()),
+            false => Cow::Borrowed(hashes),
+        }
+    }
+
+    /// indices of the ignored pairs among the hashes, the lower one first
+    pub(crate) fn pairs(&self, hashes: &Hashes) -> HashSet<(usize, usize)> {
+        let ignored = self.ignored.read().unwrap();
+        if !ignored.iter().any(|entry| matches!(entry.rule, IgnoreRule::Pair { .. })) {
+            return HashSet::new();
+        }
+        let index: HashMap<&Path, usize> = hashes.iter().enumerate().map(|(i, (file, _))| (file.path.as_path(), i)).collect();
+        ignored
+            .iter()
+            .filter_map(|entry| match &entry.rule {
+                IgnoreRule::Pair { a, b } => {
+                    let (i, j) = (*index.get(a.as_path())?, *index.get(b.as_path())?);
+                    Some((i.min(j), i.max(j)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
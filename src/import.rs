@@ -0,0 +1,89 @@
+//! Duplicate reports of other tools, read into groups of paths so they can be reviewed and resolved here.
+//! Only the paths are taken from them, sizes and dates are read from the files themselves.
+
+use eyre::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportFormat {
+    /// a path per line and a blank line between groups, `-S` size lines are fine
+    Fdupes,
+    /// the output of fdupes, or `--json`
+    Jdupes,
+    /// the text or JSON results of `czkawka_cli dup` and `image`
+    Czkawka,
+}
+
+/// the groups of a report, groups with a single path are left out
+pub fn parse(format: ImportFormat, report: &str) -> Result<Vec<Vec<PathBuf>>> {
+    let json = report.trim_start().starts_with(['{', '[']);
+    let groups = match format {
+        ImportFormat::Fdupes => fdupes(report),
+        ImportFormat::Jdupes | ImportFormat::Czkawka if json => {
+            let mut groups = Vec::new();
+            json_groups(&serde_json::from_str(report)?, &mut groups);
+            groups
+        }
+        ImportFormat::Jdupes => fdupes(report),
+        ImportFormat::Czkawka => czkawka(report),
+    };
+    Ok(groups.into_iter().filter(|group| group.len() > 1).collect())
+}
+
+fn fdupes(report: &str) -> Vec<Vec<PathBuf>> {
+    let mut groups = vec![Vec::new()];
+    for line in report.lines() {
+        if line.is_empty() {
+            groups.push(Vec::new());
+        // `-S` and `-m`
+        } else if !(line.ends_with(" bytes each:") || line.ends_with(" byte each:")) {
+            groups.last_mut().unwrap().push(PathBuf::from(line));
+        }
+    }
+    groups
+}
+
+/// quoted paths, the groups start with a `----` heading or are separated by blank lines
+fn czkawka(report: &str) -> Vec<Vec<PathBuf>> {
+    let mut groups = vec![Vec::new()];
+    for line in report.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with("----") {
+            groups.push(Vec::new());
+        } else if let Some((path, _)) = line.strip_prefix('"').and_then(|rest| rest.rsplit_once('"')) {
+            groups.last_mut().unwrap().push(PathBuf::from(path));
+        }
+    }
+    groups
+}
+
+/// lists of entries with a `path` (czkawka) or `filePath` (jdupes), wherever they are
+fn json_groups(value: &Value, groups: &mut Vec<Vec<PathBuf>>) {
+    let path = |entry: &Value| entry.get("path").or_else(|| entry.get("filePath"))?.as_str().map(PathBuf::from);
+    match value {
+        Value::Array(entries) if !entries.is_empty() && entries.iter().all(|entry| path(entry).is_some()) => {
+            groups.push(entries.iter().filter_map(path).collect());
+        }
+        Value::Array(values) => values.iter().for_each(|value| json_groups(value, groups)),
+        Value::Object(fields) => fields.values().for_each(|value| json_groups(value, groups)),
+        _ => {}
+    }
+}
+
+/// the folder every path of the groups is in, what the imported task is listed under
+pub fn common_folder(groups: &[Vec<PathBuf>]) -> Option<PathBuf> {
+    let mut paths = groups.iter().flatten();
+    let mut folder = paths.next()?.parent()?.to_owned();
+    for path in paths {
+        while !path.starts_with(&folder) {
+            if !folder.pop() {
+                return None;
+            }
+        }
+    }
+    Some(folder)
+}
@@ -0,0 +1,147 @@
+//! The hashes of the latest analysis, searchable by similarity. Kept in `index.json` of the data folder and loaded
+//! on startup, so `/search` answers right away rather than after the next analysis. Files are kept in the order they
+//! were inserted, which builds the same tree again.
+
+use eyre::Result;
+use image_hasher::ImageHash;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+};
+use uuid::Uuid;
+
+use crate::analyzer::{FileInfo, HashSize, HashType, Hashes};
+use crate::schema;
+use crate::tasks::StoredHashes;
+
+/// schema version of the stored index
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+struct Node<T> {
+    hash: ImageHash,
+    value: T,
+    /// child node index by its distance to this node
+    children: HashMap<u32, usize>,
+}
+
+/// BK-tree over perceptual hashes: finds everything within a distance
+/// of a query without comparing it to every single hash.
+#[derive(Debug)]
+pub struct BkTree<T> {
+    nodes: Vec<Node<T>>,
+}
+
+impl<T> BkTree<T> {
+    pub fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    pub fn insert(&mut self, hash: ImageHash, value: T) {
+        let new = self.nodes.len();
+        let mut current = 0;
+
+        while current < new {
+            let dist = self.nodes[current].hash.dist(&hash);
+            match self.nodes[current].children.get(&dist) {
+                Some(&child) => current = child,
+                None => {
+                    self.nodes[current].children.insert(dist, new);
+                    break;
+                }
+            }
+        }
+
+        self.nodes.push(Node { hash, value, children: HashMap::new() });
+    }
+
+    /// all values within `max_dist` from the hash, with their distances
+    pub fn find(&self, hash: &ImageHash, max_dist: u32) -> Vec<(u32, &T)> {
+        let mut found = Vec::new();
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            let dist = node.hash.dist(hash);
+            if dist <= max_dist {
+                found.push((dist, &node.value));
+            }
+
+            // triangle inequality: only children in this range may match
+            let range = dist.saturating_sub(max_dist)..=dist + max_dist;
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| range.contains(d))
+                    .map(|(_, &child)| child)
+            );
+        }
+
+        found
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// in the order of insertion
+    pub fn iter(&self) -> impl Iterator<Item = (&ImageHash, &T)> {
+        self.nodes.iter().map(|node| (&node.hash, &node.value))
+    }
+}
+
+/// Hashes of the last analysis, searchable by similarity.
+#[derive(Debug)]
+pub struct SearchIndex {
+    pub hash_type: HashType,
+    pub hash_size: HashSize,
+    pub tree: BkTree<FileInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredIndex {
+    hash_type: HashType,
+    hash_size: HashSize,
+    hashes: StoredHashes,
+}
+
+impl SearchIndex {
+    pub fn new(hash_type: HashType, hash_size: HashSize, hashes: &Hashes) -> Self {
+        let mut tree = BkTree::new();
+        for (file, hash) in hashes {
+            tree.insert(hash.clone(), file.clone());
+        }
+        Self { hash_type, hash_size, tree }
+    }
+
+    /// replaces the stored index at once, a crash leaves the earlier one
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let stored = StoredIndex {
+            hash_type: self.hash_type,
+            hash_size: self.hash_size,
+            hashes: StoredHashes::new(self.tree.iter().map(|(hash, file)| (file, hash))),
+        };
+        let tmp = path.with_file_name(Uuid::new_v4().to_string()).with_extension("tmp");
+        fs::write(&tmp, schema::encode(&stored, VERSION)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// `None` before the first analysis, and for hashes of another `HASH_VERSION`
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let (stored, _): (StoredIndex, _) = schema::decode(&fs::read(path)?, VERSION, schema::unversioned_to_v1)?;
+        let Some(hashes) = stored.hashes.hashes()? else {
+            return Ok(None);
+        };
+        Ok(Some(Self::new(stored.hash_type, stored.hash_size, &hashes)))
+    }
+}
@@ -0,0 +1,115 @@
+//! Finds duplicate and similar images in folders.
+//!
+//! The HTTP server is started by [`run`], the engine can be used without it:
+//! an [`Analyzer`] hashes and groups the images of a folder, keeping the hashes in a [`cache`],
+//! and a [`TaskManager`] queues analyses and tracks their progress.
+//! The [`roots`] and the [`sandbox`] tell the analyzer about the folders it scans,
+//! files are grouped with a [`DisjointSet`]. Hashes other than the built-in ones are added with [`register_hasher`].
+//!
+//! ```
+//! use image_analyzer::analyzer::{AnalyzeRequest, Progress, HASH_VERSION};
+//! use image_analyzer::cache::{Cache, CacheLimits};
+//! use image_analyzer::manager::CancelToken;
+//! use image_analyzer::{roots::Roots, sandbox::Sandbox, Analyzer};
+//! use std::sync::Arc;
+//!
+//! # fn main() -> eyre::Result<()> {
+//! # let data = tempfile::tempdir()?;
+//! # let photos = tempfile::tempdir()?;
+//! let cache = Cache::open(&data.path().join("cache.db"), CacheLimits::default(), HASH_VERSION)?;
+//! let roots = Arc::new(Roots::open(data.path().join("roots.json"))?);
+//! let engine = Analyzer::new(cache, roots, Arc::new(Sandbox::new(None)?));
+//!
+//! let mut req = AnalyzeRequest::new(photos.path());
+//! req.dist = 5;
+//! let (tx, _progress) = tokio::sync::watch::channel(Progress::default());
+//! let result = engine.analyze(&req, tx, &CancelToken::default())?;
+//! for group in result.groups() {
+//!     println!("{:?}", group.files().iter().map(|file| &file.path).collect::<Vec<_>>());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod analyzer;
+mod archive;
+mod assets;
+mod auth;
+mod autoresolve;
+mod backup;
+mod bookmarks;
+pub mod manager;
+mod metadata;
+mod metrics;
+mod names;
+#[cfg(feature = "ocr")]
+mod ocr;
+pub mod cache;
+mod compare;
+mod conditional;
+mod config;
+mod desktop;
+pub mod disjoint_set;
+mod download;
+mod downscale;
+mod error;
+mod events;
+mod export;
+mod files;
+mod fixtures;
+#[cfg(feature = "grpc")]
+mod grpc;
+pub mod hasher;
+mod headless;
+mod history;
+mod ignore;
+mod import;
+mod index;
+mod listen;
+mod logging;
+mod logs;
+mod marks;
+mod memo;
+mod openapi;
+mod profiles;
+mod protect;
+mod quarantine;
+mod ratelimit;
+mod remover;
+pub mod report;
+mod resolve;
+#[cfg(feature = "tui")]
+mod review;
+pub mod roots;
+mod runs;
+mod s3;
+pub mod sandbox;
+pub mod schema;
+mod server;
+mod sftp;
+mod shape;
+mod session;
+mod share;
+mod storage;
+mod tasks;
+mod tenant;
+mod thumbnail;
+mod throttle;
+mod timings;
+mod volumes;
+mod watch;
+mod webdav;
+mod webhook;
+mod workers;
+mod ws;
+mod xml;
+
+pub use analyzer::Analyzer;
+pub use config::Cli;
+pub use disjoint_set::DisjointSet;
+pub use hasher::{register_hasher, ImageHasher};
+pub use manager::TaskManager;
+pub use server::run;
+
+#[cfg(test)]
+mod tests;
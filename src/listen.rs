@@ -0,0 +1,150 @@
+//! Where the server listens: TCP by default, a Unix socket for a reverse proxy on the same machine, or the sockets
+//! systemd passes with socket activation, `LISTEN_FDS` of them from fd 3 on, in place of both.
+//! Requests over a Unix socket have no address, they share a rate limit and can't use the desktop endpoints.
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use eyre::{Result, WrapErr};
+use std::{
+    fmt,
+    net::{SocketAddr, TcpListener},
+};
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: std::os::unix::net::UnixListener,
+        /// the socket file, when the server made it rather than systemd
+        bound: Option<std::path::PathBuf>,
+    },
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "a TCP socket"),
+            },
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => match listener.local_addr().ok().as_ref().and_then(|addr| addr.as_pathname()) {
+                Some(path) => write!(f, "{}", path.display()),
+                None => write!(f, "an unnamed socket"),
+            },
+        }
+    }
+}
+
+impl Listener {
+    /// the socket file to remove on shutdown
+    #[cfg(unix)]
+    pub fn bound(&self) -> Option<&std::path::Path> {
+        match self {
+            Listener::Unix { bound, .. } => bound.as_deref(),
+            Listener::Tcp(_) => None,
+        }
+    }
+}
+
+/// the sockets systemd passed to this process, none when it wasn't started by socket activation
+#[cfg(unix)]
+fn systemd() -> Result<Vec<Listener>> {
+    use std::os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::net::UnixListener,
+    };
+
+    // inherited by the children of the process systemd started
+    if std::env::var("LISTEN_PID").ok() != Some(std::process::id().to_string()) {
+        return Ok(Vec::new());
+    }
+    let count: i32 = std::env::var("LISTEN_FDS").wrap_err("LISTEN_PID without LISTEN_FDS")?.parse().wrap_err("invalid LISTEN_FDS")?;
+    let listeners = (0..count).map(|n| {
+        // SAFETY: systemd passed these file descriptors to this process, nothing else owns them
+        let fd = unsafe { OwnedFd::from_raw_fd(3 + n) };
+        let listener = UnixListener::from(fd);
+        match listener.local_addr() {
+            Ok(_) => Listener::Unix { listener, bound: None },
+            // fails for sockets of other families
+            Err(_) => Listener::Tcp(TcpListener::from(OwnedFd::from(listener))),
+        }
+    });
+    Ok(listeners.collect())
+}
+
+/// listens on `path`, replacing the socket of a server which didn't shut down
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, mode: Option<u32>) -> Result<Listener> {
+    use std::os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    };
+
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        eyre::ensure!(UnixStream::connect(path).is_err(), "another server listens on {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).wrap_err_with(|| format!("unable to listen on {}", path.display()))?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(Listener::Unix { listener, bound: Some(path.to_owned()) })
+}
+
+/// those passed by systemd, otherwise the socket and the address as configured
+pub fn listeners(config: &Config) -> Result<Vec<Listener>> {
+    #[cfg(unix)]
+    {
+        let passed = systemd()?;
+        if !passed.is_empty() {
+            return Ok(passed);
+        }
+    }
+    let mut listeners = Vec::new();
+    #[cfg(unix)]
+    if let Some(socket) = &config.socket {
+        listeners.push(bind_unix(socket, config.socket_mode)?);
+    }
+    if config.tcp {
+        let listener = TcpListener::bind(config.addr).wrap_err_with(|| format!("unable to listen on {}", config.addr))?;
+        listeners.push(Listener::Tcp(listener));
+    }
+    Ok(listeners)
+}
+
+/// serves the app until the server fails, HTTPS over TCP only
+pub fn serve(listener: Listener, app: Router, tls: Option<RustlsConfig>) -> Result<JoinHandle<Result<()>>> {
+    let server = match listener {
+        Listener::Tcp(listener) => {
+            listener.set_nonblocking(true)?;
+            // the client address keys the rate limits
+            let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+            match tls {
+                Some(tls) => {
+                    let server = axum_server::from_tcp_rustls(listener, tls).serve(make_service);
+                    tokio::spawn(async move { Ok(server.await?) })
+                }
+                None => {
+                    let server = axum::Server::from_tcp(listener)?.serve(make_service);
+                    tokio::spawn(async move { Ok(server.await?) })
+                }
+            }
+        }
+        #[cfg(unix)]
+        Listener::Unix { listener, .. } => {
+            eyre::ensure!(tls.is_none(), "HTTPS is served over TCP only, the proxy in front of the socket serves it");
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            let incoming = hyper::server::accept::from_stream(tokio_stream::wrappers::UnixListenerStream::new(listener));
+            // the proxy may forward anyone, taken as a client not on this machine
+            let app = app.layer(Extension(ConnectInfo(SocketAddr::from(([0, 0, 0, 0], 0)))));
+            let server = axum::Server::builder(incoming).serve(app.into_make_service());
+            tokio::spawn(async move { Ok(server.await?) })
+        }
+    };
+    Ok(server)
+}
@@ -0,0 +1,250 @@
+//! Where the server logs: stdout by default, or a file rotated once it grows past a size, as text or as JSON lines.
+//! JSON lines carry the fields of the spans they are logged in, e.g. the `request_id` of the HTTP request and the
+//! `task_id` of the analysis, so the lines of one of them are found with `jq 'select(.request_id == "...")'`.
+
+use eyre::Result;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{fmt::MakeWriter, layer::Context, registry::LookupSpan, Layer};
+
+use crate::s3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    /// stdout when `None`
+    pub file: Option<PathBuf>,
+    /// in bytes, the file is rotated once it grows past it
+    pub max_size: u64,
+    /// rotated files kept, `<file>.1` being the latest
+    pub keep: usize,
+    pub format: LogFormat,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self { file: None, max_size: 10 * 1024 * 1024, keep: 5, format: LogFormat::Text }
+    }
+}
+
+struct Current {
+    file: File,
+    len: u64,
+}
+
+/// appended to, renamed to `<file>.1` once full, the older ones shifted up to `<file>.<keep>`
+pub struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    current: Mutex<Current>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { path: path.to_owned(), max_size, keep, current: Mutex::new(Current { file, len }) })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        current.file.flush()?;
+        if self.keep == 0 {
+            current.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let older = self.rotated(n);
+                if older.exists() {
+                    fs::rename(&older, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            current.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        current.len = 0;
+        Ok(())
+    }
+
+    fn write_line(&self, line: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        if current.len > 0 && current.len + line.len() as u64 > self.max_size {
+            // keeps logging to the full file rather than losing the line
+            if let Err(err) = self.rotate(&mut current) {
+                eprintln!("unable to rotate the log file {}: {}", self.path.display(), err);
+            }
+        }
+        current.file.write_all(line)?;
+        current.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// writes each line it is given at once, events come in a single write
+pub struct LogFileWriter<'a>(&'a LogFile);
+
+impl Write for LogFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_line(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.current.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogFileWriter(self)
+    }
+}
+
+/// `2024-01-31T12:00:00.000Z`
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = s3::civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since.subsec_millis()
+    )
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_owned(), format!("{:?}", value).into());
+    }
+}
+
+/// the fields of a span, kept in its extensions
+struct SpanFields(Map<String, Value>);
+
+/// an object per line with the time, level, target, message, the fields of the event and of its spans
+pub struct JsonLayer<W> {
+    writer: W,
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert("time".to_owned(), timestamp(SystemTime::now()).into());
+        line.insert("level".to_owned(), metadata.level().as_str().into());
+        line.insert("target".to_owned(), metadata.target().into());
+        // outer spans first, inner fields win
+        let mut spans = Vec::new();
+        for span in ctx.event_scope(event).into_iter().flat_map(|scope| scope.from_root()) {
+            spans.push(span.name());
+            if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                line.extend(fields.clone());
+            }
+        }
+        if !spans.is_empty() {
+            line.insert("spans".to_owned(), spans.join(":").into());
+        }
+        event.record(&mut JsonVisitor(&mut line));
+
+        let mut json = Value::Object(line).to_string();
+        json.push('\n');
+        let _ = self.writer.make_writer().write_all(json.as_bytes());
+    }
+}
+
+/// formats the events as configured, writing them to stdout or the log file
+pub fn layer<S>(options: &LogOptions) -> Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let file = match &options.file {
+        Some(path) => Some(LogFile::open(path, options.max_size, options.keep)?),
+        None => None,
+    };
+    Ok(match (options.format, file) {
+        (LogFormat::Text, None) => tracing_subscriber::fmt::layer().boxed(),
+        (LogFormat::Text, Some(file)) => tracing_subscriber::fmt::layer().with_ansi(false).with_writer(file).boxed(),
+        (LogFormat::Json, None) => JsonLayer { writer: io::stdout }.boxed(),
+        (LogFormat::Json, Some(file)) => JsonLayer { writer: file }.boxed(),
+    })
+}
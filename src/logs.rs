@@ -0,0 +1,121 @@
+//! Log lines of each analysis task, captured from tracing events inside a `task` span.
+
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Write},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// lines kept per task, the oldest are dropped first
+const MAX_LINES: usize = 1000;
+
+static LOGS: LazyLock<Mutex<HashMap<Uuid, VecDeque<LogLine>>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LogLine {
+    /// ms since the epoch
+    time: u64,
+    level: String,
+    message: String,
+}
+
+/// lines logged by the task so far
+pub fn get(task_id: &Uuid) -> Vec<LogLine> {
+    let logs = LOGS.lock().unwrap();
+    logs.get(task_id).map(|lines| lines.iter().cloned().collect()).unwrap_or_default()
+}
+
+pub fn remove(task_id: &Uuid) {
+    LOGS.lock().unwrap().remove(task_id);
+}
+
+/// task the span belongs to, kept in its extensions
+struct TaskId(Uuid);
+
+#[derive(Default)]
+struct TaskIdVisitor(Option<Uuid>);
+
+impl Visit for TaskIdVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "task_id" {
+            self.0 = value.parse().ok();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "task_id" {
+            self.0 = format!("{:?}", value).parse().ok();
+        }
+    }
+}
+
+/// the message followed by the other fields as `name=value`
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let fields = std::mem::take(&mut self.0);
+            let _ = write!(self.0, "{:?}{}", value, fields);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+pub struct TaskLogs;
+
+/// captures events of spans named `task` with a `task_id` field
+pub fn layer() -> TaskLogs {
+    TaskLogs
+}
+
+impl<S> Layer<S> for TaskLogs
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != "task" {
+            return;
+        }
+        let mut visitor = TaskIdVisitor::default();
+        attrs.record(&mut visitor);
+        if let (Some(task_id), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(TaskId(task_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+        let Some(task_id) = scope.into_iter().find_map(|span| span.extensions().get::<TaskId>().map(|id| id.0)) else {
+            return;
+        };
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let line = LogLine {
+            time: crate::tasks::to_millis(SystemTime::now()),
+            level: event.metadata().level().to_string(),
+            message: visitor.0,
+        };
+
+        let mut logs = LOGS.lock().unwrap();
+        let lines = logs.entry(task_id).or_default();
+        if lines.len() == MAX_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
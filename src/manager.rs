@@ -1,61 +1,558 @@
 use std::{
     collections::HashMap,
+    fmt,
     hash::Hash,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
+use eyre::{Report, Result};
+use serde::{Deserialize, Serialize};
 use tokio::{
     task::{self, JoinHandle},
-    sync::watch,
+    sync::{mpsc, oneshot, watch},
 };
+use utoipa::ToSchema;
 
 pub enum TaskResponse<P, R> {
+    /// waits for a free slot behind this many tasks
+    Queued(usize),
     Pending(P),
     Completed(R),
 }
 
-pub struct TaskManager<K, P, R> {
-    tasks: HashMap<K, (JoinHandle<R>, watch::Receiver<P>)>,
+const RUNNING: u8 = 0;
+const CANCELLED: u8 = 1;
+const TIMED_OUT: u8 = 2;
+const FINISHED: u8 = 3;
+
+/// Asks a task to stop, it is up to the task to check it.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicU8>);
+
+impl CancelToken {
+    /// `false` if the task finished first
+    pub fn cancel(&self) -> bool {
+        match self.0.compare_exchange(RUNNING, CANCELLED, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => true,
+            Err(state) => state != FINISHED,
+        }
+    }
+
+    /// Marks the work as done, `false` if it was cancelled or timed out first.
+    /// After it, cancelling fails, so an accepted cancellation always ends the task.
+    pub fn finish(&self) -> bool {
+        match self.0.compare_exchange(RUNNING, FINISHED, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => true,
+            Err(state) => state == FINISHED,
+        }
+    }
+
+    fn time_out(&self) {
+        let _ = self.0.compare_exchange(RUNNING, TIMED_OUT, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.0.load(Ordering::Relaxed), CANCELLED | TIMED_OUT)
+    }
+
+    fn is_timed_out(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == TIMED_OUT
+    }
+
+    /// what a task that stopped on the token fails with
+    pub fn error(&self) -> Report {
+        if self.is_timed_out() {
+            TimedOut.into()
+        } else {
+            Cancelled.into()
+        }
+    }
+}
+
+/// error of a task that stopped on request
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// What a task whose work panicked is settled with, rather than taking
+/// down whoever collects it.
+pub trait Panicked {
+    fn panicked(message: String) -> Self;
+}
+
+impl<T> Panicked for Result<T> {
+    fn panicked(message: String) -> Self {
+        Err(eyre::eyre!("analysis panicked: {}", message))
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown panic".to_owned()),
+    }
+}
+
+/// error of a task cancelled for running longer than allowed
+#[derive(Debug)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// queued tasks of a higher priority start first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Priority {
+    /// e.g. nightly re-index jobs
+    Low,
+    #[default]
+    Normal,
+    /// e.g. quick ad-hoc checks
+    High,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskOptions {
+    pub priority: Priority,
+    /// the task is cancelled after running that long
+    pub timeout: Option<Duration>,
+}
+
+/// the work of a task, started once a slot is free
+type Job<R> = Box<dyn FnOnce() -> R + Send>;
+
+enum Status<R> {
+    Queued(Job<R>),
+    Running(JoinHandle<R>),
+    /// results stay around for repeated polls
+    Finished(Arc<R>),
+}
+
+struct Task<M, P, R> {
+    meta: M,
+    options: TaskOptions,
+    /// submission order, queued tasks of the same priority start in it
+    seq: u64,
+    submitted: SystemTime,
+    /// when it times out, set once it starts
+    deadline: Option<Instant>,
+    finished: Option<SystemTime>,
+    progress: watch::Receiver<P>,
+    cancel: CancelToken,
+    status: Status<R>,
+    /// get the result once it is there
+    waiters: Vec<oneshot::Sender<Arc<R>>>,
+}
+
+impl<M, P, R> Task<M, P, R> {
+    /// sorts queued tasks, the next to start first
+    fn queue_order(&self) -> (std::cmp::Reverse<Priority>, u64) {
+        (std::cmp::Reverse(self.options.priority), self.seq)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TaskState {
+    Queued,
+    Running,
+    Finished,
+}
+
+/// what is known about a task, `M` describes what the task does
+#[derive(Debug, Clone)]
+pub struct TaskInfo<K, M, P> {
+    pub id: K,
+    pub meta: M,
+    pub state: TaskState,
+    pub progress: P,
+    pub submitted: SystemTime,
+    pub finished: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TaskLimits {
+    /// tasks running at once, the rest wait in a queue
+    pub concurrency: usize,
+    /// how long results of finished tasks are kept around
+    pub ttl: Option<Duration>,
+    /// finished tasks kept at most, the oldest are dropped first
+    pub keep: Option<usize>,
+    /// bytes of decoded images held at once by the analyses, unbounded when `None`
+    pub memory_budget: Option<u64>,
+}
+
+impl Default for TaskLimits {
+    fn default() -> Self {
+        Self { concurrency: 2, ttl: Some(Duration::from_secs(60 * 60)), keep: Some(100), memory_budget: None }
+    }
+}
+
+impl TaskLimits {
+    /// defaults overridden by `TASK_CONCURRENCY`, `TASK_RESULT_TTL` (seconds)
+    /// and `TASK_RESULT_KEEP`, 0 disables the retention limits
+    pub fn from_env() -> Result<Self> {
+        let mut limits = Self::default();
+        if let Ok(concurrency) = std::env::var("TASK_CONCURRENCY") {
+            limits.concurrency = concurrency.parse()?;
+            eyre::ensure!(limits.concurrency > 0, "TASK_CONCURRENCY must be at least 1");
+        }
+        if let Ok(ttl) = std::env::var("TASK_RESULT_TTL") {
+            limits.ttl = Some(Duration::from_secs(ttl.parse()?)).filter(|ttl| !ttl.is_zero());
+        }
+        if let Ok(keep) = std::env::var("TASK_RESULT_KEEP") {
+            limits.keep = Some(keep.parse()?).filter(|&keep| keep > 0);
+        }
+        Ok(limits)
+    }
 }
 
-impl<K, P, R> TaskManager<K, P, R>
+pub struct TaskManager<K, M, P, R> {
+    tasks: HashMap<K, Task<M, P, R>>,
+    limits: TaskLimits,
+    seq: u64,
+    /// no more queued tasks are started, e.g. on shutdown
+    draining: bool,
+    /// keys of tasks whose work is done, so queued ones can start
+    done_tx: mpsc::UnboundedSender<K>,
+    done_rx: mpsc::UnboundedReceiver<K>,
+}
+
+impl<K, M, P, R> TaskManager<K, M, P, R>
 where
-    K: Eq + Hash,
+    K: Eq + Hash + Clone + Send + 'static,
+    M: Clone,
     P: Send + Sync + 'static,
-    R: Send + 'static,
+    R: Panicked + Send + 'static,
 {
-    pub fn new() -> Self {
-        Self { tasks: HashMap::new() }
+    pub fn new(limits: TaskLimits) -> Self {
+        let (done_tx, done_rx) = mpsc::unbounded_channel();
+        Self { tasks: HashMap::new(), limits, seq: 0, draining: false, done_tx, done_rx }
     }
 
-    pub fn submit<F>(&mut self, key: K, f: F) -> &mut (JoinHandle<R>, watch::Receiver<P>)
+    pub fn submit<F>(&mut self, key: K, meta: M, options: TaskOptions, f: F)
     where
-        F: FnOnce(watch::Sender<P>) -> R + Send + 'static,
+        F: FnOnce(watch::Sender<P>, CancelToken) -> R + Send + 'static,
         P: Default,
     {
-        self.tasks.entry(key).or_insert_with(|| {
-            let (tx, rx) = watch::channel(Default::default());
-            let join_handle = task::spawn_blocking(|| f(tx));
-            (join_handle, rx)
-        })
+        if self.tasks.contains_key(&key) {
+            return;
+        }
+
+        let (tx, rx) = watch::channel(Default::default());
+        let cancel = CancelToken::default();
+        let token = cancel.clone();
+        let done_tx = self.done_tx.clone();
+        let done_key = key.clone();
+        let job: Job<R> = Box::new(move || {
+            let result = f(tx, token);
+            let _ = done_tx.send(done_key);
+            result
+        });
+
+        self.seq += 1;
+        self.tasks.insert(key, Task {
+            meta,
+            options,
+            seq: self.seq,
+            submitted: SystemTime::now(),
+            deadline: None,
+            finished: None,
+            progress: rx,
+            cancel,
+            status: Status::Queued(job),
+            waiters: Vec::new(),
+        });
+        self.schedule();
+    }
+
+    /// queued tasks ahead of this one
+    fn position(&self, task: &Task<M, P, R>) -> usize {
+        self.tasks
+            .values()
+            .filter(|other| matches!(other.status, Status::Queued(_)) && other.queue_order() < task.queue_order())
+            .count()
     }
 
-    pub async fn poll(&mut self, key: &K) -> Option<TaskResponse<P, R>>
+    /// adds a task finished before, e.g. by an earlier run of the server
+    pub fn restore(&mut self, key: K, meta: M, submitted: SystemTime, finished: SystemTime, result: R)
+    where
+        P: Default,
+    {
+        let (_, rx) = watch::channel(Default::default());
+        self.seq += 1;
+        self.tasks.insert(key, Task {
+            meta,
+            options: TaskOptions::default(),
+            seq: self.seq,
+            submitted,
+            deadline: None,
+            finished: Some(finished),
+            progress: rx,
+            cancel: CancelToken::default(),
+            status: Status::Finished(Arc::new(result)),
+            waiters: Vec::new(),
+        });
+    }
+
+    fn start(&mut self, key: &K) {
+        let Some(mut task) = self.tasks.remove(key) else {
+            return;
+        };
+        task.status = match task.status {
+            Status::Queued(job) => {
+                task.deadline = task.options.timeout.map(|timeout| Instant::now() + timeout);
+                Status::Running(task::spawn_blocking(job))
+            }
+            status => status,
+        };
+        self.tasks.insert(key.clone(), task);
+    }
+
+    /// tasks running, except timed out ones which may be stuck, e.g. on a hung mount
+    pub fn running(&self) -> usize {
+        self.tasks
+            .values()
+            .filter(|task| matches!(task.status, Status::Running(_)) && !task.cancel.is_timed_out())
+            .count()
+    }
+
+    /// tasks waiting for a free slot
+    pub fn queued(&self) -> usize {
+        self.tasks.values().filter(|task| matches!(task.status, Status::Queued(_))).count()
+    }
+
+    /// starts queued tasks in submission order while there are free slots
+    fn schedule(&mut self) {
+        let free = self.limits.concurrency.saturating_sub(self.running());
+        if free == 0 || self.draining {
+            return;
+        }
+
+        let mut queued: Vec<_> = self.tasks
+            .iter()
+            .filter(|(_, task)| matches!(task.status, Status::Queued(_)))
+            .map(|(key, task)| (task.queue_order(), key.clone()))
+            .collect();
+        queued.sort_by_key(|(order, _)| *order);
+        for (_, key) in queued.into_iter().take(free) {
+            self.start(&key);
+        }
+    }
+
+    async fn collect(&mut self, key: &K) {
+        let Some(task) = self.tasks.get_mut(key) else {
+            return;
+        };
+        if let Status::Running(join_handle) = &mut task.status {
+            let result = match join_handle.await {
+                Ok(result) => result,
+                Err(err) if err.is_panic() => R::panicked(panic_message(&*err.into_panic())),
+                Err(err) => R::panicked(err.to_string()),
+            };
+            let result = Arc::new(result);
+            for waiter in task.waiters.drain(..) {
+                // the waiter may be gone
+                let _ = waiter.send(result.clone());
+            }
+            task.status = Status::Finished(result);
+            task.finished = Some(SystemTime::now());
+        }
+    }
+
+    /// the result once the task is done, right away if it is already
+    pub fn wait(&mut self, key: &K) -> Option<oneshot::Receiver<Arc<R>>> {
+        let task = self.tasks.get_mut(key)?;
+        let (tx, rx) = oneshot::channel();
+        match &task.status {
+            Status::Finished(result) => {
+                let _ = tx.send(result.clone());
+            }
+            Status::Queued(_) | Status::Running(_) => task.waiters.push(tx),
+        }
+        Some(rx)
+    }
+
+    /// key of the next task whose work is done, cancel safe
+    pub async fn done(&mut self) -> Option<K> {
+        self.done_rx.recv().await
+    }
+
+    /// collects the result of a task reported by `done` and starts the next queued one
+    pub async fn settle(&mut self, key: &K) {
+        self.collect(key).await;
+        self.schedule();
+    }
+
+    /// collects results of tasks that are done
+    async fn reap(&mut self) {
+        while let Ok(key) = self.done_rx.try_recv() {
+            self.collect(&key).await;
+        }
+        // the ones that panicked never report
+        let finished: Vec<_> = self.tasks
+            .iter()
+            .filter(|(_, task)| matches!(&task.status, Status::Running(handle) if handle.is_finished()))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in finished {
+            self.collect(&key).await;
+        }
+        self.schedule();
+    }
+
+    /// stops starting queued tasks, returns how many are still running
+    pub async fn drain(&mut self) -> usize {
+        self.draining = true;
+        self.reap().await;
+        self.running()
+    }
+
+    /// cancels running tasks past their deadline, returns their keys
+    pub fn expire(&mut self) -> Vec<K> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (key, task) in &self.tasks {
+            let overdue = task.deadline.is_some_and(|deadline| deadline <= now);
+            if overdue && matches!(task.status, Status::Running(_)) && !task.cancel.is_cancelled() {
+                task.cancel.time_out();
+                expired.push(key.clone());
+            }
+        }
+        if !expired.is_empty() {
+            self.schedule();
+        }
+        expired
+    }
+
+    /// collects finished tasks and drops the ones past retention,
+    /// returns the dropped keys
+    pub async fn cleanup(&mut self) -> Vec<K> {
+        self.reap().await;
+        let now = SystemTime::now();
+
+        let mut finished: Vec<_> = self.tasks
+            .iter()
+            .filter_map(|(key, task)| Some((task.finished?, key.clone())))
+            .collect();
+        // the most recent first
+        finished.sort_by_key(|(finished, _)| std::cmp::Reverse(*finished));
+
+        let mut dropped = Vec::new();
+        for (i, (finished, key)) in finished.into_iter().enumerate() {
+            let expired = self.limits.ttl.is_some_and(|ttl| now.duration_since(finished).unwrap_or_default() >= ttl);
+            let over = self.limits.keep.is_some_and(|keep| i >= keep);
+            if expired || over {
+                self.tasks.remove(&key);
+                dropped.push(key);
+            }
+        }
+        dropped
+    }
+
+    /// `None` if there is no such task, `Some(false)` if it is already done
+    pub fn cancel(&mut self, key: &K) -> Option<bool> {
+        let task = self.tasks.get(key)?;
+        match task.status {
+            Status::Queued(_) => {
+                // skips the queue, it stops right away
+                task.cancel.cancel();
+                self.start(key);
+                Some(true)
+            }
+            Status::Running(_) => Some(task.cancel.cancel()),
+            Status::Finished(_) => Some(false),
+        }
+    }
+
+    /// doesn't wait for the task
+    pub async fn poll(&mut self, key: &K) -> Option<TaskResponse<P, Arc<R>>>
     where
         P: Copy
     {
-        let (key, (join_handle, mut rx)) = self.tasks.remove_entry(key)?;
-        Some(if rx.changed().await.is_err() {
-            let progress = *rx.borrow();
-            // still in progress: put handles back to tasks
-            self.tasks.insert(key, (join_handle, rx));
-            TaskResponse::Pending(progress)
-        } else {
-            let result = join_handle.await.unwrap();
-            TaskResponse::Completed(result)
+        self.reap().await;
+        let task = self.tasks.get(key)?;
+        Some(match &task.status {
+            Status::Queued(_) => TaskResponse::Queued(self.position(task)),
+            Status::Running(_) => TaskResponse::Pending(*task.progress.borrow()),
+            Status::Finished(result) => TaskResponse::Completed(result.clone()),
         })
     }
 
     pub fn progress(&self, key: &K) -> Option<watch::Receiver<P>> {
-        let (_, rx) = self.tasks.get(key)?;
-        Some(rx.clone())
+        Some(self.tasks.get(key)?.progress.clone())
+    }
+
+    /// a queued or running task with matching meta
+    pub fn find_unfinished(&self, matches: impl Fn(&M) -> bool) -> Option<K> {
+        self.tasks
+            .iter()
+            .find(|(_, task)| !matches!(task.status, Status::Finished(_)) && matches(&task.meta))
+            .map(|(key, _)| key.clone())
+    }
+
+    /// the result of the last finished task with matching meta and result
+    pub fn latest_result(&self, matches: impl Fn(&M, &R) -> bool) -> Option<Arc<R>> {
+        self.tasks
+            .values()
+            .filter_map(|task| match &task.status {
+                Status::Finished(result) if matches(&task.meta, result) => Some((task.finished, result)),
+                _ => None,
+            })
+            .max_by_key(|(finished, _)| *finished)
+            .map(|(_, result)| result.clone())
+    }
+
+    pub fn meta(&self, key: &K) -> Option<M> {
+        Some(self.tasks.get(key)?.meta.clone())
+    }
+
+    /// all tasks, the most recent first
+    pub async fn list(&mut self) -> Vec<TaskInfo<K, M, P>>
+    where
+        P: Copy
+    {
+        self.reap().await;
+        let mut tasks: Vec<_> = self.tasks
+            .iter()
+            .map(|(id, task)| TaskInfo {
+                id: id.clone(),
+                meta: task.meta.clone(),
+                state: match task.status {
+                    Status::Queued(_) => TaskState::Queued,
+                    Status::Running(_) => TaskState::Running,
+                    Status::Finished(_) => TaskState::Finished,
+                },
+                progress: *task.progress.borrow(),
+                submitted: task.submitted,
+                finished: task.finished,
+            })
+            .collect();
+        tasks.sort_by_key(|task| std::cmp::Reverse(task.submitted));
+        tasks
+    }
+
+    /// result of a finished task
+    pub fn result(&self, key: &K) -> Option<Arc<R>> {
+        match &self.tasks.get(key)?.status {
+            Status::Finished(result) => Some(result.clone()),
+            Status::Queued(_) | Status::Running(_) => None,
+        }
     }
 }
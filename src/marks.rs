@@ -0,0 +1,238 @@
+//! Review decisions on files and groups, kept in `marks.db` so long reviews survive reloads and restarts.
+//! Marks of an analysis are seen by everyone seeing it, global ones are of the session and apply to every result,
+//! those of the analysis win. Groups are marked by fingerprint, so marks of a group carry over while it has the same files.
+
+use eyre::{bail, Result};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::analyzer::Group;
+use crate::schema::Migration;
+use crate::tasks::to_millis;
+
+/// schema version of the marks database
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Mark {
+    Keep,
+    Delete,
+    Reviewed,
+    Ignored,
+}
+
+impl Mark {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Keep => "keep",
+            Self::Delete => "delete",
+            Self::Reviewed => "reviewed",
+            Self::Ignored => "ignored",
+        }
+    }
+
+    fn parse(mark: &str) -> Result<Self> {
+        Ok(match mark {
+            "keep" => Self::Keep,
+            "delete" => Self::Delete,
+            "reviewed" => Self::Reviewed,
+            "ignored" => Self::Ignored,
+            _ => bail!("unknown mark {}", mark),
+        })
+    }
+}
+
+/// the marks of a group and its files, as merged into results
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMarks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mark: Option<Mark>,
+    /// by path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(value_type = BTreeMap<String, Mark>)]
+    pub files: BTreeMap<PathBuf, Mark>,
+}
+
+/// marks files and groups, or clears their marks without `mark`
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkRequest {
+    /// the task or run the marks are for, global marks without it
+    pub task: Option<Uuid>,
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub files: Vec<PathBuf>,
+    /// fingerprints
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub mark: Option<Mark>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkEntry {
+    /// `None` for global marks
+    pub task: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub path: Option<PathBuf>,
+    /// fingerprint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub mark: Mark,
+    /// ms since the epoch
+    pub updated: u64,
+}
+
+/// the marks applying to one result
+#[derive(Debug, Default)]
+pub struct Scoped {
+    files: HashMap<PathBuf, Mark>,
+    groups: HashMap<String, Mark>,
+}
+
+impl Scoped {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.groups.is_empty()
+    }
+
+    pub fn apply(&self, group: &mut Group) {
+        let marks = GroupMarks {
+            mark: self.groups.get(group.fingerprint()).copied(),
+            files: group.files().iter().filter_map(|file| Some((file.path.clone(), *self.files.get(&file.path)?))).collect(),
+        };
+        group.set_marks((marks != GroupMarks::default()).then_some(marks));
+    }
+}
+
+/// the task and owner columns are empty instead of NULL, so they can be part of the key
+fn column(id: Option<Uuid>) -> String {
+    id.map_or_else(String::new, |id| id.to_string())
+}
+
+pub struct Marks {
+    db: Mutex<Connection>,
+}
+
+impl Marks {
+    pub fn open(path: &Path) -> Result<(Self, Option<Migration>)> {
+        let mut db = Connection::open(path)?;
+        let migration = Self::migrate(&mut db)?;
+        Ok((Self { db: Mutex::new(db) }, migration))
+    }
+
+    /// brings the database to the current schema version, kept in `user_version`
+    fn migrate(db: &mut Connection) -> Result<Option<Migration>> {
+        let found: u32 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if found > VERSION {
+            bail!("marks schema version {} is newer than the supported {}", found, VERSION);
+        }
+        if found == VERSION {
+            return Ok(None);
+        }
+
+        let tx = db.transaction()?;
+        for version in found..VERSION {
+            match version {
+                0 => tx.execute_batch(
+                    "CREATE TABLE marks (
+                        task TEXT NOT NULL,
+                        owner TEXT NOT NULL,
+                        kind TEXT NOT NULL,
+                        target TEXT NOT NULL,
+                        mark TEXT NOT NULL,
+                        updated INTEGER NOT NULL,
+                        PRIMARY KEY (task, owner, kind, target)
+                    )"
+                )?,
+                _ => bail!("no marks migration from version {}", version),
+            }
+        }
+        tx.pragma_update(None, "user_version", VERSION)?;
+        let items: i64 = tx.query_row("SELECT COUNT(*) FROM marks", [], |row| row.get(0))?;
+        tx.commit()?;
+
+        // a new database isn't an upgrade
+        Ok((found > 0).then(|| Migration::new("marks", found, VERSION, items as usize)))
+    }
+
+    /// marks of a task are shared, the owner only keeps global marks apart
+    pub fn set(&self, owner: Option<Uuid>, request: &MarkRequest) -> Result<()> {
+        let task = column(request.task);
+        let owner = column(owner.filter(|_| request.task.is_none()));
+        let targets = request.files.iter().map(|path| ("file", path.to_string_lossy().into_owned()))
+            .chain(request.groups.iter().map(|group| ("group", group.clone())));
+
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        for (kind, target) in targets {
+            match request.mark {
+                Some(mark) => tx.execute(
+                    "INSERT OR REPLACE INTO marks (task, owner, kind, target, mark, updated) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    rusqlite::params![task, owner, kind, target, mark.as_str(), to_millis(SystemTime::now()) as i64],
+                )?,
+                None => tx.execute(
+                    "DELETE FROM marks WHERE task = ?1 AND owner = ?2 AND kind = ?3 AND target = ?4",
+                    rusqlite::params![task, owner, kind, target],
+                )?,
+            };
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// the global marks of the owner, and those of the task when given, oldest first
+    pub fn list(&self, owner: Option<Uuid>, task: Option<Uuid>) -> Result<Vec<MarkEntry>> {
+        let db = self.db.lock().unwrap();
+        let mut select = db.prepare(
+            "SELECT task, kind, target, mark, updated FROM marks
+            WHERE (task = '' AND owner = ?1) OR (task != '' AND task = ?2)
+            ORDER BY updated, kind, target"
+        )?;
+        let rows = select.query_map([column(owner), column(task)], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, i64>(4)?))
+        })?;
+
+        let mut marks = Vec::new();
+        for row in rows {
+            let (task, kind, target, mark, updated) = row?;
+            let (path, group) = match kind.as_str() {
+                "file" => (Some(PathBuf::from(target)), None),
+                _ => (None, Some(target)),
+            };
+            marks.push(MarkEntry {
+                task: (!task.is_empty()).then(|| task.parse()).transpose()?,
+                path,
+                group,
+                mark: Mark::parse(&mark)?,
+                updated: updated as u64,
+            });
+        }
+        Ok(marks)
+    }
+
+    /// what applies to a result, marks of the task over global ones
+    pub fn scoped(&self, owner: Option<Uuid>, task: Option<Uuid>) -> Result<Scoped> {
+        let mut scoped = Scoped::default();
+        let mut marks = self.list(owner, task)?;
+        marks.sort_by_key(|mark| mark.task.is_some());
+        for entry in marks {
+            match (entry.path, entry.group) {
+                (Some(path), _) => scoped.files.insert(path, entry.mark),
+                (_, Some(group)) => scoped.groups.insert(group, entry.mark),
+                _ => None,
+            };
+        }
+        Ok(scoped)
+    }
+}
@@ -0,0 +1,103 @@
+//! Images decoded by an analysis, kept for the rest of it so files aren't decoded again: the fine pass hashes
+//! the candidates of the coarse pass once more, and pixel checksums take the images small enough to be decoded
+//! at full size for hashing. Bounded by half the memory budget, the other half left to decoding, the oldest go first.
+
+use image::DynamicImage;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::analyzer::FileStamp;
+use crate::throttle::{MemoryBudget, Reservation};
+
+/// kept without a memory budget
+const UNBUDGETED_BYTES: u64 = 256 * 1024 * 1024;
+
+type Key = (PathBuf, FileStamp);
+
+pub(crate) struct DecodeMemo<'a> {
+    budget: Option<&'a MemoryBudget>,
+    limit: u64,
+    entries: Mutex<Entries<'a>>,
+}
+
+#[derive(Default)]
+struct Entries<'a> {
+    /// oldest first
+    order: VecDeque<Key>,
+    images: HashMap<Key, Entry<'a>>,
+    bytes: u64,
+}
+
+struct Entry<'a> {
+    image: Arc<DynamicImage>,
+    /// at the size of the file rather than scaled down
+    full: bool,
+    bytes: u64,
+    /// of the budget, while kept
+    _reservation: Option<Reservation<'a>>,
+}
+
+impl Entries<'_> {
+    /// `false` when there was nothing to give up
+    fn evict_oldest(&mut self) -> bool {
+        let Some(key) = self.order.pop_front() else {
+            return false;
+        };
+        if let Some(entry) = self.images.remove(&key) {
+            self.bytes -= entry.bytes;
+        }
+        true
+    }
+}
+
+impl<'a> DecodeMemo<'a> {
+    pub fn new(budget: Option<&'a MemoryBudget>) -> Self {
+        let limit = budget.map_or(UNBUDGETED_BYTES, |budget| budget.total() / 2);
+        Self { budget, limit, entries: Mutex::default() }
+    }
+
+    /// the image decoded for `path` as it was at `stamp`, and whether it has the size of the file
+    pub fn get(&self, path: &Path, stamp: FileStamp) -> Option<(Arc<DynamicImage>, bool)> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.images.get(&(path.to_owned(), stamp))?;
+        Some((entry.image.clone(), entry.full))
+    }
+
+    /// kept unless larger than the memo, or the budget is taken by decoding
+    pub fn insert(&self, path: PathBuf, stamp: FileStamp, image: Arc<DynamicImage>, full: bool) {
+        let bytes = image.as_bytes().len() as u64;
+        if bytes > self.limit {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let key = (path, stamp);
+        if entries.images.contains_key(&key) {
+            return;
+        }
+        while entries.bytes + bytes > self.limit && entries.evict_oldest() {}
+        let reservation = match self.budget {
+            Some(budget) => match budget.try_reserve(bytes) {
+                Some(reservation) => Some(reservation),
+                None => return,
+            },
+            None => None,
+        };
+        entries.bytes += bytes;
+        entries.order.push_back(key.clone());
+        entries.images.insert(key, Entry { image, full, bytes, _reservation: reservation });
+    }
+
+    /// Waits for the bytes an image takes while decoding, only estimated with a budget,
+    /// the kept images are given up when they hold what's missing.
+    pub fn reserve(&self, bytes: impl FnOnce() -> u64) -> Option<Reservation<'a>> {
+        let budget = self.budget?;
+        let bytes = bytes();
+        let mut entries = self.entries.lock().unwrap();
+        while budget.available() < bytes.min(budget.total()) && entries.evict_oldest() {}
+        drop(entries);
+        Some(budget.reserve(bytes))
+    }
+}
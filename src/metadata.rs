@@ -0,0 +1,109 @@
+use eyre::Result;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageMetadata {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub format: Option<String>,
+    pub size: u64,
+    pub exif: BTreeMap<String, String>,
+    /// base64 encoded perceptual hash
+    pub hash: Option<String>,
+}
+
+/// EXIF fields of the main image, empty when the file has none
+pub fn read_exif(path: &Path) -> Result<BTreeMap<String, String>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let exif = match exif::Reader::new().read_from_container(&mut reader) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(exif
+        .fields()
+        .filter(|field| field.ifd_num == exif::In::PRIMARY)
+        .map(|field| (field.tag.to_string(), field.display_value().with_unit(&exif).to_string()))
+        .collect())
+}
+
+/// EXIF orientation of the main image, from 1 (upright) to 8
+pub fn read_orientation(path: &Path) -> Option<u32> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+    field.value.get_uint(0)
+}
+
+/// the JPEG preview cameras embed in the EXIF data, `None` without one
+pub fn read_preview(path: &Path) -> Option<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let field = |tag| exif.get_field(tag, exif::In::THUMBNAIL)?.value.get_uint(0);
+    let offset = field(exif::Tag::JPEGInterchangeFormat)? as usize;
+    let len = field(exif::Tag::JPEGInterchangeFormatLength)? as usize;
+    exif.buf().get(offset..offset.checked_add(len)?).map(<[u8]>::to_vec)
+}
+
+/// year from days since 1970-01-01 in the proleptic Gregorian calendar
+fn civil_year(days: i64) -> i32 {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let year = yoe + era * 400 + if mp >= 10 { 1 } else { 0 };
+    year as i32
+}
+
+/// year the photo was taken according to EXIF, file date (millis) otherwise
+pub fn year_taken(path: &Path, date: u64) -> i32 {
+    let from_exif = (|| {
+        let mut reader = BufReader::new(File::open(path).ok()?);
+        let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+        let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+        match &field.value {
+            exif::Value::Ascii(values) => {
+                let value = values.first()?;
+                std::str::from_utf8(value.get(..4)?).ok()?.parse().ok()
+            }
+            _ => None,
+        }
+    })();
+
+    from_exif.unwrap_or_else(|| civil_year((date / 86_400_000) as i64))
+}
+
+/// reads everything but the hash, without decoding the pixel data
+pub fn read_metadata(path: &Path) -> Result<ImageMetadata> {
+    let size = fs::metadata(path)?.len();
+    let reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    let format = reader.format().map(|f| format!("{:?}", f));
+    let (width, height) = reader.into_dimensions()?;
+    let exif = read_exif(path).unwrap_or_else(|err| {
+        tracing::warn!(path = path.to_str(), "unable to read EXIF: {:?}", err);
+        BTreeMap::new()
+    });
+
+    Ok(ImageMetadata {
+        path: path.to_owned(),
+        width,
+        height,
+        format,
+        size,
+        exif,
+        hash: None,
+    })
+}
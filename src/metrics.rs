@@ -0,0 +1,84 @@
+//! Prometheus metrics in a process wide registry, tenants add up.
+
+use axum::{
+    extract::MatchedPath,
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use eyre::Result;
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::{sync::LazyLock, time::Instant};
+
+pub struct Metrics {
+    registry: Registry,
+    /// decoded and hashed, cached hashes aren't counted
+    pub files_hashed: IntCounter,
+    /// lookups by result, `hit` or `miss`
+    pub cache_lookups: IntCounterVec,
+    /// seconds by outcome
+    pub task_duration: HistogramVec,
+    pub queued_tasks: IntGauge,
+    pub running_tasks: IntGauge,
+    /// seconds by method, route and status
+    pub http_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("image_analyzer".to_owned()), None)?;
+        let files_hashed = IntCounter::new("files_hashed_total", "images decoded and hashed")?;
+        let cache_lookups = IntCounterVec::new(Opts::new("cache_lookups_total", "hash cache lookups"), &["result"])?;
+        let task_duration = HistogramVec::new(
+            // from a second up to about 9 hours
+            HistogramOpts::new("task_duration_seconds", "time analyses took").buckets(exponential_buckets(1.0, 4.0, 9)?),
+            &["outcome"],
+        )?;
+        let queued_tasks = IntGauge::new("queued_tasks", "analyses waiting for a free slot")?;
+        let running_tasks = IntGauge::new("running_tasks", "analyses running")?;
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "time requests took to answer"),
+            &["method", "route", "status"],
+        )?;
+
+        registry.register(Box::new(files_hashed.clone()))?;
+        registry.register(Box::new(cache_lookups.clone()))?;
+        registry.register(Box::new(task_duration.clone()))?;
+        registry.register(Box::new(queued_tasks.clone()))?;
+        registry.register(Box::new(running_tasks.clone()))?;
+        registry.register(Box::new(http_duration.clone()))?;
+        Ok(Self { registry, files_hashed, cache_lookups, task_duration, queued_tasks, running_tasks, http_duration })
+    }
+
+    /// the text exposition format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics::new().expect("valid metric definitions"));
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// times requests by route rather than path, paths with ids would make a series each
+pub async fn track<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+    let method = request.method().clone();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    metrics()
+        .http_duration
+        .with_label_values(&[method.as_str(), &route, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
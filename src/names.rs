@@ -0,0 +1,62 @@
+//! Likely duplicates found by their names alone: `IMG_1234.jpg`, `IMG_1234 (1).jpg` and `IMG_1234 - Copy.jpg` of the same
+//! size, in any folders. Nothing is read but the listing, a first sweep of a library before hashing it.
+//! Numbers of the name itself are kept, `IMG_1234_1.jpg` may be another photo of a burst.
+
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use crate::analyzer::FileInfo;
+
+/// what copies are called by file managers, e.g. `Kopie` by the German Windows Explorer
+const COPY_WORDS: [&str; 5] = ["copy", "kopie", "copie", "copia", "kopia"];
+
+fn strip_number(name: &str) -> Option<&str> {
+    let rest = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    (rest.len() < name.len()).then_some(rest)
+}
+
+/// ` (1)`, as browsers and file managers name downloads and copies of existing files
+fn strip_counter(name: &str) -> Option<&str> {
+    let rest = strip_number(name.strip_suffix(')')?)?.strip_suffix('(')?;
+    Some(rest.trim_end())
+}
+
+/// ` - Copy`, `-copy` or `_copy 2`, as file managers name copies in the same folder
+fn strip_copy(name: &str) -> Option<&str> {
+    let name = strip_number(name).map_or(name, |rest| rest.trim_end_matches([' ', '_', '-']));
+    let rest = COPY_WORDS.iter().find_map(|word| name.strip_suffix(word))?;
+    let trimmed = rest.trim_end_matches([' ', '_', '-']);
+    // a separator, `Scopy` is a name
+    (trimmed.len() < rest.len() && !trimmed.is_empty()).then_some(trimmed)
+}
+
+/// the name without the suffixes of copies, extensions of the same format alike, in lowercase
+pub fn original_name(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?.to_lowercase();
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_lowercase();
+    let extension = match extension.as_str() {
+        "jpeg" | "jpe" => "jpg",
+        "tif" => "tiff",
+        other => other,
+    };
+    let mut name = stem.trim();
+    while let Some(rest) = strip_counter(name).or_else(|| strip_copy(name)) {
+        name = rest;
+    }
+    Some(format!("{}.{}", name, extension))
+}
+
+/// files of the same size and original name, the largest groups first
+pub fn group(files: &[FileInfo]) -> Vec<Vec<PathBuf>> {
+    let mut by_name: HashMap<(String, u64), Vec<PathBuf>> = HashMap::new();
+    for file in files.iter().filter(|file| file.size > 0) {
+        if let Some(name) = original_name(&file.path) {
+            by_name.entry((name, file.size)).or_default().push(file.path.clone());
+        }
+    }
+    let mut groups: Vec<_> = by_name.into_values().filter(|paths| paths.len() > 1).collect();
+    for paths in &mut groups {
+        paths.sort();
+    }
+    groups.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].cmp(&b[0])));
+    groups
+}
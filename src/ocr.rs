@@ -0,0 +1,171 @@
+//! Text based matching of screenshots, pixel hashes don't survive
+//! a switch between dark and light themes but the text does.
+
+use eyre::{bail, Result};
+use image::ImageFormat;
+use rayon::prelude::*;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+};
+
+use crate::analyzer::{sniff_format, FileStamp};
+use crate::metadata;
+
+/// word overlap above which two screenshots show the same content
+pub const SIMILARITY: f32 = 0.8;
+
+/// screenshots with fewer words aren't matched, too little to tell them apart
+const MIN_WORDS: usize = 5;
+
+/// lowercased, in the software tag of the screenshots they take
+const SCREENSHOT_TOOLS: &[&str] = &[
+    "screenshot", "screencapture", "spectacle", "flameshot", "greenshot", "sharex", "snipping", "shottr", "scrot",
+];
+
+/// sides of common displays, phones included, full screen captures have two of them
+const SCREEN_SIDES: &[u32] = &[
+    640, 720, 750, 768, 800, 828, 900, 960, 1024, 1050, 1080, 1125, 1136, 1170, 1179, 1200, 1242, 1280, 1284, 1290,
+    1334, 1366, 1440, 1536, 1600, 1620, 1664, 1680, 1792, 1920, 2048, 2160, 2208, 2224, 2360, 2388, 2436, 2532, 2556,
+    2560, 2688, 2732, 2778, 2796, 2880, 3024, 3072, 3200, 3456, 3840,
+];
+
+/// Named like a screenshot, or a PNG with no camera in the EXIF that a
+/// screenshot tool wrote or that is as large as a common display.
+pub fn is_screenshot(path: &Path) -> bool {
+    let named = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_lowercase().replace(['_', '-', ' '], ""))
+        .is_some_and(|name| name.contains("screenshot") || name.contains("screencapture"));
+    if named {
+        return true;
+    }
+
+    if !matches!(sniff_format(path), Ok(Some(ImageFormat::Png))) {
+        return false;
+    }
+    let exif = metadata::read_exif(path).unwrap_or_default();
+    if exif.contains_key("Make") || exif.contains_key("Model") {
+        return false;
+    }
+    // macOS says so in the EXIF comment, other tools in a text chunk
+    let software = [exif.get("Software").cloned(), exif.get("UserComment").cloned(), png_software(path)];
+    if software.iter().flatten().any(|software| is_screenshot_tool(software)) {
+        return true;
+    }
+    image::image_dimensions(path).is_ok_and(|(width, height)| is_screen_size(width, height))
+}
+
+pub(crate) fn is_screenshot_tool(software: &str) -> bool {
+    let software = software.to_lowercase().replace(['_', '-', ' '], "");
+    SCREENSHOT_TOOLS.iter().any(|tool| software.contains(tool))
+}
+
+/// squares are left out, they are rather cropped photos
+pub(crate) fn is_screen_size(width: u32, height: u32) -> bool {
+    width != height && SCREEN_SIDES.contains(&width) && SCREEN_SIDES.contains(&height)
+}
+
+/// the `Software` text chunk of a PNG, those are before the image data
+fn png_software(path: &Path) -> Option<String> {
+    let mut data = Vec::new();
+    File::open(path).ok()?.take(64 * 1024).read_to_end(&mut data).ok()?;
+    // after the signature
+    let mut i = 8;
+    while let Some(header) = data.get(i..i + 8) {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..] {
+            b"IDAT" | b"IEND" => return None,
+            b"tEXt" => {
+                let chunk = data.get(i + 8..i + 8 + len)?;
+                if let Some(text) = chunk.strip_prefix(b"Software\0") {
+                    return Some(String::from_utf8_lossy(text).into_owned());
+                }
+            }
+            _ => {}
+        }
+        // the header, the data and the CRC
+        i = i.saturating_add(12).saturating_add(len);
+    }
+    None
+}
+
+/// text of the image, through the `tesseract` command
+pub fn extract_text(path: &Path) -> Result<String> {
+    let output = Command::new("tesseract").arg(path).arg("-").arg("--psm").arg("3").output()?;
+    if !output.status.success() {
+        bail!("tesseract failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+type Words = Arc<BTreeSet<String>>;
+
+/// Words of the screenshots read so far, while their files don't change.
+/// Reading the text is by far the slowest part of matching screenshots.
+#[derive(Debug, Default)]
+pub struct TextCache(Mutex<HashMap<PathBuf, (FileStamp, Words)>>);
+
+impl TextCache {
+    fn words(&self, path: &Path) -> Result<Words> {
+        let stamp = FileStamp::of(path)?;
+        if let Some((cached, words)) = self.0.lock().unwrap().get(path) {
+            if *cached == stamp {
+                return Ok(words.clone());
+            }
+        }
+        let words = Arc::new(words(&extract_text(path)?));
+        self.0.lock().unwrap().insert(path.to_owned(), (stamp, words.clone()));
+        Ok(words)
+    }
+}
+
+pub(crate) fn words(text: &str) -> BTreeSet<String> {
+    text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+pub(crate) fn similarity(a: &BTreeSet<String>, b: &BTreeSet<String>) -> f32 {
+    let common = a.intersection(b).count();
+    let all = a.len() + b.len() - common;
+    if all == 0 { 0.0 } else { common as f32 / all as f32 }
+}
+
+/// Pairs of files, by index, showing the same text. Files that aren't
+/// screenshots are left out, those that can't be read are logged and left out too.
+pub fn similar_pairs<'a, I>(paths: I, threshold: f32, cache: &TextCache) -> Vec<(usize, usize)>
+where
+    I: IntoParallelIterator<Item = (usize, &'a Path)>,
+{
+    let texts: Vec<(usize, Words)> = paths
+        .into_par_iter()
+        .filter(|(_, path)| is_screenshot(path))
+        .filter_map(|(i, path)| match cache.words(path) {
+            Ok(words) => Some((i, words)),
+            Err(err) => {
+                tracing::warn!(path = path.to_str(), "unable to extract text: {:?}", err);
+                None
+            }
+        })
+        .filter(|(_, words)| words.len() >= MIN_WORDS)
+        .collect();
+    tracing::info!(screenshots = texts.len(), "text extracted");
+
+    (0..texts.len())
+        .into_par_iter()
+        .flat_map_iter(|a| {
+            let texts = &texts;
+            (a + 1..texts.len())
+                .filter(move |&b| similarity(&texts[a].1, &texts[b].1) >= threshold)
+                .map(move |b| (texts[a].0, texts[b].0))
+        })
+        .collect()
+}
@@ -0,0 +1,120 @@
+//! OpenAPI document of the HTTP API, generated from the annotated handlers.
+
+use axum::Json;
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "image-analyzer", description = "Finds duplicate and similar images in folders."),
+    paths(
+        crate::server::serve_image,
+        crate::server::serve_thumbnail,
+        crate::server::serve_preview,
+        crate::server::image_metadata,
+        crate::server::search,
+        crate::server::search_upload,
+        crate::server::cache_stats,
+        crate::server::clear_cache,
+        crate::server::prune_cache,
+        crate::server::export_cache,
+        crate::server::import_cache,
+        crate::server::compare_files,
+        crate::server::diff_image,
+        crate::server::list_folder,
+        crate::server::folder_stats,
+        crate::server::folder_tree,
+        crate::server::list_volumes,
+        crate::server::delete_file,
+        crate::server::delete_files,
+        crate::server::move_files,
+        crate::server::download_files,
+        crate::server::link_files,
+        crate::server::desktop_open,
+        crate::server::desktop_reveal,
+        crate::server::list_quarantined,
+        crate::server::restore_quarantined,
+        crate::server::purge_quarantined,
+        crate::server::resolve_groups,
+        crate::server::plan_resolution,
+        crate::server::undo,
+        crate::server::list_deleted,
+        crate::server::exclude_deleted,
+        crate::server::serve_deleted,
+        crate::server::restore_file,
+        crate::server::restore_all,
+        crate::server::list_journal,
+        crate::server::list_migrations,
+        crate::server::complete_journal_entry,
+        crate::server::rollback_journal_entry,
+        crate::server::list_roots,
+        crate::server::set_root,
+        crate::server::remove_root,
+        crate::server::list_bookmarks,
+        crate::server::get_bookmark,
+        crate::server::add_bookmark,
+        crate::server::update_bookmark,
+        crate::server::remove_bookmark,
+        crate::server::list_profiles,
+        crate::server::get_profile,
+        crate::server::add_profile,
+        crate::server::update_profile,
+        crate::server::remove_profile,
+        crate::server::analyze_profile,
+        crate::server::list_ignored,
+        crate::server::add_ignored,
+        crate::server::remove_ignored,
+        crate::server::list_protected,
+        crate::server::add_protected,
+        crate::server::remove_protected,
+        crate::server::warm_status,
+        crate::server::start_warming,
+        crate::server::healthz,
+        crate::server::readyz,
+        crate::server::serve_metrics,
+        crate::server::analyze,
+        crate::server::import_report,
+        crate::server::group_names,
+        crate::server::poll,
+        crate::server::cancel,
+        crate::server::list_tasks,
+        crate::server::task_logs,
+        crate::server::regroup_task,
+        crate::server::task_groups,
+        crate::server::export_task,
+        crate::server::list_runs,
+        crate::server::get_run,
+        crate::server::diff_runs,
+        crate::server::delete_run,
+        crate::server::list_workers,
+        crate::server::claim_batch,
+        crate::server::answer_batch,
+        crate::server::list_watched,
+        crate::server::watched_groups,
+        crate::server::list_marks,
+        crate::server::set_marks,
+        crate::ws::ws,
+        crate::server::subscribe,
+        crate::server::server_events,
+        crate::server::share_task,
+    ),
+    // the body of every 4xx and 5xx response
+    components(schemas(crate::error::ErrorBody)),
+    tags(
+        (name = "tasks", description = "analyses and their results"),
+        (name = "files", description = "actions on the files of duplicate groups"),
+        (name = "images", description = "single images"),
+        (name = "desktop", description = "files opened on the machine of the server, with `desktop = true`"),
+        (name = "deleted", description = "the bin of removed files"),
+        (name = "cache", description = "the hash cache"),
+        (name = "roots", description = "library roots and their storage classes"),
+        (name = "bookmarks", description = "folders saved to be scanned again"),
+        (name = "profiles", description = "named sets of analysis options"),
+        (name = "admin", description = "health and the journal of interrupted actions"),
+    ),
+)]
+struct ApiDoc;
+
+/// `GET /api/openapi.json`
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
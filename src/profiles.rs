@@ -0,0 +1,121 @@
+//! Named sets of analysis options, e.g. a quick check of a camera import and a deep audit of an archive, kept in
+//! `profiles.json` and submitted by name with only what differs, usually the `path`. They are shared by every
+//! session, like the bookmarks.
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{fs, path::PathBuf, sync::RwLock};
+use utoipa::ToSchema;
+
+use crate::analyzer::AnalyzeRequest;
+use crate::schema;
+
+/// schema version of the profiles file
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub name: String,
+    /// fields of the body of `/analyze`, all but the `path` when the profile is for any folder
+    #[schema(value_type = Object)]
+    pub options: Map<String, Value>,
+}
+
+/// the new options of a profile
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileRequest {
+    #[schema(value_type = Object)]
+    pub options: Map<String, Value>,
+}
+
+impl Profile {
+    /// the options with those given on submission, which take precedence
+    pub fn request(&self, given: Map<String, Value>) -> Result<AnalyzeRequest, serde_json::Error> {
+        let mut options = self.options.clone();
+        options.extend(given);
+        serde_json::from_value(Value::Object(options))
+    }
+
+    /// whether the options make a request once given a path, the error of the first invalid one otherwise
+    pub fn check(&self) -> Result<(), serde_json::Error> {
+        let path = match self.options.contains_key("path") {
+            true => Map::new(),
+            false => Map::from_iter([("path".to_owned(), Value::from(""))]),
+        };
+        self.request(path).map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+pub struct Profiles {
+    file: PathBuf,
+    profiles: RwLock<Vec<Profile>>,
+}
+
+impl Profiles {
+    pub fn open<T>(file: T) -> Result<Self>
+    where
+        PathBuf: From<T>
+    {
+        let file = PathBuf::from(file);
+        let profiles = match file.exists() {
+            true => schema::decode(&fs::read(&file)?, VERSION, schema::unversioned_to_v1)?.0,
+            false => Vec::new(),
+        };
+        Ok(Self { file, profiles: RwLock::new(profiles) })
+    }
+
+    fn save(&self, profiles: &[Profile]) -> Result<()> {
+        fs::write(&self.file, schema::encode_pretty(&profiles, VERSION)?)?;
+        Ok(())
+    }
+
+    /// by name
+    pub fn list(&self) -> Vec<Profile> {
+        let mut profiles = self.profiles.read().unwrap().clone();
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<Profile> {
+        self.profiles.read().unwrap().iter().find(|profile| profile.name == name).cloned()
+    }
+
+    /// `false` if there is a profile of that name already
+    pub fn add(&self, profile: Profile) -> Result<bool> {
+        let mut profiles = self.profiles.write().unwrap();
+        if profiles.iter().any(|existing| existing.name == profile.name) {
+            return Ok(false);
+        }
+        profiles.push(profile);
+        self.save(&profiles)?;
+        Ok(true)
+    }
+
+    /// `None` if there is no such profile
+    pub fn update(&self, name: &str, options: Map<String, Value>) -> Result<Option<Profile>> {
+        let mut profiles = self.profiles.write().unwrap();
+        let Some(profile) = profiles.iter_mut().find(|profile| profile.name == name) else {
+            return Ok(None);
+        };
+        profile.options = options;
+        let profile = profile.clone();
+        self.save(&profiles)?;
+        Ok(Some(profile))
+    }
+
+    /// `false` if there was no such profile
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let mut profiles = self.profiles.write().unwrap();
+        let len = profiles.len();
+        profiles.retain(|profile| profile.name != name);
+        if profiles.len() == len {
+            return Ok(false);
+        }
+        self.save(&profiles)?;
+        Ok(true)
+    }
+}
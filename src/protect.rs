@@ -0,0 +1,119 @@
+//! Protected folders, e.g. a master archive: nothing in them is deleted, moved or replaced by a link,
+//! whatever a plan or a request says, and suggestions keep their copies first. Those of the config
+//! stay, those added through the API are kept in `protected.json`.
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+use utoipa::ToSchema;
+
+use crate::schema;
+
+/// schema version of the protected folders file
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtectedFolder {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    /// from the config, it can't be removed through the API
+    pub configured: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtectRequest {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Default)]
+pub struct Protected {
+    configured: Vec<PathBuf>,
+    /// kept in memory only when `None`, as the folders of the config until the server opens the file
+    file: Option<PathBuf>,
+    added: RwLock<Vec<PathBuf>>,
+}
+
+impl Protected {
+    pub fn new(configured: Vec<PathBuf>) -> Self {
+        Self { configured, ..Self::default() }
+    }
+
+    /// the folders added before, and those of the config
+    pub fn open<T>(file: T, configured: Vec<PathBuf>) -> Result<Self>
+    where
+        PathBuf: From<T>
+    {
+        let file = PathBuf::from(file);
+        let added = match file.exists() {
+            true => schema::decode(&fs::read(&file)?, VERSION, schema::unversioned_to_v1)?.0,
+            false => Vec::new(),
+        };
+        Ok(Self { configured, file: Some(file), added: RwLock::new(added) })
+    }
+
+    pub fn configured(&self) -> &[PathBuf] {
+        &self.configured
+    }
+
+    fn save(&self, added: &[PathBuf]) -> Result<()> {
+        if let Some(file) = &self.file {
+            fs::write(file, schema::encode_pretty(&added, VERSION)?)?;
+        }
+        Ok(())
+    }
+
+    /// those of the config first, then in the order they were added
+    pub fn list(&self) -> Vec<ProtectedFolder> {
+        let configured = self.configured.iter().map(|path| ProtectedFolder { path: path.clone(), configured: true });
+        let added = self.added.read().unwrap();
+        configured.chain(added.iter().map(|path| ProtectedFolder { path: path.clone(), configured: false })).collect()
+    }
+
+    /// `false` if it was protected already
+    pub fn add(&self, path: &Path) -> Result<bool> {
+        let mut added = self.added.write().unwrap();
+        if self.configured.iter().chain(added.iter()).any(|folder| folder == path) {
+            return Ok(false);
+        }
+        added.push(path.to_owned());
+        self.save(&added)?;
+        Ok(true)
+    }
+
+    /// `false` if it wasn't added through the API
+    pub fn remove(&self, path: &Path) -> Result<bool> {
+        let mut added = self.added.write().unwrap();
+        let len = added.len();
+        added.retain(|folder| folder != path);
+        if added.len() == len {
+            return Ok(false);
+        }
+        self.save(&added)?;
+        Ok(true)
+    }
+
+    /// the protected folder the file is in
+    pub fn folder_of(&self, path: &Path) -> Option<PathBuf> {
+        let added = self.added.read().unwrap();
+        self.configured.iter().chain(added.iter()).find(|folder| path.starts_with(folder)).cloned()
+    }
+
+    pub fn covers(&self, path: &Path) -> bool {
+        self.folder_of(path).is_some()
+    }
+
+    /// fails for files in a protected folder, for the actions which would change them
+    pub fn check(&self, path: &Path) -> Result<()> {
+        match self.folder_of(path) {
+            Some(folder) => eyre::bail!("in the protected folder {}", folder.display()),
+            None => Ok(()),
+        }
+    }
+}
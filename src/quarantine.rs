@@ -0,0 +1,131 @@
+//! Copies set aside instead of deleted: they are moved under a quarantine root, in the folders they were in
+//! below it, and listed in `quarantine.json` to be restored or purged later. Files moved out of the quarantine
+//! by other means, e.g. by an undo, are no longer listed.
+
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::files;
+use crate::schema;
+use crate::tasks::to_millis;
+
+/// schema version of the manifest
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QuarantinedFile {
+    pub id: Uuid,
+    /// where it was, and is restored to
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    #[schema(value_type = String)]
+    pub quarantined: PathBuf,
+    pub size: u64,
+    /// ms since the epoch
+    pub added: u64,
+}
+
+/// where the file goes under the root, e.g. `/photos/a.jpg` to `<root>/photos/a.jpg`
+fn mirrored(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.components().filter(|c| matches!(c, Component::Normal(_))).collect::<PathBuf>())
+}
+
+#[derive(Debug)]
+pub struct Quarantine {
+    file: PathBuf,
+    /// of the actions naming none
+    root: PathBuf,
+    entries: RwLock<Vec<QuarantinedFile>>,
+}
+
+impl Quarantine {
+    /// the manifest and the default root in the data directory
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let file = data_dir.join("quarantine.json");
+        let entries = match file.exists() {
+            true => schema::decode(&fs::read(&file)?, VERSION, schema::unversioned_to_v1)?.0,
+            false => Vec::new(),
+        };
+        Ok(Self { file, root: data_dir.join("quarantine"), entries: RwLock::new(entries) })
+    }
+
+    fn save(&self, entries: &[QuarantinedFile]) -> Result<()> {
+        fs::write(&self.file, schema::encode_pretty(&entries, VERSION)?)?;
+        Ok(())
+    }
+
+    /// the files still in quarantine, oldest first
+    pub fn list(&self) -> Vec<QuarantinedFile> {
+        self.entries.read().unwrap().iter().filter(|entry| entry.quarantined.is_file()).cloned().collect()
+    }
+
+    /// moves the file under the root, the default one without, never overwrites
+    pub fn add(&self, path: &Path, root: Option<&Path>) -> Result<QuarantinedFile> {
+        let size = fs::metadata(path)?.len();
+        let dest = mirrored(root.unwrap_or(&self.root), path);
+        let Some(dir) = dest.parent() else {
+            bail!("not a file");
+        };
+        let quarantined = files::move_to(path, dir)?;
+        let entry = QuarantinedFile { id: Uuid::new_v4(), path: path.to_owned(), quarantined, size, added: to_millis(SystemTime::now()) };
+
+        let mut entries = self.entries.write().unwrap();
+        entries.push(entry.clone());
+        if let Err(err) = self.save(&entries) {
+            entries.pop();
+            if let Some(dir) = path.parent() {
+                files::move_to(&entry.quarantined, dir)?;
+            }
+            return Err(err);
+        }
+        Ok(entry)
+    }
+
+    /// Takes the entry out of the manifest once `action` is done with its file,
+    /// or when the file already left the quarantine.
+    fn take(&self, id: Uuid, action: impl FnOnce(&QuarantinedFile) -> Result<()>) -> Result<QuarantinedFile> {
+        let mut entries = self.entries.write().unwrap();
+        let Some(i) = entries.iter().position(|entry| entry.id == id) else {
+            bail!("not in quarantine");
+        };
+        let entry = entries[i].clone();
+        let done = match entry.quarantined.is_file() {
+            true => action(&entry),
+            false => Err(eyre::eyre!("no longer in quarantine")),
+        };
+        if done.is_ok() || !entry.quarantined.is_file() {
+            entries.remove(i);
+            self.save(&entries)?;
+        }
+        done.map(|()| entry)
+    }
+
+    /// moves the file back where it was, fails if another file is there now
+    pub fn restore(&self, id: Uuid) -> Result<QuarantinedFile> {
+        self.take(id, |entry| {
+            let Some(dir) = entry.path.parent() else {
+                bail!("no folder to move {} back to", entry.path.display());
+            };
+            files::move_to(&entry.quarantined, dir)?;
+            Ok(())
+        })
+    }
+
+    /// deletes the file for good
+    pub fn purge(&self, id: Uuid) -> Result<QuarantinedFile> {
+        self.take(id, |entry| {
+            tracing::info!(path = entry.quarantined.to_str(), "purging quarantined file");
+            fs::remove_file(&entry.quarantined)?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,113 @@
+//! Per-client quotas for the expensive routes, a token bucket per client address.
+//! Behind a reverse proxy all clients share its address, and so its quota.
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::error::{ErrorBody, ErrorCode};
+
+/// clients tracked before idle ones are forgotten
+const MAX_CLIENTS: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// up to `per_minute` requests at once, refilled evenly over a minute
+#[derive(Debug)]
+pub struct Limiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self { per_minute, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn refilled(&self, bucket: Bucket, now: Instant) -> f64 {
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.per_minute as f64 / 60.0;
+        (bucket.tokens + refill).min(self.per_minute as f64)
+    }
+
+    /// takes a token, or tells how long until the next one
+    pub fn acquire(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            // forgetting a full bucket changes nothing
+            buckets.retain(|_, bucket| self.refilled(*bucket, now) < self.per_minute as f64);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: self.per_minute as f64, updated: now });
+        let tokens = self.refilled(*bucket, now);
+        if tokens >= 1.0 {
+            *bucket = Bucket { tokens: tokens - 1.0, updated: now };
+            Ok(())
+        } else {
+            *bucket = Bucket { tokens, updated: now };
+            Err(Duration::from_secs_f64((1.0 - tokens) * 60.0 / self.per_minute as f64))
+        }
+    }
+}
+
+/// whether `path` is below the route, `:name` segments stand for any one
+fn matches(route: &str, path: &str) -> bool {
+    let mut segments = path.split('/');
+    route.split('/').all(|expected| {
+        segments.next().is_some_and(|segment| segment == expected || expected.starts_with(':') && !segment.is_empty())
+    })
+}
+
+/// limiters by route prefix, the routes of one share its quota
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    routes: Vec<(Vec<&'static str>, Limiter)>,
+}
+
+impl RateLimits {
+    pub fn with(mut self, routes: &[&'static str], per_minute: u32) -> Self {
+        self.routes.push((routes.to_vec(), Limiter::new(per_minute)));
+        self
+    }
+
+    fn limiter(&self, path: &str) -> Option<&Limiter> {
+        self.routes
+            .iter()
+            .find(|(routes, _)| routes.iter().any(|route| matches(route, path)))
+            .map(|(_, limiter)| limiter)
+    }
+}
+
+pub async fn guard<B>(State(limits): State<Arc<RateLimits>>, request: Request<B>, next: Next<B>) -> Response {
+    let Some(limiter) = limits.limiter(request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    // served without connection info in tests, they all count as one client
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ConnectInfo(addr)| addr.ip());
+    match limiter.acquire(client) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            tracing::warn!(client = %client, "rate limited");
+            let retry_after = wait.as_secs().max(1).to_string();
+            let body = ErrorBody::new(ErrorCode::TooManyRequests, "too many requests, try again later");
+            ([(header::RETRY_AFTER, retry_after)], body).into_response()
+        }
+    }
+}
@@ -1,14 +1,43 @@
 use eyre::Result;
-use serde::{Serialize, de::DeserializeOwned};
-use std::{path::{PathBuf, Path}, fs};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::{collections::BTreeMap, path::{PathBuf, Path}, fs};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize)]
+use crate::schema::{self, Migration};
+use crate::volumes;
+
+/// schema version of the metadata of removed files
+const META_VERSION: u32 = 1;
+/// schema version of journal entries
+const JOURNAL_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RemovedFile {
     id: String,
+    #[schema(value_type = String)]
     path: PathBuf,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[schema(as = JournalAction)]
+pub enum Action {
+    Remove,
+    Restore,
+}
+
+/// A file move that has been started but not yet confirmed as finished.
+/// Entries left over after a restart mean the server died mid-action.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JournalEntry {
+    id: String,
+    action: Action,
+    #[schema(value_type = String)]
+    src: PathBuf,
+    #[schema(value_type = String)]
+    dest: PathBuf,
+}
+
 /// "removes" files by placing them into a designated directory
 /// and remembering the original location.
 /// Emulates OS recycled bin.
@@ -33,17 +62,47 @@ impl Remover {
         self.root.join(id).with_extension("dat")
     }
 
+    fn journal_dir(&self) -> PathBuf {
+        self.root.join("journal")
+    }
+
+    fn journal_path(&self, id: &str) -> PathBuf {
+        self.journal_dir().join(id).with_extension("json")
+    }
+
+    fn begin(&self, id: &str, action: Action, src: &Path, dest: &Path) -> Result<()> {
+        fs::create_dir_all(self.journal_dir())?;
+        let entry = JournalEntry {
+            id: id.to_owned(),
+            action,
+            src: src.to_owned(),
+            dest: dest.to_owned(),
+        };
+        fs::write(self.journal_path(id), schema::encode(&entry, JOURNAL_VERSION)?)?;
+        Ok(())
+    }
+
+    fn commit(&self, id: &str) -> Result<()> {
+        fs::remove_file(self.journal_path(id))?;
+        Ok(())
+    }
+
+    fn journal_entry(&self, id: &str) -> Result<JournalEntry> {
+        let content = fs::read(self.journal_path(id))?;
+        let (entry, _) = schema::decode(&content, JOURNAL_VERSION, schema::unversioned_to_v1)?;
+        Ok(entry)
+    }
+
     fn read_meta<T: DeserializeOwned>(&self, id: &str) -> Result<T> {
         let path = self.meta_path(id);
         let content = fs::read(path)?;
-        let meta = serde_json::from_slice(&content)?;
+        let (meta, _) = schema::decode(&content, META_VERSION, schema::unversioned_to_v1)?;
         Ok(meta)
     }
 
     fn write_meta<T: Serialize + ?Sized>(&self, id: &str, meta: &T) -> Result<()> {
         let path = self.meta_path(id);
-        let content = serde_json::to_string(meta)?;
-        fs::write(path, content)?;
+        fs::write(path, schema::encode(&meta, META_VERSION)?)?;
         Ok(())
     }
 
@@ -75,24 +134,144 @@ impl Remover {
 
     pub fn remove(&self, path: &Path) -> Result<String> {
         let id = Uuid::new_v4().to_string();
+        let dest = self.data_path(&id);
+        self.begin(&id, Action::Remove, path, &dest)?;
         self.write_meta(&id, path)?;
 
         // move the file
-        let dest = self.data_path(&id);
         tracing::info!(src = path.to_str(), dest = dest.to_str(), "moving file");
-        fs::rename(path, dest)?;
+        if let Err(err) = fs::rename(path, dest) {
+            self.rollback(&id)?;
+            return Err(err.into());
+        }
+        self.commit(&id)?;
         Ok(id)
     }
 
     pub fn restore(&self, id: &str) -> Result<PathBuf> {
         let dest: PathBuf = self.read_meta(id)?;
         let src = self.data_path(id);
+        self.begin(id, Action::Restore, &src, &dest)?;
         tracing::info!(src = src.to_str(), dest = dest.to_str(), "moving file");
-        fs::rename(src, &dest)?;
+        if let Err(err) = fs::rename(src, &dest) {
+            self.rollback(id)?;
+            return Err(err.into());
+        }
         self.remove_meta(id)?;
+        self.commit(id)?;
         Ok(dest)
     }
 
+    /// Rewrites JSON files of older schema versions in the directory,
+    /// returns how many there were by version. Unreadable files are skipped.
+    fn migrate_dir<T>(dir: &Path, current: u32) -> Result<BTreeMap<u32, usize>>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let mut migrated = BTreeMap::new();
+        if !dir.exists() {
+            return Ok(migrated);
+        }
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+
+            // one unreadable file shouldn't keep the server from starting
+            match Self::migrate_file::<T>(&path, current) {
+                Ok(Some(found)) => *migrated.entry(found).or_default() += 1,
+                Ok(None) => {}
+                Err(err) => tracing::error!(path = path.to_str(), "unable to migrate, left as it is: {:?}", err),
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    /// the version the file was rewritten from, `None` when it is current
+    fn migrate_file<T>(path: &Path, current: u32) -> Result<Option<u32>>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let content = fs::read(path)?;
+        let (data, found): (T, u32) = schema::decode(&content, current, schema::unversioned_to_v1)?;
+        if found >= current {
+            return Ok(None);
+        }
+        fs::write(path, schema::encode(&data, current)?)?;
+        Ok(Some(found))
+    }
+
+    /// upgrades metadata and journal entries written by older versions
+    pub fn migrate(&self) -> Result<Vec<Migration>> {
+        let mut migrations = Vec::new();
+        for (from, items) in Self::migrate_dir::<PathBuf>(&self.root, META_VERSION)? {
+            migrations.push(Migration::new("removed", from, META_VERSION, items));
+        }
+        for (from, items) in Self::migrate_dir::<JournalEntry>(&self.journal_dir(), JOURNAL_VERSION)? {
+            migrations.push(Migration::new("journal", from, JOURNAL_VERSION, items));
+        }
+        Ok(migrations)
+    }
+
+    /// actions interrupted before they were committed
+    pub fn pending(&self) -> Result<Vec<JournalEntry>> {
+        let dir = self.journal_dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let content = fs::read(&path)?;
+            match schema::decode(&content, JOURNAL_VERSION, schema::unversioned_to_v1) {
+                Ok((entry, _)) => entries.push(entry),
+                Err(err) => tracing::error!(path = path.to_str(), "invalid journal entry: {:?}", err),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// finishes an interrupted action
+    pub fn complete(&self, id: &str) -> Result<()> {
+        let entry = self.journal_entry(id)?;
+        if entry.src.exists() && !entry.dest.exists() {
+            tracing::info!(src = entry.src.to_str(), dest = entry.dest.to_str(), "moving file");
+            fs::rename(&entry.src, &entry.dest)?;
+        }
+
+        match entry.action {
+            Action::Remove => self.write_meta(id, &entry.src)?,
+            Action::Restore => if self.meta_path(id).exists() {
+                self.remove_meta(id)?;
+            },
+        }
+
+        self.commit(id)
+    }
+
+    /// undoes an interrupted action, putting the file back where it was
+    pub fn rollback(&self, id: &str) -> Result<()> {
+        let entry = self.journal_entry(id)?;
+        if entry.dest.exists() && !entry.src.exists() {
+            tracing::info!(src = entry.dest.to_str(), dest = entry.src.to_str(), "moving file");
+            fs::rename(&entry.dest, &entry.src)?;
+        }
+
+        match entry.action {
+            Action::Remove => if self.meta_path(id).exists() {
+                self.remove_meta(id)?;
+            },
+            Action::Restore => self.write_meta(id, &entry.dest)?,
+        }
+
+        self.commit(id)
+    }
+
     pub fn list_removed(&self) -> Result<Vec<RemovedFile>> {
         let mut files = Vec::new();
 
@@ -107,6 +286,17 @@ impl Remover {
         Ok(files)
     }
 
+    /// absolute locations of removed files in the bin
+    pub fn data_files(&self) -> Result<Vec<PathBuf>> {
+        let root = volumes::canonicalize(&self.root)?;
+        let files = self
+            .list_removed()?
+            .iter()
+            .map(|file| root.join(&file.id).with_extension("dat"))
+            .collect();
+        Ok(files)
+    }
+
     pub fn restore_all(&self) -> Result<()> {
         let files = self.list_removed()?;
         for file in files {
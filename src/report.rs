@@ -0,0 +1,216 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+use utoipa::ToSchema;
+
+use crate::analyzer::{AnalyzeResult, FileInfo, Groups, Listing, SkipReason};
+use crate::metadata;
+use crate::roots::StorageClass;
+
+/// how many folders are listed in the statistics
+const TOP_FOLDERS: usize = 10;
+
+/// how many of the largest images are listed in folder statistics
+const LARGEST_FILES: usize = 10;
+
+/// Redundant copies of each group, assuming the largest file is the one to keep,
+/// the first path of the largest ones on ties.
+fn redundant_copies(groups: &Groups) -> impl Iterator<Item = &FileInfo> {
+    groups.iter().flat_map(|group| {
+        let keep = group
+            .iter()
+            .max_by(|a, b| a.size.cmp(&b.size).then_with(|| b.path.cmp(&a.path)))
+            .map(|file| &file.path);
+        group.iter().filter(move |file| Some(&file.path) != keep)
+    })
+}
+
+/// space freed on a storage class by keeping only one copy in each group
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClassSavings {
+    storage_class: Option<StorageClass>,
+    files: usize,
+    bytes: u64,
+}
+
+pub fn reclaimable_by_class(groups: &Groups) -> Vec<ClassSavings> {
+    let mut savings: BTreeMap<Option<StorageClass>, ClassSavings> = BTreeMap::new();
+
+    for file in redundant_copies(groups) {
+        let entry = savings
+            .entry(file.storage_class)
+            .or_insert(ClassSavings { storage_class: file.storage_class, files: 0, bytes: 0 });
+        entry.files += 1;
+        entry.bytes += file.size;
+    }
+
+    savings.into_values().collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct YearStats {
+    year: i32,
+    files: usize,
+    bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FolderStats {
+    #[schema(value_type = String)]
+    folder: PathBuf,
+    files: usize,
+    bytes: u64,
+}
+
+/// where the redundant copies come from
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateStats {
+    by_year: Vec<YearStats>,
+    top_folders: Vec<FolderStats>,
+}
+
+pub fn duplicate_stats(groups: &Groups) -> DuplicateStats {
+    let copies: Vec<&FileInfo> = redundant_copies(groups).collect();
+    let years: Vec<i32> = copies
+        .par_iter()
+        .map(|file| metadata::year_taken(&file.path, file.date))
+        .collect();
+
+    let mut by_year: BTreeMap<i32, YearStats> = BTreeMap::new();
+    let mut by_folder: HashMap<PathBuf, FolderStats> = HashMap::new();
+
+    for (file, year) in copies.iter().zip(years) {
+        let entry = by_year
+            .entry(year)
+            .or_insert(YearStats { year, files: 0, bytes: 0 });
+        entry.files += 1;
+        entry.bytes += file.size;
+
+        let folder = file.path.parent().map(PathBuf::from).unwrap_or_default();
+        let entry = by_folder
+            .entry(folder.clone())
+            .or_insert(FolderStats { folder, files: 0, bytes: 0 });
+        entry.files += 1;
+        entry.bytes += file.size;
+    }
+
+    let mut top_folders: Vec<FolderStats> = by_folder.into_values().collect();
+    top_folders.sort_by(|a, b| b.files.cmp(&a.files).then_with(|| a.folder.cmp(&b.folder)));
+    top_folders.truncate(TOP_FOLDERS);
+
+    DuplicateStats {
+        by_year: by_year.into_values().collect(),
+        top_folders,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Reclaimable {
+    files: usize,
+    bytes: u64,
+}
+
+impl Reclaimable {
+    /// of all storage classes together
+    pub fn of(result: &AnalyzeResult) -> Self {
+        Self {
+            files: result.reclaimable().iter().map(|savings| savings.files).sum(),
+            bytes: result.reclaimable().iter().map(|savings| savings.bytes).sum(),
+        }
+    }
+}
+
+/// aggregates of a folder, so they needn't be computed from a full listing
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderSummary {
+    /// images and other files, hidden ones left out
+    files: usize,
+    images: usize,
+    /// images by format, e.g. `jpeg`
+    by_format: BTreeMap<String, usize>,
+    /// of the images
+    bytes: u64,
+    /// largest images first
+    largest: Vec<FileInfo>,
+    /// redundant copies by the latest completed analysis of the folder, `None` without one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reclaimable: Option<Reclaimable>,
+}
+
+pub fn folder_summary(listing: &Listing, analysis: Option<&AnalyzeResult>) -> FolderSummary {
+    let unsupported = listing
+        .skipped
+        .iter()
+        .filter(|file| matches!(file.reason(), SkipReason::Unsupported))
+        .count();
+
+    let mut largest: Vec<&FileInfo> = listing.files.iter().collect();
+    largest.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    largest.truncate(LARGEST_FILES);
+
+    let reclaimable = analysis.map(Reclaimable::of);
+
+    FolderSummary {
+        files: listing.files.len() + unsupported,
+        images: listing.files.len(),
+        by_format: listing.formats.clone(),
+        bytes: listing.files.iter().map(|file| file.size).sum(),
+        largest: largest.into_iter().cloned().collect(),
+        reclaimable,
+    }
+}
+
+/// a folder with the totals of everything below it, and its folders down to the requested depth
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderTree {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    /// in it and the folders below
+    images: usize,
+    /// of the images
+    bytes: u64,
+    /// folders right below, listed in `children` or not
+    folders: usize,
+    /// by path, empty below the requested depth
+    #[schema(no_recursion)]
+    children: Vec<FolderTree>,
+}
+
+/// the tree of a recursive listing of `root`, `depth` levels of folders below it, all of them when `None`
+pub fn folder_tree(root: &Path, listing: &Listing, depth: Option<usize>) -> FolderTree {
+    let mut totals: HashMap<&Path, (usize, u64)> = HashMap::new();
+    for file in &listing.files {
+        for folder in file.path.ancestors().skip(1).take_while(|folder| folder.starts_with(root)) {
+            let total = totals.entry(folder).or_default();
+            total.0 += 1;
+            total.1 += file.size;
+        }
+    }
+    let mut below: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for dir in &listing.dirs {
+        if let Some(parent) = dir.path.parent() {
+            below.entry(parent).or_default().push(&dir.path);
+        }
+    }
+    tree_node(root, depth, &totals, &below)
+}
+
+/// `totals` of images and bytes by folder, the folders right `below` each
+fn tree_node(path: &Path, depth: Option<usize>, totals: &HashMap<&Path, (usize, u64)>, below: &HashMap<&Path, Vec<&Path>>) -> FolderTree {
+    let (images, bytes) = totals.get(path).copied().unwrap_or_default();
+    let mut dirs = below.get(path).cloned().unwrap_or_default();
+    dirs.sort();
+    let children = match depth {
+        Some(0) => Vec::new(),
+        _ => dirs.iter().map(|dir| tree_node(dir, depth.map(|depth| depth - 1), totals, below)).collect(),
+    };
+    FolderTree { path: path.to_owned(), images, bytes, folders: dirs.len(), children }
+}
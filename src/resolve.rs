@@ -0,0 +1,397 @@
+//! Resolution plans, what to keep and what to do with the other copies of each group.
+//! Groups are applied one at a time, a failure undoes what was done to the group so far.
+
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use utoipa::ToSchema;
+
+use crate::analyzer::{AnalyzeResult, FileInfo, Group};
+use crate::archive;
+use crate::files::{self, FileOutcome, LinkMode};
+use crate::history::Undoable;
+use crate::protect::Protected;
+use crate::quarantine::Quarantine;
+use crate::remover::Remover;
+use crate::roots::StorageClass;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "action", rename_all = "camelCase")]
+#[schema(as = FileAction)]
+pub enum Action {
+    /// into the bin of removed files, so it can be undone
+    Delete,
+    Move {
+        #[schema(value_type = String)]
+        target: PathBuf,
+    },
+    Link {
+        #[serde(default)]
+        mode: LinkMode,
+    },
+    /// under the quarantine root in the folders it was in, to be restored or purged later
+    Quarantine {
+        /// the `quarantine` folder of the data directory when not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(value_type = Option<String>)]
+        root: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedAction {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedGroup {
+    #[schema(value_type = String)]
+    pub keep: PathBuf,
+    pub actions: Vec<PlannedAction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    pub groups: Vec<PlannedGroup>,
+}
+
+/// which copy of a group is kept, the first path wins on ties
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Policy {
+    Largest,
+    /// by creation date
+    Newest,
+    Oldest,
+    /// the suggestion of the keep rules, groups without one are left alone
+    Suggested,
+}
+
+impl Policy {
+    fn keep(self, group: &Group) -> Option<&FileInfo> {
+        let files = group.files();
+        let by = |order: fn(&FileInfo, &FileInfo) -> Ordering| files.iter().min_by(|a, b| order(a, b).then_with(|| a.path.cmp(&b.path)));
+        match self {
+            Self::Largest => by(|a, b| b.size.cmp(&a.size)),
+            Self::Newest => by(|a, b| b.date.cmp(&a.date)),
+            Self::Oldest => by(|a, b| a.date.cmp(&b.date)),
+            Self::Suggested => {
+                let keep = group.suggestion()?;
+                files.iter().find(|file| file.path == keep.keep)
+            }
+        }
+    }
+}
+
+/// extensions of camera raw files
+const RAW_EXTENSIONS: &[&str] = &["arw", "cr2", "cr3", "dng", "nef", "orf", "pef", "raf", "rw2", "srw"];
+
+pub(crate) fn is_raw(path: &Path) -> bool {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+    extension.is_some_and(|extension| RAW_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// a heuristic telling which copy of a group to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum KeepRule {
+    /// by creation date
+    Newest,
+    Oldest,
+    Largest,
+    /// most pixels, read from the headers of local files
+    HighestResolution,
+    /// in the first of the preferred folders holding a copy
+    PreferredFolder,
+    /// on the first of the preferred storage classes holding a copy, by the roots the copies are in
+    PreferredStorage,
+    /// camera raw files over the JPEGs and others made of them
+    PreferRaw,
+    /// in a protected folder, always applied before the others
+    Protected,
+}
+
+/// the copy the keep rules would keep, and the rules which picked it out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Suggestion {
+    #[schema(value_type = String)]
+    pub keep: PathBuf,
+    /// each narrowed the copies left by the ones before, empty when the first path won
+    pub rules: Vec<KeepRule>,
+}
+
+/// applied in order, each one to the copies the ones before left tied
+#[derive(Debug, Clone)]
+pub struct KeepRules {
+    pub rules: Vec<KeepRule>,
+    /// for `preferredFolder`, the first ones win
+    pub preferred: Vec<PathBuf>,
+    /// for `preferredStorage`, the first ones win
+    pub storage: Vec<StorageClass>,
+    pub protected: Arc<Protected>,
+}
+
+impl Default for KeepRules {
+    fn default() -> Self {
+        Self { rules: vec![KeepRule::Largest], preferred: Vec::new(), storage: Vec::new(), protected: Arc::default() }
+    }
+}
+
+impl KeepRules {
+    /// the higher, the better a copy to keep
+    fn score(&self, rule: KeepRule, file: &FileInfo) -> u64 {
+        match rule {
+            KeepRule::Newest => file.date,
+            KeepRule::Oldest => u64::MAX - file.date,
+            KeepRule::Largest => file.size,
+            KeepRule::HighestResolution => image::image_dimensions(&file.path).map_or(0, |(width, height)| width as u64 * height as u64),
+            KeepRule::PreferredFolder => self
+                .preferred
+                .iter()
+                .position(|folder| file.path.starts_with(folder))
+                .map_or(0, |i| (self.preferred.len() - i) as u64),
+            KeepRule::PreferredStorage => file
+                .storage_class
+                .and_then(|class| self.storage.iter().position(|preferred| *preferred == class))
+                .map_or(0, |i| (self.storage.len() - i) as u64),
+            KeepRule::PreferRaw => is_raw(&file.path) as u64,
+            KeepRule::Protected => self.protected.covers(&file.path) as u64,
+        }
+    }
+
+    pub fn suggest(&self, files: &[FileInfo]) -> Option<Suggestion> {
+        let mut left: Vec<&FileInfo> = files.iter().collect();
+        let mut rules = Vec::new();
+        for &rule in iter::once(&KeepRule::Protected).chain(&self.rules) {
+            if left.len() < 2 {
+                break;
+            }
+            let scored: Vec<_> = left.into_iter().map(|file| (self.score(rule, file), file)).collect();
+            let best = scored.iter().map(|(score, _)| *score).max().unwrap_or(0);
+            let before = scored.len();
+            left = scored.into_iter().filter(|(score, _)| *score == best).map(|(_, file)| file).collect();
+            if left.len() < before {
+                rules.push(rule);
+            }
+        }
+        let keep = left.into_iter().min_by(|a, b| a.path.cmp(&b.path))?;
+        Some(Suggestion { keep: keep.path.clone(), rules })
+    }
+}
+
+/// a plan as it would be applied, with what it frees
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanPreview {
+    plan: Plan,
+    /// acted on
+    files: usize,
+    /// freed once the bin and the quarantine are emptied, moved files take as much space as before
+    bytes: u64,
+}
+
+/// Keeps one copy of each group by the policy and applies the action to the others,
+/// from the result alone so nothing on disk is touched. With `exact_only`, only to the copies
+/// with the same content as the kept one, groups without any are left out. Protected copies
+/// are left alone too.
+pub fn plan(result: &AnalyzeResult, policy: Policy, action: &Action, exact_only: bool, protected: &Protected) -> PlanPreview {
+    let mut files = 0;
+    let mut bytes = 0;
+    let groups = result
+        .groups()
+        .iter()
+        .filter_map(|group| {
+            let keep = policy.keep(group)?;
+            let kept = group.files().iter().position(|file| file.path == keep.path)?;
+            let same = group.identical().iter().find(|same| same.contains(&kept));
+            let actions: Vec<_> = group
+                .files()
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != kept && (!exact_only || same.is_some_and(|same| same.contains(&i))))
+                // archives are left as they are
+                .filter(|(_, file)| !protected.covers(&file.path) && !archive::is_entry(&file.path))
+                .map(|(_, file)| {
+                    files += 1;
+                    if !matches!(action, Action::Move { .. }) {
+                        bytes += file.size;
+                    }
+                    PlannedAction { path: file.path.clone(), action: action.clone() }
+                })
+                .collect();
+            if exact_only && actions.is_empty() {
+                return None;
+            }
+            Some(PlannedGroup { keep: keep.path.clone(), actions })
+        })
+        .collect();
+    PlanPreview { plan: Plan { groups }, files, bytes }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupReport {
+    #[schema(value_type = String)]
+    keep: PathBuf,
+    /// all actions of the group went through, otherwise none of them stayed
+    applied: bool,
+    outcomes: Vec<FileOutcome>,
+}
+
+impl GroupReport {
+    /// the files acted on
+    pub fn done(&self) -> usize {
+        if self.applied { self.outcomes.len() } else { 0 }
+    }
+}
+
+fn apply_action(
+    remover: &Remover,
+    quarantine: &Quarantine,
+    check: &impl Fn(&Path) -> Result<()>,
+    protected: &Protected,
+    keep: &Path,
+    planned: &PlannedAction,
+) -> Result<(Option<PathBuf>, Option<Undoable>)> {
+    let path = &planned.path;
+    check(path)?;
+    protected.check(path)?;
+    if path == keep {
+        bail!("the kept copy itself");
+    }
+
+    match &planned.action {
+        Action::Delete => {
+            if !path.is_file() {
+                bail!("not a file");
+            }
+            let id = remover.remove(path)?;
+            Ok((None, Some(Undoable::Removed { id, path: path.clone() })))
+        }
+        Action::Move { target } => {
+            let Some(ancestor) = files::existing_ancestor(target) else {
+                bail!("invalid target {}", target.display());
+            };
+            check(ancestor)?;
+            let dest = files::move_to(path, target)?;
+            Ok((Some(dest.clone()), Some(Undoable::Moved { from: dest, to: path.clone() })))
+        }
+        Action::Link { mode } => {
+            files::link_to(keep, path, *mode)?;
+            Ok((None, Some(Undoable::Linked { path: path.clone() })))
+        }
+        Action::Quarantine { root } => {
+            if let Some(root) = root {
+                let Some(ancestor) = files::existing_ancestor(root) else {
+                    bail!("invalid quarantine root {}", root.display());
+                };
+                check(ancestor)?;
+            }
+            let entry = quarantine.add(path, root.as_deref())?;
+            Ok((Some(entry.quarantined.clone()), Some(Undoable::Moved { from: entry.quarantined, to: path.clone() })))
+        }
+    }
+}
+
+/// the report of the group, the actions that stayed if it was applied
+fn apply_group(
+    remover: &Remover,
+    quarantine: &Quarantine,
+    check: &impl Fn(&Path) -> Result<()>,
+    protected: &Protected,
+    group: PlannedGroup,
+) -> (GroupReport, Vec<Undoable>) {
+    let keep = group.keep;
+    let kept = check(&keep).and_then(|()| if keep.is_file() { Ok(()) } else { bail!("kept copy is missing") });
+    if let Err(err) = kept {
+        let outcomes = group
+            .actions
+            .into_iter()
+            .map(|planned| FileOutcome::new(planned.path, Err(eyre::eyre!("not applied: {}", err))))
+            .collect();
+        return (GroupReport { keep, applied: false, outcomes }, Vec::new());
+    }
+
+    let mut done = Vec::new();
+    let mut failure = None;
+    for (i, planned) in group.actions.iter().enumerate() {
+        match apply_action(remover, quarantine, check, protected, &keep, planned) {
+            Ok((dest, undo)) => done.push((dest, undo)),
+            Err(err) => {
+                failure = Some((i, err));
+                break;
+            }
+        }
+    }
+
+    let Some((failed, err)) = failure else {
+        let (dests, undoables): (Vec<_>, Vec<_>) = done.into_iter().unzip();
+        let outcomes = group
+            .actions
+            .into_iter()
+            .zip(dests)
+            .map(|(planned, dest)| FileOutcome::moved(planned.path, Ok(dest)))
+            .collect();
+        let report = GroupReport { keep, applied: true, outcomes };
+        return (report, undoables.into_iter().flatten().collect());
+    };
+
+    // most recent first
+    let mut undone = Vec::new();
+    while let Some((_, undoable)) = done.pop() {
+        undone.push(undoable.map_or(Ok(()), |undoable| undoable.undo(remover)));
+    }
+    undone.reverse();
+
+    let cause = err.to_string();
+    let mut outcomes: Vec<FileOutcome> = group
+        .actions
+        .iter()
+        .zip(undone)
+        .map(|(planned, undone)| {
+            let error = match undone {
+                Ok(()) => eyre::eyre!("undone: {}", cause),
+                Err(undo) => eyre::eyre!("undo failed after {}: {}", cause, undo),
+            };
+            FileOutcome::new(planned.path.clone(), Err(error))
+        })
+        .collect();
+    let mut rest = group.actions.into_iter().skip(failed);
+    if let Some(planned) = rest.next() {
+        outcomes.push(FileOutcome::new(planned.path, Err(err)));
+    }
+    outcomes.extend(rest.map(|planned| FileOutcome::new(planned.path, Err(eyre::eyre!("not applied: {}", cause)))));
+    (GroupReport { keep, applied: false, outcomes }, Vec::new())
+}
+
+/// Applies the groups one after another, `check` rejects paths out of reach,
+/// groups with an action on a protected copy are not applied.
+/// Also returns the actions of the applied groups, to be undone later.
+pub fn apply(
+    remover: &Remover,
+    quarantine: &Quarantine,
+    check: impl Fn(&Path) -> Result<()>,
+    protected: &Protected,
+    plan: Plan,
+) -> (Vec<GroupReport>, Vec<Undoable>) {
+    let mut reports = Vec::new();
+    let mut undoables = Vec::new();
+    for group in plan.groups {
+        let (report, applied) = apply_group(remover, quarantine, &check, protected, group);
+        reports.push(report);
+        undoables.extend(applied);
+    }
+    (reports, undoables)
+}
@@ -0,0 +1,289 @@
+//! Terminal review of an exported analysis, for servers without a browser at hand.
+
+use eyre::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::analyzer;
+use crate::metadata;
+use crate::protect::Protected;
+use crate::remover::Remover;
+use crate::thumbnail;
+
+#[derive(Debug, Deserialize)]
+struct ReviewFile {
+    path: PathBuf,
+    size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReviewGroup {
+    fingerprint: String,
+    files: Vec<ReviewFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Export {
+    groups: Vec<ReviewGroup>,
+}
+
+/// reads the result of a completed analysis, as returned by `/poll` or just its `data`
+fn read_export(file: &Path) -> Result<Export> {
+    let mut value: serde_json::Value = serde_json::from_slice(&std::fs::read(file)?)?;
+    if let Some(data) = value.get_mut("data") {
+        value = data.take();
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Groups,
+    Files,
+}
+
+struct Review {
+    groups: Vec<ReviewGroup>,
+    group: ListState,
+    file: ListState,
+    focus: Focus,
+    remover: Remover,
+    protected: Protected,
+    /// removal ids, most recent last, for undo
+    removed: Vec<(String, PathBuf)>,
+    status: String,
+}
+
+impl Review {
+    fn selected_group(&self) -> Option<&ReviewGroup> {
+        self.groups.get(self.group.selected()?)
+    }
+
+    fn selected_file(&self) -> Option<&ReviewFile> {
+        self.selected_group()?.files.get(self.file.selected()?)
+    }
+
+    fn is_removed(&self, path: &Path) -> bool {
+        self.removed.iter().any(|(_, p)| p == path)
+    }
+
+    fn remove_selected(&mut self) {
+        let Some(path) = self.selected_file().map(|file| file.path.clone()) else {
+            return;
+        };
+        if self.is_removed(&path) {
+            return;
+        }
+        if let Err(err) = self.protected.check(&path) {
+            self.status = format!("unable to remove {}: {}", path.display(), err);
+            return;
+        }
+
+        self.status = match self.remover.remove(&path) {
+            Ok(id) => {
+                let status = format!("removed {}, u to undo", path.display());
+                self.removed.push((id, path));
+                status
+            }
+            Err(err) => format!("unable to remove {}: {}", path.display(), err),
+        };
+    }
+
+    fn undo(&mut self) {
+        let Some((id, path)) = self.removed.pop() else {
+            self.status = "nothing to undo".to_owned();
+            return;
+        };
+
+        self.status = match self.remover.restore(&id) {
+            Ok(_) => format!("restored {}", path.display()),
+            Err(err) => {
+                let status = format!("unable to restore {}: {}", path.display(), err);
+                self.removed.push((id, path));
+                status
+            }
+        };
+    }
+
+    fn step(&mut self, delta: isize) {
+        let (state, len) = match self.focus {
+            Focus::Groups => (&mut self.group, self.groups.len()),
+            Focus::Files => {
+                let len = self.group.selected().and_then(|g| self.groups.get(g)).map_or(0, |g| g.files.len());
+                (&mut self.file, len)
+            }
+        };
+        if len == 0 {
+            return;
+        }
+
+        let current = state.selected().unwrap_or(0) as isize;
+        state.select(Some((current + delta).rem_euclid(len as isize) as usize));
+        if self.focus == Focus::Groups {
+            self.file.select(Some(0));
+        }
+    }
+}
+
+/// colored half blocks, two pixels per cell, understood by truecolor terminals
+fn preview(path: &Path, area: Rect) -> Vec<Line<'static>> {
+    let (width, height) = (area.width as u32, area.height as u32 * 2);
+    let image = match analyzer::open_image(path, width.max(height)) {
+        Ok((image, _)) => image,
+        Err(err) => return vec![Line::from(format!("no preview: {}", err))],
+    };
+    let image = match metadata::read_orientation(path) {
+        Some(orientation) => thumbnail::orient(image, orientation),
+        None => image,
+    };
+    let image = image.thumbnail(width, height).to_rgb8();
+
+    (0..image.height())
+        .step_by(2)
+        .map(|y| {
+            let spans = (0..image.width()).map(|x| {
+                let top = image.get_pixel(x, y);
+                let style = Style::default().fg(Color::Rgb(top[0], top[1], top[2]));
+                let style = if y + 1 < image.height() {
+                    let bottom = image.get_pixel(x, y + 1);
+                    style.bg(Color::Rgb(bottom[0], bottom[1], bottom[2]))
+                } else {
+                    style
+                };
+                Span::styled("▀", style)
+            });
+            Line::from(spans.collect::<Vec<_>>())
+        })
+        .collect()
+}
+
+fn details(file: &ReviewFile) -> Vec<Line<'static>> {
+    let mut lines = vec![
+        Line::from(file.path.display().to_string()),
+        Line::from(format!("{} bytes", file.size)),
+    ];
+    if let Ok(meta) = metadata::read_metadata(&file.path) {
+        lines.push(Line::from(format!("{}x{} {}", meta.width, meta.height, meta.format.unwrap_or_default())));
+        for (tag, value) in meta.exif.into_iter().take(8) {
+            lines.push(Line::from(format!("{}: {}", tag, value)));
+        }
+    }
+    lines
+}
+
+fn draw(frame: &mut Frame, review: &mut Review) {
+    let [main, status] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+    let [groups, files, side] = Layout::horizontal([
+        Constraint::Percentage(20),
+        Constraint::Percentage(40),
+        Constraint::Percentage(40),
+    ]).areas(main);
+    let [info, image] = Layout::vertical([Constraint::Length(12), Constraint::Min(0)]).areas(side);
+
+    let highlight = Style::default().add_modifier(Modifier::REVERSED);
+    let border = |focus| if review.focus == focus { Style::default().fg(Color::Yellow) } else { Style::default() };
+
+    let items: Vec<ListItem> = review.groups
+        .iter()
+        .map(|group| ListItem::new(format!("{} ({})", group.fingerprint, group.files.len())))
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("groups").border_style(border(Focus::Groups)))
+        .highlight_style(highlight);
+    frame.render_stateful_widget(list, groups, &mut review.group);
+
+    let items: Vec<ListItem> = review
+        .selected_group()
+        .map(|group| group.files.iter().map(|file| {
+            let style = if review.is_removed(&file.path) {
+                Style::default().add_modifier(Modifier::CROSSED_OUT | Modifier::DIM)
+            } else {
+                Style::default()
+            };
+            ListItem::new(file.path.display().to_string()).style(style)
+        }).collect())
+        .unwrap_or_default();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("files").border_style(border(Focus::Files)))
+        .highlight_style(highlight);
+    frame.render_stateful_widget(list, files, &mut review.file);
+
+    if let Some(file) = review.selected_file() {
+        let block = Block::default().borders(Borders::ALL).title("details");
+        frame.render_widget(Paragraph::new(details(file)).block(block), info);
+
+        let block = Block::default().borders(Borders::ALL).title("preview");
+        let inner = block.inner(image);
+        frame.render_widget(block, image);
+        frame.render_widget(Paragraph::new(preview(&file.path, inner)), inner);
+    }
+
+    let help = "↑↓ move  tab switch  d remove  u undo  q quit";
+    let line = if review.status.is_empty() { help.to_owned() } else { format!("{}  |  {}", review.status, help) };
+    frame.render_widget(Paragraph::new(line), status);
+}
+
+fn run(terminal: &mut DefaultTerminal, review: &mut Review) -> Result<()> {
+    loop {
+        terminal.draw(|frame| draw(frame, review))?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Up | KeyCode::Char('k') => review.step(-1),
+            KeyCode::Down | KeyCode::Char('j') => review.step(1),
+            KeyCode::Tab | KeyCode::Left | KeyCode::Right => {
+                review.focus = match review.focus {
+                    Focus::Groups => Focus::Files,
+                    Focus::Files => Focus::Groups,
+                };
+            }
+            KeyCode::Char('d') if review.focus == Focus::Files => review.remove_selected(),
+            KeyCode::Char('u') => review.undo(),
+            _ => {}
+        }
+    }
+}
+
+/// `review <export-file>`: browses the groups and moves files to the bin
+/// of the data directory, the same way the web UI does, files in protected folders excepted
+pub fn review(file: Option<String>, data_dir: &Path, protected: Protected) -> Result<()> {
+    let file = file.ok_or_else(|| eyre::eyre!("usage: review <export-file>"))?;
+    let export = read_export(Path::new(&file))?;
+
+    let remover = Remover::new(data_dir.join("removed"));
+    let pending = remover.pending()?;
+    if !pending.is_empty() {
+        eyre::bail!("{} interrupted actions found in the journal, review them first", pending.len());
+    }
+
+    let mut review = Review {
+        group: ListState::default().with_selected((!export.groups.is_empty()).then_some(0)),
+        file: ListState::default().with_selected(Some(0)),
+        groups: export.groups,
+        focus: Focus::Groups,
+        remover,
+        protected,
+        removed: Vec::new(),
+        status: String::new(),
+    };
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut review);
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,121 @@
+use eyre::Result;
+use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
+
+use crate::schema::{self, Migration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+/// where the files of a root physically live
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageClass {
+    Ssd,
+    Hdd,
+    Nas,
+    CloudSync,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Root {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    pub storage_class: StorageClass,
+    /// link to the group in an external system (DAM, gallery),
+    /// `{fingerprint}` is replaced with the group fingerprint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub review_url: Option<String>,
+}
+
+/// schema version of the roots file
+const VERSION: u32 = 1;
+
+/// Storage class tags of library roots, persisted as a JSON file.
+#[derive(Debug)]
+pub struct Roots {
+    file: PathBuf,
+    roots: RwLock<Vec<Root>>,
+    migration: Option<Migration>,
+}
+
+impl Roots {
+    pub fn open<T>(file: T) -> Result<Self>
+    where
+        PathBuf: From<T>
+    {
+        let file = PathBuf::from(file);
+        let (roots, found) = if file.exists() {
+            let content = fs::read(&file)?;
+            schema::decode(&content, VERSION, schema::unversioned_to_v1)?
+        } else {
+            (Vec::new(), VERSION)
+        };
+
+        let mut migration = None;
+        if found < VERSION {
+            Self::write(&file, &roots)?;
+            migration = Some(Migration::new("roots", found, VERSION, 1));
+        }
+
+        Ok(Self { file, roots: RwLock::new(roots), migration })
+    }
+
+    fn write(file: &Path, roots: &[Root]) -> Result<()> {
+        fs::write(file, schema::encode_pretty(&roots, VERSION)?)?;
+        Ok(())
+    }
+
+    fn save(&self, roots: &[Root]) -> Result<()> {
+        Self::write(&self.file, roots)
+    }
+
+    /// upgrade of the file on open, if any
+    pub fn migration(&self) -> Option<Migration> {
+        self.migration.clone()
+    }
+
+    pub fn list(&self) -> Vec<Root> {
+        self.roots.read().unwrap().clone()
+    }
+
+    /// tags a root, replacing the previous tag of the same path
+    pub fn set(&self, root: Root) -> Result<()> {
+        let mut roots = self.roots.write().unwrap();
+        roots.retain(|r| r.path != root.path);
+        roots.push(root);
+        self.save(&roots)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<bool> {
+        let mut roots = self.roots.write().unwrap();
+        let len = roots.len();
+        roots.retain(|r| r.path != path);
+        self.save(&roots)?;
+        Ok(roots.len() != len)
+    }
+
+    fn innermost(&self, path: &Path) -> Option<Root> {
+        self.roots
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|r| path.starts_with(&r.path))
+            .max_by_key(|r| r.path.components().count())
+            .cloned()
+    }
+
+    /// storage class of the innermost tagged root containing the path
+    pub fn classify(&self, path: &Path) -> Option<StorageClass> {
+        self.innermost(path).map(|r| r.storage_class)
+    }
+
+    /// review link of a group, from the innermost root containing the path
+    pub fn review_url(&self, path: &Path, fingerprint: &str) -> Option<String> {
+        let template = self.innermost(path)?.review_url?;
+        Some(template.replace("{fingerprint}", fingerprint))
+    }
+}
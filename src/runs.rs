@@ -0,0 +1,305 @@
+//! Completed analyses kept for good in `runs.db`, unlike task results they don't expire,
+//! so past scans of a library can be looked at again without running them. The hashes of the
+//! latest run of each folder are kept as well, incremental analyses only hash what changed since.
+
+use eyre::{bail, Result};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::analyzer::{AnalyzeRequest, AnalyzeResult, Group, Hashes};
+use crate::schema::Migration;
+use crate::tasks;
+
+/// schema version of the runs database
+const VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RunSummary {
+    pub id: Uuid,
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    /// ms since the epoch
+    pub submitted: u64,
+    pub finished: u64,
+    pub groups: usize,
+    /// in the groups
+    pub files: usize,
+    /// freed by keeping only the largest copy of each group
+    pub wasted_bytes: u64,
+    #[serde(skip)]
+    pub owner: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Run {
+    pub id: Uuid,
+    pub request: AnalyzeRequest,
+    pub submitted: u64,
+    pub finished: u64,
+    pub result: AnalyzeResult,
+}
+
+/// a group of the earlier run which shares files with one of the later run, but not all of them
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupChange {
+    /// fingerprints
+    pub earlier: String,
+    pub later: String,
+    /// in the later group only
+    #[schema(value_type = Vec<String>)]
+    pub added: Vec<PathBuf>,
+    /// in the earlier group only
+    #[schema(value_type = Vec<String>)]
+    pub removed: Vec<PathBuf>,
+}
+
+/// what changed between two runs of a folder
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RunDiff {
+    pub earlier: Uuid,
+    pub later: Uuid,
+    /// later groups sharing no file with earlier ones
+    pub new: Vec<Group>,
+    /// earlier groups sharing no file with later ones, e.g. cleaned up
+    pub resolved: Vec<Group>,
+    /// for each earlier and later group sharing some files, in the order of the earlier run
+    pub changed: Vec<GroupChange>,
+    /// groups with the same files in both
+    pub unchanged: usize,
+    pub earlier_wasted_bytes: u64,
+    pub later_wasted_bytes: u64,
+}
+
+impl RunDiff {
+    pub fn new(earlier: &Run, later: &Run) -> Self {
+        let (before, after) = (earlier.result.groups(), later.result.groups());
+        let by_fingerprint: HashMap<&str, usize> = after.iter().enumerate().map(|(i, group)| (group.fingerprint(), i)).collect();
+        let mut later_of: HashMap<&Path, usize> = HashMap::new();
+        for (i, group) in after.iter().enumerate() {
+            later_of.extend(group.files().iter().map(|file| (file.path.as_path(), i)));
+        }
+
+        let mut diff = RunDiff {
+            earlier: earlier.id,
+            later: later.id,
+            new: Vec::new(),
+            resolved: Vec::new(),
+            changed: Vec::new(),
+            unchanged: 0,
+            earlier_wasted_bytes: before.iter().map(Group::wasted_bytes).sum(),
+            later_wasted_bytes: after.iter().map(Group::wasted_bytes).sum(),
+        };
+        let mut matched = vec![false; after.len()];
+        for group in before {
+            if let Some(&i) = by_fingerprint.get(group.fingerprint()) {
+                diff.unchanged += 1;
+                matched[i] = true;
+                continue;
+            }
+            let overlapping: BTreeSet<usize> = group.files().iter().filter_map(|file| later_of.get(file.path.as_path()).copied()).collect();
+            if overlapping.is_empty() {
+                diff.resolved.push(group.clone());
+            }
+            let paths: BTreeSet<&Path> = group.files().iter().map(|file| file.path.as_path()).collect();
+            for i in overlapping {
+                matched[i] = true;
+                let later_paths: BTreeSet<&Path> = after[i].files().iter().map(|file| file.path.as_path()).collect();
+                diff.changed.push(GroupChange {
+                    earlier: group.fingerprint().to_owned(),
+                    later: after[i].fingerprint().to_owned(),
+                    added: later_paths.difference(&paths).map(|path| path.to_path_buf()).collect(),
+                    removed: paths.difference(&later_paths).map(|path| path.to_path_buf()).collect(),
+                });
+            }
+        }
+        diff.new = after.iter().zip(matched).filter(|(_, matched)| !matched).map(|(group, _)| group.clone()).collect();
+        diff
+    }
+}
+
+/// requests and results are stored as JSON, the summary columns are what listings need
+pub struct Runs {
+    db: Mutex<Connection>,
+}
+
+impl Runs {
+    pub fn open(path: &Path) -> Result<(Self, Option<Migration>)> {
+        let mut db = Connection::open(path)?;
+        let migration = Self::migrate(&mut db)?;
+        Ok((Self { db: Mutex::new(db) }, migration))
+    }
+
+    /// brings the database to the current schema version, kept in `user_version`
+    fn migrate(db: &mut Connection) -> Result<Option<Migration>> {
+        let found: u32 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if found > VERSION {
+            bail!("runs schema version {} is newer than the supported {}", found, VERSION);
+        }
+        if found == VERSION {
+            return Ok(None);
+        }
+
+        let tx = db.transaction()?;
+        for version in found..VERSION {
+            match version {
+                0 => tx.execute_batch(
+                    "CREATE TABLE runs (
+                        id TEXT PRIMARY KEY,
+                        path TEXT NOT NULL,
+                        owner TEXT,
+                        submitted INTEGER NOT NULL,
+                        finished INTEGER NOT NULL,
+                        groups INTEGER NOT NULL,
+                        files INTEGER NOT NULL,
+                        wasted_bytes INTEGER NOT NULL,
+                        request TEXT NOT NULL,
+                        result TEXT NOT NULL
+                    );
+                    CREATE INDEX runs_by_path ON runs (path, finished)"
+                )?,
+                // hashes of the latest run of each path, NULL for the others
+                1 => tx.execute_batch("ALTER TABLE runs ADD COLUMN hashes TEXT")?,
+                _ => bail!("no runs migration from version {}", version),
+            }
+        }
+        tx.pragma_update(None, "user_version", VERSION)?;
+        let items: i64 = tx.query_row("SELECT COUNT(*) FROM runs", [], |row| row.get(0))?;
+        tx.commit()?;
+
+        // a new database isn't an upgrade
+        Ok((found > 0).then(|| Migration::new("runs", found, VERSION, items as usize)))
+    }
+
+    pub fn record(&self, id: Uuid, request: &AnalyzeRequest, submitted: u64, finished: u64, result: &AnalyzeResult) -> Result<()> {
+        let groups = result.groups();
+        let files: usize = groups.iter().map(|group| group.files().len()).sum();
+        let wasted_bytes: u64 = groups.iter().map(|group| group.wasted_bytes()).sum();
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO runs (id, path, owner, submitted, finished, groups, files, wasted_bytes, request, result)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            rusqlite::params![
+                id.to_string(),
+                request.path.to_string_lossy(),
+                request.owner.map(|owner| owner.to_string()),
+                submitted as i64,
+                finished as i64,
+                groups.len() as i64,
+                files as i64,
+                wasted_bytes as i64,
+                serde_json::to_string(request)?,
+                serde_json::to_string(result)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// keeps the hashes of the run, those of earlier runs of its folder are dropped
+    pub fn save_hashes(&self, id: Uuid, request: &AnalyzeRequest, hashes: &Hashes) -> Result<()> {
+        let hashes = tasks::encode_hashes(hashes)?;
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        tx.execute("UPDATE runs SET hashes = NULL WHERE path = ?1 AND id != ?2", rusqlite::params![request.path.to_string_lossy(), id.to_string()])?;
+        tx.execute("UPDATE runs SET hashes = ?2 WHERE id = ?1", [id.to_string(), hashes])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// the hashes of the latest run of the folder, `None` unless they were hashed the same way, with the current `HASH_VERSION`
+    pub fn latest_hashes(&self, request: &AnalyzeRequest) -> Result<Option<Hashes>> {
+        let row: Option<(String, String)> = self.db.lock().unwrap()
+            .query_row(
+                "SELECT request, hashes FROM runs WHERE path = ?1 AND hashes IS NOT NULL ORDER BY finished DESC LIMIT 1",
+                [request.path.to_string_lossy()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((earlier, hashes)) = row else {
+            return Ok(None);
+        };
+        let earlier: AnalyzeRequest = serde_json::from_str(&earlier)?;
+        if !earlier.hashed_like(request) {
+            return Ok(None);
+        }
+        tasks::decode_hashes(&hashes)
+    }
+
+    /// newest first, of the folder and those below it when given
+    pub fn list(&self, path: Option<&Path>) -> Result<Vec<RunSummary>> {
+        let db = self.db.lock().unwrap();
+        let mut select = db.prepare(
+            "SELECT id, path, owner, submitted, finished, groups, files, wasted_bytes FROM runs ORDER BY finished DESC, id"
+        )?;
+        let rows = select.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                [row.get::<_, i64>(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?],
+            ))
+        })?;
+
+        let mut runs = Vec::new();
+        for row in rows {
+            let (id, run_path, owner, [submitted, finished, groups, files, wasted_bytes]) = row?;
+            let run_path = PathBuf::from(run_path);
+            if path.is_some_and(|path| !run_path.starts_with(path)) {
+                continue;
+            }
+            runs.push(RunSummary {
+                id: id.parse()?,
+                path: run_path,
+                submitted: submitted as u64,
+                finished: finished as u64,
+                groups: groups as usize,
+                files: files as usize,
+                wasted_bytes: wasted_bytes as u64,
+                owner: owner.map(|owner| owner.parse()).transpose()?,
+            });
+        }
+        Ok(runs)
+    }
+
+    pub fn get(&self, id: Uuid) -> Result<Option<Run>> {
+        let row: Option<(i64, i64, String, String)> = self.db.lock().unwrap()
+            .query_row(
+                "SELECT submitted, finished, request, result FROM runs WHERE id = ?1",
+                [id.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+        let Some((submitted, finished, request, result)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Run {
+            id,
+            request: serde_json::from_str(&request)?,
+            submitted: submitted as u64,
+            finished: finished as u64,
+            result: serde_json::from_str(&result)?,
+        }))
+    }
+
+    /// `None` if there is no such run
+    pub fn owner(&self, id: Uuid) -> Result<Option<Option<Uuid>>> {
+        let owner: Option<Option<String>> = self.db.lock().unwrap()
+            .query_row("SELECT owner FROM runs WHERE id = ?1", [id.to_string()], |row| row.get(0))
+            .optional()?;
+        Ok(owner.map(|owner| owner.map(|owner| owner.parse()).transpose()).transpose()?)
+    }
+
+    /// `false` if there was no such run
+    pub fn delete(&self, id: Uuid) -> Result<bool> {
+        Ok(self.db.lock().unwrap().execute("DELETE FROM runs WHERE id = ?1", [id.to_string()])? > 0)
+    }
+}
@@ -0,0 +1,291 @@
+//! Reads images from S3-compatible buckets, for analyses of `s3://bucket/prefix` paths.
+//! Requests are signed with AWS Signature V4 and use path-style URLs, which MinIO and the like expect too.
+
+use eyre::{Result, WrapErr};
+use sha2::{Digest, Sha256};
+use image::ImageFormat;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::runtime::Handle;
+use url::Url;
+
+use crate::analyzer::{FileInfo, Listing, SkipReason, SkippedFile};
+use crate::storage::Storage;
+use crate::xml::{element, elements, unescape};
+
+pub const SCHEME: &str = "s3";
+/// of GETs, the body is empty
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub endpoint: Url,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+/// a bucket and a key, or a prefix of keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl Location {
+    /// `None` unless the path is an `s3://` URL
+    pub fn parse(path: &Path) -> Option<Self> {
+        let rest = path.to_str()?.strip_prefix(SCHEME)?.strip_prefix("://")?;
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        (!bucket.is_empty()).then(|| Self { bucket: bucket.to_owned(), key: key.to_owned() })
+    }
+
+    /// path of an object of the bucket, as in the results
+    pub fn object(&self, key: &str) -> PathBuf {
+        PathBuf::from(format!("{}://{}/{}", SCHEME, self.bucket, key))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    /// in ms
+    pub last_modified: u64,
+    /// without quotes, changes whenever the content does
+    pub etag: String,
+}
+
+/// Blocking calls for the analysis threads, on the runtime the client was created on.
+pub struct Client {
+    http: reqwest::Client,
+    config: S3Config,
+    runtime: Handle,
+}
+
+impl Client {
+    /// needs to be called on the runtime
+    pub fn new(config: S3Config) -> Result<Self> {
+        let http = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        let runtime = Handle::try_current().wrap_err("the S3 client needs a runtime")?;
+        Ok(Self { http, config, runtime })
+    }
+
+    /// every object below the prefix, in key order
+    pub fn list(&self, location: &Location) -> Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_owned()), ("prefix", location.key.clone())];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+            let body = self.runtime.block_on(self.get(&location.bucket, "", query))?;
+            let page = String::from_utf8(body)?;
+
+            for contents in elements(&page, "Contents") {
+                let field = |name| element(contents, name).map(unescape).ok_or_else(|| eyre::eyre!("listed object without {}", name));
+                objects.push(Object {
+                    key: field("Key")?,
+                    size: field("Size")?.parse()?,
+                    last_modified: parse_timestamp(&field("LastModified")?)?,
+                    etag: field("ETag")?.trim_matches('"').to_owned(),
+                });
+            }
+
+            if element(&page, "IsTruncated") != Some("true") {
+                return Ok(objects);
+            }
+            token = Some(element(&page, "NextContinuationToken").map(unescape).ok_or_else(|| eyre::eyre!("truncated listing without a token"))?);
+        }
+    }
+
+    /// the content of an object, `location` names a key
+    pub fn fetch(&self, location: &Location) -> Result<Vec<u8>> {
+        self.runtime.block_on(self.get(&location.bucket, &location.key, Vec::new()))
+    }
+
+    async fn get(&self, bucket: &str, key: &str, mut query: Vec<(&str, String)>) -> Result<Vec<u8>> {
+        let endpoint = &self.config.endpoint;
+        let host = match endpoint.port() {
+            Some(port) => format!("{}:{}", endpoint.host_str().unwrap_or_default(), port),
+            None => endpoint.host_str().unwrap_or_default().to_owned(),
+        };
+        let mut path = format!("{}/{}", endpoint.path().trim_end_matches('/'), encode(bucket, false));
+        if !key.is_empty() {
+            path = format!("{}/{}", path, encode(key, true));
+        }
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", encode(name, false), encode(value, false)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let authorization = self.authorization(&host, &path, &query, now);
+        let mut url = format!("{}://{}{}", endpoint.scheme(), host, path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let response = self
+            .http
+            .get(url)
+            .header("x-amz-content-sha256", EMPTY_SHA256)
+            .header("x-amz-date", amz_date(now))
+            .header("authorization", authorization)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            let message = element(&String::from_utf8_lossy(&body), "Message").map(unescape).unwrap_or_default();
+            let message = format!("S3 answered {} for {}/{}: {}", status, bucket, key, message);
+            if status == reqwest::StatusCode::NOT_FOUND {
+                return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+            }
+            eyre::bail!(message);
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Signature V4 of a GET without a body
+    fn authorization(&self, host: &str, path: &str, query: &str, now: u64) -> String {
+        let amz_date = amz_date(now);
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "GET\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            path, query, host, EMPTY_SHA256, amz_date, signed_headers, EMPTY_SHA256,
+        );
+        let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex::encode(Sha256::digest(canonical)));
+
+        let key = format!("AWS4{}", self.config.secret_key);
+        let key = hmac(key.as_bytes(), date.as_bytes());
+        let key = hmac(&key, self.config.region.as_bytes());
+        let key = hmac(&key, b"s3");
+        let key = hmac(&key, b"aws4_request");
+        let signature = hex::encode(hmac(&key, to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key, scope, signed_headers, signature,
+        )
+    }
+}
+
+impl Storage for Client {
+    /// folders are only prefixes of keys, the listing has none
+    fn scan(&self, dir: &Path) -> Result<Listing> {
+        let location = Location::parse(dir).ok_or_else(|| eyre::eyre!("not a bucket: {}", dir.display()))?;
+        let mut listing = Listing::default();
+        // keys ending with `/` are placeholders of folders
+        for object in self.list(&location)?.into_iter().filter(|object| !object.key.ends_with('/')) {
+            let path = location.object(&object.key);
+            if object.key.split('/').any(|part| part.starts_with('.')) {
+                listing.skipped.push(SkippedFile::new(path, SkipReason::Hidden));
+            } else if let Ok(format) = ImageFormat::from_path(&path) {
+                *listing.formats.entry(format!("{:?}", format).to_lowercase()).or_default() += 1;
+                listing.tags.insert(path.clone(), format!("etag:{}", object.etag));
+                listing.files.push(FileInfo {
+                    path,
+                    size: object.size,
+                    date: object.last_modified,
+                    modified: object.last_modified,
+                    storage_class: None,
+                    hash: None,
+                    checksum: None,
+                });
+            } else {
+                listing.skipped.push(SkippedFile::new(path, SkipReason::Unsupported));
+            }
+        }
+        listing.sort();
+        Ok(listing)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let location = Location::parse(path).ok_or_else(|| eyre::eyre!("not an object: {}", path.display()))?;
+        self.fetch(&location)
+    }
+}
+
+/// HMAC-SHA256, RFC 2104
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha256::new().chain_update(block.map(|b| b ^ 0x36)).chain_update(data).finalize();
+    Sha256::new().chain_update(block.map(|b| b ^ 0x5c)).chain_update(inner).finalize().into()
+}
+
+/// percent-encodes all but the unreserved characters, and `/` in keys
+fn encode(value: &str, keep_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if keep_slash => "/".to_owned(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `20240131T120000Z` of seconds since the epoch
+fn amz_date(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// date from days since 1970-01-01 in the proleptic Gregorian calendar
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// days since 1970-01-01 of a date, the inverse of `civil_from_days`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// ms of `2024-01-31T12:00:00.000Z`, as in listings
+fn parse_timestamp(value: &str) -> Result<u64> {
+    let invalid = || eyre::eyre!("invalid timestamp {:?}", value);
+    let (date, time) = value.trim_end_matches('Z').split_once('T').ok_or_else(invalid)?;
+    let mut date = date.splitn(3, '-').map(str::parse::<u32>);
+    let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) = (date.next(), date.next(), date.next()) else {
+        return Err(invalid());
+    };
+    let (time, millis) = time.split_once('.').unwrap_or((time, "0"));
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (Some(Ok(hours)), Some(Ok(minutes)), Some(Ok(seconds))) = (time.next(), time.next(), time.next()) else {
+        return Err(invalid());
+    };
+    let millis: u64 = format!("{:0<3}", millis).get(..3).ok_or_else(invalid)?.parse()?;
+
+    let days = days_from_civil(year as i64, month, day);
+    let secs = u64::try_from(days).map_err(|_| invalid())? * 86_400 + hours * 3600 + minutes * 60 + seconds;
+    Ok(secs * 1000 + millis)
+}
@@ -0,0 +1,49 @@
+//! The folders the server may touch, from `--library` or the tenant configuration.
+//! Paths are resolved first, so `..` and symlinks can't lead out of them.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::volumes;
+
+#[derive(Debug)]
+pub enum Denied {
+    /// can't be resolved, so it can't be checked either
+    NotFound,
+    Outside,
+}
+
+/// canonicalized folders, anything goes without any
+#[derive(Debug, Default)]
+pub struct Sandbox {
+    libraries: Option<Vec<PathBuf>>,
+}
+
+impl Sandbox {
+    pub fn new(libraries: Option<&[PathBuf]>) -> io::Result<Self> {
+        let libraries = libraries
+            .map(|libraries| libraries.iter().map(|library| volumes::canonicalize(library)).collect::<io::Result<Vec<_>>>())
+            .transpose()?;
+        Ok(Self { libraries })
+    }
+
+    /// resolved, `None` when anything goes
+    pub fn libraries(&self) -> Option<&[PathBuf]> {
+        self.libraries.as_deref()
+    }
+
+    pub fn check(&self, path: &Path) -> Result<(), Denied> {
+        let Some(libraries) = &self.libraries else {
+            return Ok(());
+        };
+
+        let path = volumes::canonicalize(path).map_err(|_| Denied::NotFound)?;
+        if libraries.iter().any(|library| path.starts_with(library)) {
+            Ok(())
+        } else {
+            Err(Denied::Outside)
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Schema versions of the persisted state, stores are upgraded in place on startup.
+
+use eyre::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+/// a store upgraded on startup
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Migration {
+    pub store: String,
+    pub from: u32,
+    pub to: u32,
+    /// files or rows rewritten
+    pub items: usize,
+}
+
+impl Migration {
+    pub fn new(store: &str, from: u32, to: u32, items: usize) -> Self {
+        Self { store: store.to_owned(), from, to, items }
+    }
+}
+
+/// JSON document along with the schema version of its data
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub data: T,
+}
+
+/// upgrades data of the given version by one version
+pub type Migrate = fn(u32, Value) -> Result<Value>;
+
+/// the first versioned schema kept the data as it was
+pub fn unversioned_to_v1(version: u32, data: Value) -> Result<Value> {
+    match version {
+        0 => Ok(data),
+        _ => bail!("no migration from version {}", version),
+    }
+}
+
+/// Reads a versioned JSON document, documents written before versioning are version 0.
+/// Returns the data upgraded to the current version and the version it was read as.
+pub fn decode<T: DeserializeOwned>(content: &[u8], current: u32, migrate: Migrate) -> Result<(T, u32)> {
+    let value: Value = serde_json::from_slice(content)?;
+    let (found, mut data) = match value {
+        Value::Object(mut object) if object.len() == 2 && object.contains_key("data") => {
+            let version = object.get("version").and_then(Value::as_u64);
+            match version {
+                Some(version) => (version as u32, object.remove("data").unwrap_or_default()),
+                None => (0, Value::Object(object)),
+            }
+        }
+        value => (0, value),
+    };
+
+    if found > current {
+        bail!("schema version {} is newer than the supported {}, downgrades aren't supported", found, current);
+    }
+    for version in found..current {
+        data = migrate(version, data)?;
+    }
+
+    Ok((serde_json::from_value(data)?, found))
+}
+
+pub fn encode<T: Serialize>(data: &T, version: u32) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&Versioned { version, data })?)
+}
+
+pub fn encode_pretty<T: Serialize>(data: &T, version: u32) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(&Versioned { version, data })?)
+}
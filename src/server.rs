@@ -0,0 +1,4242 @@
+//! The HTTP server, its state and its handlers.
+
+use crate::{analyzer, archive, auth, backup, compare, conditional, desktop, download, export, files, fixtures, headless, import, listen, logging, logs, metadata, metrics, names, openapi, ratelimit, remover, report, resolve, s3, session, sftp, shape, tasks, tenant, volumes, webdav, workers, ws};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+#[cfg(feature = "tui")]
+use crate::review;
+use crate::cache::{Cache, CacheLimits, CacheStats};
+use crate::files::{FileOutcome, LinkMode};
+use crate::analyzer::{Analyzer, AnalyzeRequest, AnalyzeResult, ExactBy, Group, HashSize, HashType, Progress, ProgressUnit, SearchMatch, WarmRequest, WarmStatus};
+use crate::autoresolve::{self, AutoRule};
+use crate::manager::{panic_message, Cancelled, Priority, TaskLimits, TaskManager, TaskOptions, TaskResponse, TaskState, TimedOut};
+use crate::config::{Cli, Command, Config};
+use crate::error::{ErrorBody, ErrorCode};
+use crate::events::{Events, ServerEvent};
+use crate::history::{History, Undoable};
+use crate::ignore::{IgnoreList, IgnoreRule, Ignored};
+use crate::protect::{ProtectRequest, Protected, ProtectedFolder};
+use crate::quarantine::{Quarantine, QuarantinedFile};
+use crate::import::ImportFormat;
+use crate::marks::{MarkEntry, MarkRequest, Marks, Scoped};
+use crate::remover::{JournalEntry, Remover};
+use crate::resolve::KeepRules;
+use crate::roots::{Root, Roots};
+use crate::bookmarks::{Bookmark, BookmarkRequest, BookmarkStatus, Bookmarks};
+use crate::profiles::{Profile, ProfileRequest, Profiles};
+use crate::compare::Comparison;
+use crate::runs::{Run, RunDiff, RunSummary, Runs};
+use crate::sandbox::{Denied, Sandbox};
+use crate::schema::Migration;
+use crate::shape::{shape, PageParams, ShapeParams};
+use crate::session::Session;
+use crate::share::Shares;
+use crate::storage::{Remotes, Storage};
+use crate::tasks::{Outcome, StoredTask, TaskStore};
+use crate::tenant::Tenants;
+use crate::thumbnail::{self, Rendition, Thumbnails};
+use crate::watch::{WatchStatus, Watcher};
+use crate::webhook::Webhooks;
+use crate::workers::{Answer, Batch, Claim, WorkerOptions, WorkerStatus};
+use url::Url;
+use tracing::Span;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    time::{Instant, Duration, SystemTime},
+};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use serde_json::Value;
+use eyre::{Result, Report};
+use axum_server::tls_rustls::RustlsConfig;
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Method, Request, StatusCode, Response},
+    extract::{multipart::MultipartRejection, rejection::{JsonRejection, QueryRejection}, ConnectInfo, DefaultBodyLimit, FromRequest, Multipart, Query, State, Path},
+    middleware::{self, Next},
+    routing::{delete, get, post},
+    response::{
+        Json, IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+    Router,
+};
+use tower::ServiceExt;
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    services,
+    trace::TraceLayer,
+};
+use tokio::{
+    task::{self, JoinHandle},
+    sync::{mpsc, oneshot, watch},
+};
+use futures::{
+    stream::{Stream, StreamExt},
+    FutureExt,
+};
+use std::panic::AssertUnwindSafe;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream};
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+pub(crate) type TaskResult = Result<AnalyzeResult>;
+
+/// what an analysis task was asked to do
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct TaskSummary {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    hash_type: HashType,
+    hash_size: HashSize,
+    dist: u32,
+    priority: Priority,
+}
+
+impl TaskSummary {
+    fn new(req: &AnalyzeRequest) -> Self {
+        Self { path: req.path.clone(), hash_type: req.hash_type, hash_size: req.hash_size, dist: req.dist, priority: req.priority }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+enum TaskStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct TaskListing {
+    task_id: Uuid,
+    request: TaskSummary,
+    status: TaskStatus,
+    progress: Progress,
+    /// ms since the epoch
+    submitted: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished: Option<u64>,
+    #[serde(skip)]
+    owner: Option<Uuid>,
+}
+
+enum AnalyzeCommand {
+    /// with the span of the request submitting it, its tasks are logged as part of it
+    Submit(AnalyzeRequest, Span, oneshot::Sender<Uuid>),
+    /// a result of another tool or a regrouped one, listed as a completed task
+    Import(AnalyzeRequest, Box<AnalyzeResult>, oneshot::Sender<Uuid>),
+    Subscribe(Uuid, oneshot::Sender<Option<watch::Receiver<Progress>>>),
+    Poll(Uuid, oneshot::Sender<Option<TaskResponse<Progress, Arc<TaskResult>>>>),
+    Request(Uuid, oneshot::Sender<Option<AnalyzeRequest>>),
+    Cancel(Uuid, oneshot::Sender<Option<bool>>),
+    Wait(Uuid, oneshot::Sender<Option<oneshot::Receiver<Arc<TaskResult>>>>),
+    List(oneshot::Sender<Vec<TaskListing>>),
+    /// the result of the last completed analysis of a folder
+    Latest(PathBuf, Session, oneshot::Sender<Option<Arc<TaskResult>>>),
+    /// stops starting queued tasks, replies with how many still run
+    Drain(oneshot::Sender<usize>),
+    /// replies with the depth of the task queue
+    Ping(oneshot::Sender<QueueDepth>),
+    /// panics in the actor loop rather than in a command
+    #[cfg(test)]
+    Crash,
+}
+
+/// what the watchdog knows about the analyzer actor
+#[derive(Debug, Default)]
+struct ActorHealth {
+    restarts: AtomicUsize,
+    last_panic: Mutex<Option<String>>,
+}
+
+impl ActorHealth {
+    fn record_panic(&self, message: String) {
+        tracing::error!("analyzer actor panicked, restarting: {}", message);
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+        *self.last_panic.lock().unwrap() = Some(message);
+    }
+}
+
+/// state of the analyzer actor, kept across restarts
+struct AnalyzerActor {
+    engine: Arc<Analyzer>,
+    manager: TaskManager<Uuid, AnalyzeRequest, Progress, TaskResult>,
+    store: TaskStore,
+    /// completed analyses, kept after their tasks expire
+    runs: Arc<Runs>,
+    webhooks: Webhooks,
+    events: Events,
+}
+
+impl AnalyzerActor {
+    fn submit(&mut self, task_id: Uuid, req: AnalyzeRequest, parent: Span) {
+        let submitted = tasks::to_millis(SystemTime::now());
+        let stored = StoredTask::<AnalyzeResult> { id: task_id, request: req.clone(), submitted, finished: None, outcome: None };
+        if let Err(err) = self.store.save(&stored) {
+            tracing::error!("unable to store analyze task {}: {:?}", task_id, err);
+        }
+
+        let engine = self.engine.clone();
+        let store = self.store.clone();
+        let runs = self.runs.clone();
+        let options = TaskOptions {
+            priority: req.priority,
+            timeout: req.timeout_minutes.map(|minutes| Duration::from_secs(minutes * 60)),
+        };
+        let callback = req.callback.clone().or_else(|| self.webhooks.fallback().cloned());
+        let path = req.path.clone();
+        let announced = req.clone();
+        self.manager.submit(task_id, req.clone(), options, move |tx, cancel| {
+            // captures the logs of the task
+            let span = tracing::info_span!(parent: &parent, "task", task_id = %task_id);
+            let _span = span.enter();
+            let started = Instant::now();
+            let earlier = match req.incremental {
+                true => runs.latest_hashes(&req).unwrap_or_else(|err| {
+                    tracing::error!("unable to load the hashes of the latest run: {:?}", err);
+                    None
+                }),
+                false => None,
+            };
+            if req.incremental && earlier.is_none() {
+                tracing::info!("no earlier run hashed the same way, hashing every file");
+            }
+            let mut result = engine.analyze_since(&req, earlier.unwrap_or_default(), tx, &cancel);
+            // cancelled past the last check, the cancellation was accepted all the same
+            if result.is_ok() && !cancel.finish() {
+                result = Err(cancel.error());
+            }
+            let elapsed = started.elapsed();
+            let mut hashes = Vec::new();
+            if let Ok(data) = &mut result {
+                hashes = data.take_hashes();
+                if let Err(err) = store.save_hashes(&task_id, &hashes) {
+                    tracing::error!("unable to store the hashes of analyze task {}: {:?}", task_id, err);
+                }
+            }
+            tracing::info!("analyze task {:?} completed in {:?}", req, elapsed);
+
+            let (outcome, label) = match &result {
+                Ok(data) => (Outcome::Completed { data }, "completed"),
+                Err(err) if err.is::<Cancelled>() => (Outcome::Cancelled, "cancelled"),
+                Err(err) if err.is::<TimedOut>() => (Outcome::TimedOut, "timedOut"),
+                Err(err) => {
+                    let ErrorBody { code, message, path } = ErrorBody::of_report(err);
+                    (Outcome::Failed { error: message, code, path }, "failed")
+                }
+            };
+            metrics::metrics().task_duration.with_label_values(&[label]).observe(elapsed.as_secs_f64());
+            let finished = tasks::to_millis(SystemTime::now());
+            if let Outcome::Completed { data } = &outcome {
+                if let Err(err) = runs.record(task_id, &req, submitted, finished, data) {
+                    tracing::error!("unable to record analyze task {}: {:?}", task_id, err);
+                } else if let Err(err) = runs.save_hashes(task_id, &req, &hashes) {
+                    tracing::error!("unable to keep the hashes of analyze task {}: {:?}", task_id, err);
+                }
+            }
+            let stored = StoredTask { id: task_id, request: req, submitted, finished: Some(finished), outcome: Some(outcome) };
+            if let Err(err) = store.save(&stored) {
+                tracing::error!("unable to store analyze task {}: {:?}", task_id, err);
+            }
+            result
+        });
+        self.announce(task_id, &announced);
+        if let Some(url) = callback {
+            self.notify(url, task_id, path);
+        }
+    }
+
+    fn import(&mut self, task_id: Uuid, req: AnalyzeRequest, mut result: AnalyzeResult) {
+        let hashes = result.take_hashes();
+        if !hashes.is_empty() {
+            if let Err(err) = self.store.save_hashes(&task_id, &hashes) {
+                tracing::error!("unable to store the hashes of task {}: {:?}", task_id, err);
+            }
+        }
+        let now = SystemTime::now();
+        let finished = tasks::to_millis(now);
+        let outcome = Some(Outcome::Completed { data: &result });
+        let stored = StoredTask { id: task_id, request: req.clone(), submitted: finished, finished: Some(finished), outcome };
+        if let Err(err) = self.store.save(&stored) {
+            tracing::error!("unable to store imported task {}: {:?}", task_id, err);
+        }
+        if let Err(err) = self.runs.record(task_id, &req, finished, finished, &result) {
+            tracing::error!("unable to record imported task {}: {:?}", task_id, err);
+        } else if !hashes.is_empty() {
+            if let Err(err) = self.runs.save_hashes(task_id, &req, &hashes) {
+                tracing::error!("unable to keep the hashes of task {}: {:?}", task_id, err);
+            }
+        }
+        let announced = req.clone();
+        self.manager.restore(task_id, req, now, now, Ok(result));
+        self.announce(task_id, &announced);
+    }
+
+    /// tells `/events` about the task, and about its progress and outcome as they come
+    fn announce(&mut self, task_id: Uuid, req: &AnalyzeRequest) {
+        self.events.send(ServerEvent::Submitted { task_id, path: req.path.clone(), owner: req.owner });
+        if let Some(done) = self.manager.wait(&task_id) {
+            self.events.follow(task_id, req.path.clone(), req.owner, self.manager.progress(&task_id), done);
+        }
+    }
+
+    fn notify(&mut self, url: Url, task_id: Uuid, path: PathBuf) {
+        if let Some(done) = self.manager.wait(&task_id) {
+            self.webhooks.notify(url, task_id, path, done);
+        }
+    }
+
+    /// re-lists tasks of an earlier run and resumes the unfinished ones,
+    /// hashes they computed before are in the cache already
+    fn restore(&mut self) -> Result<()> {
+        for task in self.store.load()? {
+            let Some(outcome) = task.outcome else {
+                tracing::info!("resuming analyze task {}", task.id);
+                self.submit(task.id, task.request, Span::none());
+                continue;
+            };
+
+            let result = match outcome {
+                Outcome::Completed { data } => Ok(data),
+                Outcome::Failed { error, code, path } => Err(ErrorBody { code, message: error, path }.into()),
+                Outcome::Cancelled => Err(Cancelled.into()),
+                Outcome::TimedOut => Err(TimedOut.into()),
+            };
+            let submitted = tasks::from_millis(task.submitted);
+            let finished = tasks::from_millis(task.finished.unwrap_or(task.submitted));
+            self.manager.restore(task.id, task.request, submitted, finished, result);
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, command: AnalyzeCommand) {
+        match command {
+            AnalyzeCommand::Submit(req, span, tx) => {
+                // e.g. a double click, the scan is already underway
+                let task_id = span.clone().in_scope(|| match self.manager.find_unfinished(|other| other.same_scan(&req)) {
+                    Some(task_id) => {
+                        tracing::info!("analyze task {:?} already submitted as {}", req, task_id);
+                        // the first submission's callback is notified already
+                        let first = self.manager.meta(&task_id).and_then(|first| first.callback);
+                        let first = first.or_else(|| self.webhooks.fallback().cloned());
+                        if let Some(url) = req.callback.filter(|url| first.as_ref() != Some(url)) {
+                            self.notify(url, task_id, req.path);
+                        }
+                        task_id
+                    }
+                    None => {
+                        tracing::info!("analyze task {:?} submitted", req);
+                        let task_id = Uuid::new_v4();
+                        self.submit(task_id, req, span);
+                        task_id
+                    }
+                });
+                if tx.send(task_id).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Import(req, result, tx) => {
+                let task_id = Uuid::new_v4();
+                tracing::info!("{} groups listed as completed task {}", result.groups().len(), task_id);
+                self.import(task_id, req, *result);
+                if tx.send(task_id).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Subscribe(task_id, tx) => {
+                let rx = self.manager.progress(&task_id);
+                if tx.send(rx).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Poll(task_id, tx) => {
+                let resp = self.manager.poll(&task_id).await;
+                if tx.send(resp).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Request(task_id, tx) => {
+                if tx.send(self.manager.meta(&task_id)).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Cancel(task_id, tx) => {
+                tracing::info!("cancelling analyze task {}", task_id);
+                if tx.send(self.manager.cancel(&task_id)).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Wait(task_id, tx) => {
+                if tx.send(self.manager.wait(&task_id)).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Latest(path, session, tx) => {
+                let latest = self.manager.latest_result(|req, result| {
+                    session.sees(req.owner) && result.is_ok() && (req.path == path || volumes::canonicalize(&req.path).is_ok_and(|req_path| req_path == path))
+                });
+                if tx.send(latest).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::List(tx) => {
+                let tasks = self.manager.list().await.into_iter().map(|task| {
+                    let status = match task.state {
+                        TaskState::Queued => TaskStatus::Queued,
+                        TaskState::Running => TaskStatus::Running,
+                        TaskState::Finished => match self.manager.result(&task.id).as_deref() {
+                            Some(Ok(_)) => TaskStatus::Completed,
+                            Some(Err(err)) if err.is::<Cancelled>() => TaskStatus::Cancelled,
+                            Some(Err(err)) if err.is::<TimedOut>() => TaskStatus::TimedOut,
+                            _ => TaskStatus::Failed,
+                        },
+                    };
+                    TaskListing {
+                        task_id: task.id,
+                        request: TaskSummary::new(&task.meta),
+                        status,
+                        progress: task.progress,
+                        submitted: tasks::to_millis(task.submitted),
+                        finished: task.finished.map(tasks::to_millis),
+                        owner: task.meta.owner,
+                    }
+                }).collect();
+                if tx.send(tasks).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Drain(tx) => {
+                if tx.send(self.manager.drain().await).is_err() {
+                    tracing::error!("unable to send response back to the client");
+                }
+            }
+            AnalyzeCommand::Ping(tx) => {
+                let _ = tx.send(QueueDepth { queued: self.manager.queued(), running: self.manager.running() });
+            }
+            // taken by the loop before it gets here
+            #[cfg(test)]
+            AnalyzeCommand::Crash => {}
+        }
+    }
+}
+
+/// how often expired task results are dropped
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// how often running tasks are checked for timeouts
+const TIMEOUT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the channel and the state of the analyzer actor, they outlive its task
+struct ActorSlot {
+    rx: mpsc::Receiver<AnalyzeCommand>,
+    actor: AnalyzerActor,
+}
+
+/// A single loop keeps the books of all tasks, which keeps polls, listings and
+/// deduplication consistent. It never waits for an analysis: those run on the
+/// blocking pool sharing one `Analyzer` and cache, up to `TASK_CONCURRENCY` at once.
+async fn task_analyzer(slot: Arc<tokio::sync::Mutex<ActorSlot>>, health: Arc<ActorHealth>) {
+    tracing::info!("manager task started");
+
+    let mut slot = slot.lock().await;
+    let ActorSlot { rx, actor } = &mut *slot;
+    let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+    let mut timeouts = tokio::time::interval(TIMEOUT_INTERVAL);
+
+    loop {
+        let command = tokio::select! {
+            command = rx.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            Some(task_id) = actor.manager.done() => {
+                actor.manager.settle(&task_id).await;
+                continue;
+            }
+            _ = timeouts.tick() => {
+                for task_id in actor.manager.expire() {
+                    tracing::warn!("analyze task {} timed out, cancelling", task_id);
+                }
+                continue;
+            }
+            _ = cleanup.tick() => {
+                let dropped = actor.manager.cleanup().await;
+                for task_id in &dropped {
+                    logs::remove(task_id);
+                    if let Err(err) = actor.store.remove(task_id) {
+                        tracing::error!("unable to remove stored task {}: {:?}", task_id, err);
+                    }
+                }
+                if !dropped.is_empty() {
+                    tracing::info!("dropped {} expired task results", dropped.len());
+                }
+                continue;
+            }
+        };
+
+        #[cfg(test)]
+        if let AnalyzeCommand::Crash = command {
+            panic!("analyzer actor crashed on request");
+        }
+
+        // a panicking command would otherwise take the channel down with it,
+        // the actor is restarted in place with its tasks and the channel intact
+        if let Err(panic) = AssertUnwindSafe(actor.handle(command)).catch_unwind().await {
+            health.record_panic(panic_message(&*panic));
+        }
+    }
+
+    tracing::info!("manager task exiting");
+}
+
+/// Runs the actor loop until its channel closes. Should the loop itself die,
+/// it is spawned again on the same channel and state, the lock of the slot
+/// being released as the dead task is dropped.
+async fn supervise_analyzer(mut slot: ActorSlot, health: Arc<ActorHealth>) {
+    if let Err(err) = slot.actor.restore() {
+        tracing::error!("unable to restore analyze tasks: {:?}", err);
+    }
+    let slot = Arc::new(tokio::sync::Mutex::new(slot));
+
+    loop {
+        match tokio::spawn(task_analyzer(slot.clone(), health.clone())).await {
+            Ok(()) => break,
+            Err(err) if err.is_panic() => health.record_panic(panic_message(&*err.into_panic())),
+            // the runtime is shutting down
+            Err(_) => break,
+        }
+    }
+}
+
+fn spawn_analyzer(
+    engine: Arc<Analyzer>,
+    limits: TaskLimits,
+    store: TaskStore,
+    runs: Arc<Runs>,
+    health: Arc<ActorHealth>,
+    webhooks: Webhooks,
+    events: Events,
+) -> (JoinHandle<()>, mpsc::Sender<AnalyzeCommand>) {
+    let (tx, rx) = mpsc::channel(32);
+    let actor = AnalyzerActor { engine, manager: TaskManager::new(limits), store, runs, webhooks, events };
+    let join_handle = tokio::spawn(supervise_analyzer(ActorSlot { rx, actor }, health));
+    (join_handle, tx)
+}
+
+/// stops the analyzer actor loop, for tests of its supervision
+#[cfg(test)]
+pub(crate) async fn crash_analyzer(state: &AppState) {
+    state.task_sender.send(AnalyzeCommand::Crash).await.unwrap();
+}
+
+pub(crate) enum AppError {
+    Internal(Report),
+    Provided(StatusCode),
+}
+
+impl AppError {
+    fn not_found() -> Self {
+        Self::Provided(StatusCode::NOT_FOUND)
+    }
+
+    fn unauthorized() -> Self {
+        Self::Provided(StatusCode::UNAUTHORIZED)
+    }
+
+    fn forbidden() -> Self {
+        Self::Provided(StatusCode::FORBIDDEN)
+    }
+
+    fn locked() -> Self {
+        Self::Provided(StatusCode::LOCKED)
+    }
+}
+
+impl<T> From<T> for AppError
+where
+    T: Into<Report>
+{
+    fn from(inner: T) -> Self {
+        Self::Internal(inner.into())
+    }
+}
+
+impl AppError {
+    pub(crate) fn body(self) -> ErrorBody {
+        match self {
+            Self::Internal(err) => ErrorBody::of_report(&err),
+            Self::Provided(code) => ErrorBody::of_status(code),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        self.body().into_response()
+    }
+}
+
+pub(crate) type AppResult<T> = Result<T, AppError>;
+type JsonResponse<T> = AppResult<Json<T>>;
+
+pub(crate) struct AppState {
+    task_sender: mpsc::Sender<AnalyzeCommand>,
+    actor_health: Arc<ActorHealth>,
+    engine: Arc<Analyzer>,
+    remover: Remover,
+    /// copies set aside by plans, to be restored or purged
+    quarantine: Quarantine,
+    /// recent batches of file actions, to undo them
+    history: History,
+    roots: Arc<Roots>,
+    /// folders saved to be scanned again
+    bookmarks: Bookmarks,
+    /// named sets of analysis options
+    profiles: Profiles,
+    /// kept out of the groups, shared with the engine
+    ignored: Arc<IgnoreList>,
+    /// nothing in them is changed by file actions, shared with the keep rules
+    protected: Arc<Protected>,
+    pub(crate) shares: Shares,
+    thumbnails: Thumbnails,
+    /// set on startup when the journal has interrupted actions,
+    /// destructive actions are blocked until they are reviewed
+    safe_mode: AtomicBool,
+    /// folders a tenant is confined to, shared with the engine scanning them
+    sandbox: Arc<Sandbox>,
+    /// schema upgrades done on startup
+    migrations: Vec<Migration>,
+    /// folders kept analyzed
+    watcher: Arc<Watcher>,
+    /// completed analyses, kept for good
+    runs: Arc<Runs>,
+    /// review decisions
+    marks: Marks,
+    /// for the hashes of completed tasks
+    tasks: TaskStore,
+    /// of the tasks and file actions, for `/events`
+    events: Events,
+    /// tells the callbacks tasks may name
+    webhooks: Webhooks,
+    /// files are opened on the machine of the server
+    desktop: bool,
+    /// carried by workers, the worker endpoints aren't served without
+    worker_token: Option<Arc<str>>,
+    /// set on shutdown, no new work is accepted
+    pub(crate) draining: AtomicBool,
+}
+
+impl AppState {
+    /// rejects paths outside of the tenant libraries
+    fn check_library(&self, path: &std::path::Path) -> AppResult<()> {
+        self.sandbox.check(path).map_err(|denied| match denied {
+            Denied::NotFound => AppError::not_found(),
+            Denied::Outside => AppError::forbidden(),
+        })
+    }
+
+    /// rejects files in protected folders, for the actions which would change them
+    fn check_unprotected(&self, path: &std::path::Path) -> AppResult<()> {
+        match self.protected.folder_of(path) {
+            Some(folder) => Err(ErrorBody::new(ErrorCode::Forbidden, format!("in the protected folder {}", folder.display())).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// remote folders are up to their storage, local ones have to be in the libraries
+    fn check_folder(&self, path: &std::path::Path) -> AppResult<()> {
+        if self.engine.remote(path)?.is_none() {
+            check_path(path)?;
+            self.check_library(path)?;
+        }
+        Ok(())
+    }
+
+    /// the actions already went through, so a failure to record them is only logged
+    fn record(&self, actions: Vec<Undoable>) {
+        if !actions.is_empty() {
+            self.events.send(ServerEvent::files(&actions));
+        }
+        if let Err(err) = self.history.record(actions) {
+            tracing::error!("unable to record file actions: {:?}", err);
+        }
+    }
+
+    fn check_safe_mode(&self) -> AppResult<()> {
+        if self.safe_mode.load(Ordering::Relaxed) {
+            Err(AppError::locked())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// applies the rules to the groups of a watched folder, returns the number of files acted on
+    fn auto_resolve(&self, rules: &[AutoRule], groups: &[Group]) -> usize {
+        if self.check_safe_mode().is_err() {
+            tracing::warn!("in safe mode, the groups of watched folders aren't resolved");
+            return 0;
+        }
+        let plan = autoresolve::plan(rules, groups, &self.protected);
+        if plan.groups.is_empty() {
+            return 0;
+        }
+        let check = |path: &std::path::Path| self.check_library(path).map_err(|_| eyre::eyre!("not found in the libraries"));
+        let (reports, applied) = resolve::apply(&self.remover, &self.quarantine, check, &self.protected, plan);
+        self.record(applied);
+        reports.iter().map(|report| report.done()).sum()
+    }
+
+    fn check_draining(&self) -> AppResult<()> {
+        if self.draining.load(Ordering::Relaxed) {
+            Err(AppError::Provided(StatusCode::SERVICE_UNAVAILABLE))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub(crate) enum AnalyzeResponse<'a> {
+    Queued { position: usize },
+    Pending { progress: Progress },
+    Completed { data: &'a AnalyzeResult },
+    Failed {
+        error: String,
+        code: ErrorCode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[schema(value_type = Option<String>)]
+        path: Option<PathBuf>,
+    },
+    Cancelled,
+    TimedOut,
+}
+
+#[derive(Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PathParams {
+    #[param(value_type = String)]
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TaskParams {
+    pub(crate) task_id: Uuid,
+}
+
+/// a JSON body, or the query string of clients from before there were bodies
+struct JsonOrQuery<T>(T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S, Body> for JsonOrQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request<Body>, state: &S) -> AppResult<Self> {
+        let json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+        let parsed = if json {
+            Json::<T>::from_request(req, state).await.map(|Json(parsed)| parsed).map_err(|err| err.body_text())
+        } else {
+            Query::<T>::try_from_uri(req.uri()).map(|Query(parsed)| parsed).map_err(|err| err.body_text())
+        };
+        parsed.map(Self).map_err(|message| ErrorBody::new(ErrorCode::BadRequest, message).into())
+    }
+}
+
+fn check_path(path: &std::path::Path) -> AppResult<()> {
+    if !path.is_dir() {
+        Err(AppError::not_found())
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+enum SortKey {
+    /// file name, then path
+    Name,
+    Size,
+    Mtime,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+struct ListParams {
+    /// by path when not given
+    sort_by: Option<SortKey>,
+    #[serde(default)]
+    order: SortOrder,
+    /// on unless turned off, `false` lists the folders below as well
+    only_images: Option<bool>,
+    /// the folders below and no images
+    #[serde(default)]
+    only_dirs: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum FolderEntry {
+    Image(analyzer::FileInfo),
+    Folder(analyzer::FileInfo),
+}
+
+impl FolderEntry {
+    fn info(&self) -> &analyzer::FileInfo {
+        match self {
+            Self::Image(info) | Self::Folder(info) => info,
+        }
+    }
+}
+
+impl ListParams {
+    fn entries(&self, listing: analyzer::Listing) -> Vec<FolderEntry> {
+        let mut entries = Vec::new();
+        if !self.only_dirs {
+            entries.extend(listing.files.into_iter().map(FolderEntry::Image));
+        }
+        if self.only_dirs || self.only_images == Some(false) {
+            entries.extend(listing.dirs.into_iter().map(FolderEntry::Folder));
+        }
+
+        entries.sort_by(|a, b| {
+            let (a, b) = (a.info(), b.info());
+            let order = match self.sort_by {
+                None => std::cmp::Ordering::Equal,
+                Some(SortKey::Name) => a.path.file_name().cmp(&b.path.file_name()),
+                Some(SortKey::Size) => a.size.cmp(&b.size),
+                Some(SortKey::Mtime) => a.modified.cmp(&b.modified),
+            };
+            let order = order.then_with(|| a.path.cmp(&b.path));
+            match self.order {
+                SortOrder::Asc => order,
+                SortOrder::Desc => order.reverse(),
+            }
+        });
+        entries
+    }
+}
+
+/// the images below a folder, or its folders
+#[utoipa::path(
+    get,
+    path = "/list_folder",
+    tag = "files",
+    params(PathParams, ListParams, PageParams, ShapeParams),
+    responses(
+        (status = 200, body = Vec<FolderEntry>, headers(("x-total-count" = usize, description = "entries in all"))),
+        (status = 400, description = "both `onlyImages` and `onlyDirs`"),
+        (status = 404, description = "no such folder"),
+    ),
+)]
+async fn list_folder(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PathParams>,
+    Query(list_params): Query<ListParams>,
+    Query(page_params): Query<PageParams>,
+    Query(shape_params): Query<ShapeParams>,
+) -> AppResult<impl IntoResponse> {
+    state.check_folder(&params.path)?;
+    if list_params.only_dirs && list_params.only_images == Some(true) {
+        return Err(AppError::Provided(StatusCode::BAD_REQUEST));
+    }
+
+    let engine = state.engine.clone();
+    let listing = task::spawn_blocking(move || engine.scan(&params.path)).await??;
+    let entries = list_params.entries(listing);
+    let page = shape(&page_params.page(&entries), &shape_params)?;
+    Ok(([(shape::TOTAL_COUNT, entries.len().to_string())], Json(page)))
+}
+
+/// totals of the images below a folder, with what the last analysis of it found
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "files",
+    params(PathParams),
+    responses(
+        (status = 200, body = report::FolderSummary),
+        (status = 404, description = "no such folder"),
+    ),
+)]
+async fn folder_stats(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<PathParams>,
+) -> JsonResponse<report::FolderSummary> {
+    state.check_folder(&params.path)?;
+
+    let path = volumes::canonicalize(&params.path).unwrap_or_else(|_| params.path.clone());
+    let (tx, rx) = oneshot::channel();
+    state.task_sender.send(AnalyzeCommand::Latest(path, session, tx)).await?;
+    let latest = rx.await?;
+
+    let engine = state.engine.clone();
+    let summary = task::spawn_blocking(move || -> Result<_> {
+        let listing = engine.scan(&params.path)?;
+        let analysis = latest.as_deref().and_then(|result| result.as_ref().ok());
+        Ok(report::folder_summary(&listing, analysis))
+    })
+    .await??;
+    Ok(Json(summary))
+}
+
+/// where folders are picked from: the configured libraries, or the drives of Windows, `/` and the mounted volumes
+#[utoipa::path(
+    get,
+    path = "/volumes",
+    tag = "files",
+    responses((status = 200, body = Vec<volumes::Volume>)),
+)]
+async fn list_volumes(State(state): State<Arc<AppState>>) -> JsonResponse<Vec<volumes::Volume>> {
+    let found = match state.sandbox.libraries() {
+        Some(libraries) => libraries.iter().map(|path| volumes::Volume { path: path.clone(), kind: volumes::VolumeKind::Library }).collect(),
+        // network drives may take a while to answer
+        None => task::spawn_blocking(volumes::list).await?,
+    };
+    Ok(Json(found))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TreeParams {
+    #[param(value_type = String)]
+    path: PathBuf,
+    /// levels of folders listed below `path`, all of them when not given
+    depth: Option<usize>,
+}
+
+/// the folders below a folder as a tree, with the images and bytes of each in one request
+#[utoipa::path(
+    get,
+    path = "/tree",
+    tag = "files",
+    params(TreeParams),
+    responses(
+        (status = 200, body = report::FolderTree),
+        (status = 404, description = "no such folder"),
+    ),
+)]
+async fn folder_tree(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TreeParams>,
+) -> JsonResponse<report::FolderTree> {
+    state.check_folder(&params.path)?;
+
+    let engine = state.engine.clone();
+    let tree = task::spawn_blocking(move || -> Result<_> {
+        let listing = engine.scan(&params.path)?;
+        Ok(report::folder_tree(&params.path, &listing, params.depth))
+    })
+    .await??;
+    Ok(Json(tree))
+}
+
+#[utoipa::path(
+    post,
+    path = "/delete_file",
+    tag = "files",
+    params(PathParams),
+    responses(
+        (status = 200, description = "id of the removed file", body = String),
+        (status = 403, description = "outside of the libraries or in a protected folder"),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn delete_file(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PathParams>,
+) -> JsonResponse<String> {
+    state.check_safe_mode()?;
+    state.check_library(&params.path)?;
+    state.check_unprotected(&params.path)?;
+    let base_name = state.remover.remove(&params.path)?;
+    // restored from the bin of removed files, it isn't undone by `/undo`
+    state.events.send(ServerEvent::files(&[Undoable::Removed { id: base_name.clone(), path: params.path }]));
+    Ok(Json(base_name))
+}
+
+/// the desktop endpoints are for browsers on the machine of the server, requests without a peer address come from tests
+fn check_local(connect: Option<ConnectInfo<std::net::SocketAddr>>) -> AppResult<()> {
+    match connect {
+        Some(ConnectInfo(addr)) if !addr.ip().is_loopback() => {
+            Err(ErrorBody::new(ErrorCode::Forbidden, "only for browsers on the machine of the server").into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// the file to open on the desktop, in the libraries
+fn desktop_file(state: &AppState, path: &std::path::Path) -> AppResult<()> {
+    if !path.exists() {
+        return Err(AppError::not_found());
+    }
+    state.check_library(path)
+}
+
+#[utoipa::path(
+    post,
+    path = "/desktop/open",
+    tag = "desktop",
+    params(PathParams),
+    responses(
+        (status = 204, description = "the viewer was started"),
+        (status = 403, description = "outside of the libraries, requested from another machine or by another site"),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn desktop_open(
+    State(state): State<Arc<AppState>>,
+    connect: Option<ConnectInfo<std::net::SocketAddr>>,
+    Query(params): Query<PathParams>,
+) -> AppResult<StatusCode> {
+    check_local(connect)?;
+    desktop_file(&state, &params.path)?;
+    tracing::info!(path = params.path.to_str(), "opening on the desktop");
+    desktop::open(&params.path)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/desktop/reveal",
+    tag = "desktop",
+    params(PathParams),
+    responses(
+        (status = 204, description = "the file manager was started"),
+        (status = 403, description = "outside of the libraries, requested from another machine or by another site"),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn desktop_reveal(
+    State(state): State<Arc<AppState>>,
+    connect: Option<ConnectInfo<std::net::SocketAddr>>,
+    Query(params): Query<PathParams>,
+) -> AppResult<StatusCode> {
+    check_local(connect)?;
+    desktop_file(&state, &params.path)?;
+    tracing::info!(path = params.path.to_str(), "revealing on the desktop");
+    task::spawn_blocking(move || desktop::reveal(&params.path)).await??;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// set by the pages calling the desktop endpoints
+const REQUESTED_WITH: &str = "x-requested-with";
+
+/// Other sites can make browsers POST to the server but can't set headers, which the page of
+/// an allowed origin does, nor send JSON without a preflight the CORS origins decide on.
+async fn same_origin<B>(request: Request<B>, next: Next<B>) -> AppResult<axum::response::Response> {
+    let headers = request.headers();
+    let json = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).is_some_and(|value| value.starts_with("application/json"));
+    if !json && !headers.contains_key(REQUESTED_WITH) {
+        return Err(ErrorBody::new(ErrorCode::Forbidden, "send the X-Requested-With header, or a JSON body").into());
+    }
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize, ToSchema)]
+struct DeleteFilesRequest {
+    #[schema(value_type = Vec<String>)]
+    paths: Vec<PathBuf>,
+    /// skips the system trash
+    #[serde(default)]
+    permanent: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/files/delete",
+    tag = "files",
+    request_body = DeleteFilesRequest,
+    responses(
+        (status = 200, body = Vec<FileOutcome>),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn delete_files(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<DeleteFilesRequest>,
+) -> JsonResponse<Vec<FileOutcome>> {
+    state.check_safe_mode()?;
+
+    let outcomes = task::spawn_blocking(move || {
+        let mut trashed = Vec::new();
+        let outcomes = req.paths
+            .into_iter()
+            .map(|path| {
+                let result = state
+                    .check_library(&path)
+                    .map_err(|_| eyre::eyre!("not found in the libraries"))
+                    .and_then(|()| state.protected.check(&path))
+                    .and_then(|()| files::delete(&path, req.permanent));
+                if result.is_ok() && !req.permanent {
+                    trashed.push(Undoable::Trashed { path: path.clone() });
+                }
+                FileOutcome::new(path, result)
+            })
+            .collect();
+        state.record(trashed);
+        outcomes
+    }).await?;
+    Ok(Json(outcomes))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct DownloadRequest {
+    /// e.g. the files of a group, named in the archive by their paths below the folders they share
+    #[schema(value_type = Vec<String>)]
+    paths: Vec<PathBuf>,
+}
+
+/// the storage a file is read from, local files have to be in the libraries
+fn file_storage(state: &AppState, path: &std::path::Path) -> AppResult<Arc<dyn Storage>> {
+    if let Some(storage) = state.engine.remote(path)? {
+        return Ok(storage);
+    }
+    let file = archive::split(path).map_or_else(|| path.to_owned(), |(archive, _)| archive);
+    state.check_library(&file)?;
+    if !file.is_file() {
+        return Err(ErrorBody::new(ErrorCode::NotFound, format!("no such file {}", path.display())).into());
+    }
+    Ok(Arc::new(state.engine.archives()))
+}
+
+/// the files as a ZIP archive, streamed a file at a time
+#[utoipa::path(
+    post,
+    path = "/download",
+    tag = "files",
+    request_body = DownloadRequest,
+    responses(
+        (status = 200, description = "the files, stored as they are", content_type = "application/zip"),
+        (status = 400, description = "no files, or too many for an archive"),
+        (status = 403, description = "outside of the libraries"),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn download_files(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<DownloadRequest>,
+) -> AppResult<impl IntoResponse> {
+    let mut paths = req.paths;
+    paths.sort();
+    paths.dedup();
+    if paths.is_empty() || paths.len() > download::MAX_ENTRIES {
+        let message = format!("between 1 and {} files", download::MAX_ENTRIES);
+        return Err(ErrorBody::new(ErrorCode::BadRequest, message).into());
+    }
+    let files = paths.into_iter().map(|path| Ok((file_storage(&state, &path)?, path))).collect::<AppResult<Vec<_>>>()?;
+
+    // a few files ahead of a slow client at most
+    let (tx, rx) = tokio::sync::mpsc::channel(2);
+    task::spawn_blocking(move || download::write(&files, |chunk| tx.blocking_send(chunk).is_ok()));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"images.zip\""),
+        ],
+        axum::body::StreamBody::new(tokio_stream::wrappers::ReceiverStream::new(rx)),
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct MoveFilesRequest {
+    #[schema(value_type = Vec<String>)]
+    paths: Vec<PathBuf>,
+    /// folder the files are moved into, e.g. one to review them later
+    #[schema(value_type = String)]
+    target: PathBuf,
+}
+
+#[utoipa::path(
+    post,
+    path = "/files/move",
+    tag = "files",
+    request_body = MoveFilesRequest,
+    responses(
+        (status = 200, body = Vec<FileOutcome>),
+        (status = 400, description = "invalid target"),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn move_files(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<MoveFilesRequest>,
+) -> JsonResponse<Vec<FileOutcome>> {
+    state.check_safe_mode()?;
+    let ancestor = files::existing_ancestor(&req.target).ok_or(AppError::Provided(StatusCode::BAD_REQUEST))?;
+    state.check_library(ancestor)?;
+
+    let outcomes = task::spawn_blocking(move || {
+        let mut moved = Vec::new();
+        let outcomes = req.paths
+            .into_iter()
+            .map(|path| {
+                let result = state
+                    .check_library(&path)
+                    .map_err(|_| eyre::eyre!("not found in the libraries"))
+                    .and_then(|()| state.protected.check(&path))
+                    .and_then(|()| files::move_to(&path, &req.target));
+                if let Ok(dest) = &result {
+                    moved.push(Undoable::Moved { from: dest.clone(), to: path.clone() });
+                }
+                FileOutcome::moved(path, result.map(Some))
+            })
+            .collect();
+        state.record(moved);
+        outcomes
+    }).await?;
+    Ok(Json(outcomes))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct LinkFilesRequest {
+    /// copy the others are linked to
+    #[schema(value_type = String)]
+    keep: PathBuf,
+    #[schema(value_type = Vec<String>)]
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    mode: LinkMode,
+}
+
+#[utoipa::path(
+    post,
+    path = "/files/link",
+    tag = "files",
+    request_body = LinkFilesRequest,
+    responses(
+        (status = 200, body = Vec<FileOutcome>),
+        (status = 404, description = "no kept copy"),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn link_files(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<LinkFilesRequest>,
+) -> JsonResponse<Vec<FileOutcome>> {
+    state.check_safe_mode()?;
+    state.check_library(&req.keep)?;
+    if !req.keep.is_file() {
+        return Err(AppError::not_found());
+    }
+
+    let outcomes = task::spawn_blocking(move || {
+        let mut linked = Vec::new();
+        let outcomes = req.paths
+            .into_iter()
+            .map(|path| {
+                let result = state
+                    .check_library(&path)
+                    .map_err(|_| eyre::eyre!("not found in the libraries"))
+                    .and_then(|()| state.protected.check(&path))
+                    .and_then(|()| files::link_to(&req.keep, &path, req.mode));
+                if result.is_ok() {
+                    linked.push(Undoable::Linked { path: path.clone() });
+                }
+                FileOutcome::new(path, result)
+            })
+            .collect();
+        state.record(linked);
+        outcomes
+    }).await?;
+    Ok(Json(outcomes))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct PlanRequest {
+    task_id: Uuid,
+    policy: resolve::Policy,
+    /// what happens to the other copies
+    #[serde(flatten)]
+    action: resolve::Action,
+    /// only the copies with the same content as the kept one, files which only look alike are left alone
+    #[serde(default)]
+    exact_only: bool,
+}
+
+/// dry run of a plan for a completed analysis, to be sent to `/resolve` as is
+#[utoipa::path(
+    post,
+    path = "/resolve/plan",
+    tag = "files",
+    request_body = PlanRequest,
+    responses(
+        (status = 200, body = resolve::PlanPreview),
+        (status = 400, description = "no valid action"),
+        (status = 404, description = "unknown task"),
+        (status = 409, description = "the task did not complete"),
+    ),
+)]
+async fn plan_resolution(
+    State(state): State<Arc<AppState>>,
+    body: Result<Json<PlanRequest>, JsonRejection>,
+) -> JsonResponse<resolve::PlanPreview> {
+    let Json(req) = body.map_err(|err| ErrorBody::new(ErrorCode::BadRequest, err.body_text()))?;
+    let resp = request_poll(&state, req.task_id).await?;
+    let result = completed(&resp)?;
+    Ok(Json(resolve::plan(result, req.policy, &req.action, req.exact_only, &state.protected)))
+}
+
+/// applies a whole resolution plan, e.g. from the client once the user picked the copies to keep
+#[utoipa::path(
+    post,
+    path = "/resolve",
+    tag = "files",
+    request_body = resolve::Plan,
+    responses(
+        (status = 200, body = Vec<resolve::GroupReport>),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn resolve_groups(
+    State(state): State<Arc<AppState>>,
+    Json(plan): Json<resolve::Plan>,
+) -> JsonResponse<Vec<resolve::GroupReport>> {
+    state.check_safe_mode()?;
+
+    let reports = task::spawn_blocking(move || {
+        let check = |path: &std::path::Path| {
+            state.check_library(path).map_err(|_| eyre::eyre!("not found in the libraries"))
+        };
+        let (reports, applied) = resolve::apply(&state.remover, &state.quarantine, check, &state.protected, plan);
+        state.record(applied);
+        reports
+    }).await?;
+    Ok(Json(reports))
+}
+
+/// the copies set aside by the `quarantine` action of plans, oldest first
+#[utoipa::path(
+    get,
+    path = "/quarantine",
+    tag = "files",
+    responses((status = 200, body = Vec<QuarantinedFile>)),
+)]
+async fn list_quarantined(State(state): State<Arc<AppState>>) -> Json<Vec<QuarantinedFile>> {
+    Json(state.quarantine.list())
+}
+
+/// either the files or all of them, so an empty body acts on nothing
+#[derive(Deserialize, ToSchema)]
+struct QuarantineRequest {
+    #[serde(default)]
+    ids: Vec<Uuid>,
+    /// every quarantined file, without `ids`
+    #[serde(default)]
+    all: bool,
+}
+
+/// the files of the request, unknown ids are rejected before anything is done
+fn quarantined(state: &AppState, req: QuarantineRequest) -> AppResult<Vec<QuarantinedFile>> {
+    match (req.ids.is_empty(), req.all) {
+        (true, false) => return Err(ErrorBody::new(ErrorCode::BadRequest, "name the files by their ids, or all of them with \"all\": true").into()),
+        (false, true) => return Err(ErrorBody::new(ErrorCode::BadRequest, "either ids or \"all\": true, not both").into()),
+        _ => {}
+    }
+    let listed = state.quarantine.list();
+    if req.all {
+        return Ok(listed);
+    }
+    req.ids
+        .into_iter()
+        .map(|id| listed.iter().find(|entry| entry.id == id).cloned().ok_or_else(AppError::not_found))
+        .collect()
+}
+
+/// moves the files back where they were, each one fails on its own if another file is there now
+#[utoipa::path(
+    post,
+    path = "/quarantine/restore",
+    tag = "files",
+    request_body = QuarantineRequest,
+    responses(
+        (status = 200, body = Vec<FileOutcome>),
+        (status = 400, description = "neither ids nor all"),
+        (status = 404, description = "not in quarantine"),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn restore_quarantined(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<QuarantineRequest>,
+) -> JsonResponse<Vec<FileOutcome>> {
+    state.check_safe_mode()?;
+    let files = quarantined(&state, req)?;
+
+    let outcomes = task::spawn_blocking(move || {
+        files
+            .into_iter()
+            .map(|file| {
+                let restored = state.quarantine.restore(file.id);
+                FileOutcome::moved(file.quarantined, restored.map(|entry| Some(entry.path)))
+            })
+            .collect()
+    }).await?;
+    Ok(Json(outcomes))
+}
+
+/// deletes the files for good, there is no undo
+#[utoipa::path(
+    post,
+    path = "/quarantine/purge",
+    tag = "files",
+    request_body = QuarantineRequest,
+    responses(
+        (status = 200, body = Vec<FileOutcome>),
+        (status = 400, description = "neither ids nor all"),
+        (status = 404, description = "not in quarantine"),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn purge_quarantined(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<QuarantineRequest>,
+) -> JsonResponse<Vec<FileOutcome>> {
+    state.check_safe_mode()?;
+    let files = quarantined(&state, req)?;
+
+    let outcomes = task::spawn_blocking(move || {
+        files
+            .into_iter()
+            .map(|file| FileOutcome::new(file.quarantined, state.quarantine.purge(file.id).map(|_| ())))
+            .collect()
+    }).await?;
+    Ok(Json(outcomes))
+}
+
+/// takes back the last batch of deletes and moves, 404 when there is none left
+#[utoipa::path(
+    post,
+    path = "/undo",
+    tag = "files",
+    responses(
+        (status = 200, body = Vec<FileOutcome>),
+        (status = 404, description = "nothing to undo"),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn undo(State(state): State<Arc<AppState>>) -> JsonResponse<Vec<FileOutcome>> {
+    state.check_safe_mode()?;
+
+    let outcomes = task::spawn_blocking(move || state.history.undo_last(&state.remover)).await??;
+    Ok(Json(outcomes.ok_or_else(AppError::not_found)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/deleted/{id}/restore",
+    tag = "deleted",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "where the file is back", body = String),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn restore_file(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> JsonResponse<PathBuf> {
+    // TODO: check id
+    state.check_safe_mode()?;
+
+    let path = state.remover.restore(&id)?;
+    Ok(Json(path))
+}
+
+#[utoipa::path(
+    post,
+    path = "/deleted/restore_all",
+    tag = "deleted",
+    responses(
+        (status = 200),
+        (status = 423, description = "in safe mode"),
+    ),
+)]
+async fn restore_all(
+    State(state): State<Arc<AppState>>,
+) -> AppResult<()> {
+    state.check_safe_mode()?;
+    state.remover.restore_all()?;
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/deleted",
+    tag = "deleted",
+    params(ShapeParams),
+    responses((status = 200, body = Vec<remover::RemovedFile>)),
+)]
+async fn list_deleted(
+    State(state): State<Arc<AppState>>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    let files = state.remover.list_removed()?;
+    Ok(Json(shape(&files, &shape_params)?))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExcludeParams {
+    format: backup::ExcludeFormat,
+}
+
+/// exclude file for backup tools listing removed duplicates,
+/// so backups shrink before the files are deleted for good
+#[utoipa::path(
+    get,
+    path = "/deleted/exclude",
+    tag = "deleted",
+    params(ExcludeParams),
+    responses((status = 200, description = "one pattern per line", content_type = "text/plain")),
+)]
+async fn exclude_deleted(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExcludeParams>,
+) -> AppResult<impl IntoResponse> {
+    let files = state.remover.data_files()?;
+    let content = backup::exclude_list(params.format, &files);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"exclude.txt\""),
+        ],
+        content,
+    ))
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct JournalResponse {
+    safe_mode: bool,
+    pending: Vec<JournalEntry>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/journal",
+    tag = "admin",
+    responses((status = 200, body = JournalResponse)),
+)]
+async fn list_journal(
+    State(state): State<Arc<AppState>>,
+) -> JsonResponse<JournalResponse> {
+    let safe_mode = state.safe_mode.load(Ordering::Relaxed);
+    let pending = state.remover.pending()?;
+    Ok(Json(JournalResponse { safe_mode, pending }))
+}
+
+fn leave_safe_mode(state: &AppState) -> AppResult<()> {
+    if state.remover.pending()?.is_empty() && state.safe_mode.swap(false, Ordering::Relaxed) {
+        tracing::info!("journal reviewed, leaving safe mode");
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/migrations",
+    tag = "admin",
+    responses((status = 200, body = Vec<Migration>)),
+)]
+async fn list_migrations(State(state): State<Arc<AppState>>) -> Json<Vec<Migration>> {
+    Json(state.migrations.clone())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/journal/{id}/complete",
+    tag = "admin",
+    params(("id" = String, Path)),
+    responses((status = 200)),
+)]
+async fn complete_journal_entry(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> AppResult<()> {
+    state.remover.complete(&id)?;
+    leave_safe_mode(&state)
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/journal/{id}/rollback",
+    tag = "admin",
+    params(("id" = String, Path)),
+    responses((status = 200)),
+)]
+async fn rollback_journal_entry(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> AppResult<()> {
+    state.remover.rollback(&id)?;
+    leave_safe_mode(&state)
+}
+
+#[utoipa::path(
+    get,
+    path = "/roots",
+    tag = "roots",
+    params(ShapeParams),
+    responses((status = 200, body = Vec<Root>)),
+)]
+async fn list_roots(
+    State(state): State<Arc<AppState>>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    let roots = state.roots.list();
+    Ok(Json(shape(&roots, &shape_params)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/roots",
+    tag = "roots",
+    request_body = Root,
+    responses(
+        (status = 200),
+        (status = 404, description = "no such folder"),
+    ),
+)]
+async fn set_root(
+    State(state): State<Arc<AppState>>,
+    Json(root): Json<Root>,
+) -> AppResult<()> {
+    check_path(&root.path)?;
+    state.check_library(&root.path)?;
+    state.roots.set(root)?;
+    Ok(())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/roots",
+    tag = "roots",
+    params(PathParams),
+    responses(
+        (status = 200),
+        (status = 404, description = "not a root"),
+    ),
+)]
+async fn remove_root(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PathParams>,
+) -> AppResult<()> {
+    if state.roots.remove(&params.path)? {
+        Ok(())
+    } else {
+        Err(AppError::not_found())
+    }
+}
+
+/// the saved folders, with the latest run of each the session sees
+#[utoipa::path(
+    get,
+    path = "/bookmarks",
+    tag = "bookmarks",
+    params(ShapeParams),
+    responses((status = 200, body = Vec<BookmarkStatus>)),
+)]
+async fn list_bookmarks(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    let bookmarks = state.bookmarks.list();
+    let mut runs = task::spawn_blocking(move || state.runs.list(None)).await??;
+    runs.retain(|run| session.sees(run.owner));
+    let listed: Vec<_> = bookmarks
+        .into_iter()
+        .map(|bookmark| {
+            let last_run = runs.iter().find(|run| run.path == bookmark.path).cloned();
+            BookmarkStatus { bookmark, last_run }
+        })
+        .collect();
+    Ok(Json(shape(&listed, &shape_params)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/bookmarks/{id}",
+    tag = "bookmarks",
+    params(("id" = Uuid, Path)),
+    responses(
+        (status = 200, body = Bookmark),
+        (status = 404, description = "unknown bookmark"),
+    ),
+)]
+async fn get_bookmark(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> JsonResponse<Bookmark> {
+    Ok(Json(state.bookmarks.get(id).ok_or_else(AppError::not_found)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/bookmarks",
+    tag = "bookmarks",
+    request_body = BookmarkRequest,
+    responses(
+        (status = 200, body = Bookmark),
+        (status = 404, description = "no such folder"),
+        (status = 409, description = "the folder is bookmarked already"),
+    ),
+)]
+async fn add_bookmark(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<BookmarkRequest>,
+) -> JsonResponse<Bookmark> {
+    state.check_folder(&request.path)?;
+    if state.bookmarks.contains(&request.path, None) {
+        return Err(AppError::Provided(StatusCode::CONFLICT));
+    }
+    Ok(Json(state.bookmarks.add(&request)?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/bookmarks/{id}",
+    tag = "bookmarks",
+    params(("id" = Uuid, Path)),
+    request_body = BookmarkRequest,
+    responses(
+        (status = 200, body = Bookmark),
+        (status = 404, description = "unknown bookmark or no such folder"),
+        (status = 409, description = "another bookmark is of the folder"),
+    ),
+)]
+async fn update_bookmark(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<BookmarkRequest>,
+) -> JsonResponse<Bookmark> {
+    state.check_folder(&request.path)?;
+    if state.bookmarks.contains(&request.path, Some(id)) {
+        return Err(AppError::Provided(StatusCode::CONFLICT));
+    }
+    Ok(Json(state.bookmarks.update(id, &request)?.ok_or_else(AppError::not_found)?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/bookmarks/{id}",
+    tag = "bookmarks",
+    params(("id" = Uuid, Path)),
+    responses(
+        (status = 200),
+        (status = 404, description = "unknown bookmark"),
+    ),
+)]
+async fn remove_bookmark(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> AppResult<()> {
+    if state.bookmarks.remove(id)? {
+        Ok(())
+    } else {
+        Err(AppError::not_found())
+    }
+}
+
+fn invalid_options(err: serde_json::Error) -> AppError {
+    ErrorBody::new(ErrorCode::BadRequest, format!("invalid options: {}", err)).into()
+}
+
+#[utoipa::path(
+    get,
+    path = "/profiles",
+    tag = "profiles",
+    responses((status = 200, body = Vec<Profile>)),
+)]
+async fn list_profiles(State(state): State<Arc<AppState>>) -> JsonResponse<Vec<Profile>> {
+    Ok(Json(state.profiles.list()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = Profile),
+        (status = 404, description = "unknown profile"),
+    ),
+)]
+async fn get_profile(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> JsonResponse<Profile> {
+    Ok(Json(state.profiles.get(&name).ok_or_else(AppError::not_found)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/profiles",
+    tag = "profiles",
+    request_body = Profile,
+    responses(
+        (status = 200, body = Profile),
+        (status = 400, description = "invalid options"),
+        (status = 409, description = "there is a profile of that name already"),
+    ),
+)]
+async fn add_profile(
+    State(state): State<Arc<AppState>>,
+    Json(profile): Json<Profile>,
+) -> JsonResponse<Profile> {
+    if profile.name.is_empty() {
+        return Err(ErrorBody::new(ErrorCode::BadRequest, "profiles need a name").into());
+    }
+    profile.check().map_err(invalid_options)?;
+    if !state.profiles.add(profile.clone())? {
+        return Err(AppError::Provided(StatusCode::CONFLICT));
+    }
+    Ok(Json(profile))
+}
+
+#[utoipa::path(
+    put,
+    path = "/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    request_body = ProfileRequest,
+    responses(
+        (status = 200, body = Profile),
+        (status = 400, description = "invalid options"),
+        (status = 404, description = "unknown profile"),
+    ),
+)]
+async fn update_profile(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(request): Json<ProfileRequest>,
+) -> JsonResponse<Profile> {
+    Profile { name: name.clone(), options: request.options.clone() }.check().map_err(invalid_options)?;
+    Ok(Json(state.profiles.update(&name, request.options)?.ok_or_else(AppError::not_found)?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200),
+        (status = 404, description = "unknown profile"),
+    ),
+)]
+async fn remove_profile(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> AppResult<()> {
+    if state.profiles.remove(&name)? {
+        Ok(())
+    } else {
+        Err(AppError::not_found())
+    }
+}
+
+/// analyzes with the options of the profile, those of the body taking precedence, e.g. `{"path": ...}`
+#[utoipa::path(
+    post,
+    path = "/profiles/{name}/analyze",
+    tag = "profiles",
+    params(("name" = String, Path)),
+    request_body(content = Object, description = "fields of the body of `/analyze`"),
+    responses(
+        (status = 200, body = TaskParams),
+        (status = 400, description = "invalid options, or no path in the profile nor the body"),
+        (status = 404, description = "unknown profile or no such folder"),
+        (status = 503, description = "shutting down"),
+    ),
+)]
+async fn analyze_profile(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(name): Path<String>,
+    Json(given): Json<serde_json::Map<String, Value>>,
+) -> JsonResponse<TaskParams> {
+    let profile = state.profiles.get(&name).ok_or_else(AppError::not_found)?;
+    let mut req = profile.request(given).map_err(invalid_options)?;
+    req.owner = session.id;
+    let task_id = request_submit(&state, req).await?;
+    Ok(Json(TaskParams { task_id }))
+}
+
+/// the files, folders and pairs kept out of the groups of every analysis
+#[utoipa::path(
+    get,
+    path = "/ignored",
+    tag = "tasks",
+    params(ShapeParams),
+    responses((status = 200, body = Vec<Ignored>)),
+)]
+async fn list_ignored(
+    State(state): State<Arc<AppState>>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    Ok(Json(shape(&state.ignored.list(), &shape_params)?))
+}
+
+/// applies to the analyses from now on, the entry added before is answered for a rule added again
+#[utoipa::path(
+    post,
+    path = "/ignored",
+    tag = "tasks",
+    request_body = IgnoreRule,
+    responses(
+        (status = 200, body = Ignored),
+        (status = 403, description = "outside of the libraries"),
+        (status = 404, description = "no such file or folder"),
+    ),
+)]
+async fn add_ignored(
+    State(state): State<Arc<AppState>>,
+    Json(rule): Json<IgnoreRule>,
+) -> JsonResponse<Ignored> {
+    for path in rule.paths() {
+        if !path.exists() {
+            return Err(AppError::not_found());
+        }
+        state.check_library(path)?;
+    }
+    Ok(Json(state.ignored.add(rule)?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/ignored/{id}",
+    tag = "tasks",
+    params(("id" = Uuid, Path)),
+    responses(
+        (status = 200),
+        (status = 404, description = "unknown entry"),
+    ),
+)]
+async fn remove_ignored(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> AppResult<()> {
+    if state.ignored.remove(id)? {
+        Ok(())
+    } else {
+        Err(AppError::not_found())
+    }
+}
+
+/// folders nothing is deleted, moved or linked in, those of the config first
+#[utoipa::path(
+    get,
+    path = "/protected",
+    tag = "files",
+    responses((status = 200, body = Vec<ProtectedFolder>)),
+)]
+async fn list_protected(State(state): State<Arc<AppState>>) -> Json<Vec<ProtectedFolder>> {
+    Json(state.protected.list())
+}
+
+/// applies to the file actions and suggestions from now on, the suggestions of completed analyses stay
+#[utoipa::path(
+    post,
+    path = "/protected",
+    tag = "files",
+    request_body = ProtectRequest,
+    responses(
+        (status = 200, body = Vec<ProtectedFolder>),
+        (status = 403, description = "outside of the libraries"),
+        (status = 404, description = "no such folder"),
+    ),
+)]
+async fn add_protected(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ProtectRequest>,
+) -> JsonResponse<Vec<ProtectedFolder>> {
+    check_path(&req.path)?;
+    state.check_library(&req.path)?;
+    state.protected.add(&req.path)?;
+    Ok(Json(state.protected.list()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/protected",
+    tag = "files",
+    params(PathParams),
+    responses(
+        (status = 200, body = Vec<ProtectedFolder>),
+        (status = 404, description = "not a protected folder"),
+        (status = 409, description = "protected by the config"),
+    ),
+)]
+async fn remove_protected(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PathParams>,
+) -> JsonResponse<Vec<ProtectedFolder>> {
+    if state.protected.configured().contains(&params.path) {
+        return Err(AppError::Provided(StatusCode::CONFLICT));
+    }
+    if !state.protected.remove(&params.path)? {
+        return Err(AppError::not_found());
+    }
+    Ok(Json(state.protected.list()))
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct QueueDepth {
+    queued: usize,
+    running: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AnalyzerHealth {
+    alive: bool,
+    restarts: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_panic: Option<String>,
+    /// `None` when the analyzer does not answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue: Option<QueueDepth>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CacheHealth {
+    reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Health {
+    analyzer: AnalyzerHealth,
+    cache: CacheHealth,
+    /// shutting down, no new work is accepted
+    draining: bool,
+}
+
+/// how long the analyzer actor and the cache have to answer a health check
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+async fn check_health(state: &AppState) -> Health {
+    let (tx, rx) = oneshot::channel();
+    let queue = match state.task_sender.send(AnalyzeCommand::Ping(tx)).await {
+        Ok(()) => tokio::time::timeout(PING_TIMEOUT, rx).await.ok().and_then(Result::ok),
+        Err(_) => None,
+    };
+    let analyzer = AnalyzerHealth {
+        alive: queue.is_some(),
+        restarts: state.actor_health.restarts.load(Ordering::Relaxed),
+        last_panic: state.actor_health.last_panic.lock().unwrap().clone(),
+        queue,
+    };
+
+    let engine = state.engine.clone();
+    let pong = tokio::time::timeout(PING_TIMEOUT, task::spawn_blocking(move || engine.ping_cache())).await;
+    let error = match pong {
+        Ok(Ok(Ok(()))) => None,
+        Ok(Ok(Err(err))) => Some(err.to_string()),
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some("timed out".to_owned()),
+    };
+    let cache = CacheHealth { reachable: error.is_none(), error };
+
+    Health { analyzer, cache, draining: state.draining.load(Ordering::Relaxed) }
+}
+
+/// alive, a supervisor should restart the server otherwise
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "admin",
+    responses(
+        (status = 200, body = Health),
+        (status = 503, description = "the analyzer does not answer", body = Health),
+    ),
+)]
+async fn healthz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Health>) {
+    let health = check_health(&state).await;
+    let status = if health.analyzer.alive { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+/// in the Prometheus text format, the queue is the one of the state answering
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "admin",
+    responses((status = 200, description = "counters and histograms", content_type = "text/plain")),
+)]
+async fn serve_metrics(State(state): State<Arc<AppState>>) -> AppResult<impl IntoResponse> {
+    let metrics = metrics::metrics();
+    let (tx, rx) = oneshot::channel();
+    if state.task_sender.send(AnalyzeCommand::Ping(tx)).await.is_ok() {
+        if let Ok(Ok(depth)) = tokio::time::timeout(PING_TIMEOUT, rx).await {
+            metrics.queued_tasks.set(depth.queued as i64);
+            metrics.running_tasks.set(depth.running as i64);
+        }
+    }
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics.encode()?))
+}
+
+/// ready to take work, a load balancer should send requests elsewhere otherwise
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "admin",
+    responses(
+        (status = 200, body = Health),
+        (status = 503, description = "the analyzer or the cache does not answer, or shutting down", body = Health),
+    ),
+)]
+async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Health>) {
+    let health = check_health(&state).await;
+    let ready = health.analyzer.alive && health.cache.reachable && !health.draining;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+#[utoipa::path(
+    post,
+    path = "/index",
+    tag = "cache",
+    params(WarmRequest),
+    responses(
+        (status = 202, description = "warming started"),
+        (status = 409, description = "already warming"),
+        (status = 503, description = "shutting down"),
+    ),
+)]
+async fn start_warming(
+    State(state): State<Arc<AppState>>,
+    Query(req): Query<WarmRequest>,
+) -> AppResult<StatusCode> {
+    check_path(&req.path)?;
+    state.check_library(&req.path)?;
+    state.check_draining()?;
+
+    if state.engine.start_warming(req) {
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err(AppError::Provided(StatusCode::CONFLICT))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/index",
+    tag = "cache",
+    responses((status = 200, body = WarmStatus)),
+)]
+async fn warm_status(State(state): State<Arc<AppState>>) -> Json<WarmStatus> {
+    Json(state.engine.warm_status())
+}
+
+#[utoipa::path(
+    post,
+    path = "/analyze",
+    tag = "tasks",
+    request_body = AnalyzeRequest,
+    responses(
+        (status = 200, body = TaskParams),
+        (status = 400, description = "invalid options"),
+        (status = 404, description = "no such folder"),
+        (status = 503, description = "shutting down"),
+    ),
+)]
+async fn analyze(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    JsonOrQuery(mut req): JsonOrQuery<AnalyzeRequest>,
+) -> JsonResponse<TaskParams> {
+    req.owner = session.id;
+    let task_id = request_submit(&state, req).await?;
+    Ok(Json(TaskParams { task_id }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReportParams {
+    format: ImportFormat,
+}
+
+/// loads the duplicate report of another tool as a completed task, to review and resolve its groups here
+#[utoipa::path(
+    post,
+    path = "/import",
+    tag = "tasks",
+    params(ReportParams),
+    request_body(content = String, description = "the report as the tool wrote it", content_type = "text/plain"),
+    responses(
+        (status = 200, body = TaskParams),
+        (status = 400, description = "the report can't be read or has no groups"),
+        (status = 503, description = "shutting down"),
+    ),
+)]
+async fn import_report(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<ReportParams>,
+    report: String,
+) -> JsonResponse<TaskParams> {
+    state.check_draining()?;
+    let (mut req, groups) = read_report(params.format, &report).map_err(|err| ErrorBody::new(ErrorCode::BadRequest, format!("{:#}", err)))?;
+    req.owner = session.id;
+    let engine = state.engine.clone();
+    let result = task::spawn_blocking(move || engine.import(groups)).await?;
+
+    let (tx, rx) = oneshot::channel();
+    state
+        .task_sender
+        .send(AnalyzeCommand::Import(req, Box::new(result), tx))
+        .await?;
+    Ok(Json(TaskParams { task_id: rx.await? }))
+}
+
+/// Lists the files of the same size named like copies of each other, e.g. `IMG_1234 (1).jpg` of `IMG_1234.jpg`,
+/// as a completed task. Nothing is decoded, a first sweep before hashing the folder.
+#[utoipa::path(
+    post,
+    path = "/names",
+    tag = "tasks",
+    params(PathParams),
+    responses(
+        (status = 200, body = TaskParams),
+        (status = 400, description = "a remote folder"),
+        (status = 404, description = "no such folder"),
+        (status = 503, description = "shutting down"),
+    ),
+)]
+async fn group_names(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<PathParams>,
+) -> JsonResponse<TaskParams> {
+    state.check_folder(&params.path)?;
+    state.check_draining()?;
+    if state.engine.remote(&params.path)?.is_some() {
+        return Err(ErrorBody::new(ErrorCode::BadRequest, "names are grouped in local folders only").into());
+    }
+
+    let engine = state.engine.clone();
+    let path = params.path.clone();
+    let result = task::spawn_blocking(move || -> Result<_> {
+        let listing = engine.scan(&path)?;
+        Ok(engine.import(names::group(&listing.files)))
+    })
+    .await??;
+    let req = AnalyzeRequest { owner: session.id, ..headless::request(params.path, 0, HashType::DHash, HashSize::default()) };
+
+    let (tx, rx) = oneshot::channel();
+    state
+        .task_sender
+        .send(AnalyzeCommand::Import(req, Box::new(result), tx))
+        .await?;
+    Ok(Json(TaskParams { task_id: rx.await? }))
+}
+
+/// the groups of a report, and the request its task is listed with
+fn read_report(format: ImportFormat, report: &str) -> Result<(AnalyzeRequest, Vec<Vec<PathBuf>>)> {
+    let groups = import::parse(format, report).map_err(|err| eyre::eyre!("unable to read the report: {:#}", err))?;
+    let folder = import::common_folder(&groups).ok_or_else(|| eyre::eyre!("the report has no groups"))?;
+    Ok((headless::request(folder, 0, HashType::DHash, HashSize::default()), groups))
+}
+
+pub(crate) async fn request_submit(state: &AppState, req: AnalyzeRequest) -> AppResult<Uuid> {
+    state.check_folder(&req.path)?;
+    state.check_draining()?;
+    if let Some(callback) = req.callback.as_ref().filter(|callback| !state.webhooks.allows(callback)) {
+        return Err(ErrorBody::new(ErrorCode::BadRequest, format!("callbacks to {} aren't allowed", callback.host_str().unwrap_or_default())).into());
+    }
+    if let Some(files) = &req.files {
+        if state.engine.remote(&req.path)?.is_some() {
+            return Err(ErrorBody::new(ErrorCode::BadRequest, "lists of files are analyzed in local folders only").into());
+        }
+        if let Some(outside) = files.iter().find(|file| !file.starts_with(&req.path)) {
+            return Err(ErrorBody::new(ErrorCode::BadRequest, format!("{} is outside of the folder", outside.display())).into());
+        }
+    }
+
+    let (tx, rx) = oneshot::channel();
+
+    state
+        .task_sender
+        .send(AnalyzeCommand::Submit(req, Span::current(), tx))
+        .await?;
+
+    Ok(rx.await?)
+}
+
+/// the request of a task, the tasks of other sessions are unknown too
+pub(crate) async fn request_task(state: &AppState, session: &Session, task_id: Uuid) -> AppResult<AnalyzeRequest> {
+    let (tx, rx) = oneshot::channel();
+
+    state
+        .task_sender
+        .send(AnalyzeCommand::Request(task_id, tx))
+        .await?;
+
+    rx.await?.filter(|req| session.sees(req.owner)).ok_or_else(AppError::not_found)
+}
+
+impl<'a> AnalyzeResponse<'a> {
+    pub(crate) fn new(resp: &'a TaskResponse<Progress, Arc<TaskResult>>) -> Self {
+        match resp {
+            TaskResponse::Queued(position) => Self::Queued { position: *position },
+            TaskResponse::Pending(progress) => Self::Pending { progress: *progress },
+            TaskResponse::Completed(result) => match &**result {
+                Ok(data) => Self::Completed { data },
+                Err(err) if err.is::<Cancelled>() => Self::Cancelled,
+                Err(err) if err.is::<TimedOut>() => Self::TimedOut,
+                Err(err) => {
+                    let ErrorBody { code, message, path } = ErrorBody::of_report(err);
+                    Self::Failed { error: message, code, path }
+                }
+            },
+        }
+    }
+}
+
+pub(crate) async fn request_poll(state: &AppState, task_id: Uuid) -> AppResult<TaskResponse<Progress, Arc<TaskResult>>> {
+    let (tx, rx) = oneshot::channel();
+
+    state
+        .task_sender
+        .send(AnalyzeCommand::Poll(task_id, tx))
+        .await?;
+
+    rx.await?.ok_or_else(AppError::not_found)
+}
+
+/// the result of a task that completed, 409 while it runs or if it failed
+fn completed(resp: &TaskResponse<Progress, Arc<TaskResult>>) -> AppResult<&AnalyzeResult> {
+    match resp {
+        TaskResponse::Completed(result) => result.as_ref().as_ref().map_err(|_| AppError::Provided(StatusCode::CONFLICT)),
+        _ => Err(AppError::Provided(StatusCode::CONFLICT)),
+    }
+}
+
+/// longest a poll waits for a task to move on before answering with its progress
+const POLL_WAIT: Duration = Duration::from_secs(10);
+
+/// a long poll, answers once the progress of the task changes or it finishes
+#[utoipa::path(
+    get,
+    path = "/poll",
+    tag = "tasks",
+    params(TaskParams, ShapeParams),
+    responses(
+        (status = 200, body = AnalyzeResponse),
+        (status = 404, description = "unknown task"),
+    ),
+)]
+async fn poll(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<TaskParams>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    request_task(&state, &session, params.task_id).await?;
+    // waits here rather than in the actor, which keeps serving everyone else
+    let mut progress = request_progress(&state, params.task_id).await?;
+    progress.borrow_and_update();
+    let _ = tokio::time::timeout(POLL_WAIT, progress.changed()).await;
+    let resp = request_poll(&state, params.task_id).await?;
+    let marked = match completed(&resp) {
+        Ok(result) => {
+            let scoped = scoped_marks(&state, &session, Some(params.task_id)).await?;
+            (!scoped.is_empty()).then(|| {
+                let mut result = result.clone();
+                result.groups_mut().iter_mut().for_each(|group| scoped.apply(group));
+                result
+            })
+        }
+        Err(_) => None,
+    };
+    let response = match &marked {
+        Some(data) => AnalyzeResponse::Completed { data },
+        None => AnalyzeResponse::new(&resp),
+    };
+    Ok(Json(shape(&response, &shape_params)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/tasks",
+    tag = "tasks",
+    params(ShapeParams),
+    responses((status = 200, body = Vec<TaskListing>)),
+)]
+async fn list_tasks(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    let (tx, rx) = oneshot::channel();
+
+    state
+        .task_sender
+        .send(AnalyzeCommand::List(tx))
+        .await?;
+
+    let mut tasks = rx.await?;
+    tasks.retain(|task| session.sees(task.owner));
+    Ok(Json(shape(&tasks, &shape_params)?))
+}
+
+/// `false` if the task is already finished
+pub(crate) async fn request_cancel(state: &AppState, task_id: Uuid) -> AppResult<bool> {
+    let (tx, rx) = oneshot::channel();
+
+    state
+        .task_sender
+        .send(AnalyzeCommand::Cancel(task_id, tx))
+        .await?;
+
+    rx.await?.ok_or_else(AppError::not_found)
+}
+
+#[utoipa::path(
+    get,
+    path = "/tasks/{id}/logs",
+    tag = "tasks",
+    params(("id" = Uuid, Path), ShapeParams),
+    responses(
+        (status = 200, body = Vec<logs::LogLine>),
+        (status = 404, description = "unknown task"),
+    ),
+)]
+async fn task_logs(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(task_id): Path<Uuid>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    // only tasks of this state, logs are kept for all of them together
+    request_task(&state, &session, task_id).await?;
+
+    Ok(Json(shape(&logs::get(&task_id), &shape_params)?))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct RegroupRequest {
+    /// the hash distance files match within
+    dist: u32,
+}
+
+/// groups the files of a completed analysis again with another distance, from the hashes it kept,
+/// the groups are listed as a new completed task
+#[utoipa::path(
+    post,
+    path = "/tasks/{id}/regroup",
+    tag = "tasks",
+    params(("id" = Uuid, Path)),
+    request_body = RegroupRequest,
+    responses(
+        (status = 200, body = TaskParams),
+        (status = 404, description = "unknown task"),
+        (status = 409, description = "the task did not complete or kept no hashes, e.g. an import"),
+        (status = 503, description = "shutting down"),
+    ),
+)]
+async fn regroup_task(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(task_id): Path<Uuid>,
+    Json(regroup): Json<RegroupRequest>,
+) -> JsonResponse<TaskParams> {
+    state.check_draining()?;
+    let mut req = request_task(&state, &session, task_id).await?;
+    let resp = request_poll(&state, task_id).await?;
+    let earlier = completed(&resp)?.clone();
+    req.dist = regroup.dist;
+    req.owner = session.id;
+
+    let engine = state.engine.clone();
+    let tasks = state.tasks.clone();
+    let (req, result) = task::spawn_blocking(move || -> AppResult<_> {
+        let hashes = tasks.load_hashes(&task_id)?.ok_or(AppError::Provided(StatusCode::CONFLICT))?;
+        let result = engine.regroup(&req, hashes, &earlier)?;
+        Ok((req, result))
+    })
+    .await??;
+
+    let (tx, rx) = oneshot::channel();
+    state
+        .task_sender
+        .send(AnalyzeCommand::Import(req, Box::new(result), tx))
+        .await?;
+    Ok(Json(TaskParams { task_id: rx.await? }))
+}
+
+/// narrows the groups of a task, all of them pass without any
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+struct GroupFilter {
+    /// groups of at least that many files
+    min_group_size: Option<usize>,
+    /// groups freeing at least that many bytes by keeping only the largest copy
+    min_wasted_bytes: Option<u64>,
+    /// groups with a file under that folder
+    #[param(value_type = Option<String>)]
+    path_prefix: Option<PathBuf>,
+}
+
+impl GroupFilter {
+    fn matches(&self, group: &analyzer::Group) -> bool {
+        self.min_group_size.is_none_or(|size| group.files().len() >= size)
+            && self.min_wasted_bytes.is_none_or(|bytes| group.wasted_bytes() >= bytes)
+            && self.path_prefix.as_ref().is_none_or(|prefix| group.files().iter().any(|file| file.path.starts_with(prefix)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+enum GroupSortKey {
+    Files,
+    TotalBytes,
+    /// freed by keeping only the largest copy
+    ReclaimableBytes,
+}
+
+/// orders the groups of a task, as found when not given
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+struct GroupSort {
+    sort_by: Option<GroupSortKey>,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+impl GroupSort {
+    /// ties keep the order they were found in
+    fn sort(&self, groups: &mut [&analyzer::Group]) {
+        let Some(key) = self.sort_by else {
+            return;
+        };
+        let value = |group: &analyzer::Group| {
+            let stats = group.stats();
+            match key {
+                GroupSortKey::Files => stats.files as u64,
+                GroupSortKey::TotalBytes => stats.total_bytes,
+                GroupSortKey::ReclaimableBytes => stats.reclaimable_bytes,
+            }
+        };
+        groups.sort_by(|a, b| {
+            let order = value(a).cmp(&value(b));
+            match self.order {
+                SortOrder::Asc => order,
+                SortOrder::Desc => order.reverse(),
+            }
+        });
+    }
+}
+
+/// the groups of a completed task, a page at a time
+#[utoipa::path(
+    get,
+    path = "/tasks/{id}/groups",
+    tag = "tasks",
+    params(("id" = Uuid, Path), GroupFilter, GroupSort, PageParams, ShapeParams),
+    responses(
+        (status = 200, body = Vec<analyzer::Group>, headers(("x-total-count" = usize, description = "groups passing the filter"))),
+        (status = 404, description = "unknown task"),
+        (status = 409, description = "the task did not complete"),
+    ),
+)]
+async fn task_groups(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(task_id): Path<Uuid>,
+    Query(filter): Query<GroupFilter>,
+    Query(sort): Query<GroupSort>,
+    Query(page_params): Query<PageParams>,
+    Query(shape_params): Query<ShapeParams>,
+) -> AppResult<impl IntoResponse> {
+    request_task(&state, &session, task_id).await?;
+    let resp = request_poll(&state, task_id).await?;
+    let mut groups: Vec<_> = completed(&resp)?.groups().iter().filter(|group| filter.matches(group)).collect();
+    sort.sort(&mut groups);
+    let scoped = scoped_marks(&state, &session, Some(task_id)).await?;
+    let page = shape(&marked(&scoped, page_params.page(&groups)), &shape_params)?;
+    Ok(([(shape::TOTAL_COUNT, groups.len().to_string())], Json(page)))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportParams {
+    #[serde(default = "default_export_format")]
+    format: export::ExportFormat,
+}
+
+fn default_export_format() -> export::ExportFormat {
+    export::ExportFormat::Json
+}
+
+/// the groups of a completed task as a download
+#[utoipa::path(
+    get,
+    path = "/tasks/{id}/export",
+    tag = "tasks",
+    params(("id" = Uuid, Path), ExportParams),
+    responses(
+        (status = 200, description = "the groups as a download", content(
+            (String = "text/csv"),
+            (String = "application/json"),
+            (String = "text/html"),
+        )),
+        (status = 404, description = "unknown task"),
+        (status = 409, description = "the task did not complete"),
+    ),
+)]
+async fn export_task(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(task_id): Path<Uuid>,
+    Query(params): Query<ExportParams>,
+) -> AppResult<impl IntoResponse> {
+    request_task(&state, &session, task_id).await?;
+    let resp = request_poll(&state, task_id).await?;
+    completed(&resp)?;
+    let format = params.format;
+    // HTML reports render the thumbnails missing from the cache
+    let content = task::spawn_blocking(move || -> AppResult<_> {
+        let thumbnail = |path: &std::path::Path| {
+            state.check_library(path).ok()?;
+            std::fs::read(state.thumbnails.get(path, export::THUMBNAIL_SIZE).ok()?).ok()
+        };
+        Ok(export::export(format, completed(&resp)?, thumbnail)?)
+    })
+    .await??;
+    let disposition = format!("attachment; filename=\"{}.{}\"", task_id, params.format.extension());
+    Ok((
+        [
+            (header::CONTENT_TYPE, params.format.content_type().to_owned()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        content,
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RunsParams {
+    /// runs of that folder and the ones below it
+    #[param(value_type = Option<String>)]
+    path: Option<PathBuf>,
+}
+
+/// past analyses, newest first, a page at a time
+#[utoipa::path(
+    get,
+    path = "/runs",
+    tag = "tasks",
+    params(RunsParams, PageParams, ShapeParams),
+    responses((status = 200, body = Vec<RunSummary>, headers(("x-total-count" = usize, description = "runs of the folder")))),
+)]
+async fn list_runs(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<RunsParams>,
+    Query(page_params): Query<PageParams>,
+    Query(shape_params): Query<ShapeParams>,
+) -> AppResult<impl IntoResponse> {
+    let mut runs = task::spawn_blocking(move || state.runs.list(params.path.as_deref())).await??;
+    runs.retain(|run| session.sees(run.owner));
+    let page = shape(&page_params.page(&runs), &shape_params)?;
+    Ok(([(shape::TOTAL_COUNT, runs.len().to_string())], Json(page)))
+}
+
+/// a past analysis with its groups
+#[utoipa::path(
+    get,
+    path = "/runs/{id}",
+    tag = "tasks",
+    params(("id" = Uuid, Path), ShapeParams),
+    responses(
+        (status = 200, body = Run),
+        (status = 404, description = "unknown run"),
+    ),
+)]
+async fn get_run(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(id): Path<Uuid>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    let run = {
+        let state = state.clone();
+        task::spawn_blocking(move || state.runs.get(id)).await??
+    };
+    let mut run = run.filter(|run| session.sees(run.request.owner)).ok_or_else(AppError::not_found)?;
+    let scoped = scoped_marks(&state, &session, Some(id)).await?;
+    run.result.groups_mut().iter_mut().for_each(|group| scoped.apply(group));
+    Ok(Json(shape(&run, &shape_params)?))
+}
+
+/// what changed since an earlier run of the same folder: new groups, resolved ones and those with other files
+#[utoipa::path(
+    get,
+    path = "/runs/{id}/diff/{since}",
+    tag = "tasks",
+    params(("id" = Uuid, Path), ("since" = Uuid, Path, description = "the earlier run"), ShapeParams),
+    responses(
+        (status = 200, body = RunDiff),
+        (status = 400, description = "the runs are of different folders"),
+        (status = 404, description = "unknown run"),
+    ),
+)]
+async fn diff_runs(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path((id, since)): Path<(Uuid, Uuid)>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    let (later, earlier) = task::spawn_blocking(move || Ok::<_, eyre::Report>((state.runs.get(id)?, state.runs.get(since)?))).await??;
+    let visible = |run: Option<Run>| run.filter(|run| session.sees(run.request.owner)).ok_or_else(AppError::not_found);
+    let (later, earlier) = (visible(later)?, visible(earlier)?);
+    if later.request.path != earlier.request.path {
+        return Err(ErrorBody::new(ErrorCode::BadRequest, "the runs are of different folders").into());
+    }
+    Ok(Json(shape(&RunDiff::new(&earlier, &later), &shape_params)?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/runs/{id}",
+    tag = "tasks",
+    params(("id" = Uuid, Path)),
+    responses(
+        (status = 200),
+        (status = 404, description = "unknown run"),
+    ),
+)]
+async fn delete_run(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(id): Path<Uuid>,
+) -> AppResult<()> {
+    let deleted = task::spawn_blocking(move || match state.runs.owner(id)? {
+        Some(owner) if session.sees(owner) => state.runs.delete(id),
+        _ => Ok(false),
+    });
+    if deleted.await?? {
+        Ok(())
+    } else {
+        Err(AppError::not_found())
+    }
+}
+
+/// Workers write hashes into the cache every analysis trusts, only those knowing the `worker-token` are let in.
+async fn worker_guard<B>(State(token): State<Arc<str>>, request: Request<B>, next: Next<B>) -> AppResult<axum::response::Response> {
+    let given = request.headers().get(workers::TOKEN_HEADER).map(header::HeaderValue::as_bytes);
+    if !given.is_some_and(|given| auth::constant_time_eq(given, token.as_bytes())) {
+        return Err(ErrorBody::new(ErrorCode::Unauthorized, "missing or wrong worker token").into());
+    }
+    Ok(next.run(request).await)
+}
+
+/// the next batch of files to hash, for workers
+#[utoipa::path(
+    post,
+    path = "/workers/claim",
+    tag = "tasks",
+    request_body = Claim,
+    responses(
+        (status = 200, body = Batch),
+        (status = 204, description = "nothing to hash"),
+    ),
+)]
+async fn claim_batch(State(state): State<Arc<AppState>>, Json(claim): Json<Claim>) -> axum::response::Response {
+    match state.engine.workers().claim(&claim.worker) {
+        Some(batch) => Json(batch).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// the hashes of a claimed batch
+#[utoipa::path(
+    post,
+    path = "/workers/batches/{id}",
+    tag = "tasks",
+    params(("id" = Uuid, Path)),
+    request_body = Answer,
+    responses(
+        (status = 200),
+        (status = 404, description = "unknown batch, e.g. its analysis is over"),
+    ),
+)]
+async fn answer_batch(State(state): State<Arc<AppState>>, Path(id): Path<Uuid>, Json(answer): Json<Answer>) -> AppResult<()> {
+    // the files are hashed here instead
+    let files = match answer.hash_version == analyzer::HASH_VERSION {
+        true => answer.files,
+        false => {
+            tracing::warn!(worker = answer.worker, hash_version = answer.hash_version, "worker hashes another way, update it");
+            Vec::new()
+        }
+    };
+    if state.engine.workers().answer(&answer.worker, id, files) {
+        Ok(())
+    } else {
+        Err(AppError::not_found())
+    }
+}
+
+/// the workers that claimed batches since the start
+#[utoipa::path(
+    get,
+    path = "/workers",
+    tag = "tasks",
+    responses((status = 200, body = Vec<WorkerStatus>)),
+)]
+async fn list_workers(State(state): State<Arc<AppState>>) -> Json<Vec<WorkerStatus>> {
+    Json(state.engine.workers().status())
+}
+
+/// the folders kept analyzed
+#[utoipa::path(
+    get,
+    path = "/watch",
+    tag = "tasks",
+    responses((status = 200, body = Vec<WatchStatus>)),
+)]
+async fn list_watched(State(state): State<Arc<AppState>>) -> Json<Vec<WatchStatus>> {
+    Json(state.watcher.status())
+}
+
+/// the current groups of a watched folder, a page at a time
+#[utoipa::path(
+    get,
+    path = "/watch/groups",
+    tag = "tasks",
+    params(PathParams, GroupFilter, GroupSort, PageParams, ShapeParams),
+    responses(
+        (status = 200, body = Vec<analyzer::Group>, headers(("x-total-count" = usize, description = "groups passing the filter"))),
+        (status = 404, description = "the folder isn't watched"),
+        (status = 409, description = "the folder wasn't scanned yet"),
+    ),
+)]
+async fn watched_groups(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<PathParams>,
+    Query(filter): Query<GroupFilter>,
+    Query(sort): Query<GroupSort>,
+    Query(page_params): Query<PageParams>,
+    Query(shape_params): Query<ShapeParams>,
+) -> AppResult<impl IntoResponse> {
+    let groups = state.watcher.groups(&params.path).ok_or_else(AppError::not_found)?;
+    let groups = groups.ok_or(AppError::Provided(StatusCode::CONFLICT))?;
+    let mut groups: Vec<_> = groups.iter().filter(|group| filter.matches(group)).collect();
+    sort.sort(&mut groups);
+    let scoped = scoped_marks(&state, &session, None).await?;
+    let page = shape(&marked(&scoped, page_params.page(&groups)), &shape_params)?;
+    Ok(([(shape::TOTAL_COUNT, groups.len().to_string())], Json(page)))
+}
+
+/// the marks of the session and the task or run, which has to be visible to it
+async fn scoped_marks(state: &Arc<AppState>, session: &Session, task: Option<Uuid>) -> AppResult<Scoped> {
+    let state = state.clone();
+    let owner = session.id;
+    Ok(task::spawn_blocking(move || state.marks.scoped(owner, task)).await??)
+}
+
+fn marked(scoped: &Scoped, groups: &[&analyzer::Group]) -> Vec<analyzer::Group> {
+    groups
+        .iter()
+        .map(|group| {
+            let mut group = (*group).clone();
+            scoped.apply(&mut group);
+            group
+        })
+        .collect()
+}
+
+/// tasks still known and runs can be marked, while the session sees them
+async fn check_marked(state: &Arc<AppState>, session: &Session, task: Option<Uuid>) -> AppResult<()> {
+    let Some(id) = task else {
+        return Ok(());
+    };
+    if request_task(state, session, id).await.is_ok() {
+        return Ok(());
+    }
+    let runs = state.runs.clone();
+    match task::spawn_blocking(move || runs.owner(id)).await?? {
+        Some(owner) if session.sees(owner) => Ok(()),
+        _ => Err(AppError::not_found()),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MarksParams {
+    /// the marks of that task or run besides the global ones
+    task: Option<Uuid>,
+}
+
+/// the review decisions of the session, and of the task or run when given
+#[utoipa::path(
+    get,
+    path = "/marks",
+    tag = "files",
+    params(MarksParams, ShapeParams),
+    responses(
+        (status = 200, body = Vec<MarkEntry>),
+        (status = 404, description = "unknown task"),
+    ),
+)]
+async fn list_marks(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<MarksParams>,
+    Query(shape_params): Query<ShapeParams>,
+) -> JsonResponse<Value> {
+    check_marked(&state, &session, params.task).await?;
+    let owner = session.id;
+    let marks = task::spawn_blocking(move || state.marks.list(owner, params.task)).await??;
+    Ok(Json(shape(&marks, &shape_params)?))
+}
+
+/// marks files and groups as kept, to delete, reviewed or ignored, for a task or run or for every result
+#[utoipa::path(
+    post,
+    path = "/marks",
+    tag = "files",
+    request_body = MarkRequest,
+    responses(
+        (status = 200),
+        (status = 404, description = "unknown task"),
+    ),
+)]
+async fn set_marks(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Json(request): Json<MarkRequest>,
+) -> AppResult<()> {
+    check_marked(&state, &session, request.task).await?;
+    let owner = session.id;
+    task::spawn_blocking(move || state.marks.set(owner, &request)).await??;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/cancel",
+    tag = "tasks",
+    params(TaskParams),
+    responses(
+        (status = 200),
+        (status = 404, description = "unknown task"),
+        (status = 409, description = "already finished"),
+    ),
+)]
+async fn cancel(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<TaskParams>,
+) -> AppResult<()> {
+    request_task(&state, &session, params.task_id).await?;
+    if request_cancel(&state, params.task_id).await? {
+        Ok(())
+    } else {
+        // already finished
+        Err(AppError::Provided(StatusCode::CONFLICT))
+    }
+}
+
+pub(crate) async fn request_progress(state: &AppState, task_id: Uuid) -> AppResult<watch::Receiver<Progress>> {
+    let (tx, rx) = oneshot::channel();
+
+    state
+        .task_sender
+        .send(AnalyzeCommand::Subscribe(task_id, tx))
+        .await?;
+
+    rx.await?.ok_or_else(AppError::not_found)
+}
+
+/// resolves once the task is done
+pub(crate) async fn request_result(state: &AppState, task_id: Uuid) -> AppResult<oneshot::Receiver<Arc<TaskResult>>> {
+    let (tx, rx) = oneshot::channel();
+
+    state
+        .task_sender
+        .send(AnalyzeCommand::Wait(task_id, tx))
+        .await?;
+
+    rx.await?.ok_or_else(AppError::not_found)
+}
+
+#[utoipa::path(
+    get,
+    path = "/subscribe",
+    tag = "tasks",
+    params(TaskParams),
+    responses(
+        (status = 200, description = "progress events, then a `completed` or `failed` one", content_type = "text/event-stream"),
+        (status = 404, description = "unknown task"),
+    ),
+)]
+async fn subscribe(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<TaskParams>,
+) -> AppResult<Sse<impl Stream<Item = serde_json::error::Result<Event>>>> {
+    tracing::info!("SSE handler called {:?}", params.task_id);
+    request_task(&state, &session, params.task_id).await?;
+
+    let progress = request_progress(&state, params.task_id).await?;
+    let result = request_result(&state, params.task_id).await?;
+
+    // progress until the task is done, then a terminal event with the outcome
+    let finished = futures::stream::once(async move {
+        match result.await {
+            Ok(result) => match &*result {
+                Ok(data) => Event::default().event("completed").json_data(data),
+                Err(err) => Event::default().event("failed").json_data(FailedEvent::new(ErrorBody::of_report(err))),
+            },
+            // dropped from the manager in the meantime
+            Err(_) => {
+                let gone = ErrorBody::new(ErrorCode::NotFound, "task is gone");
+                Event::default().event("failed").json_data(FailedEvent::new(gone))
+            }
+        }
+    });
+    let stream = WatchStream::new(progress)
+        .map(|p| Event::default().json_data(p))
+        .chain(finished);
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// what happens to every task the session sees, and the file actions, for dashboards
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "tasks",
+    responses(
+        (status = 200, description = "`submitted`, `progress`, `finished` and `files` events, `lagged` when some were missed", content_type = "text/event-stream"),
+    ),
+)]
+async fn server_events(State(state): State<Arc<AppState>>, session: Session) -> Sse<impl Stream<Item = serde_json::error::Result<Event>>> {
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(move |event| {
+        let event = match event {
+            Ok(event) if event.seen_by(&session) => Some(Event::default().event(event.name()).json_data(&*event)),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Event::default().event("lagged").json_data(serde_json::json!({ "missed": missed }))),
+        };
+        futures::future::ready(event)
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[derive(Serialize, ToSchema)]
+struct FailedEvent {
+    error: String,
+    code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    path: Option<PathBuf>,
+}
+
+impl FailedEvent {
+    fn new(body: ErrorBody) -> Self {
+        Self { error: body.message, code: body.code, path: body.path }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+struct MetadataParams {
+    #[param(value_type = String)]
+    path: PathBuf,
+    #[serde(default)]
+    hash_type: HashType,
+    #[serde(default)]
+    hash_size: HashSize,
+}
+
+#[utoipa::path(
+    get,
+    path = "/metadata",
+    tag = "images",
+    params(MetadataParams),
+    responses(
+        (status = 200, body = metadata::ImageMetadata),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn image_metadata(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MetadataParams>,
+) -> JsonResponse<metadata::ImageMetadata> {
+    if !params.path.is_file() {
+        return Err(AppError::not_found());
+    }
+    state.check_library(&params.path)?;
+
+    let meta = task::spawn_blocking(move || -> Result<_> {
+        let mut meta = metadata::read_metadata(&params.path)?;
+        let hash = state.engine.hash_file(params.hash_type, params.hash_size, &params.path)?;
+        meta.hash = Some(hash.to_base64());
+        Ok(meta)
+    }).await??;
+
+    Ok(Json(meta))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct SearchRequest {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    #[serde(default = "default_search_dist")]
+    max_dist: u32,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+fn default_search_dist() -> u32 {
+    10
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+#[utoipa::path(
+    post,
+    path = "/search",
+    tag = "images",
+    request_body = SearchRequest,
+    responses(
+        (status = 200, body = Vec<SearchMatch>),
+        (status = 404, description = "no such file, or nothing analyzed yet"),
+    ),
+)]
+async fn search(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<SearchRequest>,
+) -> JsonResponse<Vec<SearchMatch>> {
+    if !req.path.is_file() {
+        return Err(AppError::not_found());
+    }
+    state.check_library(&req.path)?;
+
+    let matches = task::spawn_blocking(move || {
+        state.engine.search(&req.path, req.max_dist, req.limit)
+    }).await??;
+
+    // nothing to search in before the first analysis
+    let matches = matches.ok_or_else(AppError::not_found)?;
+    Ok(Json(matches))
+}
+
+/// largest body of an upload, it is held in memory
+const MAX_UPLOAD: usize = 64 * 1024 * 1024;
+
+/// the form of `/search/upload`, for the documentation
+#[derive(ToSchema)]
+#[allow(dead_code)]
+struct SearchUpload {
+    #[schema(value_type = String, format = Binary)]
+    image: Vec<u8>,
+    /// 10 when not given
+    max_dist: Option<u32>,
+    /// 20 when not given
+    limit: Option<usize>,
+}
+
+/// like `/search`, for an image uploaded rather than one of the libraries, e.g. one someone sent
+#[utoipa::path(
+    post,
+    path = "/search/upload",
+    tag = "images",
+    request_body(content = SearchUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = Vec<SearchMatch>),
+        (status = 400, description = "no image in the form, or not an image"),
+        (status = 404, description = "nothing analyzed yet"),
+    ),
+)]
+async fn search_upload(
+    State(state): State<Arc<AppState>>,
+    form: Result<Multipart, MultipartRejection>,
+) -> JsonResponse<Vec<SearchMatch>> {
+    let bad_request = |message: String| AppError::from(ErrorBody::new(ErrorCode::BadRequest, message));
+    let mut form = form.map_err(|rejection| bad_request(rejection.body_text()))?;
+
+    let mut image = None;
+    let mut max_dist = default_search_dist();
+    let mut limit = default_search_limit();
+    while let Some(field) = form.next_field().await.map_err(|err| bad_request(err.body_text()))? {
+        let name = field.name().unwrap_or_default().to_owned();
+        if name == "image" {
+            image = Some(field.bytes().await.map_err(|err| bad_request(err.body_text()))?);
+            continue;
+        }
+        let text = field.text().await.map_err(|err| bad_request(err.body_text()))?;
+        let text = text.trim();
+        match name.as_str() {
+            "maxDist" => max_dist = text.parse().map_err(|_| bad_request(format!("invalid maxDist {}", text)))?,
+            "limit" => limit = text.parse().map_err(|_| bad_request(format!("invalid limit {}", text)))?,
+            _ => {}
+        }
+    }
+    let image = image.ok_or_else(|| bad_request("no image in the form".to_owned()))?;
+
+    let matches = task::spawn_blocking(move || {
+        state.engine.search_image(&image, max_dist, limit)
+    }).await?;
+    let matches = matches.map_err(|err| bad_request(format!("not an image: {:#}", err)))?;
+
+    // nothing to search in before the first analysis
+    let matches = matches.ok_or_else(AppError::not_found)?;
+    Ok(Json(matches))
+}
+
+#[utoipa::path(
+    get,
+    path = "/cache/stats",
+    tag = "cache",
+    responses((status = 200, body = CacheStats)),
+)]
+async fn cache_stats(State(state): State<Arc<AppState>>) -> JsonResponse<CacheStats> {
+    let stats = task::spawn_blocking(move || state.engine.cache_stats()).await??;
+    Ok(Json(stats))
+}
+
+#[derive(Serialize, ToSchema)]
+struct PruneResponse {
+    removed: usize,
+}
+
+#[utoipa::path(
+    post,
+    path = "/cache/clear",
+    tag = "cache",
+    responses((status = 200, body = PruneResponse)),
+)]
+async fn clear_cache(State(state): State<Arc<AppState>>) -> JsonResponse<PruneResponse> {
+    let removed = task::spawn_blocking(move || state.engine.prune_cache(None)).await??;
+    Ok(Json(PruneResponse { removed }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PruneParams {
+    /// e.g. `3600`, `90m`, `12h` or `30d`
+    older_than: String,
+}
+
+fn parse_age(age: &str) -> Option<Duration> {
+    let (value, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => age.split_at(pos),
+        None => (age, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let value: u64 = value.parse().ok()?;
+    Some(Duration::from_secs(value.checked_mul(multiplier)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/cache/prune",
+    tag = "cache",
+    params(PruneParams),
+    responses(
+        (status = 200, body = PruneResponse),
+        (status = 400, description = "invalid age"),
+    ),
+)]
+async fn prune_cache(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PruneParams>,
+) -> JsonResponse<PruneResponse> {
+    let age = parse_age(&params.older_than).ok_or(AppError::Provided(StatusCode::BAD_REQUEST))?;
+    let removed = task::spawn_blocking(move || state.engine.prune_cache(Some(age))).await??;
+    Ok(Json(PruneResponse { removed }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/cache/export",
+    tag = "cache",
+    responses((status = 200, description = "one entry per line", content_type = "application/x-ndjson")),
+)]
+async fn export_cache(State(state): State<Arc<AppState>>) -> AppResult<impl IntoResponse> {
+    let dump = task::spawn_blocking(move || state.engine.export_cache()).await??;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"cache.jsonl\""),
+        ],
+        dump,
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImportParams {
+    /// library location on the machine the cache was exported from
+    #[param(value_type = Option<String>)]
+    from: Option<PathBuf>,
+    /// and where it is mounted here
+    #[param(value_type = Option<String>)]
+    to: Option<PathBuf>,
+}
+
+impl ImportParams {
+    fn remap(self) -> AppResult<Option<(PathBuf, PathBuf)>> {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) => Ok(Some((from, to))),
+            (None, None) => Ok(None),
+            _ => Err(AppError::Provided(StatusCode::BAD_REQUEST)),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ImportResponse {
+    imported: usize,
+}
+
+#[utoipa::path(
+    post,
+    path = "/cache/import",
+    tag = "cache",
+    params(ImportParams),
+    request_body(content = String, description = "a dump from `/cache/export`", content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, body = ImportResponse),
+        (status = 400, description = "only one of `from` and `to`"),
+    ),
+)]
+async fn import_cache(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ImportParams>,
+    dump: Bytes,
+) -> JsonResponse<ImportResponse> {
+    let remap = params.remap()?;
+    let imported = task::spawn_blocking(move || state.engine.import_cache(&dump, remap)).await??;
+    Ok(Json(ImportResponse { imported }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CompareParams {
+    #[param(value_type = String)]
+    left: PathBuf,
+    #[param(value_type = String)]
+    right: PathBuf,
+}
+
+#[utoipa::path(
+    get,
+    path = "/compare/diff-image",
+    tag = "images",
+    params(CompareParams),
+    responses(
+        (status = 200, description = "where the images differ", content_type = "image/png"),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn diff_image(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CompareParams>,
+) -> AppResult<impl IntoResponse> {
+    if !params.left.is_file() || !params.right.is_file() {
+        return Err(AppError::not_found());
+    }
+    state.check_library(&params.left)?;
+    state.check_library(&params.right)?;
+
+    let content = task::spawn_blocking(move || compare::diff_image(&params.left, &params.right)).await??;
+    Ok(([(header::CONTENT_TYPE, "image/png")], content))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+struct CompareFilesParams {
+    #[param(value_type = String)]
+    a: PathBuf,
+    #[param(value_type = String)]
+    b: PathBuf,
+    #[serde(default)]
+    hash_type: HashType,
+    #[serde(default)]
+    hash_size: HashSize,
+}
+
+/// whether two files are copies of each other and how far apart their hashes are, with the metadata of both
+#[utoipa::path(
+    get,
+    path = "/compare",
+    tag = "images",
+    params(CompareFilesParams),
+    responses(
+        (status = 200, body = Comparison),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn compare_files(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CompareFilesParams>,
+) -> JsonResponse<Comparison> {
+    if !params.a.is_file() || !params.b.is_file() {
+        return Err(AppError::not_found());
+    }
+    state.check_library(&params.a)?;
+    state.check_library(&params.b)?;
+
+    let comparison = task::spawn_blocking(move || {
+        compare::compare_files(&state.engine, params.hash_type, params.hash_size, &params.a, &params.b)
+    }).await??;
+    Ok(Json(comparison))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(rename_all = "camelCase")]
+struct ShareParams {
+    task_id: Uuid,
+    #[serde(default = "default_share_hours")]
+    ttl_hours: u64,
+}
+
+fn default_share_hours() -> u64 {
+    24
+}
+
+/// longest a share link is valid for, a year
+const MAX_SHARE_HOURS: u64 = 24 * 365;
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ShareResponse {
+    token: String,
+    expires: u64,
+}
+
+#[utoipa::path(
+    post,
+    path = "/share",
+    tag = "tasks",
+    params(ShareParams),
+    responses(
+        (status = 200, body = ShareResponse),
+        (status = 404, description = "unknown task"),
+    ),
+)]
+async fn share_task(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(params): Query<ShareParams>,
+) -> JsonResponse<ShareResponse> {
+    let path = request_task(&state, &session, params.task_id).await?.path;
+    let ttl = Some(params.ttl_hours)
+        .filter(|hours| *hours <= MAX_SHARE_HOURS)
+        .and_then(|hours| hours.checked_mul(3600))
+        .map(Duration::from_secs)
+        .ok_or_else(|| ErrorBody::new(ErrorCode::BadRequest, format!("ttlHours is at most {}", MAX_SHARE_HOURS)))?;
+    let (token, expires) = state.shares.mint(params.task_id, &path, ttl)?;
+    let expires = expires.duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64;
+    Ok(Json(ShareResponse { token, expires }))
+}
+
+/// Requests carrying a share token (`token` query parameter) are limited
+/// to what the token claims permit, other requests pass through.
+async fn share_guard<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> AppResult<axum::response::Response> {
+    let Ok(Query(query)) = Query::<HashMap<String, String>>::try_from_uri(request.uri()) else {
+        return Ok(next.run(request).await);
+    };
+
+    if let Some(token) = query.get("token") {
+        let claims = state.shares.get(token).ok_or_else(AppError::unauthorized)?;
+        let read_only = request.method() == Method::GET || request.method() == Method::HEAD;
+        if !read_only || !claims.permits(request.uri().path(), &query) {
+            return Err(AppError::forbidden());
+        }
+    }
+
+    Ok(next.run(request).await)
+}
+
+type FileResponse = Response<tower_http::services::fs::ServeFileSystemResponseBody>;
+
+/// supports `Range` and `HEAD`, so large originals needn't be downloaded whole,
+/// and `If-None-Match` and `If-Modified-Since`, so unchanged ones needn't be downloaded again
+#[utoipa::path(
+    get,
+    path = "/image",
+    tag = "images",
+    params(PathParams),
+    responses(
+        (status = 200, description = "the file as is, typed by its content", content_type = "application/octet-stream"),
+        (status = 206, description = "the requested range"),
+        (status = 304, description = "unchanged since the client got it"),
+        (status = 400, description = "no path given"),
+        (status = 403, description = "outside of the libraries"),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn serve_image<T>(
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<PathParams>, QueryRejection>,
+    mut request: Request<T>,
+) -> AppResult<axum::response::Response>
+where
+    T: Send + 'static
+{
+    let Query(params) = query.map_err(|_| AppError::Provided(StatusCode::BAD_REQUEST))?;
+    if let Some(storage) = state.engine.remote(&params.path)? {
+        return serve_remote(storage, params.path, request.headers()).await;
+    }
+    if let Some((archive, _)) = archive::split(&params.path) {
+        state.check_library(&archive)?;
+        if !archive.is_file() {
+            return Err(AppError::not_found());
+        }
+        return serve_remote(Arc::new(state.engine.archives()), params.path, request.headers()).await;
+    }
+    state.check_library(&params.path)?;
+    if !params.path.is_file() {
+        return Err(AppError::not_found());
+    }
+    let etag = conditional::file_tag(&params.path, &std::fs::metadata(&params.path)?, "")?;
+    if conditional::matches(request.headers(), &etag) {
+        return Ok(conditional::not_modified(etag));
+    }
+    conditional::prefer_tag(&mut request);
+
+    // the extension may be missing or lie
+    let service = match analyzer::sniff_format(&params.path)? {
+        Some(format) => services::ServeFile::new_with_mime(&params.path, &format.to_mime_type().parse::<mime::Mime>()?),
+        None => services::ServeFile::new(&params.path),
+    };
+    let mut response = service.oneshot(request).await?;
+    response.headers_mut().insert(header::ETAG, etag);
+    Ok(response.map(axum::body::boxed))
+}
+
+/// whole, tagged by the content as remote storage and archive entries have no mtimes to rely on
+async fn serve_remote(storage: Arc<dyn Storage>, path: PathBuf, headers: &header::HeaderMap) -> AppResult<axum::response::Response> {
+    let data = task::spawn_blocking(move || storage.read(&path)).await??;
+    let etag = conditional::tag(&sha256::digest(data.as_slice()));
+    if conditional::matches(headers, &etag) {
+        return Ok(conditional::not_modified(etag));
+    }
+    let mime = image::guess_format(&data).map_or("application/octet-stream", |format| format.to_mime_type());
+    Ok(([(header::CONTENT_TYPE, header::HeaderValue::from_static(mime)), (header::ETAG, etag)], data).into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ThumbnailParams {
+    #[param(value_type = String)]
+    path: PathBuf,
+    #[serde(default = "default_thumbnail_size")]
+    size: u32,
+}
+
+fn default_thumbnail_size() -> u32 {
+    256
+}
+
+#[utoipa::path(
+    get,
+    path = "/thumbnail",
+    tag = "images",
+    params(ThumbnailParams),
+    responses(
+        (status = 200, description = "the image scaled down", content_type = "image/jpeg"),
+        (status = 304, description = "the original is unchanged since the client got it"),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn serve_thumbnail<T>(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ThumbnailParams>,
+    mut request: Request<T>,
+) -> AppResult<axum::response::Response>
+where
+    T: Send + 'static
+{
+    if !params.path.is_file() {
+        return Err(AppError::not_found());
+    }
+    state.check_library(&params.path)?;
+
+    let size = params.size.clamp(16, 1024);
+    // of the original, a match needn't even look up the thumbnail
+    let etag = conditional::file_tag(&params.path, &std::fs::metadata(&params.path)?, &size.to_string())?;
+    if conditional::matches(request.headers(), &etag) {
+        return Ok(conditional::not_modified(etag));
+    }
+    conditional::prefer_tag(&mut request);
+
+    let path = task::spawn_blocking(move || state.thumbnails.get(&params.path, size)).await??;
+    let service = services::ServeFile::new(&path);
+    let mut response = service.oneshot(request).await?;
+    response.headers_mut().insert(header::ETAG, etag);
+    Ok(response.map(axum::body::boxed))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PreviewParams {
+    #[param(value_type = String)]
+    path: PathBuf,
+    /// of the box the upright image is scaled down to fit into, up to 4096 pixels
+    width: Option<u32>,
+    height: Option<u32>,
+    /// of the JPEG, 1 to 100 [default: 85]
+    quality: Option<u8>,
+}
+
+/// Images as browsers can show them, upright and scaled down to fit into `width` x `height` when given:
+/// the file itself when nothing has to change, a JPEG of it otherwise, e.g. of TIFF, raw and HEIC files.
+#[utoipa::path(
+    get,
+    path = "/preview",
+    tag = "images",
+    params(PreviewParams),
+    responses(
+        (status = 200, description = "the file as is, or rendered as a JPEG", content_type = "image/jpeg"),
+        (status = 304, description = "the original is unchanged since the client got it"),
+        (status = 403, description = "outside of the libraries"),
+        (status = 404, description = "no such file"),
+    ),
+)]
+async fn serve_preview<T>(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PreviewParams>,
+    mut request: Request<T>,
+) -> AppResult<axum::response::Response>
+where
+    T: Send + 'static
+{
+    // served as they are
+    if archive::is_entry(&params.path) || state.engine.remote(&params.path)?.is_some() {
+        return serve_image(State(state), Ok(Query(PathParams { path: params.path })), request).await;
+    }
+    if !params.path.is_file() {
+        return Err(AppError::not_found());
+    }
+    state.check_library(&params.path)?;
+    let resized = params.width.is_some() || params.height.is_some() || params.quality.is_some();
+    if !resized && thumbnail::browser_displayable(analyzer::sniff_format(&params.path)?) && metadata::read_orientation(&params.path).unwrap_or(1) == 1 {
+        return serve_image(State(state), Ok(Query(PathParams { path: params.path })), request).await;
+    }
+
+    let fit = |side: Option<u32>| side.unwrap_or(thumbnail::PREVIEW_SIZE).clamp(16, thumbnail::PREVIEW_SIZE);
+    let quality = params.quality.unwrap_or(thumbnail::DEFAULT_QUALITY).clamp(1, 100);
+    let rendition = Rendition { width: fit(params.width), height: fit(params.height), quality };
+    let etag = conditional::file_tag(&params.path, &std::fs::metadata(&params.path)?, &rendition.key())?;
+    if conditional::matches(request.headers(), &etag) {
+        return Ok(conditional::not_modified(etag));
+    }
+    conditional::prefer_tag(&mut request);
+
+    let path = task::spawn_blocking(move || state.thumbnails.render(&params.path, rendition)).await??;
+    let service = services::ServeFile::new(&path);
+    let mut response = service.oneshot(request).await?;
+    response.headers_mut().insert(header::ETAG, etag);
+    Ok(response.map(axum::body::boxed))
+}
+
+#[utoipa::path(
+    get,
+    path = "/deleted/{id}",
+    tag = "deleted",
+    params(("id" = String, Path)),
+    responses((status = 200, description = "the removed file", content_type = "application/octet-stream")),
+)]
+async fn serve_deleted<T>(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    request: Request<T>,
+) -> AppResult<FileResponse>
+where
+    T: Send + 'static
+{
+    let path = state.remover.resolve(&id)?;
+    let service = services::ServeFile::new(&path);
+    let response = service.oneshot(request).await?;
+    Ok(response)
+}
+
+pub(crate) fn open_engine(data_dir: &std::path::Path, roots: Arc<Roots>, sandbox: Arc<Sandbox>) -> Result<Analyzer> {
+    let cache = Cache::open(&data_dir.join("cache.db"), CacheLimits::from_env()?, analyzer::HASH_VERSION)?;
+    Ok(Analyzer::new(cache, roots, sandbox))
+}
+
+/// what a server is set up with besides its data folder, the defaults are those of a bare `serve`
+#[derive(Default)]
+pub(crate) struct StateOptions {
+    /// the folders files are confined to, any readable file without them
+    pub libraries: Option<Vec<PathBuf>>,
+    pub limits: TaskLimits,
+    pub webhooks: Webhooks,
+    pub remotes: Remotes,
+    pub watcher: Watcher,
+    pub keep_rules: KeepRules,
+    /// serves the desktop endpoints
+    pub desktop: bool,
+    /// serves the worker endpoints to requests carrying it
+    pub worker_token: Option<String>,
+}
+
+pub(crate) fn create_state(data_dir: &std::path::Path, options: StateOptions) -> Result<Arc<AppState>> {
+    let StateOptions { libraries, limits, webhooks, remotes, watcher, keep_rules, desktop, worker_token } = options;
+    let roots = Arc::new(Roots::open(data_dir.join("roots.json"))?);
+    let sandbox = Arc::new(Sandbox::new(libraries.as_deref())?);
+    let ignored = Arc::new(IgnoreList::open(data_dir.join("ignored.json"))?);
+    let protected = Arc::new(Protected::open(data_dir.join("protected.json"), keep_rules.protected.configured().to_vec())?);
+    let keep_rules = KeepRules { protected: protected.clone(), ..keep_rules };
+    let engine = open_engine(data_dir, roots.clone(), sandbox.clone())?
+        .with_remotes(remotes)
+        .with_keep_rules(keep_rules)
+        .with_memory_budget(limits.memory_budget)
+        .with_ignored(ignored.clone())
+        .with_index_file(data_dir.join("index.json"));
+    let engine = Arc::new(engine);
+    let actor_health = Arc::new(ActorHealth::default());
+    let (runs, runs_migration) = Runs::open(&data_dir.join("runs.db"))?;
+    let runs = Arc::new(runs);
+    let (marks, marks_migration) = Marks::open(&data_dir.join("marks.db"))?;
+    let tasks = TaskStore::new(data_dir.join("tasks"));
+    let events = Events::default();
+    let (_, task_sender) = spawn_analyzer(
+        engine.clone(),
+        limits,
+        tasks.clone(),
+        runs.clone(),
+        actor_health.clone(),
+        webhooks.clone(),
+        events.clone(),
+    );
+    std::fs::create_dir_all(data_dir.join("removed"))?;
+    let remover = Remover::new(data_dir.join("removed"));
+    let quarantine = Quarantine::open(data_dir)?;
+    let history = History::new(data_dir.join("history"));
+
+    let mut migrations: Vec<Migration> = roots.migration().into_iter().collect();
+    migrations.extend(engine.cache_migrations());
+    migrations.extend(runs_migration);
+    migrations.extend(marks_migration);
+    migrations.extend(remover.migrate()?);
+    for migration in &migrations {
+        tracing::info!(
+            store = migration.store,
+            from = migration.from,
+            to = migration.to,
+            items = migration.items,
+            "schema migrated",
+        );
+    }
+
+    let pending = remover.pending()?;
+    if !pending.is_empty() {
+        tracing::warn!("{} interrupted actions found in the journal, starting in safe mode", pending.len());
+    }
+    let safe_mode = AtomicBool::new(!pending.is_empty());
+    let bookmarks = Bookmarks::open(data_dir.join("bookmarks.json"))?;
+    let profiles = Profiles::open(data_dir.join("profiles.json"))?;
+    let shares = Shares::new();
+    let thumbnails = Thumbnails::new(data_dir.join("thumbnails"));
+    let watcher = Arc::new(watcher);
+
+    let state = Arc::new(AppState {
+        task_sender,
+        actor_health,
+        engine,
+        remover,
+        quarantine,
+        history,
+        roots,
+        bookmarks,
+        profiles,
+        ignored,
+        protected,
+        shares,
+        thumbnails,
+        safe_mode,
+        sandbox,
+        migrations,
+        watcher,
+        runs,
+        marks,
+        tasks,
+        events,
+        webhooks,
+        desktop,
+        worker_token: worker_token.map(Arc::from),
+        draining: AtomicBool::new(false),
+    });
+    let resolving = Arc::downgrade(&state);
+    let resolve = move |rules: &[AutoRule], groups: &[Group]| resolving.upgrade().map_or(0, |state| state.auto_resolve(rules, groups));
+    state.watcher.spawn(state.engine.clone(), Box::new(resolve));
+    Ok(state)
+}
+
+pub(crate) fn app(shared_state: Arc<AppState>) -> Router {
+    // served with `desktop = true` only
+    let desktop = match shared_state.desktop {
+        true => Router::new()
+            .route("/desktop/open", post(desktop_open))
+            .route("/desktop/reveal", post(desktop_reveal))
+            .route_layer(middleware::from_fn(same_origin)),
+        false => Router::new(),
+    };
+    // served with a `worker-token` only
+    let workers = match shared_state.worker_token.clone() {
+        Some(token) => Router::new()
+            .route("/workers", get(list_workers))
+            .route("/workers/claim", post(claim_batch))
+            .route("/workers/batches/:id", post(answer_batch).layer(DefaultBodyLimit::disable()))
+            .route_layer(middleware::from_fn_with_state(token, worker_guard)),
+        None => Router::new(),
+    };
+    // results of large libraries are tens of megabytes of JSON, compressed when the client accepts it
+    let large_json = Router::new()
+        .route("/list_folder", get(list_folder))
+        .route("/poll", get(poll))
+        .route("/tasks", get(list_tasks))
+        .route("/tasks/:id/groups", get(task_groups))
+        .route("/tasks/:id/export", get(export_task))
+        .route("/watch/groups", get(watched_groups))
+        .route("/runs", get(list_runs))
+        .route("/runs/:id", get(get_run).delete(delete_run))
+        .route("/runs/:id/diff/:since", get(diff_runs))
+        .layer(CompressionLayer::new());
+    Router::new()
+        .route("/api/openapi.json", get(openapi::openapi))
+        .route("/image", get(serve_image))
+        .route("/thumbnail", get(serve_thumbnail))
+        .route("/preview", get(serve_preview))
+        .route("/metadata", get(image_metadata))
+        .route("/search", post(search))
+        .route("/search/upload", post(search_upload).layer(DefaultBodyLimit::max(MAX_UPLOAD)))
+        .route("/cache/stats", get(cache_stats))
+        .route("/cache/clear", post(clear_cache))
+        .route("/cache/prune", post(prune_cache))
+        .route("/cache/export", get(export_cache))
+        .route("/cache/import", post(import_cache).layer(DefaultBodyLimit::disable()))
+        .route("/compare", get(compare_files))
+        .route("/compare/diff-image", get(diff_image))
+        .route("/stats", get(folder_stats))
+        .route("/tree", get(folder_tree))
+        .route("/volumes", get(list_volumes))
+        .route("/delete_file", post(delete_file))
+        .route("/files/delete", post(delete_files))
+        .route("/files/move", post(move_files))
+        .route("/download", post(download_files))
+        .route("/files/link", post(link_files))
+        .route("/quarantine", get(list_quarantined))
+        .route("/quarantine/restore", post(restore_quarantined))
+        .route("/quarantine/purge", post(purge_quarantined))
+        .route("/resolve", post(resolve_groups))
+        .route("/resolve/plan", post(plan_resolution))
+        .route("/undo", post(undo))
+        .route("/deleted", get(list_deleted))
+        .route("/deleted/exclude", get(exclude_deleted))
+        .route("/deleted/:id", get(serve_deleted))
+        .route("/deleted/:id/restore", post(restore_file))
+        .route("/deleted/restore_all", post(restore_all))
+        .route("/admin/journal", get(list_journal))
+        .route("/admin/migrations", get(list_migrations))
+        .route("/admin/journal/:id/complete", post(complete_journal_entry))
+        .route("/admin/journal/:id/rollback", post(rollback_journal_entry))
+        .route("/roots", get(list_roots).post(set_root).delete(remove_root))
+        .route("/bookmarks", get(list_bookmarks).post(add_bookmark))
+        .route("/bookmarks/:id", get(get_bookmark).put(update_bookmark).delete(remove_bookmark))
+        .route("/profiles", get(list_profiles).post(add_profile))
+        .route("/profiles/:name", get(get_profile).put(update_profile).delete(remove_profile))
+        .route("/profiles/:name/analyze", post(analyze_profile))
+        .route("/ignored", get(list_ignored).post(add_ignored))
+        .route("/ignored/:id", delete(remove_ignored))
+        .route("/protected", get(list_protected).post(add_protected).delete(remove_protected))
+        .route("/index", get(warm_status).post(start_warming))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(serve_metrics))
+        // lists of files run into megabytes
+        .route("/analyze", post(analyze).layer(DefaultBodyLimit::disable()))
+        .route("/import", post(import_report).layer(DefaultBodyLimit::disable()))
+        .route("/names", post(group_names))
+        .route("/cancel", post(cancel))
+        .route("/tasks/:id/logs", get(task_logs))
+        .route("/tasks/:id/regroup", post(regroup_task))
+        .route("/ws", get(ws::ws))
+        .route("/events", get(server_events))
+        .route("/subscribe", get(subscribe))
+        .route("/share", post(share_task))
+        .route("/watch", get(list_watched))
+        .route("/marks", get(list_marks).post(set_marks))
+        .merge(large_json)
+        .merge(desktop)
+        .merge(workers)
+        .route_layer(middleware::from_fn(metrics::track))
+        .layer(middleware::from_fn_with_state(shared_state.clone(), share_guard))
+        .with_state(shared_state)
+}
+
+/// lets frontends hosted elsewhere call the API
+pub(crate) fn cors_layer(origins: &[header::HeaderValue]) -> CorsLayer {
+    let origins = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().cloned())
+    };
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::HeaderName::from_static("x-api-key"),
+            header::HeaderName::from_static(session::ADMIN_HEADER),
+        ])
+        // export downloads are named by it
+        .expose_headers([header::CONTENT_DISPOSITION, header::HeaderName::from_static(shape::TOTAL_COUNT)])
+}
+
+/// writes the synthetic test images, for reproducing bugs
+fn gen_fixtures(dir: &std::path::Path) -> Result<()> {
+    let fixtures = fixtures::generate(dir)?;
+    let expected_groups = fixtures::expected_groups(&fixtures);
+    let manifest = serde_json::json!({ "fixtures": fixtures, "expectedGroups": expected_groups });
+    println!("{}", serde_json::to_string_pretty(&manifest)?);
+    Ok(())
+}
+
+/// dumps the hash cache for another machine
+fn export_cache_cmd(data_dir: &std::path::Path, file: &std::path::Path) -> Result<()> {
+    let engine = open_engine(data_dir, Arc::new(Roots::open(data_dir.join("roots.json"))?), Arc::default())?;
+    std::fs::write(file, engine.export_cache()?)?;
+    Ok(())
+}
+
+/// loads a dump, moving paths under `from` to `to`
+fn import_cache_cmd(data_dir: &std::path::Path, file: &std::path::Path, remap: Option<(PathBuf, PathBuf)>) -> Result<()> {
+    let engine = open_engine(data_dir, Arc::new(Roots::open(data_dir.join("roots.json"))?), Arc::default())?;
+    let imported = engine.import_cache(&std::fs::read(file)?, remap)?;
+    println!("{} entries imported", imported);
+    Ok(())
+}
+
+/// stores a report of another tool as a task, listed by the server from its next start
+fn import_report_cmd(data_dir: &std::path::Path, file: &std::path::Path, format: ImportFormat) -> Result<()> {
+    let (request, groups) = read_report(format, &std::fs::read_to_string(file)?)?;
+    let engine = open_engine(data_dir, Arc::new(Roots::open(data_dir.join("roots.json"))?), Arc::default())?;
+    let result = engine.import(groups);
+    let finished = tasks::to_millis(SystemTime::now());
+    let id = Uuid::new_v4();
+    let (runs, _) = Runs::open(&data_dir.join("runs.db"))?;
+    runs.record(id, &request, finished, finished, &result)?;
+    let outcome = Some(Outcome::Completed { data: &result });
+    TaskStore::new(data_dir.join("tasks")).save(&StoredTask { id, request, submitted: finished, finished: Some(finished), outcome })?;
+    println!("{} groups imported as task {}", result.groups().len(), id);
+    Ok(())
+}
+
+/// the command line subcommands, they don't start the server
+async fn run_command(command: Command, config: Config) -> Result<()> {
+    let data_dir = config.data_dir;
+    match command {
+        Command::GenFixtures { dir } => gen_fixtures(&dir),
+        Command::Analyze { path, files_from, dist, hash_type, hash_size, json, fast, byte_progress, pixel_exact } => {
+            let progress_unit = if byte_progress { ProgressUnit::Bytes } else { ProgressUnit::Files };
+            let exact_by = if pixel_exact { ExactBy::Pixels } else { ExactBy::Bytes };
+            let files = files_from.as_deref().map(headless::read_list).transpose()?;
+            let common = files.as_ref().and_then(|files| import::common_folder(std::slice::from_ref(files)));
+            let path = path.or(common).ok_or_else(|| eyre::eyre!("the listed files have no folder in common"))?;
+            let req = AnalyzeRequest { fast, progress_unit, exact_by, files, ..headless::request(path, dist, hash_type, hash_size) };
+            headless::analyze(data_dir, req, json).await
+        }
+        // the cache blocks on its own thread, keep it off the runtime
+        Command::ExportCache { file } => task::spawn_blocking(move || export_cache_cmd(&data_dir, &file)).await?,
+        Command::ImportCache { file, from, to } => {
+            let remap = from.zip(to);
+            task::spawn_blocking(move || import_cache_cmd(&data_dir, &file, remap)).await?
+        }
+        Command::Worker { coordinator, name, token, worker_token, from, to } => {
+            tracing_subscriber::registry().with(LevelFilter::INFO).with(logging::layer(&config.log)?).init();
+            let name = name.unwrap_or_else(|| Uuid::new_v4().to_string());
+            workers::work(WorkerOptions { coordinator, name, token, worker_token, remap: from.zip(to) }).await
+        }
+        Command::ImportReport { file, format } => task::spawn_blocking(move || import_report_cmd(&data_dir, &file, format)).await?,
+        #[cfg(feature = "tui")]
+        Command::Review { file } => {
+            std::fs::create_dir_all(data_dir.join("removed"))?;
+            let protected = Protected::open(data_dir.join("protected.json"), config.keep_rules.protected.configured().to_vec())?;
+            review::review(file, &data_dir, protected)
+        }
+    }
+}
+
+/// runs a subcommand, or the server until it is stopped
+pub async fn run(cli: Cli) -> Result<()> {
+    let config = Config::new(cli.settings, cli.config.as_ref())?;
+    if let Some(command) = cli.command {
+        return run_command(command, config).await;
+    }
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(logging::layer(&config.log)?)
+        .with(logs::layer())
+        .init();
+    tracing::info!("starting...");
+
+    if let Some(threads) = config.hash_threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
+    }
+    let mut limits = TaskLimits::from_env()?;
+    if let Some(concurrency) = config.task_concurrency {
+        limits.concurrency = concurrency;
+    }
+    limits.memory_budget = config.memory_budget;
+
+    let webhooks = Webhooks::new(config.webhook.clone(), config.webhook_hosts.clone());
+
+    let data_dir = config.data_dir.as_path();
+    let tenants = tenant::load(&data_dir.join("tenants.json"))?;
+    #[cfg(feature = "grpc")]
+    eyre::ensure!(
+        config.grpc_addr.is_none() || tenants.is_none(),
+        "the gRPC service can't tell tenants apart, it is served without tenants only"
+    );
+    eyre::ensure!(config.watch.is_empty() || tenants.is_none(), "folders are watched without tenants only");
+    eyre::ensure!(!config.desktop || tenants.is_none(), "files are opened on the desktop without tenants only");
+    let (app, states) = match tenants {
+        Some(configs) => {
+            let tenants = Arc::new(Tenants::new(data_dir, configs, limits, webhooks, config.keep_rules.clone(), &config.assets)?);
+            (tenant::app(tenants.clone()), tenants.states())
+        }
+        None => {
+            if config.libraries.is_none() {
+                tracing::warn!("no libraries configured, any file readable by the server can be accessed");
+            }
+            let mut remotes = Remotes::default();
+            if let Some(s3) = config.s3.clone() {
+                remotes = remotes.with(s3::SCHEME, Arc::new(s3::Client::new(s3)?));
+            }
+            if let Some(webdav) = config.webdav.clone() {
+                remotes = remotes.with(webdav::SCHEME, Arc::new(webdav::Client::new(webdav)?));
+            }
+            if let Some(sftp) = config.sftp.clone() {
+                remotes = remotes.with(sftp::SCHEME, Arc::new(sftp::Client::new(sftp)?));
+            }
+            let watcher = Watcher::new(config.watch.clone(), config.watch_options).with_rules(config.auto_resolve.clone());
+            let options = StateOptions {
+                libraries: config.libraries.clone(),
+                limits,
+                webhooks,
+                remotes,
+                watcher,
+                keep_rules: config.keep_rules.clone(),
+                desktop: config.desktop,
+                worker_token: config.worker_token.clone(),
+            };
+            let state = create_state(data_dir, options)?;
+            (app(state.clone()).merge(config.assets.routes()), vec![state])
+        }
+    };
+
+    let http_logger = TraceLayer::new_for_http()
+        .make_span_with(|req: &Request<_>| {
+            let path = req.uri().path();
+            let method = req.method().as_str();
+            // set by the client or made up, returned in the response either way
+            let request_id = req.headers().get("x-request-id").and_then(|id| id.to_str().ok()).unwrap_or_default();
+            let status = tracing::field::Empty;
+            tracing::info_span!("http", request_id, method, path, status)
+        })
+        .on_response(|resp: &Response<_>, elapsed: Duration, span: &Span| {
+            let status = resp.status().as_u16();
+            span.record("status", status);
+            let level = if status >= 500 {
+                log::Level::Error
+            } else if status >= 400 {
+                log::Level::Warn
+            } else {
+                log::Level::Info
+            };
+            // tracing doesn't accept dynamic log levels
+            log::log!(level, "completed in {:?}", elapsed);
+        });
+
+    #[cfg(feature = "grpc")]
+    let grpc = match config.grpc_addr {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tracing::info!("serving gRPC on {}", addr);
+            let serving = grpc::serve(listener, states[0].clone(), config.auth.clone(), config.analyze_per_minute);
+            Some(tokio::spawn(async move {
+                if let Err(err) = serving.await {
+                    tracing::error!("gRPC server failed: {:?}", err);
+                }
+            }))
+        }
+        None => None,
+    };
+
+    let rustls = match &config.tls {
+        Some(tls) => Some(RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?),
+        None => None,
+    };
+    let listeners = listen::listeners(&config)?;
+
+    let rate_limits = ratelimit::RateLimits::default()
+        .with(&["/analyze", "/profiles/:name/analyze", "/search/upload"], config.analyze_per_minute)
+        .with(&["/thumbnail"], config.thumbnails_per_minute)
+        .with(&["/preview"], config.thumbnails_per_minute);
+    let app = app.layer(middleware::from_fn_with_state(Arc::new(rate_limits), ratelimit::guard));
+    let admin_token = config.admin_token.as_deref().map(Arc::from);
+    let app = app.layer(middleware::from_fn_with_state(admin_token, session::issue));
+    let app = match config.auth {
+        Some(auth) => {
+            let minted = states.clone();
+            let shares: auth::ShareCheck = Arc::new(move |token| minted.iter().any(|state| state.shares.get(token).is_some()));
+            app.layer(middleware::from_fn_with_state((Arc::new(auth), shares), auth::guard))
+        }
+        None => app,
+    };
+    // outside of auth, preflight requests carry no credentials
+    let app = match config.cors_origins.as_slice() {
+        [] => app,
+        origins => app.layer(cors_layer(origins)),
+    };
+    let app = app
+        .layer(http_logger)
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    #[cfg(unix)]
+    let sockets: Vec<_> = listeners.iter().filter_map(|listener| listener.bound().map(PathBuf::from)).collect();
+    // keep serving polls while draining
+    let mut servers = Vec::new();
+    for listener in listeners {
+        tracing::info!("serving {} on {}", if rustls.is_some() { "HTTPS" } else { "HTTP" }, listener);
+        servers.push(listen::serve(listener, app.clone(), rustls.clone())?);
+    }
+    tokio::select! {
+        (result, _, _) = futures::future::select_all(servers.iter_mut()) => return result?,
+        result = shutdown_signal() => result?,
+    }
+
+    tracing::info!("shutting down, waiting for running analyses");
+    let running: usize = futures::future::join_all(states.iter().map(drain)).await.into_iter().sum();
+    for server in &servers {
+        server.abort();
+    }
+    #[cfg(unix)]
+    for socket in sockets {
+        let _ = std::fs::remove_file(socket);
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = grpc {
+        grpc.abort();
+    }
+    if running > 0 {
+        // blocking tasks would keep the runtime from shutting down,
+        // they resume on the next start
+        tracing::warn!("{} analyses still running, exiting anyway", running);
+        std::process::exit(0);
+    }
+
+    tracing::info!("done");
+    Ok(())
+}
+
+/// how long running analyses may take to finish on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// resolves on SIGINT or SIGTERM
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Rejects new work and waits for running analyses within the grace period,
+/// queued ones are left for the next start. Returns how many are still running.
+async fn drain(state: &Arc<AppState>) -> usize {
+    state.draining.store(true, Ordering::Relaxed);
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+
+    let running = loop {
+        let (tx, rx) = oneshot::channel();
+        if state.task_sender.send(AnalyzeCommand::Drain(tx)).await.is_err() {
+            break 0;
+        }
+        let running = rx.await.unwrap_or(0);
+        if running == 0 || Instant::now() >= deadline {
+            break running;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    };
+
+    let engine = state.engine.clone();
+    match task::spawn_blocking(move || engine.flush_cache()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::error!("unable to flush the cache: {:?}", err),
+        Err(err) => tracing::error!("unable to flush the cache: {:?}", err),
+    }
+    running
+}
+
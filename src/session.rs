@@ -0,0 +1,81 @@
+//! Lightweight client sessions, so a browser only sees the analysis tasks it submitted.
+//! A cookie names the session, clients without one share the tasks submitted without one.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query, State},
+    http::{header, request::Parts, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use uuid::Uuid;
+
+use crate::auth;
+
+pub const COOKIE: &str = "session";
+/// carries the configured `admin-token`
+pub const ADMIN_HEADER: &str = "x-admin-token";
+/// a year, the cookie outlives browser restarts
+const MAX_AGE: u64 = 365 * 24 * 3600;
+
+/// marks requests carrying the admin token
+#[derive(Debug, Clone, Copy)]
+struct Admin;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Session {
+    /// of the cookie, `None` without one
+    pub id: Option<Uuid>,
+    /// sees the tasks of every session
+    pub admin: bool,
+    /// holds a share link, the share guard limits it to its task already
+    shared: bool,
+}
+
+impl Session {
+    /// whether the tasks submitted by `owner` are visible
+    pub fn sees(&self, owner: Option<Uuid>) -> bool {
+        self.admin || self.shared || self.id == owner
+    }
+}
+
+fn cookie(headers: &header::HeaderMap) -> Option<Uuid> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Session {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        let shared = Query::<HashMap<String, String>>::try_from_uri(&parts.uri)
+            .is_ok_and(|Query(query)| query.contains_key("token"));
+        Ok(Self { id: cookie(&parts.headers), admin: parts.extensions.get::<Admin>().is_some(), shared })
+    }
+}
+
+/// hands out a session cookie to clients without one, and tells admins apart
+pub async fn issue<B>(State(admin_token): State<Option<Arc<str>>>, mut request: Request<B>, next: Next<B>) -> Response {
+    let admin = request.headers().get(ADMIN_HEADER).zip(admin_token.as_deref());
+    if admin.is_some_and(|(given, token)| auth::constant_time_eq(given.as_bytes(), token.as_bytes())) {
+        request.extensions_mut().insert(Admin);
+    }
+    let new = cookie(request.headers()).is_none();
+
+    let mut response = next.run(request).await;
+    if new {
+        let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}", COOKIE, Uuid::new_v4(), MAX_AGE);
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+    }
+    response
+}
@@ -0,0 +1,211 @@
+//! Reads images over SFTP, e.g. from a NAS with SSH enabled, for `sftp://folder` paths below the folder of the configured endpoint.
+//! The analysis threads share one SSH connection, opened on first use and again once the server closed it.
+//! Symlinks aren't followed, they could lead back up the folder being scanned.
+
+use eyre::{Result, WrapErr};
+use image::ImageFormat;
+use russh::keys::{self, PrivateKey, PrivateKeyWithHashAlg, PublicKey, PublicKeyOrCertificate};
+use russh_sftp::{
+    client::{error::Error as SftpError, fs::Metadata, SftpSession},
+    protocol::StatusCode,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{runtime::Handle, sync::Mutex};
+use url::Url;
+
+use crate::analyzer::{FileError, FileInfo, Listing, SkipReason, SkippedFile};
+use crate::storage::Storage;
+use crate::webdav;
+
+pub const SCHEME: &str = "sftp";
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    /// `sftp://host:port/folder`, `sftp://` paths are below the folder, `sftp://host/~/folder` is below the login folder
+    pub endpoint: Url,
+    pub user: String,
+    /// either a password or an unencrypted OpenSSH private key file
+    pub password: Option<String>,
+    pub key: Option<PathBuf>,
+    /// public key of the server in OpenSSH format, looked up in `~/.ssh/known_hosts` when `None`
+    pub host_key: Option<String>,
+}
+
+/// Accepts the configured key of the server, or the one known for its host.
+struct Verifier {
+    host: String,
+    port: u16,
+    key: Option<PublicKey>,
+}
+
+impl russh::client::Handler for Verifier {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, server_key: &PublicKeyOrCertificate) -> Result<bool, Self::Error> {
+        let PublicKeyOrCertificate::PublicKey { key, .. } = server_key else {
+            return Ok(false);
+        };
+        match &self.key {
+            Some(known) => Ok(known.key_data() == key.key_data()),
+            None => Ok(keys::check_known_hosts(&self.host, self.port, key)?),
+        }
+    }
+}
+
+/// an SFTP session with the SSH connection it runs on
+struct Connection {
+    ssh: russh::client::Handle<Verifier>,
+    sftp: SftpSession,
+}
+
+/// Blocking calls for the analysis threads, on the runtime the client was created on.
+pub struct Client {
+    config: SftpConfig,
+    host_key: Option<PublicKey>,
+    key: Option<Arc<PrivateKey>>,
+    /// the folder of the endpoint on the server, relative to the login folder unless starting with a slash
+    root: String,
+    connection: Mutex<Option<Arc<Connection>>>,
+    runtime: Handle,
+}
+
+impl Client {
+    /// needs to be called on the runtime, doesn't connect yet
+    pub fn new(config: SftpConfig) -> Result<Self> {
+        let host_key = config.host_key.as_deref().map(PublicKey::from_openssh).transpose().wrap_err("invalid SFTP host key")?;
+        let key = match &config.key {
+            Some(path) => Some(Arc::new(keys::load_secret_key(path, None).wrap_err_with(|| format!("can't load the SFTP key {}", path.display()))?)),
+            None => None,
+        };
+        let path = webdav::decode(config.endpoint.path());
+        let root = match path.strip_prefix("/~") {
+            Some(folder) => folder.trim_matches('/').to_owned(),
+            None => format!("/{}", path.trim_matches('/')),
+        };
+        let runtime = Handle::try_current().wrap_err("the SFTP client needs a runtime")?;
+        Ok(Self { config, host_key, key, root, connection: Mutex::new(None), runtime })
+    }
+
+    /// the path on the server of a file or folder below the endpoint
+    fn remote(&self, relative: &str) -> String {
+        let relative = relative.trim_matches('/');
+        if relative.is_empty() {
+            return if self.root.is_empty() { ".".to_owned() } else { self.root.clone() };
+        }
+        match self.root.trim_end_matches('/') {
+            "" if self.root.is_empty() => relative.to_owned(),
+            root => format!("{}/{}", root, relative),
+        }
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let host = self.config.endpoint.host_str().unwrap_or_default().to_owned();
+        let port = self.config.endpoint.port().unwrap_or(22);
+        let verifier = Verifier { host: host.clone(), port, key: self.host_key.clone() };
+        let config = Arc::new(russh::client::Config::default());
+        let mut ssh = tokio::time::timeout(TIMEOUT, russh::client::connect(config, (host.as_str(), port), verifier))
+            .await
+            .map_err(|_| eyre::eyre!("the SFTP server {} didn't answer", host))?
+            .wrap_err_with(|| format!("can't connect to the SFTP server {}", host))?;
+        let user = self.config.user.clone();
+        let auth = match &self.key {
+            Some(key) => {
+                // only used for RSA keys
+                let hash = ssh.best_supported_rsa_hash().await?.flatten();
+                ssh.authenticate_publickey(user, PrivateKeyWithHashAlg::new(key.clone(), hash)).await?
+            }
+            None => ssh.authenticate_password(user, self.config.password.clone().unwrap_or_default()).await?,
+        };
+        eyre::ensure!(auth.success(), "the SFTP server {} refused the user {}", host, self.config.user);
+        let channel = ssh.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        let sftp = SftpSession::new(channel.into_stream()).await?;
+        Ok(Connection { ssh, sftp })
+    }
+
+    /// the open connection, or a new one
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(open) = connection.as_ref().filter(|open| !open.ssh.is_closed()) {
+            return Ok(open.clone());
+        }
+        let open = Arc::new(self.connect().await?);
+        *connection = Some(open.clone());
+        Ok(open)
+    }
+
+    /// the names and attributes of the files and folders right in a folder
+    fn list(&self, relative: &str) -> Result<Vec<(String, Metadata)>> {
+        let remote = self.remote(relative);
+        self.runtime.block_on(async {
+            let connection = self.connection().await?;
+            let entries = connection.sftp.read_dir(remote.as_str()).await.map_err(|err| error(err, &remote))?;
+            Ok(entries.map(|entry| (entry.file_name(), entry.metadata())).collect())
+        })
+    }
+
+    fn scan_rec(&self, listing: &mut Listing, relative: &str) -> Result<()> {
+        for (name, metadata) in self.list(relative)? {
+            let relative = match relative.trim_matches('/') {
+                "" => name.clone(),
+                folder => format!("{}/{}", folder, name),
+            };
+            let path = PathBuf::from(format!("{}://{}", SCHEME, relative));
+            let modified = metadata.mtime.map_or(0, |mtime| mtime as u64 * 1000);
+            let info = FileInfo { path: path.clone(), size: metadata.len(), date: modified, modified, storage_class: None, hash: None, checksum: None };
+            if name.starts_with('.') {
+                listing.skipped.push(SkippedFile::new(path, SkipReason::Hidden));
+            } else if metadata.is_dir() {
+                listing.dirs.push(FileInfo { size: 0, ..info });
+                if let Err(err) = self.scan_rec(listing, &relative) {
+                    tracing::error!("error reading folder content {:?}", path);
+                    listing.errors.push(FileError::new(path, err));
+                }
+            } else if let Some(format) = metadata.is_regular().then(|| ImageFormat::from_path(&path).ok()).flatten() {
+                *listing.formats.entry(format!("{:?}", format).to_lowercase()).or_default() += 1;
+                listing.files.push(info);
+            } else {
+                listing.skipped.push(SkippedFile::new(path, SkipReason::Unsupported));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// missing files as `io::ErrorKind::NotFound`, as for local ones
+fn error(err: SftpError, path: &str) -> eyre::Report {
+    let message = format!("SFTP failed for {}: {}", path, err);
+    match err {
+        SftpError::Status(status) if status.status_code == StatusCode::NoSuchFile => io::Error::new(io::ErrorKind::NotFound, message).into(),
+        _ => eyre::eyre!(message),
+    }
+}
+
+/// the part of an `sftp://` path below the endpoint
+fn relative(path: &Path) -> Result<&str> {
+    let relative = path.to_str().and_then(|path| path.strip_prefix(SCHEME)?.strip_prefix("://"));
+    relative.ok_or_else(|| eyre::eyre!("not an SFTP path: {}", path.display()))
+}
+
+impl Storage for Client {
+    fn scan(&self, dir: &Path) -> Result<Listing> {
+        let mut listing = Listing::default();
+        self.scan_rec(&mut listing, relative(dir)?)?;
+        listing.sort();
+        Ok(listing)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let remote = self.remote(relative(path)?);
+        self.runtime.block_on(async {
+            let connection = self.connection().await?;
+            connection.sftp.read(remote.as_str()).await.map_err(|err| error(err, &remote))
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use eyre::Result;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use utoipa::IntoParams;
+
+/// `fields=` query parameter selecting which parts of a response to return,
+/// e.g. `fields=path,size` or `fields=type,data.groups.files.path`.
+/// Arrays are transparent: a field is selected in every element.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ShapeParams {
+    fields: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct FieldTree {
+    whole: bool,
+    children: BTreeMap<String, FieldTree>,
+}
+
+impl FieldTree {
+    fn parse(fields: &str) -> Self {
+        let mut root = Self::default();
+
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let node = field
+                .split('.')
+                .fold(&mut root, |node, key| node.children.entry(key.to_owned()).or_default());
+            node.whole = true;
+        }
+
+        root
+    }
+
+    fn project(&self, value: Value) -> Value {
+        if self.whole {
+            return value;
+        }
+
+        match value {
+            Value::Array(items) => Value::Array(items.into_iter().map(|v| self.project(v)).collect()),
+            Value::Object(map) => Value::Object(
+                map
+                    .into_iter()
+                    .filter_map(|(k, v)| {
+                        let child = self.children.get(&k)?;
+                        Some((k, child.project(v)))
+                    })
+                    .collect()
+            ),
+            other => other,
+        }
+    }
+}
+
+/// `offset=` and `limit=` query parameters selecting a slice of a list,
+/// the whole list is counted in the `X-Total-Count` header.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageParams {
+    #[serde(default)]
+    offset: usize,
+    /// everything from the offset when not given
+    limit: Option<usize>,
+}
+
+pub const TOTAL_COUNT: &str = "x-total-count";
+
+impl PageParams {
+    /// the slice of the items, empty past their end
+    pub fn page<'a, T>(&self, items: &'a [T]) -> &'a [T] {
+        let start = self.offset.min(items.len());
+        let end = self.limit.map_or(items.len(), |limit| start.saturating_add(limit).min(items.len()));
+        &items[start..end]
+    }
+}
+
+/// serializes the response keeping only the requested fields
+pub fn shape<T: Serialize>(value: &T, params: &ShapeParams) -> Result<Value> {
+    let value = serde_json::to_value(value)?;
+    Ok(match &params.fields {
+        Some(fields) => FieldTree::parse(fields).project(value),
+        None => value,
+    })
+}
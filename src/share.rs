@@ -0,0 +1,81 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::volumes;
+
+/// Claims of a share link token: read-only access to a single task
+/// and to the images under the analyzed folder.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareToken {
+    task_id: Uuid,
+    root: PathBuf,
+    #[serde(skip)]
+    expires: SystemTime,
+}
+
+impl ShareToken {
+    fn contains(&self, path: &Path) -> bool {
+        // resolve `..` and symlinks before comparing
+        match volumes::canonicalize(path) {
+            Ok(path) => path.starts_with(&self.root),
+            Err(_) => false,
+        }
+    }
+
+    /// whether the token lets a GET request of the route through
+    pub fn permits(&self, route: &str, query: &HashMap<String, String>) -> bool {
+        match route {
+            "/" => true,
+            r if r.starts_with("/assets/") || r.starts_with("/static/") => true,
+            "/poll" | "/subscribe" => query
+                .get("taskId")
+                .and_then(|id| id.parse().ok())
+                .is_some_and(|id: Uuid| id == self.task_id),
+            "/image" | "/thumbnail" | "/metadata" => query
+                .get("path")
+                .is_some_and(|path| self.contains(Path::new(path))),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Shares {
+    tokens: RwLock<HashMap<String, ShareToken>>,
+}
+
+impl Shares {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mint(&self, task_id: Uuid, root: &Path, ttl: Duration) -> std::io::Result<(String, SystemTime)> {
+        let token = Uuid::new_v4().simple().to_string();
+        let expires = SystemTime::now()
+            .checked_add(ttl)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "share link expiry out of range"))?;
+        let claims = ShareToken {
+            task_id,
+            root: volumes::canonicalize(root)?,
+            expires,
+        };
+
+        self.tokens.write().unwrap().insert(token.clone(), claims);
+        Ok((token, expires))
+    }
+
+    /// claims of a token, `None` if it is unknown or expired
+    pub fn get(&self, token: &str) -> Option<ShareToken> {
+        let now = SystemTime::now();
+        let mut tokens = self.tokens.write().unwrap();
+        tokens.retain(|_, claims| claims.expires > now);
+        tokens.get(token).cloned()
+    }
+}
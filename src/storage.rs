@@ -0,0 +1,132 @@
+//! Where images are read from: local folders, or remote storage named by the scheme of the path,
+//! e.g. `s3://bucket/prefix`, `webdav://photos` or `sftp://photos`. Remote paths skip the libraries, the operator configures them.
+
+use eyre::Result;
+use sha2::{Digest, Sha256};
+use image::{DynamicImage, ImageFormat, ImageResult};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, Cursor},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::analyzer::{self, Listing};
+use crate::downscale;
+use crate::error::{ErrorBody, ErrorCode};
+use crate::metadata;
+use crate::sandbox::Sandbox;
+
+pub trait Storage: Send + Sync {
+    /// images below `dir` with the folders and what was left out, recursively
+    fn scan(&self, dir: &Path) -> Result<Listing>;
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// SHA-256 of the content, for content addressed caching
+    fn checksum(&self, path: &Path) -> Result<String> {
+        Ok(sha256::digest(self.read(path)?.as_slice()))
+    }
+
+    /// decoded to fit into `size` x `size`, and whether the image is cut short
+    fn open(&self, path: &Path, size: u32) -> ImageResult<(DynamicImage, io::Result<bool>)> {
+        decode(&self.read(path).map_err(|err| io::Error::other(format!("{:#}", err)))?, size)
+    }
+
+    /// bytes decoding the image like `open` takes, `None` when unknown without reading all of it
+    fn decoded_size(&self, _path: &Path, _size: u32) -> Option<u64> {
+        None
+    }
+
+    /// the embedded preview decoded like `open`, and whether the image itself is cut short,
+    /// `None` without one. Remote files would be downloaded in full to find it, they have none
+    fn preview(&self, _path: &Path, _size: u32) -> Option<(DynamicImage, io::Result<bool>)> {
+        None
+    }
+}
+
+/// an image read whole, as `open` has it
+pub(crate) fn decode(data: &[u8], size: u32) -> ImageResult<(DynamicImage, io::Result<bool>)> {
+    let reader = image::io::Reader::new(Cursor::new(data)).with_guessed_format()?;
+    let (image, format) = analyzer::decode_image(reader, size)?;
+    Ok((image, Ok(analyzer::is_cut_short(data, format))))
+}
+
+/// SHA-256 of the dimensions, the color type and the pixels of an image decoded in full,
+/// the same for files differing in their metadata or format only
+pub(crate) fn pixel_checksum(image: &DynamicImage) -> String {
+    let header = format!("{}x{} {:?}\n", image.width(), image.height(), image.color());
+    let digest = Sha256::new().chain_update(header).chain_update(image.as_bytes()).finalize();
+    hex::encode(digest)
+}
+
+/// the folders of the server, confined to the libraries
+pub struct Local(pub Arc<Sandbox>);
+
+impl Storage for Local {
+    fn scan(&self, dir: &Path) -> Result<Listing> {
+        analyzer::scan_dir(dir, &self.0)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        Ok(fs::read(path)?)
+    }
+
+    fn checksum(&self, path: &Path) -> Result<String> {
+        Ok(sha256::try_digest(path)?)
+    }
+
+    /// JPEGs are scaled while decoding, and only the tail is read for the end marker
+    fn open(&self, path: &Path, size: u32) -> ImageResult<(DynamicImage, io::Result<bool>)> {
+        let (image, format) = analyzer::open_image(path, size)?;
+        Ok((image, analyzer::is_truncated(path, format)))
+    }
+
+    /// from the header only
+    fn decoded_size(&self, path: &Path, size: u32) -> Option<u64> {
+        let reader = image::io::Reader::open(path).ok()?.with_guessed_format().ok()?;
+        // interlaced PNGs and the TIFFs `downscale` doesn't read are decoded at full size
+        let format = reader.format().filter(|&format| match format {
+            ImageFormat::Png | ImageFormat::Tiff => File::open(path).is_ok_and(|file| downscale::scales(BufReader::new(file), format)),
+            _ => true,
+        });
+        Some(analyzer::decoded_size(format, reader.into_dimensions().ok()?, size))
+    }
+
+    fn preview(&self, path: &Path, size: u32) -> Option<(DynamicImage, io::Result<bool>)> {
+        let preview = metadata::read_preview(path)?;
+        let reader = image::io::Reader::with_format(Cursor::new(preview), ImageFormat::Jpeg);
+        let (image, _) = analyzer::decode_image(reader, size).ok()?;
+        let format = analyzer::sniff_format(path).ok().flatten();
+        Some((image, analyzer::is_truncated(path, format)))
+    }
+}
+
+/// the scheme of `scheme://...` paths
+fn scheme(path: &Path) -> Option<&str> {
+    let (scheme, _) = path.to_str()?.split_once("://")?;
+    scheme.chars().all(|c| c.is_ascii_alphanumeric()).then_some(scheme)
+}
+
+/// remote storage by the scheme of its paths
+#[derive(Clone, Default)]
+pub struct Remotes(HashMap<&'static str, Arc<dyn Storage>>);
+
+impl Remotes {
+    pub fn with(mut self, scheme: &'static str, storage: Arc<dyn Storage>) -> Self {
+        self.0.insert(scheme, storage);
+        self
+    }
+
+    /// `None` for local paths, fails for schemes nothing is configured for
+    pub fn get(&self, path: &Path) -> Result<Option<Arc<dyn Storage>>, ErrorBody> {
+        let Some(scheme) = scheme(path) else {
+            return Ok(None);
+        };
+        match self.0.get(scheme) {
+            Some(storage) => Ok(Some(storage.clone())),
+            None => Err(ErrorBody::new(ErrorCode::BadRequest, format!("no storage is configured for {}:// paths", scheme))),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Analysis tasks kept on disk, so a restarted server still lists finished ones
+//! and picks up the ones that were running. The hashes of completed analyses are kept
+//! next to them, so their files can be grouped again without hashing them again.
+
+use eyre::Result;
+use image_hasher::ImageHash;
+use serde::{Serialize, Deserialize};
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use uuid::Uuid;
+
+use crate::analyzer::{self, AnalyzeRequest, AnalyzeResult, FileInfo, Hashes};
+use crate::error::ErrorCode;
+use crate::schema;
+
+/// schema version of stored tasks
+const VERSION: u32 = 1;
+
+/// how a task ended, `T` is the result of a completed one
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Outcome<T> {
+    Completed { data: T },
+    Failed {
+        error: String,
+        /// internal for tasks stored before there were codes
+        #[serde(default)]
+        code: ErrorCode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<PathBuf>,
+    },
+    Cancelled,
+    TimedOut,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredTask<T = AnalyzeResult> {
+    pub id: Uuid,
+    pub request: AnalyzeRequest,
+    /// ms since the epoch
+    pub submitted: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<u64>,
+    /// `None` while the task runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome<T>>,
+}
+
+pub fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+pub fn from_millis(millis: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// a hashed file of a task, the hash in base64
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StoredHash {
+    file: FileInfo,
+    hash: String,
+}
+
+/// the hashes along with the `HASH_VERSION` they were computed with, those kept before are a bare list of the first one
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum StoredHashes {
+    #[serde(rename_all = "camelCase")]
+    Versioned { hash_version: u32, hashes: Vec<StoredHash> },
+    First(Vec<StoredHash>),
+}
+
+impl StoredHashes {
+    pub(crate) fn new<'a>(hashes: impl IntoIterator<Item = (&'a FileInfo, &'a ImageHash)>) -> Self {
+        let hashes = hashes.into_iter().map(|(file, hash)| StoredHash { file: file.clone(), hash: hash.to_base64() }).collect();
+        Self::Versioned { hash_version: analyzer::HASH_VERSION, hashes }
+    }
+
+    /// `None` for hashes computed another way, the files have to be hashed again
+    pub(crate) fn hashes(self) -> Result<Option<Hashes>> {
+        let (version, stored) = match self {
+            Self::Versioned { hash_version, hashes } => (hash_version, hashes),
+            Self::First(hashes) => (1, hashes),
+        };
+        if version != analyzer::HASH_VERSION {
+            return Ok(None);
+        }
+        stored
+            .into_iter()
+            .map(|StoredHash { file, hash }| {
+                let hash = ImageHash::from_base64(&hash).map_err(|err| eyre::eyre!("invalid stored hash: {:?}", err))?;
+                Ok((file, hash))
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+}
+
+/// hashes as JSON, what tasks and runs keep them as
+pub(crate) fn encode_hashes(hashes: &Hashes) -> Result<String> {
+    Ok(serde_json::to_string(&StoredHashes::new(hashes.iter().map(|(file, hash)| (file, hash))))?)
+}
+
+/// `None` for hashes computed another way
+pub(crate) fn decode_hashes(json: &str) -> Result<Option<Hashes>> {
+    serde_json::from_str::<StoredHashes>(json)?.hashes()
+}
+
+/// one JSON file per task, and one of its hashes
+#[derive(Debug, Clone)]
+pub struct TaskStore {
+    dir: PathBuf,
+}
+
+impl TaskStore {
+    pub fn new<T>(dir: T) -> Self
+    where
+        PathBuf: From<T>
+    {
+        Self { dir: PathBuf::from(dir) }
+    }
+
+    fn path(&self, id: &Uuid) -> PathBuf {
+        self.dir.join(id.to_string()).with_extension("json")
+    }
+
+    fn hashes_path(&self, id: &Uuid) -> PathBuf {
+        self.dir.join(id.to_string()).with_extension("hashes")
+    }
+
+    pub fn save_hashes(&self, id: &Uuid, hashes: &Hashes) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.hashes_path(id), encode_hashes(hashes)?)?;
+        Ok(())
+    }
+
+    /// `None` unless the task completed with hashes kept, of the current `HASH_VERSION`
+    pub fn load_hashes(&self, id: &Uuid) -> Result<Option<Hashes>> {
+        let path = self.hashes_path(id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        decode_hashes(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<T: Serialize>(&self, task: &StoredTask<T>) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(&task.id), schema::encode(task, VERSION)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &Uuid) -> Result<()> {
+        for path in [self.path(id), self.hashes_path(id)] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// all stored tasks in the order they were submitted
+    pub fn load(&self) -> Result<Vec<StoredTask>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut tasks = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension != "json") {
+                continue;
+            }
+            let content = fs::read(&path)?;
+            match schema::decode(&content, VERSION, schema::unversioned_to_v1) {
+                Ok((task, _)) => tasks.push(task),
+                Err(err) => tracing::error!(path = path.to_str(), "invalid stored task: {:?}", err),
+            }
+        }
+
+        tasks.sort_by_key(|task: &StoredTask| task.submitted);
+        Ok(tasks)
+    }
+}
@@ -0,0 +1,164 @@
+//! Households sharing an instance, configured in `tenants.json`: each has its own data directory and libraries,
+//! requests are routed to its state by the API key they carry, or by the share link token without one.
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use eyre::{bail, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tower::ServiceExt;
+
+use crate::auth::constant_time_eq;
+use crate::error::ErrorBody;
+use crate::manager::TaskLimits;
+use crate::assets::Assets;
+use crate::webhook::Webhooks;
+use crate::server::{create_state, AppState, StateOptions};
+use crate::resolve::KeepRules;
+
+/// A household sharing the instance, as configured in `tenants.json`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantConfig {
+    /// also the name of the tenant data directory
+    id: String,
+    api_keys: Vec<String>,
+    /// folders the tenant may access, everything else is off limits
+    libraries: Vec<PathBuf>,
+}
+
+struct Tenant {
+    api_keys: Vec<String>,
+    state: Arc<AppState>,
+    // routers aren't `Sync`, requests are served by a clone
+    router: Mutex<Router>,
+}
+
+/// Tenants with their own data directories (roots, cache, tasks, removed files),
+/// requests are routed by API key to the state of a single tenant.
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+    public: Mutex<Router>,
+}
+
+/// tenant configuration, `None` when the instance is single-tenant
+pub fn load(file: &Path) -> Result<Option<Vec<TenantConfig>>> {
+    if !file.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read(file)?;
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Tenants {
+    pub fn states(&self) -> Vec<Arc<AppState>> {
+        self.tenants.iter().map(|tenant| tenant.state.clone()).collect()
+    }
+
+    pub fn new(
+        data_dir: &Path,
+        configs: Vec<TenantConfig>,
+        limits: TaskLimits,
+        webhooks: Webhooks,
+        keep_rules: KeepRules,
+        assets: &Assets,
+    ) -> Result<Self> {
+        let mut tenants: Vec<Tenant> = Vec::new();
+
+        for config in configs {
+            if !is_valid_id(&config.id) {
+                bail!("invalid tenant id {:?}", config.id);
+            }
+            if config.libraries.is_empty() {
+                bail!("tenant {} has no libraries", config.id);
+            }
+            let taken = tenants.iter().flat_map(|t| &t.api_keys).any(|key| config.api_keys.contains(key));
+            if taken || config.api_keys.iter().any(|key| key.is_empty()) {
+                bail!("tenant {} has an empty or shared API key", config.id);
+            }
+
+            let dir = data_dir.join("tenants").join(&config.id);
+            fs::create_dir_all(&dir)?;
+            // tenants are confined to their libraries, remote storage isn't read and nothing is watched for them
+            let options = StateOptions {
+                libraries: Some(config.libraries.clone()),
+                limits,
+                webhooks: webhooks.clone(),
+                keep_rules: keep_rules.clone(),
+                ..StateOptions::default()
+            };
+            let state = create_state(&dir, options)?;
+            tracing::info!(tenant = config.id, "tenant loaded");
+            tenants.push(Tenant {
+                api_keys: config.api_keys,
+                router: Mutex::new(crate::server::app(state.clone())),
+                state,
+            });
+        }
+
+        Ok(Self { tenants, public: Mutex::new(assets.routes()) })
+    }
+
+    fn by_key(&self, key: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|t| t.api_keys.iter().any(|k| constant_time_eq(k.as_bytes(), key.as_bytes())))
+    }
+
+    /// share links are opened without an API key
+    fn by_share(&self, token: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|t| t.state.shares.get(token).is_some())
+    }
+
+    fn resolve<B>(&self, request: &Request<B>) -> Option<&Tenant> {
+        let query = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+            .map(|Query(query)| query)
+            .unwrap_or_default();
+
+        // browsers can't set headers on image loads, hence the query parameter
+        let key = request
+            .headers()
+            .get("x-api-key")
+            .and_then(|key| key.to_str().ok())
+            .or_else(|| query.get("apiKey").map(String::as_str));
+
+        match (key, query.get("token")) {
+            (Some(key), _) => self.by_key(key),
+            (None, Some(token)) => self.by_share(token),
+            (None, None) => None,
+        }
+    }
+}
+
+async fn dispatch(State(tenants): State<Arc<Tenants>>, request: Request<Body>) -> Response {
+    let router = match tenants.resolve(&request) {
+        Some(tenant) => tenant.router.lock().unwrap().clone(),
+        None => {
+            let path = request.uri().path();
+            let public = path == "/" || path.starts_with("/assets/") || path.starts_with("/static/");
+            if !public {
+                return ErrorBody::of_status(StatusCode::UNAUTHORIZED).into_response();
+            }
+            tenants.public.lock().unwrap().clone()
+        }
+    };
+
+    router.oneshot(request).await.into_response()
+}
+
+pub fn app(tenants: Arc<Tenants>) -> Router {
+    Router::new().fallback(dispatch).with_state(tenants)
+}
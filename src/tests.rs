@@ -0,0 +1,3586 @@
+//! End-to-end tests driving the HTTP API over synthetic fixtures.
+
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    Router,
+};
+use image::ImageFormat;
+use serde_json::Value;
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc};
+use tower::ServiceExt;
+
+use crate::analyzer;
+use crate::server::{app, create_state, open_engine, AppState, StateOptions};
+use crate::tenant::Tenants;
+use crate::fixtures::{self, FixtureKind};
+use crate::manager::TaskLimits;
+use crate::protect::Protected;
+use crate::resolve::{KeepRule, KeepRules};
+use crate::roots::StorageClass;
+use crate::storage::Remotes;
+use crate::watch::{WatchOptions, Watcher};
+use crate::webhook::Webhooks;
+
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(20);
+
+/// a server over the data folder as `serve` sets it up without options, tests needing others pass `StateOptions`
+fn test_state(data: &std::path::Path) -> Arc<AppState> {
+    create_state(data, StateOptions::default()).unwrap()
+}
+
+fn test_app(data: &std::path::Path) -> Router {
+    app(test_state(data))
+}
+
+async fn call(app: &Router, method: Method, uri: &str) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    (status, value)
+}
+
+async fn call_json(app: &Router, method: Method, uri: &str, body: Value) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    (status, value)
+}
+
+fn paths(files: &Value) -> BTreeSet<PathBuf> {
+    files
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| PathBuf::from(file["path"].as_str().unwrap()))
+        .collect()
+}
+
+async fn analyze(app: &Router, path: &std::path::Path) -> Value {
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash&hashSize=8", path.display());
+    let (status, task) = call(app, Method::POST, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    loop {
+        let (status, resp) = call(app, Method::GET, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            "Completed" => return resp["data"].clone(),
+            other => panic!("analysis {}: {}", other, resp),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn groups_synthetic_fixtures() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let result = analyze(&app, library.path()).await;
+
+    let groups: BTreeSet<BTreeSet<PathBuf>> = result["groups"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|group| paths(&group["files"]))
+        .collect();
+    assert_eq!(groups, fixtures::expected_groups(&fixtures));
+
+    let of_kind = |kind| -> BTreeSet<PathBuf> {
+        fixtures.iter().filter(|f| f.kind == kind).map(|f| f.path.clone()).collect()
+    };
+    assert_eq!(paths(&result["corrupted"]), of_kind(FixtureKind::Truncated));
+    assert_eq!(paths(&result["skipped"]), of_kind(FixtureKind::NotAnImage));
+    assert!(result["errors"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_distances_within_groups() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    for group in analyze(&app, library.path()).await["groups"].as_array().unwrap() {
+        let files = group["files"].as_array().unwrap().len();
+        let matrix: Vec<Vec<u32>> = serde_json::from_value(group["matrix"].clone()).unwrap();
+        assert_eq!(matrix.len(), files);
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row[i], 0);
+            assert_eq!(row[0], group["distances"][i]);
+            assert!((0..files).all(|j| row[j] == matrix[j][i]));
+            let nearest = &group["nearest"][i];
+            let closest = (0..files).filter(|&j| j != i).map(|j| row[j]).min().unwrap();
+            assert_ne!(nearest["index"], i);
+            assert_eq!(nearest["distance"], closest);
+        }
+        let total = |i: usize| matrix[i].iter().sum::<u32>();
+        let representative = group["representative"].as_u64().unwrap() as usize;
+        assert!((0..files).all(|i| total(representative) <= total(i)), "{}", group);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_the_match_graph() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    assert!(analyze(&app, library.path()).await["groups"][0].get("edges").is_none());
+
+    let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "DHash", "hashSize": 8, "edges": true });
+    let (_, task) = call_json(&app, Method::POST, "/analyze", body).await;
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    let groups = loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        if resp["type"] == "Completed" {
+            break resp["data"]["groups"].clone();
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+    for group in groups.as_array().unwrap() {
+        // the edges connect every file of the group
+        let mut reached = BTreeSet::from([0]);
+        let edges = group["edges"].as_array().unwrap();
+        while let Some(edge) = edges.iter().find(|edge| {
+            let (from, to) = (edge["from"].as_u64().unwrap(), edge["to"].as_u64().unwrap());
+            reached.contains(&from) != reached.contains(&to)
+        }) {
+            reached.extend([edge["from"].as_u64().unwrap(), edge["to"].as_u64().unwrap()]);
+        }
+        assert_eq!(reached.len(), group["files"].as_array().unwrap().len(), "{}", group);
+        for edge in edges {
+            assert!(edge["distance"].as_u64().unwrap() <= 10);
+            assert_eq!(edge["distance"], group["matrix"][edge["from"].as_u64().unwrap() as usize][edge["to"].as_u64().unwrap() as usize]);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_missing_folder() {
+    let data = tempfile::tempdir().unwrap();
+    let app = test_app(data.path());
+    let missing = data.path().join("missing");
+
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash", missing.display());
+    let (status, error) = call(&app, Method::POST, &uri).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error["code"], "notFound");
+    assert!(error["message"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn takes_analyze_options_as_json() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let app = test_app(data.path());
+
+    let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "PHash", "hashSize": 16 });
+    let (status, task) = call_json(&app, Method::POST, "/analyze", body).await;
+    assert_eq!(status, StatusCode::OK);
+    // the same scan, so the same task
+    let uri = format!("/analyze?path={}&dist=10&hashType=PHash&hashSize=16", library.path().display());
+    assert_eq!(call(&app, Method::POST, &uri).await.1, task);
+
+    let invalid = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "PHash", "hashSize": 12 });
+    let (status, error) = call_json(&app, Method::POST, "/analyze", invalid).await;
+    assert_eq!((status, &error["code"]), (StatusCode::BAD_REQUEST, &Value::from("badRequest")));
+    assert!(error["message"].as_str().unwrap().contains("hash size"), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn notifies_the_callback_when_done() {
+    let (tx, mut notifications) = tokio::sync::mpsc::unbounded_channel();
+    let receiver = Router::new().route(
+        "/done",
+        axum::routing::post(move |axum::Json(body): axum::Json<Value>| async move {
+            tx.send(body).unwrap();
+        }),
+    );
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let callback = format!("http://{}/done", listener.local_addr().unwrap());
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(receiver.into_make_service()));
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let webhooks = Webhooks::new(None, vec!["127.0.0.1".to_owned()]);
+    let app = app(create_state(data.path(), StateOptions { webhooks, ..StateOptions::default() }).unwrap());
+    // only the allowed hosts are called back
+    for refused in ["http://169.254.169.254/latest/meta-data", "file:///etc/passwd"] {
+        let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "DHash", "callback": refused });
+        assert_eq!(call_json(&app, Method::POST, "/analyze", body).await.0, StatusCode::BAD_REQUEST, "{}", refused);
+    }
+    let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "DHash", "callback": callback });
+    let (status, task) = call_json(&app, Method::POST, "/analyze", body).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let notification = tokio::time::timeout(std::time::Duration::from_secs(30), notifications.recv()).await.unwrap().unwrap();
+    assert_eq!(notification["taskId"], task["taskId"]);
+    assert_eq!(notification["status"], "completed");
+    let groups = fixtures::expected_groups(&fixtures).len();
+    assert_eq!(notification["summary"]["groups"], groups);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn analyzes_s3_buckets() {
+    use axum::extract::{Path, Query, State};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let key = |path: &std::path::Path| format!("photos/{}", path.strip_prefix(library.path()).unwrap().display());
+    let objects: Arc<HashMap<String, Vec<u8>>> = Arc::new(fixtures.iter().map(|f| (key(&f.path), std::fs::read(&f.path).unwrap())).collect());
+    let reads = Arc::new(AtomicUsize::new(0));
+
+    /// objects by key, and the GETs of objects
+    type Bucket = (Arc<HashMap<String, Vec<u8>>>, Arc<AtomicUsize>);
+    async fn list(State((objects, _)): State<Bucket>, Query(query): Query<HashMap<String, String>>) -> String {
+        let contents: String = objects
+            .iter()
+            .filter(|(key, _)| key.starts_with(&query["prefix"]))
+            .map(|(key, data)| format!(
+                "<Contents><Key>{}</Key><LastModified>2024-01-31T12:00:00.000Z</LastModified><ETag>&quot;{}&quot;</ETag><Size>{}</Size></Contents>",
+                key, sha256::digest(data.as_slice()), data.len(),
+            ))
+            .collect();
+        format!("<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>", contents)
+    }
+    async fn object(
+        State((objects, reads)): State<Bucket>,
+        Path(key): Path<String>,
+        headers: axum::http::HeaderMap,
+    ) -> Result<Vec<u8>, StatusCode> {
+        let signed = headers["authorization"].to_str().unwrap().starts_with("AWS4-HMAC-SHA256 Credential=access/");
+        assert!(signed && headers.contains_key("x-amz-date"));
+        reads.fetch_add(1, Ordering::SeqCst);
+        objects.get(&key).cloned().ok_or(StatusCode::NOT_FOUND)
+    }
+    let s3 = Router::new()
+        .route("/bucket", axum::routing::get(list))
+        .route("/bucket/*key", axum::routing::get(object))
+        .with_state((objects, reads.clone()));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(s3.into_make_service()));
+
+    let data = tempfile::tempdir().unwrap();
+    let config = crate::s3::S3Config { endpoint, region: "us-east-1".to_owned(), access_key: "access".to_owned(), secret_key: "secret".to_owned() };
+    let client = crate::s3::Client::new(config).unwrap();
+    let app = app(create_state(data.path(), StateOptions { remotes: Remotes::default().with(crate::s3::SCHEME, Arc::new(client)), ..StateOptions::default() }).unwrap());
+    let object = |path: &std::path::Path| PathBuf::from(format!("s3://bucket/{}", key(path)));
+
+    let result = analyze(&app, std::path::Path::new("s3://bucket/photos")).await;
+    let groups: BTreeSet<BTreeSet<PathBuf>> = result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    let expected = fixtures::expected_groups(&fixtures).iter().map(|group| group.iter().map(|path| object(path)).collect()).collect();
+    assert_eq!(groups, expected);
+    let truncated: BTreeSet<PathBuf> = fixtures.iter().filter(|f| f.kind == FixtureKind::Truncated).map(|f| object(&f.path)).collect();
+    assert_eq!(paths(&result["corrupted"]), truncated);
+
+    // hashes are cached by ETag, only the corrupted objects are read again
+    reads.store(0, Ordering::SeqCst);
+    analyze(&app, std::path::Path::new("s3://bucket/photos")).await;
+    assert_eq!(reads.load(Ordering::SeqCst), truncated.len());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn analyzes_webdav_shares() {
+    use axum::extract::{Path, State};
+    use axum::response::IntoResponse;
+
+    let share = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(&share.path().join("photos")).unwrap();
+
+    async fn dav(State(share): State<Arc<PathBuf>>, Path(path): Path<String>, method: Method) -> axum::response::Response {
+        let file = share.join(&path);
+        if method != "PROPFIND" {
+            return std::fs::read(&file).map_or(StatusCode::NOT_FOUND.into_response(), IntoResponse::into_response);
+        }
+        let entry = |file: &std::path::Path| {
+            let metadata = std::fs::metadata(file).unwrap();
+            let mut href = url::Url::parse("http://nas/dav").unwrap();
+            href.path_segments_mut().unwrap().extend(file.strip_prefix(share.as_path()).unwrap().iter().map(|part| part.to_str().unwrap()));
+            let props = if metadata.is_dir() {
+                "<D:resourcetype><D:collection/></D:resourcetype>".to_owned()
+            } else {
+                let modified = httpdate::fmt_http_date(metadata.modified().unwrap());
+                format!(
+                    "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getlastmodified>{}</D:getlastmodified><lp1:getetag>\"{}\"</lp1:getetag>",
+                    metadata.len(), modified, metadata.len(),
+                )
+            };
+            format!("<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop></D:propstat></D:response>", href, props)
+        };
+        let mut body = String::from(r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:" xmlns:lp1="DAV:">"#);
+        body += &entry(&file);
+        for child in std::fs::read_dir(&file).unwrap() {
+            body += &entry(&child.unwrap().path());
+        }
+        body += "</D:multistatus>";
+        (StatusCode::MULTI_STATUS, body).into_response()
+    }
+    let server = Router::new().route("/dav/*path", axum::routing::any(dav)).with_state(Arc::new(share.path().to_owned()));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/dav", listener.local_addr().unwrap()).parse().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(server.into_make_service()));
+
+    let data = tempfile::tempdir().unwrap();
+    let client = crate::webdav::Client::new(crate::webdav::WebDavConfig { endpoint, user: None, password: None }).unwrap();
+    let remotes = Remotes::default().with(crate::webdav::SCHEME, Arc::new(client));
+    let app = app(create_state(data.path(), StateOptions { remotes, ..StateOptions::default() }).unwrap());
+    let remote = |path: &std::path::Path| PathBuf::from(format!("webdav://{}", path.strip_prefix(share.path()).unwrap().display()));
+
+    let result = analyze(&app, std::path::Path::new("webdav://photos")).await;
+    let groups: BTreeSet<BTreeSet<PathBuf>> = result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    let expected = fixtures::expected_groups(&fixtures).iter().map(|group| group.iter().map(|path| remote(path)).collect()).collect();
+    assert_eq!(groups, expected);
+
+    let (status, dirs) = call(&app, Method::GET, "/list_folder?path=webdav://photos&onlyDirs=true").await;
+    assert_eq!(status, StatusCode::OK);
+    let expected = ["copies", "originals", "unrelated"].map(|dir| PathBuf::from(format!("webdav://photos/{}", dir)));
+    assert_eq!(paths(&dirs), BTreeSet::from(expected));
+
+    let original = &fixtures.iter().find(|f| f.kind == FixtureKind::Original).unwrap().path;
+    let uri = format!("/image?path={}", remote(original).display());
+    let response = app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, std::fs::read(original).unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn analyzes_sftp_shares() {
+    use russh::keys::{ssh_key::private::Ed25519Keypair, PrivateKey};
+    use russh::server::{Auth, ChannelOpenHandle, Msg, Session};
+    use russh::{Channel, ChannelId};
+    use russh_sftp::protocol::{Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode as SftpStatus, Version};
+    use std::collections::{HashMap, HashSet};
+    use std::io::{Read, Seek, SeekFrom};
+
+    let share = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(&share.path().join("photos")).unwrap();
+
+    /// an SSH server of the SFTP subsystem only
+    #[derive(Default)]
+    struct Ssh(HashMap<ChannelId, Channel<Msg>>);
+
+    impl russh::server::Handler for Ssh {
+        type Error = russh::Error;
+
+        async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+            Ok(if (user, password) == ("photos", "secret") { Auth::Accept } else { Auth::reject() })
+        }
+
+        async fn channel_open_session(&mut self, channel: Channel<Msg>, reply: ChannelOpenHandle, _: &mut Session) -> Result<(), Self::Error> {
+            self.0.insert(channel.id(), channel);
+            reply.accept().await;
+            Ok(())
+        }
+
+        async fn subsystem_request(&mut self, id: ChannelId, name: &str, session: &mut Session) -> Result<(), Self::Error> {
+            match self.0.remove(&id).filter(|_| name == "sftp") {
+                Some(channel) => {
+                    session.channel_success(id)?;
+                    russh_sftp::server::run(channel.into_stream(), Sftp::default()).await;
+                }
+                None => session.channel_failure(id)?,
+            }
+            Ok(())
+        }
+    }
+
+    /// local paths as they are, handles are the paths of the opened files and folders
+    #[derive(Default)]
+    struct Sftp {
+        listed: HashSet<String>,
+    }
+
+    impl russh_sftp::server::Handler for Sftp {
+        type Error = SftpStatus;
+
+        fn unimplemented(&self) -> Self::Error {
+            SftpStatus::OpUnsupported
+        }
+
+        async fn init(&mut self, _: u32, _: HashMap<String, String>) -> Result<Version, Self::Error> {
+            Ok(Version::new())
+        }
+
+        async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+            match std::path::Path::new(&path).is_dir() {
+                true => Ok(Handle { id, handle: path }),
+                false => Err(SftpStatus::NoSuchFile),
+            }
+        }
+
+        async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+            if !self.listed.insert(handle.clone()) {
+                return Err(SftpStatus::Eof);
+            }
+            let files = std::fs::read_dir(&handle).unwrap().map(|entry| {
+                let entry = entry.unwrap();
+                File::new(entry.file_name().to_str().unwrap(), FileAttributes::from(&entry.metadata().unwrap()))
+            });
+            Ok(Name { id, files: files.collect() })
+        }
+
+        async fn open(&mut self, id: u32, filename: String, _: OpenFlags, _: FileAttributes) -> Result<Handle, Self::Error> {
+            match std::path::Path::new(&filename).is_file() {
+                true => Ok(Handle { id, handle: filename }),
+                false => Err(SftpStatus::NoSuchFile),
+            }
+        }
+
+        async fn read(&mut self, id: u32, handle: String, offset: u64, len: u32) -> Result<Data, Self::Error> {
+            let mut file = std::fs::File::open(handle).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            let mut data = Vec::new();
+            file.take(len as u64).read_to_end(&mut data).unwrap();
+            match data.is_empty() {
+                true => Err(SftpStatus::Eof),
+                false => Ok(Data { id, data }),
+            }
+        }
+
+        async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+            self.listed.remove(&handle);
+            Ok(Status { id, status_code: SftpStatus::Ok, error_message: "Ok".to_owned(), language_tag: "en-US".to_owned() })
+        }
+    }
+
+    let host_key = PrivateKey::from(Ed25519Keypair::from_seed(&[7; 32]));
+    let public_key = host_key.public_key().to_openssh().unwrap();
+    let config = Arc::new(russh::server::Config { keys: vec![host_key], ..Default::default() });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint: url::Url = format!("sftp://{}{}", listener.local_addr().unwrap(), share.path().display()).parse().unwrap();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let config = config.clone();
+            tokio::spawn(async move {
+                if let Ok(session) = russh::server::run_stream(config, socket, Ssh::default()).await {
+                    let _ = session.await;
+                }
+            });
+        }
+    });
+
+    let sftp = |host_key: &str| crate::sftp::SftpConfig {
+        endpoint: endpoint.clone(),
+        user: "photos".to_owned(),
+        password: Some("secret".to_owned()),
+        key: None,
+        host_key: Some(host_key.to_owned()),
+    };
+    let data = tempfile::tempdir().unwrap();
+    let client = crate::sftp::Client::new(sftp(&public_key)).unwrap();
+    let remotes = Remotes::default().with(crate::sftp::SCHEME, Arc::new(client));
+    let app = app(create_state(data.path(), StateOptions { remotes, ..StateOptions::default() }).unwrap());
+    let remote = |path: &std::path::Path| PathBuf::from(format!("sftp://{}", path.strip_prefix(share.path()).unwrap().display()));
+
+    let result = analyze(&app, std::path::Path::new("sftp://photos")).await;
+    let groups: BTreeSet<BTreeSet<PathBuf>> = result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    let expected = fixtures::expected_groups(&fixtures).iter().map(|group| group.iter().map(|path| remote(path)).collect()).collect();
+    assert_eq!(groups, expected);
+
+    let (status, dirs) = call(&app, Method::GET, "/list_folder?path=sftp://photos&onlyDirs=true").await;
+    assert_eq!(status, StatusCode::OK);
+    let expected = ["copies", "originals", "unrelated"].map(|dir| PathBuf::from(format!("sftp://photos/{}", dir)));
+    assert_eq!(paths(&dirs), BTreeSet::from(expected));
+
+    let original = &fixtures.iter().find(|f| f.kind == FixtureKind::Original).unwrap().path;
+    let uri = format!("/image?path={}", remote(original).display());
+    let response = app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, std::fs::read(original).unwrap());
+
+    // a server with another key isn't the configured one
+    let other = PrivateKey::from(Ed25519Keypair::from_seed(&[8; 32])).public_key().to_openssh().unwrap();
+    let client = crate::sftp::Client::new(sftp(&other)).unwrap();
+    let error = tokio::task::spawn_blocking(move || crate::storage::Storage::scan(&client, std::path::Path::new("sftp://photos"))).await.unwrap().unwrap_err();
+    assert!(format!("{:#}", error).contains("can't connect to the SFTP server"), "{:#}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_watched_folders_analyzed() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    // the copy is only seen when notified
+    let options = WatchOptions { interval: std::time::Duration::from_secs(3600), dist: 10, ..WatchOptions::default() };
+    let watcher = Watcher::new(vec![library.path().to_owned()], options);
+    let app = app(create_state(data.path(), StateOptions { watcher, ..StateOptions::default() }).unwrap());
+
+    let (status, _) = call(&app, Method::GET, "/watch/groups?path=/elsewhere").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let groups_uri = format!("/watch/groups?path={}", library.path().display());
+    // until the groups include what the folder holds
+    let groups_with = |path: PathBuf| {
+        let (app, uri) = (app.clone(), groups_uri.clone());
+        async move {
+            loop {
+                let (status, groups) = call(&app, Method::GET, &uri).await;
+                if status == StatusCode::OK {
+                    let groups: BTreeSet<BTreeSet<PathBuf>> = groups.as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+                    if groups.iter().any(|group| group.contains(&path)) {
+                        return groups;
+                    }
+                } else {
+                    assert_eq!(status, StatusCode::CONFLICT);
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    };
+
+    let mut expected = fixtures::expected_groups(&fixtures);
+    let original = expected.first().unwrap().first().unwrap().clone();
+    assert_eq!(groups_with(original.clone()).await, expected);
+    let (_, status) = call(&app, Method::GET, "/watch").await;
+    assert_eq!(status[0]["groups"], expected.len());
+    assert!(status[0]["scanned"].is_u64());
+    assert_eq!(status[0]["polled"], false);
+
+    let copy = library.path().join(format!("copy.{}", original.extension().unwrap().to_str().unwrap()));
+    std::fs::copy(&original, &copy).unwrap();
+    let mut first = expected.pop_first().unwrap();
+    first.insert(copy.clone());
+    expected.insert(first);
+    assert_eq!(groups_with(copy).await, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hashes_on_workers() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let options = StateOptions { worker_token: Some("secret".to_owned()), ..StateOptions::default() };
+    let app = app(create_state(data.path(), options).unwrap());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let coordinator = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.clone().into_make_service()));
+
+    let list = || async {
+        let request = Request::get("/workers").header(crate::workers::TOKEN_HEADER, "secret").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        serde_json::from_slice::<Value>(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+    };
+    let options = crate::workers::WorkerOptions { coordinator, name: "second".to_owned(), token: None, worker_token: "secret".to_owned(), remap: None };
+    let worker = tokio::spawn(crate::workers::work(options));
+    loop {
+        let workers = list().await;
+        if workers.as_array().unwrap().iter().any(|worker| worker["name"] == "second") {
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let result = analyze(&app, library.path()).await;
+    let groups: BTreeSet<BTreeSet<PathBuf>> = result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    assert_eq!(groups, fixtures::expected_groups(&fixtures));
+    // the coordinator reports what the worker couldn't hash
+    let truncated: BTreeSet<PathBuf> = fixtures.iter().filter(|f| f.kind == FixtureKind::Truncated).map(|f| f.path.clone()).collect();
+    assert_eq!(paths(&result["corrupted"]), truncated);
+    let workers = list().await;
+    let images = fixtures.iter().filter(|f| f.kind != FixtureKind::NotAnImage).count();
+    assert_eq!((workers[0]["name"].as_str(), workers[0]["files"].as_u64()), (Some("second"), Some(images as u64)));
+    worker.abort();
+}
+
+#[tokio::test]
+async fn rejects_workers_without_the_token() {
+    let data = tempfile::tempdir().unwrap();
+    let answer = serde_json::json!({ "worker": "rogue", "files": [{ "path": "/photos/a.jpg", "hash": "AAAAAAAAAAA=" }] });
+    let uri = format!("/workers/batches/{}", uuid::Uuid::new_v4());
+
+    // not served without a token configured
+    let (status, _) = call_json(&test_app(data.path()), Method::POST, &uri, answer.clone()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let data = tempfile::tempdir().unwrap();
+    let options = StateOptions { worker_token: Some("secret".to_owned()), ..StateOptions::default() };
+    let app = app(create_state(data.path(), options).unwrap());
+    let (status, _) = call_json(&app, Method::POST, &uri, answer.clone()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call_json(&app, Method::POST, "/workers/claim", serde_json::json!({ "worker": "rogue" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let request = |token: &str| {
+        Request::post(&uri)
+            .header("content-type", "application/json")
+            .header(crate::workers::TOKEN_HEADER, token)
+            .body(Body::from(answer.to_string()))
+            .unwrap()
+    };
+    let wrong = app.clone().oneshot(request("guess")).await.unwrap();
+    assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+    // let through, the batch is unknown
+    let right = app.clone().oneshot(request("secret")).await.unwrap();
+    assert_eq!(right.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn isolates_tenants() {
+    let data = tempfile::tempdir().unwrap();
+    let first = tempfile::tempdir().unwrap();
+    let second = tempfile::tempdir().unwrap();
+    let configs = serde_json::from_value(serde_json::json!([
+        { "id": "first", "apiKeys": ["key1"], "libraries": [first.path()] },
+        { "id": "second", "apiKeys": ["key2"], "libraries": [second.path()] },
+    ])).unwrap();
+    let app = crate::tenant::app(Arc::new(Tenants::new(data.path(), configs, TaskLimits::default(), Webhooks::default(), KeepRules::default(), &crate::assets::Assets::default()).unwrap()));
+
+    let own = format!("/list_folder?path={}&apiKey=key1", first.path().display());
+    assert_eq!(call(&app, Method::GET, &own).await.0, StatusCode::OK);
+
+    let other = format!("/list_folder?path={}&apiKey=key1", second.path().display());
+    assert_eq!(call(&app, Method::GET, &other).await.0, StatusCode::FORBIDDEN);
+
+    let anonymous = format!("/list_folder?path={}", first.path().display());
+    assert_eq!(call(&app, Method::GET, &anonymous).await.0, StatusCode::UNAUTHORIZED);
+
+    assert!(data.path().join("tenants/first/cache.db").exists());
+    assert!(data.path().join("tenants/second/cache.db").exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn keeps_symlinks_from_leaving_the_libraries() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    fixtures::generate(outside.path()).unwrap();
+    let image = std::fs::read_dir(outside.path()).unwrap().next().unwrap().unwrap().path();
+    std::os::unix::fs::symlink(outside.path(), library.path().join("escape")).unwrap();
+    std::os::unix::fs::symlink(&image, library.path().join("image.png")).unwrap();
+    let state = create_state(data.path(), StateOptions { libraries: Some(vec![library.path().to_owned()]), ..StateOptions::default() }).unwrap();
+    let app = app(state);
+
+    let (status, files) = call(&app, Method::GET, &format!("/list_folder?path={}", library.path().display())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(files, serde_json::json!([]));
+
+    for path in [library.path().join("escape"), library.path().join("image.png"), library.path().join("escape/..")] {
+        let (status, error) = call(&app, Method::GET, &format!("/image?path={}", path.display())).await;
+        assert_eq!((status, &error["code"]), (StatusCode::FORBIDDEN, &Value::from("forbidden")), "{}", path.display());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hashes_embedded_previews_in_fast_mode() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    // other pictures with the same preview
+    fixtures::camera_jpeg(&library.path().join("a.jpg"), 0, 5).unwrap();
+    fixtures::camera_jpeg(&library.path().join("b.jpg"), 1, 5).unwrap();
+    fixtures::generate(&library.path().join("plain")).unwrap();
+    let app = test_app(data.path());
+    let previews = BTreeSet::from([library.path().join("a.jpg"), library.path().join("b.jpg")]);
+
+    for fast in [false, true] {
+        let body = serde_json::json!({ "path": library.path(), "dist": 2, "hashType": "DHash", "hashSize": 8, "fast": fast });
+        let (status, task) = call_json(&app, Method::POST, "/analyze", body).await;
+        assert_eq!(status, StatusCode::OK);
+        let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+        let result = loop {
+            let (_, resp) = call(&app, Method::GET, &uri).await;
+            match resp["type"].as_str().unwrap() {
+                "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+                _ => break resp["data"].clone(),
+            }
+        };
+        let groups: BTreeSet<BTreeSet<PathBuf>> = result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+        assert_eq!(groups.contains(&previews), fast, "{:?}", groups);
+        // images without a preview are decoded in full
+        let copy = library.path().join("plain/copies/photo-0 (1).png");
+        assert!(groups.iter().any(|group| group.contains(&copy) && group.len() > 1));
+        assert!(result["corrupted"].as_array().unwrap().iter().any(|file| file["path"].as_str().unwrap().ends_with("-truncated.jpg")));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hashes_only_changed_files_incrementally() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let first = analyze(&app, library.path()).await;
+
+    // one new, one modified and one removed file
+    let originals = library.path().join("originals");
+    std::fs::copy(originals.join("photo-0.png"), originals.join("photo-0 (new).png")).unwrap();
+    std::fs::copy(originals.join("photo-1.png"), originals.join("photo-2.png")).unwrap();
+    std::fs::remove_file(library.path().join("copies/photo-0 (1).png")).unwrap();
+
+    let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "DHash", "hashSize": 8, "incremental": true });
+    let (status, task) = call_json(&app, Method::POST, "/analyze", body).await;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    let incremental = loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            "Completed" => break resp["data"].clone(),
+            other => panic!("analysis {}: {}", other, resp),
+        }
+    };
+    let hashed = first["coverage"]["hashed"].as_u64().unwrap();
+    assert_eq!(incremental["coverage"]["hashed"].as_u64().unwrap(), hashed);
+    assert_eq!(incremental["coverage"]["reused"].as_u64().unwrap(), hashed - 2);
+
+    // the same groups as hashing everything
+    let fresh = tempfile::tempdir().unwrap();
+    let app = test_app(fresh.path());
+    let full = analyze(&app, library.path()).await;
+    assert_eq!(full["coverage"]["reused"], 0);
+    assert_eq!(incremental["groups"], full["groups"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn times_the_phases_of_an_analysis() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let first = analyze(&app, library.path()).await;
+    let timings = &first["timings"];
+    let ms = |timings: &serde_json::Value, phase: &str| timings[phase].as_u64().unwrap_or_else(|| panic!("no {} in {}", phase, timings));
+    for phase in ["listingMs", "cacheLookupsMs", "decodeMs", "hashMs", "groupingMs"] {
+        ms(timings, phase);
+    }
+    assert!(ms(timings, "totalMs") >= ms(timings, "listingMs") + ms(timings, "groupingMs"), "{}", timings);
+
+    // every hash is cached by now, nothing is hashed again
+    let second = analyze(&app, library.path()).await;
+    assert_eq!(ms(&second["timings"], "hashMs"), 0, "{}", second["timings"]);
+}
+
+/// a ZIP archive of deflated entries
+fn write_zip(path: &std::path::Path, entries: &[(&str, &[u8])]) {
+    use std::io::Write;
+    let (mut zip, mut dir) = (Vec::new(), Vec::new());
+    for (name, data) in entries {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        // from the version needed to the extra field length, the same in both headers
+        let mut fields = Vec::new();
+        for field in [20u16, 0, 8, 0, 0] {
+            fields.extend(field.to_le_bytes());
+        }
+        for field in [crc.sum(), compressed.len() as u32, data.len() as u32] {
+            fields.extend(field.to_le_bytes());
+        }
+        fields.extend((name.len() as u16).to_le_bytes());
+        fields.extend(0u16.to_le_bytes());
+        dir.extend(0x0201_4b50u32.to_le_bytes());
+        dir.extend(20u16.to_le_bytes());
+        dir.extend(&fields);
+        dir.extend([0; 10]);
+        dir.extend((zip.len() as u32).to_le_bytes());
+        dir.extend(name.as_bytes());
+        zip.extend(0x0403_4b50u32.to_le_bytes());
+        zip.extend(&fields);
+        zip.extend(name.as_bytes());
+        zip.extend(compressed);
+    }
+    let offset = zip.len() as u32;
+    zip.extend(0x0605_4b50u32.to_le_bytes());
+    zip.extend([0; 4]);
+    zip.extend((entries.len() as u16).to_le_bytes());
+    zip.extend((entries.len() as u16).to_le_bytes());
+    zip.extend((dir.len() as u32).to_le_bytes());
+    zip.extend(offset.to_le_bytes());
+    zip.extend([0; 2]);
+    zip.splice(offset as usize..offset as usize, dir);
+    std::fs::write(path, zip).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scans_inside_archives() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let original = library.path().join("originals/photo-1.png");
+    let archive = library.path().join("unrelated/album.cbz");
+    write_zip(&archive, &[("scans/photo-1.png", &std::fs::read(&original).unwrap()), ("scans/notes.txt", b"not an image")]);
+    let entry = PathBuf::from(format!("{}!scans/photo-1.png", archive.display()));
+    let app = test_app(data.path());
+
+    // left out unless asked for
+    let result = analyze(&app, library.path()).await;
+    assert!(paths(&result["skipped"]).contains(&archive), "{}", result["skipped"]);
+
+    let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "DHash", "hashSize": 8, "archives": true });
+    let (_, task) = call_json(&app, Method::POST, "/analyze", body).await;
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    let result = loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            "Completed" => break resp["data"].clone(),
+            other => panic!("analysis {}: {}", other, resp),
+        }
+    };
+    let group = result["groups"].as_array().unwrap().iter().find(|group| paths(&group["files"]).contains(&entry)).expect("no group of the entry");
+    assert!(paths(&group["files"]).contains(&original), "{}", group);
+    assert!(paths(&result["skipped"]).contains(&PathBuf::from(format!("{}!scans/notes.txt", archive.display()))));
+
+    let encoded: String = url::form_urlencoded::byte_serialize(entry.to_str().unwrap().as_bytes()).collect();
+    let request = Request::builder().uri(format!("/image?path={}", encoded)).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), std::fs::read(&original).unwrap());
+
+    // nothing is planned inside the archive
+    let body = serde_json::json!({ "taskId": task["taskId"], "policy": "largest", "action": "delete" });
+    let (_, preview) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    let planned: BTreeSet<PathBuf> = preview["plan"]["groups"].as_array().unwrap().iter().flat_map(|group| paths(&group["actions"])).collect();
+    assert!(!planned.is_empty() && !planned.contains(&entry), "{:?}", planned);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refines_coarse_buckets() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let full = analyze(&app, library.path()).await;
+
+    let coarse = serde_json::json!({ "hashType": "AHash", "hashSize": 8, "dist": 12 });
+    let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "DHash", "hashSize": 8, "coarse": coarse });
+    let (status, task) = call_json(&app, Method::POST, "/analyze", body).await;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    let refined = loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            "Completed" => break resp["data"].clone(),
+            other => panic!("analysis {}: {}", other, resp),
+        }
+    };
+    // only the candidates are hashed the fine way, and grouped as a full analysis does
+    let coverage = &refined["coverage"];
+    assert_eq!(coverage["hashed"], full["coverage"]["hashed"]);
+    assert!(coverage["refined"].as_u64().unwrap() < coverage["hashed"].as_u64().unwrap(), "{}", coverage);
+    assert_eq!(refined["groups"], full["groups"]);
+    assert!(full["coverage"]["refined"].is_null());
+}
+
+struct Blank;
+
+impl crate::ImageHasher for Blank {
+    fn hash(&self, _: &image::DynamicImage) -> crate::hasher::ImageHash {
+        crate::hasher::ImageHash::from_bytes(&[0; 8]).unwrap()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn analyzes_with_registered_hashers() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let uri = format!("/analyze?path={}&dist=0&hashType=Blank&hashSize=8", library.path().display());
+    let (status, _) = call(&app, Method::POST, &uri).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    assert!(crate::register_hasher("DHash", |_| Blank).is_err());
+    let hash_type = crate::register_hasher("Blank", |_| Blank).unwrap();
+    assert_eq!(hash_type.name(), "Blank");
+    assert!(crate::register_hasher("Blank", |_| Blank).is_err());
+
+    let (status, task) = call(&app, Method::POST, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    let result = loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            "Completed" => break resp["data"].clone(),
+            other => panic!("analysis {}: {}", other, resp),
+        }
+    };
+    // every image hashes alike
+    let groups = result["groups"].as_array().unwrap();
+    assert_eq!(groups.len(), 1, "{}", result);
+    assert_eq!(groups[0]["files"].as_array().unwrap().len() as u64, result["coverage"]["hashed"].as_u64().unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn analyzes_listed_files_only() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let full = analyze(&app, library.path()).await;
+    let group = paths(&full["groups"][0]["files"]);
+    let missing = library.path().join("missing.png");
+    let mut files: Vec<_> = group.iter().cloned().collect();
+    files.push(missing.clone());
+
+    let outside = serde_json::json!({ "path": library.path().join("originals"), "dist": 10, "hashType": "DHash", "files": files });
+    let (status, body) = call_json(&app, Method::POST, "/analyze", outside).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+
+    let body = serde_json::json!({ "path": library.path(), "dist": 10, "hashType": "DHash", "files": files });
+    let (status, task) = call_json(&app, Method::POST, "/analyze", body).await;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    let result = loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            "Completed" => break resp["data"].clone(),
+            other => panic!("analysis {}: {}", other, resp),
+        }
+    };
+    assert_eq!(result["coverage"]["total"].as_u64(), Some(group.len() as u64));
+    let groups: Vec<_> = result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    assert_eq!(groups, vec![group]);
+    assert_eq!(paths(&result["errors"]), BTreeSet::from([missing]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn walks_folder_trees_in_parallel() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let samples = tempfile::tempdir().unwrap();
+    fixtures::generate(samples.path()).unwrap();
+    let image = samples.path().join("originals/photo-0.png");
+    let mut expected = BTreeSet::new();
+    for a in 0..4 {
+        for b in 0..4 {
+            let dir = library.path().join(format!("{}/{}", a, b));
+            std::fs::create_dir_all(&dir).unwrap();
+            for c in 0..3 {
+                std::fs::copy(&image, dir.join(format!("{}.png", c))).unwrap();
+                expected.insert(dir.join(format!("{}.png", c)));
+            }
+            std::fs::write(dir.join("notes.txt"), "not an image").unwrap();
+            std::fs::copy(&image, dir.join(".hidden.png")).unwrap();
+        }
+    }
+    let app = test_app(data.path());
+
+    let (status, files) = call(&app, Method::GET, &format!("/list_folder?path={}", library.path().display())).await;
+    assert_eq!(status, StatusCode::OK);
+    let listed: Vec<PathBuf> = files.as_array().unwrap().iter().map(|file| PathBuf::from(file["path"].as_str().unwrap())).collect();
+    assert_eq!(listed, expected.into_iter().collect::<Vec<_>>());
+    let (_, dirs) = call(&app, Method::GET, &format!("/list_folder?path={}&onlyDirs=true", library.path().display())).await;
+    assert_eq!(dirs.as_array().unwrap().len(), 4 + 16);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn decodes_within_the_memory_budget() {
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+
+    let mut runs = Vec::new();
+    // a single byte has every image decoded alone
+    for memory_budget in [None, Some(1)] {
+        let data = tempfile::tempdir().unwrap();
+        let limits = TaskLimits { memory_budget, ..TaskLimits::default() };
+        let app = app(create_state(data.path(), StateOptions { limits, ..StateOptions::default() }).unwrap());
+        runs.push(analyze(&app, library.path()).await["groups"].clone());
+    }
+    assert_eq!(runs[0], runs[1]);
+    assert!(!runs[0].as_array().unwrap().is_empty());
+
+    // JPEGs are scaled down while decoding by up to 8, PNGs and TIFFs by any factor, other formats aren't
+    assert_eq!(analyzer::decoded_size(Some(ImageFormat::Jpeg), (4096, 3072), 512), 1024 * 768 * 4);
+    assert_eq!(analyzer::decoded_size(Some(ImageFormat::Png), (4096, 3072), 512), 683 * 512 * 4);
+    assert_eq!(analyzer::decoded_size(Some(ImageFormat::Gif), (4096, 3072), 512), 4096 * 3072 * 4);
+}
+
+#[test]
+fn scales_pngs_and_tiffs_down_while_decoding() {
+    let dir = tempfile::tempdir().unwrap();
+    let image = image::RgbImage::from_fn(2100, 1500, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x / 7 + y / 5) % 256) as u8]));
+    for (name, image) in [("rgb.png", image::DynamicImage::ImageRgb8(image.clone())), ("rgb.tiff", image.clone().into()), ("gray16.png", image::DynamicImage::ImageRgb8(image).to_luma16().into())] {
+        let path = dir.path().join(name);
+        image.save(&path).unwrap();
+        let file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        assert!(crate::downscale::scales(file, ImageFormat::from_path(&path).unwrap()), "{}", name);
+        let (scaled, _) = analyzer::open_image(&path, 512).unwrap();
+        let full = image::open(&path).unwrap().thumbnail(512, 512).to_rgb8();
+        assert_eq!(scaled.to_rgb8().dimensions(), full.dimensions(), "{}", name);
+        let diff: u64 = scaled.to_rgb8().pixels().zip(full.pixels()).flat_map(|(a, b)| a.0.into_iter().zip(b.0).map(|(a, b)| a.abs_diff(b) as u64)).sum();
+        // the box filter and the thumbnail round differently
+        assert!(diff / (full.len() as u64) < 4, "{} is {} off on average", name, diff / full.len() as u64);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_folder_trees() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let samples = tempfile::tempdir().unwrap();
+    fixtures::generate(samples.path()).unwrap();
+    let image = samples.path().join("originals/photo-0.png");
+    let size = std::fs::metadata(&image).unwrap().len();
+    for (dir, count) in [("2024", 1), ("2024/01", 2), ("2024/01/raw", 3), ("2025", 0)] {
+        std::fs::create_dir_all(library.path().join(dir)).unwrap();
+        for i in 0..count {
+            std::fs::copy(&image, library.path().join(dir).join(format!("{}.png", i))).unwrap();
+        }
+    }
+    let app = test_app(data.path());
+
+    let (status, tree) = call(&app, Method::GET, &format!("/tree?path={}", library.path().display())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tree["images"], 6);
+    assert_eq!(tree["bytes"], 6 * size);
+    assert_eq!(tree["folders"], 2);
+    let year = &tree["children"][0];
+    assert_eq!(year["path"].as_str(), library.path().join("2024").to_str());
+    assert_eq!((year["images"].as_u64(), year["children"][0]["images"].as_u64()), (Some(6), Some(5)));
+    assert_eq!(year["children"][0]["children"][0]["images"], 3);
+    assert_eq!(tree["children"][1]["images"], 0);
+
+    // the totals still count what's below the depth
+    let (_, shallow) = call(&app, Method::GET, &format!("/tree?path={}&depth=1", library.path().display())).await;
+    assert_eq!(shallow["children"][0]["images"], 6);
+    assert_eq!(shallow["children"][0]["folders"], 1);
+    assert_eq!(shallow["children"][0]["children"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn lists_volumes_or_the_libraries() {
+    let data = tempfile::tempdir().unwrap();
+    let app = test_app(data.path());
+    let (status, volumes) = call(&app, Method::GET, "/volumes").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(volumes[0], serde_json::json!({ "path": "/", "kind": "root" }));
+
+    let library = tempfile::tempdir().unwrap();
+    let libraries = [crate::volumes::canonicalize(library.path()).unwrap()];
+    let data = tempfile::tempdir().unwrap();
+    let app = crate::server::app(create_state(data.path(), StateOptions { libraries: Some(libraries.to_vec()), ..StateOptions::default() }).unwrap());
+    let (_, volumes) = call(&app, Method::GET, "/volumes").await;
+    assert_eq!(volumes, serde_json::json!([{ "path": libraries[0], "kind": "library" }]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn downloads_files_as_a_zip_archive() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let root = crate::volumes::canonicalize(library.path()).unwrap();
+    for (dir, content) in [("a", b"first".as_slice()), ("b", b"second".as_slice())] {
+        std::fs::create_dir(root.join(dir)).unwrap();
+        std::fs::write(root.join(dir).join("x.png"), content).unwrap();
+    }
+    write_zip(&root.join("album.zip"), &[("inner.png", b"inside")]);
+    let libraries = [root.clone()];
+    let app = crate::server::app(create_state(data.path(), StateOptions { libraries: Some(libraries.to_vec()), ..StateOptions::default() }).unwrap());
+
+    let paths = [root.join("b/x.png"), root.join("a/x.png"), root.join("album.zip!inner.png"), root.join("a/x.png")];
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/download")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::json!({ "paths": paths }).to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/zip");
+    let zip = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let downloaded = data.path().join("download.zip");
+    std::fs::write(&downloaded, &zip).unwrap();
+    let entries = crate::archive::entries(&downloaded).unwrap();
+    let files: Vec<(String, Vec<u8>)> = entries.iter().map(|entry| (entry.name.clone(), crate::archive::read(&downloaded, entry).unwrap())).collect();
+    let expected = [("a/x.png", b"first".as_slice()), ("album.zip!inner.png", b"inside"), ("b/x.png", b"second")];
+    assert_eq!(files, expected.map(|(name, content)| (name.to_owned(), content.to_vec())));
+
+    let elsewhere = tempfile::NamedTempFile::new().unwrap();
+    for (paths, expected) in [
+        (vec![elsewhere.path().to_owned()], StatusCode::FORBIDDEN),
+        (vec![root.join("a/missing.png")], StatusCode::NOT_FOUND),
+        (Vec::new(), StatusCode::BAD_REQUEST),
+    ] {
+        let (status, _) = call_json(&app, Method::POST, "/download", serde_json::json!({ "paths": paths })).await;
+        assert_eq!(status, expected, "{:?}", paths);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn groups_are_deterministic() {
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let data = tempfile::tempdir().unwrap();
+        let app = test_app(data.path());
+        runs.push(analyze(&app, library.path()).await["groups"].clone());
+    }
+    assert_eq!(runs[0], runs[1]);
+}
+
+/// analyzes in content cache mode, returns the number of cache entries afterwards
+async fn cached_by_content(app: &Router, path: &std::path::Path) -> u64 {
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash&cacheMode=content", path.display());
+    let (_, task) = call(app, Method::POST, &uri).await;
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    while matches!(call(app, Method::GET, &uri).await.1["type"].as_str(), Some("Queued" | "Pending")) {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    call(app, Method::GET, "/cache/stats").await.1["entries"].as_u64().unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn content_cache_survives_renames() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let before = cached_by_content(&app, library.path()).await;
+    assert!(before > 0);
+    let original = &fixtures.iter().find(|f| f.kind == FixtureKind::Original).unwrap().path;
+    std::fs::rename(original, original.with_file_name("renamed.png")).unwrap();
+    assert_eq!(cached_by_content(&app, library.path()).await, before);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn migrates_unversioned_state() {
+    let data = tempfile::tempdir().unwrap();
+    std::fs::write(data.path().join("roots.json"), r#"[{"path": "/photos", "storageClass": "nas"}]"#).unwrap();
+    std::fs::create_dir(data.path().join("removed")).unwrap();
+    std::fs::write(data.path().join("removed/old.json"), r#""/photos/a.jpg""#).unwrap();
+    // skipped, not keeping the server from starting
+    std::fs::write(data.path().join("removed/broken.json"), "{").unwrap();
+    rusqlite::Connection::open(data.path().join("cache.db"))
+        .unwrap()
+        .execute_batch("CREATE TABLE cache (key TEXT PRIMARY KEY, value TEXT NOT NULL, created INTEGER NOT NULL)")
+        .unwrap();
+
+    let app = test_app(data.path());
+
+    let (_, migrations) = call(&app, Method::GET, "/admin/migrations").await;
+    let stores: BTreeSet<&str> = migrations.as_array().unwrap().iter().map(|m| m["store"].as_str().unwrap()).collect();
+    assert_eq!(stores, BTreeSet::from(["cache", "removed", "roots"]));
+
+    let (_, roots) = call(&app, Method::GET, "/roots").await;
+    assert_eq!(roots[0]["path"], "/photos");
+    let (_, deleted) = call(&app, Method::GET, "/deleted").await;
+    assert_eq!(deleted[0]["path"], "/photos/a.jpg");
+
+    // nothing left to do on the next start
+    let app = test_app(data.path());
+    let (_, migrations) = call(&app, Method::GET, "/admin/migrations").await;
+    assert_eq!(migrations, serde_json::json!([]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drops_cached_hashes_of_another_format() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+    let (_, stats) = call(&app, Method::GET, "/cache/stats").await;
+    let cached = stats["entries"].as_u64().unwrap();
+    assert!(cached > 0);
+
+    // exported entries tell their format, those of another one aren't imported
+    let response = app.clone().oneshot(Request::builder().uri("/cache/export").body(Body::empty()).unwrap()).await.unwrap();
+    let dump = String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+    assert!(dump.lines().all(|line| line.ends_with(&format!(r#""format":{}}}"#, analyzer::HASH_VERSION))), "{}", dump);
+    let older = dump.replace(&format!(r#""format":{}}}"#, analyzer::HASH_VERSION), r#""format":0}"#);
+    let request = Request::builder().method(Method::POST).uri("/cache/import").body(Body::from(older)).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let imported: Value = serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+    assert_eq!(imported["imported"], 0);
+
+    // as an older version would have left them
+    drop(app);
+    rusqlite::Connection::open(data.path().join("cache.db")).unwrap().execute("UPDATE cache SET format = 0", []).unwrap();
+    let app = test_app(data.path());
+    let (_, migrations) = call(&app, Method::GET, "/admin/migrations").await;
+    let dropped = serde_json::json!({ "store": "cache entries", "from": 0, "to": analyzer::HASH_VERSION, "items": cached });
+    assert!(migrations.as_array().unwrap().contains(&dropped), "{}", migrations);
+    let (_, stats) = call(&app, Method::GET, "/cache/stats").await;
+    assert_eq!(stats["entries"], 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn counts_hashes_of_changed_files_as_stale() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+    let (_, before) = call(&app, Method::GET, "/cache/stats").await;
+    assert_eq!(before["hits"], 0);
+
+    let original = &fixtures.iter().find(|f| f.kind == FixtureKind::Original).unwrap().path;
+    let file = std::fs::File::options().write(true).open(original).unwrap();
+    file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60)).unwrap();
+    analyze(&app, library.path()).await;
+    let (_, after) = call(&app, Method::GET, "/cache/stats").await;
+    assert_eq!(after["stale"], 1, "{}", after);
+    let lookups = ["hits", "misses", "stale"].map(|counter| after[counter].as_f64().unwrap()).iter().sum::<f64>();
+    assert_eq!(after["hitRate"].as_f64().unwrap(), after["hits"].as_f64().unwrap() / lookups);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_the_search_index_between_restarts() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let original = &fixtures.iter().find(|f| f.kind == FixtureKind::Original).unwrap().path;
+    let search = serde_json::json!({ "path": original, "maxDist": 0 });
+    let app = test_app(data.path());
+    let (status, _) = call_json(&app, Method::POST, "/search", search.clone()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    analyze(&app, library.path()).await;
+    let (status, found) = call_json(&app, Method::POST, "/search", search.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(found.as_array().unwrap().iter().any(|found| found["file"]["path"] == serde_json::json!(original)), "{}", found);
+
+    // the same matches without analyzing again
+    drop(app);
+    let app = test_app(data.path());
+    let (status, again) = call_json(&app, Method::POST, "/search", search).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(again, found);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn searches_by_an_uploaded_image() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let original = &fixtures.iter().find(|f| f.kind == FixtureKind::Original).unwrap().path;
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+
+    let upload = |fields: Vec<(&str, Vec<u8>)>| {
+        let mut body = b"preamble\r\n".to_vec();
+        for (name, data) in fields {
+            body.extend(format!("--XyZ\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"sent.jpg\"\r\n", name).bytes());
+            body.extend(b"Content-Type: application/octet-stream\r\n\r\n");
+            body.extend(data);
+            body.extend(b"\r\n");
+        }
+        body.extend(b"--XyZ--\r\n");
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/search/upload")
+            .header("content-type", "multipart/form-data; boundary=\"XyZ\"")
+            .body(Body::from(body))
+            .unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null))
+        }
+    };
+
+    let image = std::fs::read(original).unwrap();
+    let (status, found) = upload(vec![("image", image.clone()), ("maxDist", b"0".to_vec()), ("limit", b"50".to_vec())]).await;
+    assert_eq!(status, StatusCode::OK);
+    let found = found.as_array().unwrap();
+    assert!(found.iter().any(|found| found["file"]["path"] == serde_json::json!(original)), "{:?}", found);
+    assert!(found.iter().all(|found| found["distance"] == 0));
+    let (status, _) = upload(vec![("maxDist", b"0".to_vec())]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = upload(vec![("image", b"not an image".to_vec())]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call_json(&app, Method::POST, "/search/upload", serde_json::json!({})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancels_analysis() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash", library.path().display());
+    let (_, task) = call(&app, Method::POST, &uri).await;
+    let task_id = task["taskId"].as_str().unwrap();
+    let (status, _) = call(&app, Method::POST, &format!("/cancel?taskId={}", task_id)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let uri = format!("/poll?taskId={}", task_id);
+    loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            other => {
+                assert_eq!(other, "Cancelled", "{}", resp);
+                break;
+            }
+        }
+    }
+
+    let (status, _) = call(&app, Method::POST, &format!("/cancel?taskId={}", task_id)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    assert_eq!(tasks[0]["taskId"], task_id);
+    assert!(tasks[0]["finished"].is_u64());
+    assert!(tasks[0]["progress"]["phase"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn polls_and_cancels_over_websocket() {
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+    type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn send(socket: &mut Socket, text: &str) {
+        socket.send(Message::Text(text.to_owned())).await.unwrap();
+    }
+
+    /// the next event, progress updates are skipped unless asked for
+    async fn next(socket: &mut Socket, progress: bool) -> Value {
+        loop {
+            let Message::Text(text) = socket.next().await.unwrap().unwrap() else { continue };
+            let event: Value = serde_json::from_str(&text).unwrap();
+            if progress || event["type"] != "Progress" {
+                return event;
+            }
+        }
+    }
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.clone().into_make_service()));
+
+    // unknown tasks fail the handshake
+    let unknown = format!("ws://{}/ws?taskId={}", addr, uuid::Uuid::new_v4());
+    assert!(tokio_tungstenite::connect_async(unknown).await.is_err());
+
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash", library.path().display());
+    let (_, task) = call(&app, Method::POST, &uri).await;
+    let uri = format!("ws://{}/ws?taskId={}", addr, task["taskId"].as_str().unwrap());
+    let (mut socket, _) = tokio_tungstenite::connect_async(uri).await.unwrap();
+    assert_eq!(next(&mut socket, true).await["type"], "Progress");
+
+    loop {
+        send(&mut socket, r#"{"type": "Poll"}"#).await;
+        let event = next(&mut socket, false).await;
+        assert_eq!(event["type"], "Poll", "{}", event);
+        if event["response"]["type"] == "Completed" {
+            assert!(event["response"]["data"]["groups"].is_array());
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    send(&mut socket, r#"{"type": "Cancel"}"#).await;
+    assert_eq!(next(&mut socket, false).await, serde_json::json!({ "type": "Cancel", "cancelled": false }));
+    send(&mut socket, "nonsense").await;
+    assert_eq!(next(&mut socket, false).await["type"], "Error");
+}
+
+#[tokio::test]
+async fn settles_panicking_tasks() {
+    use crate::manager::{TaskManager, TaskOptions, TaskResponse};
+
+    let mut manager: TaskManager<u32, (), u8, eyre::Result<()>> = TaskManager::new(TaskLimits::default());
+    manager.submit(1, (), TaskOptions::default(), |_, _| panic!("unreadable"));
+    let result = loop {
+        match manager.poll(&1).await.unwrap() {
+            TaskResponse::Completed(result) => break result,
+            _ => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    };
+    assert_eq!(result.as_ref().as_ref().unwrap_err().to_string(), "analysis panicked: unreadable");
+
+    // the manager keeps going
+    manager.submit(2, (), TaskOptions::default(), |_, _| Ok(()));
+    let mut done = manager.wait(&2).unwrap();
+    while done.try_recv().is_err() {
+        manager.poll(&2).await;
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+type TestManager = crate::manager::TaskManager<u32, (), u8, eyre::Result<u32>>;
+
+/// polls the manager until the task is done
+async fn finished(manager: &mut TestManager, key: u32) -> Arc<eyre::Result<u32>> {
+    use crate::manager::TaskResponse;
+
+    loop {
+        match manager.poll(&key).await.unwrap() {
+            TaskResponse::Completed(result) => return result,
+            _ => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn starts_queued_tasks_by_priority() {
+    use crate::manager::{Priority, TaskOptions, TaskResponse};
+    use std::sync::{mpsc, Mutex};
+
+    let limits = TaskLimits { concurrency: 1, ..TaskLimits::default() };
+    let mut manager = TestManager::new(limits);
+    let (release, gate) = mpsc::channel::<()>();
+    let started = Arc::new(Mutex::new(Vec::new()));
+
+    // takes the only slot until released
+    manager.submit(0, (), TaskOptions::default(), move |_, _| {
+        gate.recv().unwrap();
+        Ok(0)
+    });
+    for (key, priority) in [(1, Priority::Low), (2, Priority::Normal), (3, Priority::High), (4, Priority::Normal)] {
+        let started = started.clone();
+        manager.submit(key, (), TaskOptions { priority, timeout: None }, move |_, _| {
+            started.lock().unwrap().push(key);
+            Ok(key)
+        });
+    }
+    assert_eq!((manager.running(), manager.queued()), (1, 4));
+    assert!(matches!(manager.poll(&3).await, Some(TaskResponse::Queued(0))));
+    assert!(matches!(manager.poll(&1).await, Some(TaskResponse::Queued(3))));
+
+    release.send(()).unwrap();
+    for key in 0..5 {
+        assert_eq!(*finished(&mut manager, key).await.as_ref().as_ref().unwrap(), key);
+    }
+    // by priority, then in submission order
+    assert_eq!(*started.lock().unwrap(), vec![3, 2, 4, 1]);
+}
+
+#[tokio::test]
+async fn times_out_running_tasks() {
+    use crate::manager::{TaskOptions, TimedOut};
+
+    let limits = TaskLimits { concurrency: 1, ..TaskLimits::default() };
+    let mut manager = TestManager::new(limits);
+    let options = TaskOptions { timeout: Some(std::time::Duration::from_millis(10)), ..TaskOptions::default() };
+    manager.submit(1, (), options, |_, cancel| {
+        while !cancel.is_cancelled() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        Err(cancel.error())
+    });
+    manager.submit(2, (), TaskOptions::default(), |_, _| Ok(2));
+    assert!(manager.expire().is_empty());
+
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert_eq!(manager.expire(), vec![1]);
+    // once
+    assert!(manager.expire().is_empty());
+    // the slot of a timed out task is free, it may be stuck for good
+    assert_eq!(*finished(&mut manager, 2).await.as_ref().as_ref().unwrap(), 2);
+    assert!(finished(&mut manager, 1).await.as_ref().as_ref().unwrap_err().is::<TimedOut>());
+}
+
+#[tokio::test]
+async fn drops_results_past_retention() {
+    use std::time::{Duration, SystemTime};
+
+    let limits = TaskLimits { ttl: Some(Duration::from_secs(60 * 60)), keep: Some(2), ..TaskLimits::default() };
+    let mut manager = TestManager::new(limits);
+    let now = SystemTime::now();
+    let ago = |secs| now - Duration::from_secs(secs);
+    // past the ttl, then the oldest over the count
+    for (key, finished) in [(1, ago(2 * 60 * 60)), (2, ago(3)), (3, ago(1)), (4, ago(2))] {
+        manager.restore(key, (), ago(3 * 60 * 60), finished, Ok(key));
+    }
+
+    let mut dropped = manager.cleanup().await;
+    dropped.sort();
+    assert_eq!(dropped, vec![1, 2]);
+    assert!(manager.poll(&1).await.is_none() && manager.poll(&2).await.is_none());
+    assert_eq!(*finished(&mut manager, 3).await.as_ref().as_ref().unwrap(), 3);
+    assert!(manager.cleanup().await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restores_tasks_after_restart() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap().to_owned();
+
+    let app = test_app(data.path());
+    let (status, resp) = call(&app, Method::GET, &format!("/poll?taskId={}", task_id)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(resp["type"], "Completed");
+    assert_eq!(resp["data"]["groups"], groups);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_runs_after_their_tasks_expire() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+
+    // the tasks are gone, as after `TASK_RESULT_TTL`
+    std::fs::remove_dir_all(data.path().join("tasks")).unwrap();
+    let app = test_app(data.path());
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    assert_eq!(tasks, serde_json::json!([]));
+    let (status, runs) = call(&app, Method::GET, &format!("/runs?path={}", library.path().display())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(runs.as_array().unwrap().len(), 1);
+    assert_eq!(runs[0]["groups"], groups.as_array().unwrap().len());
+    let (_, elsewhere) = call(&app, Method::GET, "/runs?path=/elsewhere").await;
+    assert_eq!(elsewhere, serde_json::json!([]));
+
+    let uri = format!("/runs/{}", runs[0]["id"].as_str().unwrap());
+    let (status, run) = call(&app, Method::GET, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(run["result"]["groups"], groups);
+    assert_eq!(run["request"]["path"].as_str(), library.path().to_str());
+    let (status, _) = call(&app, Method::DELETE, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(&app, Method::GET, &uri).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compares_two_files() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let original = library.path().join("originals/photo-0.png");
+    let encode = |path: &std::path::Path| url::form_urlencoded::byte_serialize(path.to_str().unwrap().as_bytes()).collect::<String>();
+    let compare = |other: &str| format!("/compare?a={}&b={}", encode(&original), encode(&library.path().join(other)));
+
+    let (status, copy) = call(&app, Method::GET, &compare("copies/photo-0 (1).png")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(copy["identical"], true);
+    assert_eq!(copy["distance"], 0);
+    assert_eq!(copy["a"]["path"].as_str(), original.to_str());
+    assert_eq!(copy["a"]["hash"], copy["b"]["hash"]);
+
+    let (_, resized) = call(&app, Method::GET, &compare("copies/photo-0-small.png")).await;
+    assert_eq!(resized["identical"], false);
+    assert!(resized["b"]["width"].as_u64() < resized["a"]["width"].as_u64());
+    let (_, unrelated) = call(&app, Method::GET, &compare("unrelated/other-0.png")).await;
+    assert!(resized["distance"].as_u64() < unrelated["distance"].as_u64());
+
+    let (status, _) = call(&app, Method::GET, &compare("missing.png")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn diffs_runs_of_a_folder() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+
+    // the copies of one photo cleaned up, one of another, and a new copy of an unrelated one
+    let copies = library.path().join("copies");
+    for entry in std::fs::read_dir(&copies).unwrap() {
+        let path = entry.unwrap().path();
+        if path.file_name().unwrap().to_str().unwrap().starts_with("photo-1") {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    std::fs::remove_file(copies.join("photo-0 (1).png")).unwrap();
+    let unrelated = library.path().join("unrelated");
+    std::fs::copy(unrelated.join("other-0.png"), unrelated.join("other-0 (1).png")).unwrap();
+    analyze(&app, library.path()).await;
+
+    let (_, runs) = call(&app, Method::GET, "/runs").await;
+    let (later, earlier) = (runs[0]["id"].as_str().unwrap(), runs[1]["id"].as_str().unwrap());
+    let (status, diff) = call(&app, Method::GET, &format!("/runs/{}/diff/{}", later, earlier)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(diff["unchanged"], 1);
+    let resolved: Vec<BTreeSet<PathBuf>> = diff["resolved"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    assert_eq!(resolved.len(), 1);
+    assert!(resolved[0].contains(&library.path().join("originals/photo-1.png")));
+    let new: Vec<BTreeSet<PathBuf>> = diff["new"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    assert_eq!(new, vec![BTreeSet::from([unrelated.join("other-0 (1).png"), unrelated.join("other-0.png")])]);
+    assert_eq!(diff["changed"].as_array().unwrap().len(), 1);
+    assert_eq!(diff["changed"][0]["removed"], serde_json::json!([copies.join("photo-0 (1).png")]));
+    assert_eq!(diff["changed"][0]["added"], serde_json::json!([]));
+    assert!(diff["laterWastedBytes"].as_u64() < diff["earlierWastedBytes"].as_u64());
+
+    let (status, _) = call(&app, Method::GET, &format!("/runs/{}/diff/{}", later, uuid::Uuid::new_v4())).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_bookmarked_folders() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let (status, bookmark) = call_json(&app, Method::POST, "/bookmarks", serde_json::json!({ "path": library.path() })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bookmark["name"].as_str(), library.path().file_name().unwrap().to_str());
+    let (status, _) = call_json(&app, Method::POST, "/bookmarks", serde_json::json!({ "path": library.path() })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = call_json(&app, Method::POST, "/bookmarks", serde_json::json!({ "path": library.path().join("missing") })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, listed) = call(&app, Method::GET, "/bookmarks").await;
+    assert_eq!(listed[0]["id"], bookmark["id"]);
+    assert_eq!(listed[0]["lastRun"], Value::Null);
+
+    let groups = analyze(&app, library.path()).await["groups"].as_array().unwrap().len();
+    let uri = format!("/bookmarks/{}", bookmark["id"].as_str().unwrap());
+    let body = serde_json::json!({ "path": library.path(), "name": "photos" });
+    let (status, renamed) = call_json(&app, Method::PUT, &uri, body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(renamed["name"], "photos");
+
+    // kept over restarts
+    let app = test_app(data.path());
+    let (_, listed) = call(&app, Method::GET, "/bookmarks").await;
+    assert_eq!(listed[0]["name"], "photos");
+    assert_eq!(listed[0]["lastRun"]["groups"], groups);
+    let (status, _) = call(&app, Method::DELETE, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(&app, Method::GET, &uri).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn analyzes_with_named_profiles() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let profile = serde_json::json!({ "name": "quick", "options": { "dist": 10, "hashType": "DHash", "hashSize": 8 } });
+    let (status, _) = call_json(&app, Method::POST, "/profiles", profile.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call_json(&app, Method::POST, "/profiles", profile).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let invalid = serde_json::json!({ "name": "broken", "options": { "dist": "far", "hashType": "DHash" } });
+    let (status, _) = call_json(&app, Method::POST, "/profiles", invalid).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call_json(&app, Method::PUT, "/profiles/quick", serde_json::json!({ "options": { "dist": 10 } })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // the path comes with the submission, the body overrides the profile
+    let (status, _) = call_json(&app, Method::POST, "/profiles/quick/analyze", serde_json::json!({})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call_json(&app, Method::POST, "/profiles/missing/analyze", serde_json::json!({ "path": library.path() })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let body = serde_json::json!({ "path": library.path(), "edges": true });
+    let (status, task) = call_json(&app, Method::POST, "/profiles/quick/analyze", body).await;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    let result = loop {
+        let (_, resp) = call(&app, Method::GET, &uri).await;
+        match resp["type"].as_str().unwrap() {
+            "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+            "Completed" => break resp["data"].clone(),
+            other => panic!("analysis {}: {}", other, resp),
+        }
+    };
+    let groups = result["groups"].as_array().unwrap();
+    assert_eq!(groups.len(), analyze(&app, library.path()).await["groups"].as_array().unwrap().len());
+    assert!(groups.iter().all(|group| group["edges"].is_array()));
+
+    let options = serde_json::json!({ "options": { "dist": 4, "hashType": "PHash" } });
+    let (status, updated) = call_json(&app, Method::PUT, "/profiles/quick", options).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["options"]["hashType"], "PHash");
+
+    // kept over restarts
+    let app = test_app(data.path());
+    let (_, listed) = call(&app, Method::GET, "/profiles").await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["options"]["dist"], 4);
+    let (status, _) = call(&app, Method::DELETE, "/profiles/quick").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(&app, Method::GET, "/profiles/quick").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ignores_files_folders_and_pairs() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let original = library.path().join("originals/photo-1.png");
+    let copies = library.path().join("copies");
+
+    let (status, file) = call_json(&app, Method::POST, "/ignored", serde_json::json!({ "type": "file", "path": original })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call_json(&app, Method::POST, "/ignored", serde_json::json!({ "type": "file", "path": library.path().join("missing.png") })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    // pairs are the same either way round
+    let (a, b) = (library.path().join("originals/photo-2.png"), copies.join("photo-2 (1).png"));
+    let (_, pair) = call_json(&app, Method::POST, "/ignored", serde_json::json!({ "type": "pair", "a": b, "b": a })).await;
+    let (_, again) = call_json(&app, Method::POST, "/ignored", serde_json::json!({ "type": "pair", "a": a, "b": b })).await;
+    assert_eq!(pair["id"], again["id"]);
+
+    let result = analyze(&app, library.path()).await;
+    let grouped: BTreeSet<PathBuf> = result["groups"].as_array().unwrap().iter().flat_map(|group| paths(&group["files"])).collect();
+    assert!(!grouped.contains(&original));
+    assert!(grouped.contains(&copies.join("photo-1.jpg")));
+    let skipped = result["skipped"].as_array().unwrap();
+    assert!(skipped.iter().any(|file| file["path"].as_str() == original.to_str() && file["reason"] == "Ignored"));
+
+    // kept over restarts, the originals alone don't match each other
+    let app = test_app(data.path());
+    let (_, listed) = call(&app, Method::GET, "/ignored").await;
+    assert_eq!(listed.as_array().unwrap().len(), 2);
+    call_json(&app, Method::POST, "/ignored", serde_json::json!({ "type": "folder", "path": copies })).await;
+    assert_eq!(analyze(&app, library.path()).await["groups"].as_array().unwrap().len(), 0);
+
+    let (status, _) = call(&app, Method::DELETE, &format!("/ignored/{}", file["id"].as_str().unwrap())).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(&app, Method::DELETE, &format!("/ignored/{}", file["id"].as_str().unwrap())).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_ignored_pairs_apart_through_other_copies() {
+    let data = tempfile::tempdir().unwrap();
+    let fixtures = tempfile::tempdir().unwrap();
+    fixtures::generate(fixtures.path()).unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let (a, b, c) = (library.path().join("a.png"), library.path().join("b.png"), library.path().join("c.png"));
+    for copy in [&a, &b, &c] {
+        std::fs::copy(fixtures.path().join("originals/photo-1.png"), copy).unwrap();
+    }
+    let app = test_app(data.path());
+
+    call_json(&app, Method::POST, "/ignored", serde_json::json!({ "type": "pair", "a": a, "b": c })).await;
+    // `b` matches both, the set of `a` and `b` isn't joined with `c`
+    let result = analyze(&app, library.path()).await;
+    let groups: Vec<BTreeSet<PathBuf>> = result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    assert_eq!(groups, vec![BTreeSet::from([a, b])]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_review_marks() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap().to_owned();
+    let fingerprint = groups[0]["fingerprint"].clone();
+    let kept = groups[0]["files"][0]["path"].clone();
+    let other = groups[1]["files"][0]["path"].clone();
+
+    let body = serde_json::json!({ "groups": [fingerprint], "files": [kept], "mark": "ignored" });
+    let (status, _) = call_json(&app, Method::POST, "/marks", body).await;
+    assert_eq!(status, StatusCode::OK);
+    // the marks of the task win over global ones
+    let body = serde_json::json!({ "task": task_id, "files": [kept, other], "mark": "keep" });
+    call_json(&app, Method::POST, "/marks", body).await;
+    let (status, _) = call_json(&app, Method::POST, "/marks", serde_json::json!({ "task": uuid::Uuid::new_v4(), "files": [kept], "mark": "keep" })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // after a restart
+    let app = test_app(data.path());
+    let (_, marked) = call(&app, Method::GET, &format!("/tasks/{}/groups", task_id)).await;
+    assert_eq!(marked[0]["marks"]["mark"], "ignored");
+    assert_eq!(marked[0]["marks"]["files"][kept.as_str().unwrap()], "keep");
+    assert_eq!(marked[1]["marks"]["files"][other.as_str().unwrap()], "keep");
+    let (_, poll) = call(&app, Method::GET, &format!("/poll?taskId={}", task_id)).await;
+    assert_eq!(poll["data"]["groups"], marked);
+    let (_, marks) = call(&app, Method::GET, "/marks").await;
+    assert_eq!(marks.as_array().unwrap().len(), 2);
+    let (_, marks) = call(&app, Method::GET, &format!("/marks?task={}", task_id)).await;
+    assert_eq!(marks.as_array().unwrap().len(), 4);
+
+    let body = serde_json::json!({ "task": task_id, "files": [other], "mark": null });
+    call_json(&app, Method::POST, "/marks", body).await;
+    let (_, marked) = call(&app, Method::GET, &format!("/tasks/{}/groups", task_id)).await;
+    assert!(marked[1].get("marks").is_none(), "{}", marked[1]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deduplicates_submissions() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash", library.path().display());
+    let (_, first) = call(&app, Method::POST, &uri).await;
+    let (_, second) = call(&app, Method::POST, &format!("{}&priority=high", uri)).await;
+    assert_eq!(first["taskId"], second["taskId"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscription_ends_with_result() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash", library.path().display());
+    let (_, task) = call(&app, Method::POST, &uri).await;
+    let uri = format!("/subscribe?taskId={}", task["taskId"].as_str().unwrap());
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+
+    let (events, last) = body.trim_end().rsplit_once("\n\n").unwrap();
+    assert!(events.contains("\"phase\""), "{}", body);
+    let field = |name: &str| last.lines().find_map(|line| line.strip_prefix(name)).map(str::trim);
+    assert_eq!(field("event:"), Some("completed"), "{}", body);
+    let result: Value = serde_json::from_str(field("data:").unwrap()).unwrap();
+    assert!(result["groups"].is_array());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streams_the_events_of_every_task() {
+    use hyper::body::HttpBody;
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let response = app.clone().oneshot(Request::get("/events").body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let mut body = response.into_body();
+
+    let result = analyze(&app, library.path()).await;
+    let copy = PathBuf::from(result["groups"][0]["files"][1]["path"].as_str().unwrap());
+    let target = library.path().join("moved");
+    std::fs::create_dir(&target).unwrap();
+    let (status, _) = call_json(&app, Method::POST, "/files/move", serde_json::json!({ "paths": [copy], "target": target })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut events: Vec<(String, Value)> = Vec::new();
+    let mut text = String::new();
+    while !events.iter().any(|(name, _)| name == "files") {
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(10), body.data()).await.unwrap().unwrap().unwrap();
+        text.push_str(std::str::from_utf8(&chunk).unwrap());
+        while let Some((event, rest)) = text.split_once("\n\n") {
+            let field = |name: &str| event.lines().find_map(|line| line.strip_prefix(name)).map(str::trim);
+            if let (Some(name), Some(data)) = (field("event:"), field("data:")) {
+                events.push((name.to_owned(), serde_json::from_str(data).unwrap()));
+            }
+            text = rest.to_owned();
+        }
+    }
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names.first(), Some(&"submitted"), "{:?}", names);
+    assert!(names.contains(&"progress"));
+    let (_, finished) = events.iter().find(|(name, _)| name == "finished").unwrap();
+    assert_eq!(finished["status"], "completed");
+    assert_eq!(finished["taskId"], events[0].1["taskId"]);
+    assert_eq!(finished["summary"]["groups"].as_u64(), Some(result["groups"].as_array().unwrap().len() as u64));
+    let (_, files) = events.last().unwrap();
+    assert_eq!(files["actions"][0]["action"], "moved");
+    assert_eq!(files["actions"][0]["path"].as_str(), copy.to_str());
+    assert!(files["actions"][0]["to"].as_str().unwrap().starts_with(target.to_str().unwrap()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn captures_task_logs() {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+    let _ = tracing_subscriber::registry().with(crate::logs::layer()).try_init();
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap();
+
+    let (status, logs) = call(&app, Method::GET, &format!("/tasks/{}/logs", task_id)).await;
+    assert_eq!(status, StatusCode::OK);
+    let messages: Vec<&str> = logs.as_array().unwrap().iter().map(|line| line["message"].as_str().unwrap()).collect();
+    assert!(messages.iter().any(|message| message.starts_with("folder scanned")), "{:?}", messages);
+
+    let other = "/tasks/00000000-0000-0000-0000-000000000000/logs";
+    assert_eq!(call(&app, Method::GET, other).await.0, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn logs_json_lines_to_rotated_files() {
+    use tracing_subscriber::layer::SubscriberExt;
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("logs/server.log");
+    let options = crate::logging::LogOptions { file: Some(file.clone()), max_size: 300, keep: 2, format: crate::logging::LogFormat::Json };
+    let subscriber = tracing_subscriber::registry().with(crate::logging::layer(&options).unwrap());
+
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("http", request_id = "abc", method = "POST");
+        let _span = span.enter();
+        for n in 0..10 {
+            tracing::info!(n, "line");
+        }
+    });
+
+    let rotated = |n: usize| dir.path().join(format!("logs/server.log.{}", n));
+    assert!(rotated(1).exists() && rotated(2).exists() && !rotated(3).exists());
+    let current = std::fs::read_to_string(&file).unwrap();
+    let lines: Vec<Value> = current.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert!(!lines.is_empty() && current.len() <= 300, "{}", current);
+    let last = lines.last().unwrap();
+    assert_eq!(last["n"], 9);
+    assert_eq!(last["message"], "line");
+    assert_eq!(last["level"], "INFO");
+    assert_eq!(last["request_id"], "abc");
+    assert_eq!(last["spans"], "http");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deletes_files_one_by_one() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let file = library.path().join("copy.jpg");
+    std::fs::write(&file, b"copy").unwrap();
+    let missing = library.path().join("missing.jpg");
+    let app = test_app(data.path());
+
+    let body = serde_json::json!({ "paths": [file, missing], "permanent": true });
+    let (status, outcomes) = call_json(&app, Method::POST, "/files/delete", body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(outcomes[0]["error"].is_null(), "{}", outcomes);
+    assert!(outcomes[1]["error"].is_string(), "{}", outcomes);
+    assert!(!file.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn moves_files_without_overwriting() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let target = library.path().join("to_review/2024");
+    let first = library.path().join("a/copy.jpg");
+    let second = library.path().join("b/copy.jpg");
+    for file in [&first, &second] {
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(file, file.to_str().unwrap()).unwrap();
+    }
+    let app = test_app(data.path());
+
+    let body = serde_json::json!({ "paths": [first, second], "target": target });
+    let (status, outcomes) = call_json(&app, Method::POST, "/files/move", body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(outcomes[0]["destination"], serde_json::json!(target.join("copy.jpg")));
+    assert!(outcomes[1]["error"].as_str().unwrap().ends_with("already exists"), "{}", outcomes);
+    assert_eq!(std::fs::read_to_string(target.join("copy.jpg")).unwrap(), first.to_str().unwrap());
+    assert!(second.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn links_identical_copies_only() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let keep = library.path().join("photo.jpg");
+    let copy = library.path().join("photo (1).jpg");
+    let edited = library.path().join("photo-edited.jpg");
+    std::fs::write(&keep, b"photo").unwrap();
+    std::fs::write(&copy, b"photo").unwrap();
+    std::fs::write(&edited, b"phot0").unwrap();
+    let app = test_app(data.path());
+
+    let body = serde_json::json!({ "keep": keep, "paths": [copy, edited] });
+    let (status, outcomes) = call_json(&app, Method::POST, "/files/link", body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(outcomes[0]["error"].is_null(), "{}", outcomes);
+    assert!(outcomes[1]["error"].is_string(), "{}", outcomes);
+
+    use std::os::unix::fs::MetadataExt;
+    let inode = |path: &std::path::Path| std::fs::metadata(path).unwrap().ino();
+    assert_eq!(inode(&keep), inode(&copy));
+    assert_ne!(inode(&keep), inode(&edited));
+
+    // a copy of its own again, edits of the kept one don't show up in it
+    let (status, outcomes) = call(&app, Method::POST, "/undo").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(outcomes[0]["error"].is_null(), "{}", outcomes);
+    assert_ne!(inode(&keep), inode(&copy));
+    std::fs::write(&keep, b"edited").unwrap();
+    assert_eq!(std::fs::read(&copy).unwrap(), b"photo");
+}
+
+#[tokio::test]
+async fn resolves_groups_atomically() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let review = library.path().join("review");
+    let path = |name: &str| library.path().join(name);
+    for name in ["a.jpg", "a (1).jpg", "a (2).jpg", "b.jpg", "b (1).jpg"] {
+        std::fs::write(path(name), &name.as_bytes()[..1]).unwrap();
+    }
+    let app = test_app(data.path());
+
+    // the second group fails at its last action, its move has to be undone
+    let body = serde_json::json!({ "groups": [
+        { "keep": path("a.jpg"), "actions": [
+            { "path": path("a (1).jpg"), "action": "delete" },
+            { "path": path("a (2).jpg"), "action": "move", "target": review },
+        ] },
+        { "keep": path("b.jpg"), "actions": [
+            { "path": path("b (1).jpg"), "action": "move", "target": review },
+            { "path": path("missing.jpg"), "action": "delete" },
+        ] },
+    ] });
+    let (status, reports) = call_json(&app, Method::POST, "/resolve", body).await;
+    assert_eq!(status, StatusCode::OK);
+
+    assert_eq!(reports[0]["applied"], true, "{}", reports);
+    assert!(reports[0]["outcomes"].as_array().unwrap().iter().all(|o| o["error"].is_null()));
+    assert!(!path("a (1).jpg").exists());
+    assert!(!path("a (2).jpg").exists());
+    assert!(review.join("a (2).jpg").exists());
+
+    assert_eq!(reports[1]["applied"], false, "{}", reports);
+    assert!(reports[1]["outcomes"].as_array().unwrap().iter().all(|o| o["error"].is_string()));
+    assert!(path("b (1).jpg").exists());
+    assert!(!review.join("b (1).jpg").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn quarantines_duplicates() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+
+    let body = serde_json::json!({ "taskId": tasks[0]["taskId"], "policy": "largest", "action": "quarantine" });
+    let (_, preview) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    let planned: Vec<PathBuf> = preview["plan"]["groups"].as_array().unwrap().iter().flat_map(|group| paths(&group["actions"])).collect();
+    let (status, reports) = call_json(&app, Method::POST, "/resolve", preview["plan"].clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(reports.as_array().unwrap().iter().all(|report| report["applied"] == true), "{}", reports);
+
+    // in the folders they were in, below the quarantine of the data directory
+    let (_, listed) = call(&app, Method::GET, "/quarantine").await;
+    assert_eq!(paths(&listed), planned.iter().cloned().collect());
+    for path in &planned {
+        assert!(!path.exists());
+        let mirrored: PathBuf = path.components().skip(1).collect();
+        assert!(data.path().join("quarantine").join(mirrored).is_file(), "{}", path.display());
+    }
+
+    let (status, _) = call_json(&app, Method::POST, "/quarantine/restore", serde_json::json!({ "ids": [uuid::Uuid::new_v4()] })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, outcomes) = call_json(&app, Method::POST, "/quarantine/restore", serde_json::json!({ "ids": [listed[0]["id"]] })).await;
+    assert!(outcomes[0]["error"].is_null(), "{}", outcomes);
+    assert!(std::path::Path::new(listed[0]["path"].as_str().unwrap()).is_file());
+
+    // kept over restarts, and purged only when asked for all of them
+    let app = test_app(data.path());
+    for body in [serde_json::json!({}), serde_json::json!({ "ids": [] }), serde_json::json!({ "ids": [listed[1]["id"]], "all": true })] {
+        let (status, _) = call_json(&app, Method::POST, "/quarantine/purge", body.clone()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    }
+    let (_, outcomes) = call_json(&app, Method::POST, "/quarantine/purge", serde_json::json!({ "all": true })).await;
+    assert_eq!(outcomes.as_array().unwrap().len(), planned.len() - 1);
+    assert!(outcomes.as_array().unwrap().iter().all(|outcome| outcome["error"].is_null()), "{}", outcomes);
+    let (_, listed) = call(&app, Method::GET, "/quarantine").await;
+    assert_eq!(listed, serde_json::json!([]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn suggests_copies_to_keep() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    // a PNG named as a camera raw file, images are told apart by their content
+    let original = fixtures.iter().find(|f| f.path.starts_with(library.path().join("originals"))).unwrap();
+    let raw = library.path().join("copies").join("raw.nef");
+    std::fs::copy(&original.path, &raw).unwrap();
+    let rules = KeepRules {
+        rules: vec![KeepRule::PreferRaw, KeepRule::PreferredFolder, KeepRule::HighestResolution],
+        preferred: vec![library.path().join("originals")],
+        ..KeepRules::default()
+    };
+    let app = app(create_state(data.path(), StateOptions { keep_rules: rules, ..StateOptions::default() }).unwrap());
+
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+    for group in groups.as_array().unwrap() {
+        let files = paths(&group["files"]);
+        let suggestion = &group["suggestion"];
+        if files.contains(&raw) {
+            assert_eq!(suggestion["keep"].as_str(), raw.to_str());
+            assert_eq!(suggestion["rules"], serde_json::json!(["preferRaw"]));
+        } else if files.iter().any(|path| path.starts_with(library.path().join("originals"))) {
+            assert!(std::path::Path::new(suggestion["keep"].as_str().unwrap()).starts_with(library.path().join("originals")), "{}", group);
+            assert_eq!(suggestion["rules"][0], "preferredFolder");
+        }
+    }
+
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let body = serde_json::json!({ "taskId": tasks[0]["taskId"], "policy": "suggested", "action": "delete" });
+    let (status, preview) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", preview);
+    let kept: Vec<_> = preview["plan"]["groups"].as_array().unwrap().iter().map(|group| group["keep"].clone()).collect();
+    let suggested: Vec<_> = groups.as_array().unwrap().iter().map(|group| group["suggestion"]["keep"].clone()).collect();
+    assert_eq!(kept, suggested);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn suggests_copies_on_preferred_storage() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let (originals, copies) = (library.path().join("originals"), library.path().join("copies"));
+    let rules = KeepRules { rules: vec![KeepRule::PreferredStorage], storage: vec![StorageClass::Nas], ..KeepRules::default() };
+    let app = app(create_state(data.path(), StateOptions { keep_rules: rules, ..StateOptions::default() }).unwrap());
+    for (path, class) in [(&originals, "ssd"), (&copies, "nas")] {
+        let (status, _) = call_json(&app, Method::POST, "/roots", serde_json::json!({ "path": path, "storageClass": class })).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+    assert!(!groups.as_array().unwrap().is_empty());
+    for group in groups.as_array().unwrap() {
+        let suggestion = &group["suggestion"];
+        assert!(std::path::Path::new(suggestion["keep"].as_str().unwrap()).starts_with(&copies), "{}", group);
+        assert_eq!(suggestion["rules"], serde_json::json!(["preferredStorage"]));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn protects_reference_folders() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let (originals, copies) = (library.path().join("originals"), library.path().join("copies"));
+    let encoded = |path: &std::path::Path| url::form_urlencoded::byte_serialize(path.to_str().unwrap().as_bytes()).collect::<String>();
+    let rules = KeepRules { protected: Arc::new(Protected::new(vec![copies.clone()])), ..KeepRules::default() };
+    let app = app(create_state(data.path(), StateOptions { keep_rules: rules, ..StateOptions::default() }).unwrap());
+
+    // the protected copy is suggested whatever the other rules say
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+    for group in groups.as_array().unwrap() {
+        assert!(std::path::Path::new(group["suggestion"]["keep"].as_str().unwrap()).starts_with(&copies), "{}", group);
+    }
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let body = serde_json::json!({ "taskId": tasks[0]["taskId"], "policy": "largest", "action": "delete" });
+    let (_, preview) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    let planned: Vec<_> = preview["plan"]["groups"].as_array().unwrap().iter().flat_map(|group| paths(&group["actions"])).collect();
+    assert!(planned.iter().all(|path| !path.starts_with(&copies)), "{}", preview);
+
+    let copy = copies.join("photo-0 (1).png");
+    let body = serde_json::json!({ "groups": [
+        { "keep": originals.join("photo-0.png"), "actions": [{ "path": copy, "action": "delete" }] },
+    ] });
+    let (_, reports) = call_json(&app, Method::POST, "/resolve", body).await;
+    assert_eq!(reports[0]["applied"], false, "{}", reports);
+    let (_, outcomes) = call_json(&app, Method::POST, "/files/move", serde_json::json!({ "paths": [copy], "target": originals })).await;
+    assert!(outcomes[0]["error"].as_str().unwrap().contains("protected"), "{}", outcomes);
+    let (status, _) = call(&app, Method::POST, &format!("/delete_file?path={}", encoded(&copy))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(copy.exists());
+
+    let (status, listed) = call_json(&app, Method::POST, "/protected", serde_json::json!({ "path": originals })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(listed[1]["configured"], false);
+    let (_, outcomes) = call_json(&app, Method::POST, "/files/delete", serde_json::json!({ "paths": [originals.join("photo-1.png")] })).await;
+    assert!(outcomes[0]["error"].is_string(), "{}", outcomes);
+    let (status, _) = call(&app, Method::DELETE, &format!("/protected?path={}", encoded(&copies))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = call(&app, Method::DELETE, &format!("/protected?path={}", encoded(&originals))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(&app, Method::DELETE, &format!("/protected?path={}", encoded(&originals))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tells_copies_apart_by_pixels_rather_than_metadata() {
+    let data = tempfile::tempdir().unwrap();
+    let samples = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(samples.path()).unwrap();
+    let png = std::fs::read(samples.path().join("originals/photo-0.png")).unwrap();
+    // a tEXt chunk right after the IHDR one, as taggers add them
+    let text = b"tEXtComment\0geotagged";
+    let mut crc = flate2::Crc::new();
+    crc.update(text);
+    let mut tagged = png[..33].to_vec();
+    tagged.extend_from_slice(&(text.len() as u32 - 4).to_be_bytes());
+    tagged.extend_from_slice(text);
+    tagged.extend_from_slice(&crc.sum().to_be_bytes());
+    tagged.extend_from_slice(&png[33..]);
+    std::fs::write(library.path().join("a.png"), &png).unwrap();
+    std::fs::write(library.path().join("b.png"), &tagged).unwrap();
+    let app = test_app(data.path());
+    // a single byte has the files decoded one at a time for their pixels, with nothing kept
+    let budgeted = tempfile::tempdir().unwrap();
+    let limits = TaskLimits { memory_budget: Some(1), ..TaskLimits::default() };
+    let budgeted = crate::server::app(create_state(budgeted.path(), StateOptions { limits, ..StateOptions::default() }).unwrap());
+
+    for (app, exact_by, likeness) in [(&app, "bytes", "near"), (&app, "pixels", "exact"), (&budgeted, "pixels", "exact")] {
+        let body = serde_json::json!({ "path": library.path(), "dist": 0, "hashType": "DHash", "exactBy": exact_by });
+        let (status, task) = call_json(app, Method::POST, "/analyze", body).await;
+        assert_eq!(status, StatusCode::OK, "{}", task);
+        let uri = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+        let result = loop {
+            let (_, resp) = call(app, Method::GET, &uri).await;
+            match resp["type"].as_str().unwrap() {
+                "Queued" | "Pending" => tokio::time::sleep(POLL_INTERVAL).await,
+                "Completed" => break resp["data"].clone(),
+                other => panic!("analysis {}: {}", other, resp),
+            }
+        };
+        assert_eq!(result["groups"].as_array().unwrap().len(), 1, "{}", result);
+        assert_eq!(result["groups"][0]["likeness"], likeness, "{}", exact_by);
+    }
+}
+
+#[test]
+fn keeps_decoded_images_within_the_budget() {
+    use crate::analyzer::FileStamp;
+    use crate::memo::DecodeMemo;
+    use crate::throttle::MemoryBudget;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, name).unwrap();
+        (FileStamp::of(&path).unwrap(), path)
+    };
+    let (a, b, c) = (path("a.png"), path("b.png"), path("c.png"));
+    let image = Arc::new(image::DynamicImage::new_rgb8(100, 100));
+    let bytes = image.as_bytes().len() as u64;
+    // half of it for the memo, two images
+    let budget = MemoryBudget::new(4 * bytes);
+    let memo = DecodeMemo::new(Some(&budget));
+
+    for (stamp, path) in [&a, &b, &c] {
+        memo.insert(path.clone(), *stamp, image.clone(), true);
+    }
+    assert!(memo.get(&a.1, a.0).is_none());
+    assert!(memo.get(&c.1, c.0).is_some_and(|(_, full)| full));
+    assert_eq!(budget.available(), 2 * bytes);
+    // edited since
+    std::fs::write(&c.1, "edited").unwrap();
+    assert!(memo.get(&c.1, FileStamp::of(&c.1).unwrap()).is_none());
+
+    // decoding takes back what the kept images hold
+    let reservation = memo.reserve(|| 4 * bytes);
+    assert!(memo.get(&b.1, b.0).is_none() && memo.get(&c.1, c.0).is_none());
+    assert_eq!(budget.available(), 0);
+    drop(reservation);
+    assert_eq!(budget.available(), 4 * bytes);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tells_exact_copies_from_near_ones() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+    let content = |path: &Value| std::fs::read(path.as_str().unwrap()).unwrap();
+
+    let mut copies = 0;
+    for group in groups.as_array().unwrap() {
+        let files = group["files"].as_array().unwrap();
+        let identical: Vec<Vec<usize>> = serde_json::from_value(group.get("identical").cloned().unwrap_or_default()).unwrap_or_default();
+        for (i, a) in files.iter().enumerate() {
+            for (j, b) in files.iter().enumerate().skip(i + 1) {
+                let same = identical.iter().any(|same| same.contains(&i) && same.contains(&j));
+                assert_eq!(same, content(&a["path"]) == content(&b["path"]), "{}", group);
+            }
+        }
+        let exact = identical.len() == 1 && identical[0].len() == files.len();
+        assert_eq!(group["likeness"], if exact { "exact" } else { "near" });
+        copies += identical.len();
+    }
+    assert!(copies > 0);
+
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let body = serde_json::json!({ "taskId": tasks[0]["taskId"], "policy": "oldest", "exactOnly": true, "action": "delete" });
+    let (status, preview) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", preview);
+    let planned = preview["plan"]["groups"].as_array().unwrap();
+    assert!(!planned.is_empty());
+    for group in planned {
+        for action in group["actions"].as_array().unwrap() {
+            assert_eq!(content(&action["path"]), content(&group["keep"]));
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn includes_hashes_and_checksums() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let groups = analyze(&app, library.path()).await["groups"].clone();
+
+    let mut checksums = 0;
+    for group in groups.as_array().unwrap() {
+        let files = group["files"].as_array().unwrap();
+        let hashes: Vec<_> = files.iter().map(|file| image_hasher::ImageHash::<Box<[u8]>>::from_base64(file["hash"].as_str().unwrap()).unwrap()).collect();
+        for (hash, distance) in hashes.iter().zip(group["distances"].as_array().unwrap()) {
+            assert_eq!(hashes[0].dist(hash) as u64, distance.as_u64().unwrap());
+        }
+        for file in files {
+            let same_size = files.iter().filter(|other| other["size"] == file["size"]).count() > 1;
+            assert_eq!(file.get("checksum").is_some(), same_size, "{}", file);
+            if let Some(checksum) = file.get("checksum") {
+                assert_eq!(checksum.as_str().unwrap(), sha256::digest(std::fs::read(file["path"].as_str().unwrap()).unwrap().as_slice()));
+                checksums += 1;
+            }
+        }
+    }
+    assert!(checksums > 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn regroups_with_other_distances() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let result = analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap().to_owned();
+    let groups = |result: &Value| -> BTreeSet<BTreeSet<PathBuf>> {
+        result["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect()
+    };
+
+    // nothing is read again
+    std::fs::remove_dir_all(library.path()).unwrap();
+    let regroup = |dist: u32| {
+        let (app, uri) = (app.clone(), format!("/tasks/{}/regroup", task_id));
+        async move {
+            let (status, task) = call_json(&app, Method::POST, &uri, serde_json::json!({ "dist": dist })).await;
+            assert_eq!(status, StatusCode::OK, "{}", task);
+            let (_, resp) = call(&app, Method::GET, &format!("/poll?taskId={}", task["taskId"].as_str().unwrap())).await;
+            assert_eq!(resp["type"], "Completed");
+            resp["data"].clone()
+        }
+    };
+    assert_eq!(groups(&regroup(10).await), groups(&result));
+    let strict = groups(&regroup(0).await);
+    assert_ne!(strict, groups(&result));
+    for group in &strict {
+        assert!(groups(&result).iter().any(|wide| wide.is_superset(group)));
+    }
+
+    let (status, _) = call_json(&app, Method::POST, &format!("/tasks/{}/regroup", uuid::Uuid::new_v4()), serde_json::json!({ "dist": 1 })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    assert_eq!(tasks.as_array().unwrap().len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plans_without_touching_files() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let result = analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].clone();
+
+    let body = serde_json::json!({ "taskId": task_id, "policy": "largest", "action": "delete" });
+    let (status, preview) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", preview);
+
+    let mut files = 0;
+    let mut bytes = 0;
+    let planned_groups = preview["plan"]["groups"].as_array().unwrap();
+    for (group, planned) in result["groups"].as_array().unwrap().iter().zip(planned_groups) {
+        let group = group["files"].as_array().unwrap();
+        let largest = group.iter().map(|file| file["size"].as_u64().unwrap()).max().unwrap();
+        let keep = group.iter().find(|file| file["path"] == planned["keep"]).unwrap();
+        assert_eq!(keep["size"].as_u64(), Some(largest));
+
+        let actions = planned["actions"].as_array().unwrap();
+        assert_eq!(actions.len(), group.len() - 1);
+        assert!(actions.iter().all(|action| action["action"] == "delete"));
+        files += actions.len() as u64;
+        bytes += group.iter().map(|file| file["size"].as_u64().unwrap()).sum::<u64>() - largest;
+    }
+    assert_eq!(preview["files"].as_u64(), Some(files));
+    assert_eq!(preview["bytes"].as_u64(), Some(bytes));
+    assert_eq!(analyze(&app, library.path()).await["groups"], result["groups"]);
+
+    let body = serde_json::json!({ "taskId": task_id, "policy": "newest", "action": "move", "target": "/review" });
+    let (_, preview) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    assert_eq!(preview["plan"]["groups"][0]["actions"][0]["target"], "/review");
+    // moved files still take their space
+    assert_eq!(preview["files"].as_u64(), Some(files));
+    assert_eq!(preview["bytes"], 0);
+
+    // no silent delete for a missing or mistyped action
+    for body in [
+        serde_json::json!({ "taskId": task_id, "policy": "largest" }),
+        serde_json::json!({ "taskId": task_id, "policy": "largest", "action": "move" }),
+        serde_json::json!({ "taskId": task_id, "policy": "largest", "action": "shred" }),
+    ] {
+        let (status, _) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    let body = serde_json::json!({ "taskId": uuid::Uuid::new_v4(), "policy": "largest", "action": "delete" });
+    let (status, _) = call_json(&app, Method::POST, "/resolve/plan", body).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn undoes_last_batch() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let review = library.path().join("review");
+    let path = |name: &str| library.path().join(name);
+    for name in ["a.jpg", "a (1).jpg", "b.jpg"] {
+        std::fs::write(path(name), name).unwrap();
+    }
+    let app = test_app(data.path());
+
+    let body = serde_json::json!({ "paths": [path("b.jpg")], "target": review });
+    let (status, _) = call_json(&app, Method::POST, "/files/move", body).await;
+    assert_eq!(status, StatusCode::OK);
+    let body = serde_json::json!({ "groups": [
+        { "keep": path("a.jpg"), "actions": [{ "path": path("a (1).jpg"), "action": "delete" }] },
+    ] });
+    let (status, _) = call_json(&app, Method::POST, "/resolve", body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!path("a (1).jpg").exists());
+
+    // the resolution first, then the move
+    let (status, outcomes) = call(&app, Method::POST, "/undo").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(outcomes[0]["error"].is_null(), "{}", outcomes);
+    assert_eq!(std::fs::read_to_string(path("a (1).jpg")).unwrap(), "a (1).jpg");
+    assert!(review.join("b.jpg").exists());
+
+    let (status, _) = call(&app, Method::POST, "/undo").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(path("b.jpg").exists());
+    assert!(!review.join("b.jpg").exists());
+
+    let (status, _) = call(&app, Method::POST, "/undo").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn imports_reports_of_other_tools() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let path = |name: &str| library.path().join(name);
+    for name in ["a.jpg", "a (1).jpg", "b.jpg", "b copy.jpg"] {
+        std::fs::write(path(name), name).unwrap();
+    }
+    let app = test_app(data.path());
+    let import = |format: &str, report: String| {
+        let (app, uri) = (app.clone(), format!("/import?format={}", format));
+        async move {
+            let request = Request::builder().method(Method::POST).uri(uri).body(Body::from(report)).unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+        }
+    };
+
+    let fdupes = format!(
+        "4 bytes each:\n{}\n{}\n{}\n\n{}\n{}\n\n",
+        path("a.jpg").display(), path("a (1).jpg").display(), path("gone.jpg").display(),
+        path("b.jpg").display(), path("b copy.jpg").display(),
+    );
+    let (status, task): (StatusCode, Value) = import("fdupes", fdupes).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, resp) = call(&app, Method::GET, &format!("/poll?taskId={}", task["taskId"].as_str().unwrap())).await;
+    assert_eq!((status, resp["type"].as_str()), (StatusCode::OK, Some("Completed")));
+    let groups: BTreeSet<BTreeSet<PathBuf>> = resp["data"]["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    let expected = BTreeSet::from([
+        BTreeSet::from([path("a.jpg"), path("a (1).jpg")]),
+        BTreeSet::from([path("b.jpg"), path("b copy.jpg")]),
+    ]);
+    assert_eq!(groups, expected);
+    assert_eq!(paths(&resp["data"]["errors"]), BTreeSet::from([path("gone.jpg")]));
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    assert_eq!(tasks[0]["request"]["path"].as_str(), library.path().to_str());
+
+    let czkawka = serde_json::json!([[{ "path": path("a.jpg"), "size": 5 }, { "path": path("a (1).jpg"), "size": 5 }], [{ "path": path("b.jpg") }]]);
+    let (status, task) = import("czkawka", czkawka.to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, resp) = call(&app, Method::GET, &format!("/poll?taskId={}", task["taskId"].as_str().unwrap())).await;
+    let groups: Vec<_> = resp["data"]["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    assert_eq!(groups, [BTreeSet::from([path("a.jpg"), path("a (1).jpg")])]);
+
+    let (status, _) = import("fdupes", "\n\n".to_owned()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn groups_files_named_like_copies() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let samples = tempfile::tempdir().unwrap();
+    fixtures::generate(samples.path()).unwrap();
+    let path = |name: &str| library.path().join(name);
+    std::fs::create_dir(path("backup")).unwrap();
+    let (photo, other) = (samples.path().join("originals/photo-0.png"), samples.path().join("originals/photo-1.png"));
+    for name in ["IMG_1234.png", "IMG_1234 (1).png", "IMG_1234 - Copy.PNG", "backup/img_1234.png", "IMG_1234_1.png", "IMG_5678.png"] {
+        std::fs::copy(&photo, path(name)).unwrap();
+    }
+    // another size
+    std::fs::copy(&other, path("IMG_5678 (1).png")).unwrap();
+    let app = test_app(data.path());
+
+    let (status, task) = call(&app, Method::POST, &format!("/names?path={}", library.path().display())).await;
+    assert_eq!(status, StatusCode::OK, "{}", task);
+    let (status, resp) = call(&app, Method::GET, &format!("/poll?taskId={}", task["taskId"].as_str().unwrap())).await;
+    assert_eq!((status, resp["type"].as_str()), (StatusCode::OK, Some("Completed")));
+    let groups: BTreeSet<BTreeSet<PathBuf>> = resp["data"]["groups"].as_array().unwrap().iter().map(|group| paths(&group["files"])).collect();
+    let expected = ["IMG_1234.png", "IMG_1234 (1).png", "IMG_1234 - Copy.PNG", "backup/img_1234.png"].map(path);
+    assert_eq!(groups, BTreeSet::from([BTreeSet::from(expected)]));
+
+    let (status, _) = call(&app, Method::POST, &format!("/names?path={}", path("missing").display())).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exports_groups_as_csv_and_json() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let result = analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap();
+    let groups = result["groups"].as_array().unwrap();
+
+    let (status, exported) = call(&app, Method::GET, &format!("/tasks/{}/export", task_id)).await;
+    assert_eq!(status, StatusCode::OK);
+    let exported = exported.as_array().unwrap();
+    assert_eq!(exported.len(), groups.len());
+    for (group, exported) in groups.iter().zip(exported) {
+        assert_eq!(paths(&exported["files"]), paths(&group["files"]));
+        assert_eq!(exported["files"][0]["distance"], 0);
+        assert!(exported["files"].as_array().unwrap().iter().all(|file| file["distance"].as_u64().unwrap() <= 10));
+    }
+
+    let request = Request::builder()
+        .uri(format!("/tasks/{}/export?format=csv", task_id))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/csv; charset=utf-8");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let csv = String::from_utf8(body.to_vec()).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("group,fingerprint,path,size,date,distance"));
+    let files: usize = groups.iter().map(|group| group["files"].as_array().unwrap().len()).sum();
+    assert_eq!(lines.count(), files);
+
+    let (status, _) = call(&app, Method::GET, &format!("/tasks/{}/export", uuid::Uuid::new_v4())).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exports_a_standalone_html_report() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    // shown as text, not as markup
+    let odd = library.path().join("<b>&");
+    std::fs::create_dir(&odd).unwrap();
+    std::fs::copy(library.path().join("originals/photo-0.png"), odd.join("photo-0.png")).unwrap();
+    let app = test_app(data.path());
+    let result = analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+
+    let request = Request::get(format!("/tasks/{}/export?format=html", tasks[0]["taskId"].as_str().unwrap())).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+    assert!(response.headers()["content-disposition"].to_str().unwrap().ends_with(".html\""));
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let html = String::from_utf8(body.to_vec()).unwrap();
+    let groups = result["groups"].as_array().unwrap();
+    let files: usize = groups.iter().map(|group| group["files"].as_array().unwrap().len()).sum();
+    assert_eq!(html.matches("<section>").count(), groups.len());
+    // every thumbnail inside the page, nothing to fetch
+    assert_eq!(html.matches("src=\"data:image/jpeg;base64,").count(), files);
+    assert!(!html.contains("<b>&") && html.contains("&lt;b&gt;&amp;"));
+    assert_eq!(html.matches("class=\"keep\"").count(), groups.len());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pages_folders_and_groups() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+
+    let get = |uri: String| {
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+            let total: usize = response.headers()["x-total-count"].to_str().unwrap().parse().unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (total, serde_json::from_slice::<Vec<Value>>(&body).unwrap())
+        }
+    };
+
+    let (total, all) = get(format!("/list_folder?path={}", library.path().display())).await;
+    assert_eq!(all.len(), total);
+    let (total, page) = get(format!("/list_folder?path={}&offset=1&limit=2", library.path().display())).await;
+    assert_eq!((total, page.as_slice()), (all.len(), &all[1..3]));
+
+    let result = analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap();
+    let groups = result["groups"].as_array().unwrap();
+    let (total, page) = get(format!("/tasks/{}/groups?offset=1&limit=1000", task_id)).await;
+    assert_eq!((total, page.as_slice()), (groups.len(), &groups[1..]));
+    let (_, past_end) = get(format!("/tasks/{}/groups?offset=1000", task_id)).await;
+    assert!(past_end.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn filters_task_groups() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let result = analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let groups_uri = format!("/tasks/{}/groups", tasks[0]["taskId"].as_str().unwrap());
+    let groups = result["groups"].as_array().unwrap();
+    let fingerprints = |groups: &[Value]| -> Vec<Value> { groups.iter().map(|group| group["fingerprint"].clone()).collect() };
+    let filtered = |query: String| {
+        let app = app.clone();
+        let uri = format!("{}?{}", groups_uri, query);
+        async move {
+            let response = app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+            let total: usize = response.headers()["x-total-count"].to_str().unwrap().parse().unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let page: Vec<Value> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(total, page.len());
+            page.iter().map(|group| group["fingerprint"].clone()).collect::<Vec<_>>()
+        }
+    };
+
+    let sizes = |group: &Value| group["files"].as_array().unwrap().iter().map(|file| file["size"].as_u64().unwrap()).collect::<Vec<_>>();
+    let wasted = |group: &Value| sizes(group).iter().sum::<u64>() - sizes(group).into_iter().max().unwrap();
+    let largest = groups.iter().map(|group| group["files"].as_array().unwrap().len()).max().unwrap();
+    let expected: Vec<Value> = groups.iter().filter(|group| group["files"].as_array().unwrap().len() >= largest).cloned().collect();
+    assert_eq!(filtered(format!("minGroupSize={}", largest)).await, fingerprints(&expected));
+    let most = groups.iter().map(wasted).max().unwrap();
+    let expected: Vec<Value> = groups.iter().filter(|group| wasted(group) >= most).cloned().collect();
+    assert_eq!(filtered(format!("minWastedBytes={}", most)).await, fingerprints(&expected));
+
+    // some fixture names have spaces
+    let file: String = url::form_urlencoded::byte_serialize(groups[0]["files"][0]["path"].as_str().unwrap().as_bytes()).collect();
+    assert_eq!(filtered(format!("pathPrefix={}", library.path().display())).await, fingerprints(groups));
+    assert_eq!(filtered(format!("pathPrefix={}", file)).await, fingerprints(&groups[..1]));
+    assert!(filtered(format!("pathPrefix={}&minGroupSize=1000", library.path().display())).await.is_empty());
+
+    for group in groups {
+        assert_eq!(group["stats"]["files"], group["files"].as_array().unwrap().len());
+        assert_eq!(group["stats"]["totalBytes"], sizes(group).iter().sum::<u64>());
+        assert_eq!(group["stats"]["reclaimableBytes"], wasted(group));
+    }
+    let mut expected = groups.clone();
+    expected.sort_by_key(|group| std::cmp::Reverse(wasted(group)));
+    assert_eq!(filtered("sortBy=reclaimableBytes&order=desc".to_owned()).await, fingerprints(&expected));
+    // ties in the order they were found
+    let mut expected = groups.clone();
+    expected.sort_by_key(|group| group["files"].as_array().unwrap().len());
+    assert_eq!(filtered("sortBy=files".to_owned()).await, fingerprints(&expected));
+}
+
+#[tokio::test]
+async fn sorts_and_filters_folder_listings() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    std::fs::create_dir(library.path().join("empty")).unwrap();
+    let app = test_app(data.path());
+    let list = |query: &str| format!("/list_folder?path={}&{}", library.path().display(), query);
+
+    let (status, files) = call(&app, Method::GET, &list("sortBy=size&order=desc")).await;
+    assert_eq!(status, StatusCode::OK);
+    let sizes: Vec<u64> = files.as_array().unwrap().iter().map(|file| file["size"].as_u64().unwrap()).collect();
+    assert!(!sizes.is_empty() && sizes.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", sizes);
+    assert!(files.as_array().unwrap().iter().all(|file| file["kind"] == "image"));
+
+    let (_, dirs) = call(&app, Method::GET, &list("onlyDirs=true")).await;
+    assert!(paths(&dirs).contains(&library.path().join("empty")), "{}", dirs);
+    assert!(dirs.as_array().unwrap().iter().all(|dir| dir["kind"] == "folder" && dir["size"] == 0));
+
+    let (_, both) = call(&app, Method::GET, &list("onlyImages=false")).await;
+    assert_eq!(both.as_array().unwrap().len(), files.as_array().unwrap().len() + dirs.as_array().unwrap().len());
+
+    let (status, _) = call(&app, Method::GET, &list("onlyImages=true&onlyDirs=true")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn serves_openapi_document() {
+    let data = tempfile::tempdir().unwrap();
+    let app = test_app(data.path());
+
+    let (status, doc) = call(&app, Method::GET, "/api/openapi.json").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+    for (path, method) in [("/analyze", "post"), ("/tasks/{id}/export", "get"), ("/roots", "delete"), ("/resolve", "post")] {
+        assert!(doc["paths"][path][method].is_object(), "{} {} missing", method, path);
+    }
+    let schemas = &doc["components"]["schemas"];
+    assert!(schemas["AnalyzeResult"].is_object(), "{}", schemas);
+    // same name in two modules
+    assert!(schemas["FileAction"].is_object() && schemas["JournalAction"].is_object(), "{}", schemas);
+    assert_eq!(schemas["FileInfo"]["properties"]["path"]["type"], "string");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resolves_watched_folders_by_rules() {
+    use clap::Parser;
+    use crate::config::{Cli, Config};
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let file = data.path().join("config.toml");
+    let config = format!(
+        r#"watch = ["{library}"]
+
+[[auto-resolve]]
+name = "recompressed copies"
+action = "quarantine"
+copies = {{ path = "**/copies/*", formats = ["JPG"] }}
+keep = {{ path = "{library}/originals/**" }}
+"#,
+        library = library.path().display(),
+    );
+    std::fs::write(&file, config).unwrap();
+    let cli = Cli::try_parse_from(["image-analyzer", "--config", file.to_str().unwrap()]).unwrap();
+    let config = Config::new(cli.settings, cli.config.as_ref()).unwrap();
+    let options = WatchOptions { interval: POLL_INTERVAL, dist: 10, ..WatchOptions::default() };
+    let watcher = Watcher::new(config.watch, options).with_rules(config.auto_resolve);
+    let app = app(create_state(data.path(), StateOptions { watcher, ..StateOptions::default() }).unwrap());
+
+    let resolved = loop {
+        let (_, status) = call(&app, Method::GET, "/watch").await;
+        if status[0]["resolved"].as_u64() > Some(0) {
+            break status[0]["resolved"].clone();
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+    // the truncated JPEG is in no group
+    let copies = library.path().join("copies");
+    let expected: BTreeSet<PathBuf> = (0..3)
+        .flat_map(|i| [copies.join(format!("photo-{}.jpg", i)), copies.join(format!("photo-{}-padded.jpg", i))])
+        .collect();
+    assert_eq!(resolved, expected.len());
+    let (_, quarantined) = call(&app, Method::GET, "/quarantine").await;
+    assert_eq!(paths(&quarantined), expected);
+    assert!(copies.join("photo-0 (1).png").is_file());
+    assert!(copies.join("photo-0-truncated.jpg").is_file());
+}
+
+#[test]
+fn command_line_wins_over_config_file() {
+    use clap::Parser;
+    use crate::config::{Cli, Config};
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("config.toml");
+    std::fs::write(&file, "port = 8080\nbind = \"127.0.0.1\"\nlibraries = [\"/srv/photos\"]\n").unwrap();
+
+    let cli = Cli::try_parse_from(["image-analyzer", "--config", file.to_str().unwrap(), "--port", "9090"]).unwrap();
+    let config = Config::new(cli.settings, cli.config.as_ref()).unwrap();
+    assert_eq!(config.addr, "127.0.0.1:9090".parse().unwrap());
+    assert_eq!(config.libraries, Some(vec![PathBuf::from("/srv/photos")]));
+    assert_eq!(config.assets, crate::assets::Assets::default());
+
+    let invalid = ["prot = 8080\n", "tls-cert = \"cert.pem\"\n", "auth-user = \"admin\"\n", "auth-token = \"\"\n", "[[auto-resolve]]\nname = \"unwatched\"\naction = \"delete\"\n"];
+    for invalid in invalid {
+        std::fs::write(&file, invalid).unwrap();
+        let cli = Cli::try_parse_from(["image-analyzer", "--config", file.to_str().unwrap()]).unwrap();
+        assert!(Config::new(cli.settings, cli.config.as_ref()).is_err(), "{}", invalid);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serves_over_a_unix_socket() {
+    use clap::Parser;
+    use crate::config::{Cli, Config};
+    use crate::listen;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let data = tempfile::tempdir().unwrap();
+    let socket = data.path().join("server.sock");
+    // left behind by a server killed before removing it
+    drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+    let cli = Cli::try_parse_from(["image-analyzer", "--socket", socket.to_str().unwrap(), "--socket-mode", "660"]).unwrap();
+    let config = Config::new(cli.settings, cli.config.as_ref()).unwrap();
+    assert!(!config.tcp);
+    let listeners = listen::listeners(&config).unwrap();
+    assert_eq!(listeners.len(), 1);
+    assert_eq!(std::fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o660);
+
+    let state = create_state(data.path(), StateOptions { desktop: true, ..StateOptions::default() }).unwrap();
+    let server = listen::serve(listeners.into_iter().next().unwrap(), crate::server::app(state), None).unwrap();
+    let request = |request: &'static str| {
+        let socket = socket.clone();
+        async move {
+            let mut stream = tokio::net::UnixStream::connect(socket).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+    };
+    assert!(request("GET /healthz HTTP/1.0\r\n\r\n").await.starts_with("HTTP/1.0 200"));
+    // the proxy in front may serve anyone
+    assert!(request("POST /desktop/open?path=/tmp/a.png HTTP/1.0\r\nX-Requested-With: fetch\r\n\r\n").await.starts_with("HTTP/1.0 403"));
+    server.abort();
+
+    let cli = Cli::try_parse_from(["image-analyzer", "--socket", "/run/a.sock", "--port", "3001"]).unwrap();
+    assert!(Config::new(cli.settings, None).unwrap().tcp);
+}
+
+#[tokio::test]
+async fn rejects_requests_without_credentials() {
+    use crate::auth::{self, Auth};
+
+    let data = tempfile::tempdir().unwrap();
+    let state = test_state(data.path());
+    let auth = Auth::Basic { user: "admin".into(), password: "secret".into() };
+    let (token, _) = state.shares.mint(uuid::Uuid::new_v4(), data.path(), std::time::Duration::from_secs(60)).unwrap();
+    let minted = state.clone();
+    let shares: auth::ShareCheck = Arc::new(move |token| minted.shares.get(token).is_some());
+    let app = app(state).layer(axum::middleware::from_fn_with_state((Arc::new(auth), shares), auth::guard));
+
+    let (status, _) = call(&app, Method::GET, "/tasks").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // a share token has to be a minted one, the share guard limits it to its task
+    let (status, _) = call(&app, Method::GET, "/tasks?token=made-up").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(&app, Method::GET, &format!("/tasks?token={}", token)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    for (credentials, expected) in [("admin:secret", StatusCode::OK), ("admin:wrong", StatusCode::UNAUTHORIZED)] {
+        use base64::Engine;
+        let header = format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials));
+        let request = Request::builder().uri("/tasks").header("authorization", header).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), expected, "{}", credentials);
+    }
+}
+
+#[test]
+fn frees_the_slots_of_panicking_reads() {
+    use crate::throttle::Throttle;
+
+    let throttle = Throttle::new(1, 1);
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _permit = throttle.acquire();
+        panic!("decoder bug");
+    }));
+    assert!(panicked.is_err());
+    // would wait forever for the leaked slot
+    throttle.acquire().done(std::time::Duration::from_millis(1));
+    throttle.acquire().done(std::time::Duration::from_millis(1));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn limits_the_lifetime_of_share_links() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap();
+
+    for hours in [u64::MAX, 24 * 365 + 1] {
+        let (status, body) = call(&app, Method::POST, &format!("/share?taskId={}&ttlHours={}", task_id, hours)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", hours);
+        assert_eq!(body["code"], "badRequest");
+    }
+    let (status, share) = call(&app, Method::POST, &format!("/share?taskId={}&ttlHours=48", task_id)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(share["token"].is_string());
+}
+
+#[tokio::test]
+async fn scopes_tasks_to_their_session() {
+    use crate::session;
+
+    async fn call_as(app: &Router, uri: &str, header: (&str, &str)) -> (StatusCode, Value) {
+        let request = Request::builder().method(Method::POST).uri(uri).header(header.0, header.1).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn listed(app: &Router, header: (&str, &str)) -> Vec<Value> {
+        let request = Request::get("/tasks").header(header.0, header.1).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let tasks: Value = serde_json::from_slice(&body).unwrap();
+        tasks.as_array().unwrap().iter().map(|task| task["taskId"].clone()).collect()
+    }
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let state = test_state(data.path());
+    let app = app(state).layer(axum::middleware::from_fn_with_state(Some(Arc::from("secret")), session::issue));
+
+    // clients without a session get one
+    let response = app.clone().oneshot(Request::get("/tasks").body(Body::empty()).unwrap()).await.unwrap();
+    let cookie = response.headers()["set-cookie"].to_str().unwrap().split(';').next().unwrap().to_owned();
+    let other = format!("session={}", uuid::Uuid::new_v4());
+
+    let uri = format!("/analyze?path={}&dist=10&hashType=DHash", library.path().display());
+    let (status, task) = call_as(&app, &uri, ("cookie", &cookie)).await;
+    assert_eq!(status, StatusCode::OK);
+    // the same scan from another session is a task of its own
+    assert_ne!(call_as(&app, &uri, ("cookie", &other)).await.1, task);
+
+    let poll = format!("/poll?taskId={}", task["taskId"].as_str().unwrap());
+    assert_eq!(listed(&app, ("cookie", &cookie)).await, [task["taskId"].clone()]);
+    assert!(!listed(&app, ("cookie", &other)).await.contains(&task["taskId"]));
+    assert_eq!(listed(&app, (session::ADMIN_HEADER, "secret")).await.len(), 2);
+    assert!(listed(&app, (session::ADMIN_HEADER, "wrong")).await.is_empty());
+
+    let own = Request::get(&poll).header("cookie", &cookie).body(Body::empty()).unwrap();
+    assert_eq!(app.clone().oneshot(own).await.unwrap().status(), StatusCode::OK);
+    let foreign = Request::get(&poll).header("cookie", &other).body(Body::empty()).unwrap();
+    assert_eq!(app.clone().oneshot(foreign).await.unwrap().status(), StatusCode::NOT_FOUND);
+    let cancel = format!("/cancel?taskId={}", task["taskId"].as_str().unwrap());
+    assert_eq!(call_as(&app, &cancel, ("cookie", &other)).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn answers_cors_preflight_before_auth() {
+    use crate::auth::{self, Auth};
+
+    let data = tempfile::tempdir().unwrap();
+    let state = test_state(data.path());
+    let shares: auth::ShareCheck = Arc::new(|_| false);
+    let app = app(state)
+        .layer(axum::middleware::from_fn_with_state((Arc::new(Auth::Token("secret".into())), shares), auth::guard))
+        .layer(crate::server::cors_layer(&["http://localhost:5173".parse().unwrap()]));
+
+    for (origin, allowed) in [("http://localhost:5173", true), ("http://elsewhere", false)] {
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/tasks")
+            .header("origin", origin)
+            .header("access-control-request-method", "GET")
+            .header("access-control-request-headers", "authorization")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let allow_origin = response.headers().get("access-control-allow-origin");
+        assert_eq!(allow_origin.is_some_and(|value| value == origin), allowed, "{}", origin);
+    }
+}
+
+#[tokio::test]
+async fn rate_limits_expensive_routes() {
+    use crate::ratelimit::{self, RateLimits};
+
+    let data = tempfile::tempdir().unwrap();
+    let state = test_state(data.path());
+    let limits = RateLimits::default().with(&["/thumbnail"], 2).with(&["/analyze", "/profiles/:name/analyze"], 1);
+    let app = app(state).layer(axum::middleware::from_fn_with_state(Arc::new(limits), ratelimit::guard));
+    let missing = data.path().join("missing.png");
+
+    let uri = format!("/thumbnail?path={}", missing.display());
+    for _ in 0..2 {
+        assert_ne!(call(&app, Method::GET, &uri).await.0, StatusCode::TOO_MANY_REQUESTS);
+    }
+    let request = Request::builder().uri(&uri).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+    // other routes and lookalike prefixes aren't limited
+    assert_eq!(call(&app, Method::GET, "/tasks").await.0, StatusCode::OK);
+    assert_eq!(call(&app, Method::GET, "/thumbnails").await.0, StatusCode::NOT_FOUND);
+    // routes of a limit share its quota
+    assert_ne!(call(&app, Method::POST, "/profiles/missing/analyze").await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(call(&app, Method::POST, "/analyze").await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(call(&app, Method::GET, "/profiles/missing").await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn reports_health_and_readiness() {
+    let data = tempfile::tempdir().unwrap();
+    let state = test_state(data.path());
+    let app = app(state.clone());
+
+    let (status, health) = call(&app, Method::GET, "/readyz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["analyzer"]["queue"], serde_json::json!({ "queued": 0, "running": 0 }));
+    assert_eq!(health["cache"]["reachable"], true);
+
+    state.draining.store(true, std::sync::atomic::Ordering::Relaxed);
+    assert_eq!(call(&app, Method::GET, "/readyz").await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(call(&app, Method::GET, "/healthz").await.0, StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn respawns_a_dead_analyzer_loop() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let state = test_state(data.path());
+    let app = app(state.clone());
+    analyze(&app, library.path()).await;
+
+    crate::server::crash_analyzer(&state).await;
+    let (status, health) = call(&app, Method::GET, "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["analyzer"]["alive"], true);
+    assert_eq!(health["analyzer"]["restarts"], 1);
+    assert_eq!(health["analyzer"]["lastPanic"], "analyzer actor crashed on request");
+
+    // the books of the tasks are kept
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    assert_eq!(tasks.as_array().unwrap().len(), 1);
+    analyze(&app, library.path()).await;
+}
+
+#[tokio::test]
+async fn exposes_prometheus_metrics() {
+    let data = tempfile::tempdir().unwrap();
+    let app = test_app(data.path());
+
+    assert_eq!(call(&app, Method::GET, "/deleted/some-id").await.0, StatusCode::NOT_FOUND);
+    let request = Request::builder().uri("/metrics").body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    // by route, not by id
+    assert!(text.contains(r#"route="/deleted/:id",status="404""#), "{}", text);
+    assert!(text.contains("image_analyzer_queued_tasks 0"), "{}", text);
+}
+
+#[test]
+fn grows_sets_after_reading_them() {
+    use crate::disjoint_set::DisjointSet;
+
+    let mut sets = DisjointSet::new();
+    for value in ["a", "b", "c", "d"] {
+        sets.insert(value);
+    }
+    sets.union(&"d", &"b");
+    assert_eq!(sets.sets(), vec![vec!["a"], vec!["b", "d"], vec!["c"]]);
+    assert_eq!(sets.take_changed().len(), 3);
+    assert!(sets.take_changed().is_empty());
+
+    // inserting again changes nothing
+    assert_eq!(sets.insert("b"), 1);
+    sets.insert("e");
+    assert_eq!(sets.union(&"e", &"c"), Some(true));
+    assert_eq!(sets.union(&"c", &"e"), Some(false));
+    // values never inserted are left alone
+    assert_eq!(sets.union(&"c", &"f"), None);
+    assert!(!sets.contains(&"f"));
+    assert_eq!(sets.take_changed(), vec![vec!["c", "e"]]);
+    sets.union(&"c", &"a");
+    assert_eq!(sets.take_changed(), vec![vec!["a", "c", "e"]]);
+    assert_eq!((sets.set_len(&"e"), sets.set_len(&"f")), (Some(3), None));
+    assert_eq!(sets.len(), 5);
+    assert_eq!(sets.into_vec(), vec![vec!["a", "c", "e"], vec!["b", "d"]]);
+}
+
+#[test]
+fn iterates_sets_whatever_the_union_order() {
+    use crate::DisjointSet;
+
+    let mut forward = DisjointSet::new();
+    let mut backward = DisjointSet::new();
+    for value in 0..8 {
+        forward.insert(value);
+        backward.insert(value);
+    }
+    for (a, b) in [(0, 2), (4, 6), (2, 6), (3, 7)] {
+        forward.union(&a, &b);
+        backward.union(&b, &a);
+    }
+    for sets in [&forward, &backward] {
+        let iterated: Vec<Vec<i32>> = sets.iter().map(|members| members.copied().collect()).collect();
+        assert_eq!(iterated, vec![vec![0, 2, 4, 6], vec![1], vec![3, 7], vec![5]]);
+        assert_eq!(sets.set_count(), 4);
+        assert_eq!(sets.members(&6).unwrap().len(), 4);
+        assert!(sets.members(&8).is_none());
+    }
+    assert_eq!(backward.find(&6), Some(0));
+    assert_eq!(backward.find(&8), None);
+    assert!(backward.same_set(&7, &3));
+    assert!(!backward.same_set(&7, &5));
+    assert!(!backward.same_set(&8, &8));
+}
+
+#[test]
+fn classifies_errors_by_their_cause() {
+    use crate::error::{ErrorBody, ErrorCode, PathError};
+    use std::io;
+
+    let denied = PathError::new(std::path::Path::new("/srv/photos"), io::ErrorKind::PermissionDenied.into());
+    let body = ErrorBody::of_report(&eyre::Report::new(denied).wrap_err("unable to scan"));
+    assert_eq!(body.code, ErrorCode::PermissionDenied);
+    assert_eq!(body.path, Some(PathBuf::from("/srv/photos")));
+
+    // only logged, the details may be about the server
+    let bug = ErrorBody::of_report(&eyre::eyre!("database at /var/lib/analyzer/cache.db is locked"));
+    assert_eq!((bug.code, bug.message.as_str()), (ErrorCode::Internal, "internal server error"));
+
+    // a failure stored with a task comes back the same
+    let stored = ErrorBody::of_report(&eyre::Report::new(body.clone()));
+    assert_eq!((stored.code, stored.path), (body.code, body.path));
+}
+
+#[tokio::test]
+async fn serves_images_by_content_in_ranges() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let image = &fixtures.iter().find(|f| f.kind != FixtureKind::NotAnImage).unwrap().path;
+    let misnamed = library.path().join("photo.bin");
+    std::fs::copy(image, &misnamed).unwrap();
+    let size = std::fs::metadata(&misnamed).unwrap().len();
+    let app = app(create_state(data.path(), StateOptions { libraries: Some(vec![library.path().to_owned()]), ..StateOptions::default() }).unwrap());
+    let uri = format!("/image?path={}", misnamed.display());
+
+    let request = |method: Method, range: Option<&str>| {
+        let mut builder = Request::builder().method(method).uri(&uri);
+        if let Some(range) = range {
+            builder = builder.header("range", range);
+        }
+        app.clone().oneshot(builder.body(Body::empty()).unwrap())
+    };
+
+    let response = request(Method::GET, Some("bytes=0-9")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()["content-range"], format!("bytes 0-9/{}", size));
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("image/"));
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap().len(), 10);
+
+    let response = request(Method::HEAD, None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-length"], size.to_string());
+    assert!(hyper::body::to_bytes(response.into_body()).await.unwrap().is_empty());
+
+    let folder = format!("/image?path={}", library.path().display());
+    assert_eq!(call(&app, Method::GET, &folder).await.0, StatusCode::NOT_FOUND);
+    let (status, error) = call(&app, Method::GET, "/image?file=x").await;
+    assert_eq!((status, &error["code"]), (StatusCode::BAD_REQUEST, &Value::from("badRequest")));
+}
+
+#[tokio::test]
+async fn revalidates_unchanged_images() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let image = &fixtures.iter().find(|f| f.kind != FixtureKind::NotAnImage).unwrap().path;
+    let app = test_app(data.path());
+
+    for uri in [format!("/image?path={}", image.display()), format!("/thumbnail?path={}&size=64", image.display())] {
+        let request = |header: Option<(&str, &str)>| {
+            let mut builder = Request::get(&uri);
+            if let Some((name, value)) = header {
+                builder = builder.header(name, value);
+            }
+            app.clone().oneshot(builder.body(Body::empty()).unwrap())
+        };
+        let response = request(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        let etag = response.headers()["etag"].to_str().unwrap().to_owned();
+        let modified = response.headers()["last-modified"].to_str().unwrap().to_owned();
+
+        let response = request(Some(("if-none-match", &etag))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", uri);
+        assert_eq!(response.headers()["etag"], etag.as_str());
+        assert!(hyper::body::to_bytes(response.into_body()).await.unwrap().is_empty());
+        assert_eq!(request(Some(("if-modified-since", &modified))).await.unwrap().status(), StatusCode::NOT_MODIFIED);
+        // the tag wins over the date
+        let stale = Request::get(&uri).header("if-none-match", "\"stale\"").header("if-modified-since", &modified);
+        assert_eq!(app.clone().oneshot(stale.body(Body::empty()).unwrap()).await.unwrap().status(), StatusCode::OK);
+    }
+
+    // a changed file gets a new tag
+    let etag = |app: Router| async move {
+        let response = app.oneshot(Request::get(format!("/image?path={}", image.display())).body(Body::empty()).unwrap()).await.unwrap();
+        response.headers()["etag"].clone()
+    };
+    let before = etag(app.clone()).await;
+    let file = std::fs::OpenOptions::new().append(true).open(image).unwrap();
+    file.set_len(file.metadata().unwrap().len() + 1).unwrap();
+    assert_ne!(etag(app.clone()).await, before);
+}
+
+#[tokio::test]
+async fn transcodes_previews_browsers_cant_display() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let png = library.path().join("photo.png");
+    let tiff = library.path().join("photo.tiff");
+    let photo = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 128])));
+    photo.save(&png).unwrap();
+    photo.save(&tiff).unwrap();
+    let app = test_app(data.path());
+    let get = |path: &std::path::Path| app.clone().oneshot(Request::get(format!("/preview?path={}", path.display())).body(Body::empty()).unwrap());
+
+    // as they are when browsers show them
+    let response = get(&png).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), std::fs::read(&png).unwrap());
+
+    for _ in 0..2 {
+        let response = get(&tiff).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "image/jpeg");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let preview = image::load_from_memory_with_format(&body, ImageFormat::Jpeg).unwrap();
+        assert_eq!((preview.width(), preview.height()), (64, 48));
+    }
+    // transcoded once
+    assert_eq!(std::fs::read_dir(data.path().join("thumbnails")).unwrap().count(), 1);
+    assert_eq!(get(&library.path().join("missing.tiff")).await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn renders_upright_scaled_previews() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let sideways = library.path().join("sideways.jpg");
+    let photo = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 128]));
+    fixtures::oriented_jpeg(&sideways, &photo, 6).unwrap();
+    let app = test_app(data.path());
+    let get = |query: &str| app.clone().oneshot(Request::get(format!("/preview?path={}{}", sideways.display(), query)).body(Body::empty()).unwrap());
+    let render = |query: &'static str| async move {
+        let response = get(query).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", query);
+        let etag = response.headers()["etag"].clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (etag, body.len(), image::load_from_memory_with_format(&body, ImageFormat::Jpeg).unwrap())
+    };
+
+    // turned upright even when browsers could show the file
+    let (_, _, upright) = render("").await;
+    assert_eq!((upright.width(), upright.height()), (48, 64));
+    let (small, _, scaled) = render("&width=24&height=24").await;
+    assert_eq!((scaled.width(), scaled.height()), (18, 24));
+    let (rough, rough_len, _) = render("&quality=5").await;
+    let (fine, fine_len, _) = render("&quality=100").await;
+    assert!(rough_len < fine_len && rough != fine && small != rough);
+    assert_eq!(get("&quality=500").await.unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn opens_files_on_the_desktop_for_local_browsers_only() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let image = &fixtures[0].path;
+    let libraries = [library.path().to_owned()];
+    let state = create_state(data.path(), StateOptions { libraries: Some(libraries.to_vec()), ..StateOptions::default() }).unwrap();
+    let uri = |action: &str, path: &std::path::Path| format!("/desktop/{}?path={}", action, path.display());
+
+    // unless enabled
+    let app = crate::server::app(state);
+    assert_eq!(call(&app, Method::POST, &uri("open", image)).await.0, StatusCode::NOT_FOUND);
+
+    let options = StateOptions { libraries: Some(libraries.to_vec()), desktop: true, ..StateOptions::default() };
+    let app = crate::server::app(create_state(data.path(), options).unwrap());
+    let post = |uri: String| Request::post(uri).header("x-requested-with", "fetch").body(Body::empty()).unwrap();
+    let remote = std::net::SocketAddr::from(([192, 168, 1, 20], 50000));
+    let mut request = post(uri("reveal", image));
+    request.extensions_mut().insert(axum::extract::ConnectInfo(remote));
+    assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+    std::fs::write(outside.path().join("a.png"), b"").unwrap();
+    let response = app.clone().oneshot(post(uri("open", &outside.path().join("a.png")))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app.clone().oneshot(post(uri("reveal", &library.path().join("missing.png")))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    // a form of another site can't set the header
+    assert_eq!(call(&app, Method::POST, &uri("reveal", &library.path().join("missing.png"))).await.0, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compresses_large_json() {
+    use tower_http::decompression::DecompressionLayer;
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    analyze(&app, library.path()).await;
+    let (_, tasks) = call(&app, Method::GET, "/tasks").await;
+    let task_id = tasks[0]["taskId"].as_str().unwrap();
+
+    for uri in [format!("/poll?taskId={}", task_id), format!("/tasks/{}/groups", task_id)] {
+        let request = Request::get(&uri).header("accept-encoding", "gzip").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], "gzip", "{}", uri);
+
+        let client = tower::ServiceBuilder::new().layer(DecompressionLayer::new()).service(app.clone());
+        let response = client.oneshot(Request::get(&uri).body(Body::empty()).unwrap()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let decompressed: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(decompressed, call(&app, Method::GET, &uri).await.1);
+    }
+    // images are left alone
+    let image = &fixtures.iter().find(|f| f.kind != FixtureKind::NotAnImage).unwrap().path;
+    let request = Request::get(format!("/image?path={}", image.display())).header("accept-encoding", "gzip").body(Body::empty()).unwrap();
+    assert!(!app.clone().oneshot(request).await.unwrap().headers().contains_key("content-encoding"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn summarizes_folders() {
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let app = test_app(data.path());
+    let uri = format!("/stats?path={}", library.path().display());
+
+    let (status, stats) = call(&app, Method::GET, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, files) = call(&app, Method::GET, &format!("/list_folder?path={}", library.path().display())).await;
+    let images = files.as_array().unwrap().len();
+    assert_eq!(stats["images"], images);
+    assert_eq!(stats["files"], fixtures.len());
+    let by_format: u64 = stats["byFormat"].as_object().unwrap().values().map(|n| n.as_u64().unwrap()).sum();
+    assert_eq!(by_format, images as u64);
+    let largest: Vec<u64> = stats["largest"].as_array().unwrap().iter().map(|f| f["size"].as_u64().unwrap()).collect();
+    assert!(!largest.is_empty() && largest.windows(2).all(|pair| pair[0] >= pair[1]));
+    assert!(stats["reclaimable"].is_null());
+
+    analyze(&app, library.path()).await;
+    let (_, stats) = call(&app, Method::GET, &uri).await;
+    assert!(stats["reclaimable"]["bytes"].as_u64().unwrap() > 0, "{}", stats);
+    // the same folder spelled another way
+    let (_, stats) = call(&app, Method::GET, &format!("/stats?path={}/../{}", library.path().display(), library.path().file_name().unwrap().to_string_lossy())).await;
+    assert!(stats["reclaimable"]["bytes"].as_u64().unwrap() > 0, "{}", stats);
+    // another session doesn't see the analysis
+    let request = Request::get(&uri).header("cookie", format!("session={}", uuid::Uuid::new_v4())).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let stats: Value = serde_json::from_slice(&body).unwrap();
+    assert!(stats["reclaimable"].is_null(), "{}", stats);
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_tasks_over_grpc() {
+    use crate::auth::Auth;
+    use crate::grpc::{self, proto::{self, analyzer_client::AnalyzerClient, task_state::State}};
+
+    fn authorized<T>(message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request.metadata_mut().insert("authorization", "Bearer secret".parse().unwrap());
+        request
+    }
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let state = test_state(data.path());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(grpc::serve(listener, state, Some(Auth::Token("secret".into())), 20));
+    let mut client = AnalyzerClient::connect(format!("http://{}", addr)).await.unwrap();
+
+    let submit = proto::SubmitRequest { path: library.path().display().to_string(), dist: 10, ..Default::default() };
+    let denied = client.submit(submit.clone()).await.unwrap_err();
+    assert_eq!(denied.code(), tonic::Code::Unauthenticated);
+    let missing = proto::SubmitRequest { path: "/no/such/folder".into(), ..submit.clone() };
+    assert_eq!(client.submit(authorized(missing)).await.unwrap_err().code(), tonic::Code::NotFound);
+    let unknown = proto::TaskId { task_id: "not-a-task".into() };
+    assert_eq!(client.poll(authorized(unknown)).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+    let task = client.submit(authorized(submit)).await.unwrap().into_inner();
+    let mut states = client.subscribe(authorized(task.clone())).await.unwrap().into_inner();
+    let mut last = None;
+    while let Some(state) = states.message().await.unwrap() {
+        last = state.state;
+    }
+    let Some(State::Completed(result)) = last else {
+        panic!("subscription ended with {:?}", last);
+    };
+    let groups: BTreeSet<BTreeSet<PathBuf>> = result
+        .groups
+        .iter()
+        .map(|group| group.files.iter().map(|file| PathBuf::from(&file.path)).collect())
+        .collect();
+    assert_eq!(groups, fixtures::expected_groups(&fixtures));
+
+    assert_eq!(client.result(authorized(task.clone())).await.unwrap().into_inner(), result);
+    let polled = client.poll(authorized(task)).await.unwrap().into_inner();
+    assert_eq!(polled.state, Some(State::Completed(result)));
+}
+
+#[test]
+fn reports_progress_by_bytes() {
+    use crate::analyzer::{Progress, ProgressUnit};
+    use crate::{headless, manager::CancelToken, roots::Roots};
+    use std::time::Duration;
+
+    // a 150 MB TIFF among nine small JPEGs
+    let by_files = Progress::hashing(ProgressUnit::Files, 1, 10, 150_000_000, 152_000_000, Duration::ZERO);
+    let by_bytes = Progress::hashing(ProgressUnit::Bytes, 1, 10, 150_000_000, 152_000_000, Duration::ZERO);
+    assert_eq!((by_files.percent, by_bytes.percent), (10, 98));
+    assert_eq!(Progress::hashing(ProgressUnit::Bytes, 0, 0, 0, 0, Duration::ZERO).percent, 100);
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    fixtures::generate(library.path()).unwrap();
+    let roots = Arc::new(Roots::open(data.path().join("roots.json")).unwrap());
+    let engine = open_engine(data.path(), roots, Arc::default()).unwrap();
+    let (tx, rx) = tokio::sync::watch::channel(Default::default());
+    let req = analyzer::AnalyzeRequest { progress_unit: ProgressUnit::Bytes, ..headless::request(library.path().to_owned(), 10, Default::default(), Default::default()) };
+    engine.analyze(&req, tx, &CancelToken::default()).unwrap();
+    let progress = *rx.borrow();
+    assert_eq!((progress.unit, progress.percent), (ProgressUnit::Bytes, 100));
+    assert!(progress.bytes > 0 && progress.bytes == progress.total_bytes, "{:?}", progress);
+    assert!(progress.segments > 0 && progress.segment == progress.segments, "{:?}", progress);
+    assert_eq!(progress.shards, progress.total_shards);
+}
+
+#[test]
+fn prints_groups_without_the_server() {
+    use crate::config::{Cli, Command};
+    use crate::{headless, manager::CancelToken, roots::Roots};
+    use clap::Parser;
+
+    let data = tempfile::tempdir().unwrap();
+    let library = tempfile::tempdir().unwrap();
+    let fixtures = fixtures::generate(library.path()).unwrap();
+    let args = ["image-analyzer", "analyze", library.path().to_str().unwrap(), "--dist", "10"];
+    let Some(Command::Analyze { path: Some(path), files_from: None, dist, hash_type, hash_size, json, fast, byte_progress, pixel_exact }) = Cli::try_parse_from(args).unwrap().command else {
+        panic!("not an analysis");
+    };
+    assert!(!json && !fast && !byte_progress && !pixel_exact);
+
+    let roots = Arc::new(Roots::open(data.path().join("roots.json")).unwrap());
+    let engine = open_engine(data.path(), roots, Arc::default()).unwrap();
+    let (tx, _) = tokio::sync::watch::channel(Default::default());
+    let req = headless::request(path, dist, hash_type, hash_size);
+    let result = engine.analyze(&req, tx, &CancelToken::default()).unwrap();
+
+    let mut out = Vec::new();
+    headless::write_groups(&mut out, &result, false).unwrap();
+    let groups: BTreeSet<BTreeSet<PathBuf>> = String::from_utf8(out)
+        .unwrap()
+        .split("\n\n")
+        .map(|group| group.lines().map(PathBuf::from).collect())
+        .collect();
+    assert_eq!(groups, fixtures::expected_groups(&fixtures));
+}
+
+#[cfg(feature = "ocr")]
+#[test]
+fn scores_the_words_of_screenshots() {
+    use crate::ocr::{similarity, words};
+
+    let a = words("Inbox (3) - Mail, Settings: a b");
+    assert_eq!(a, ["inbox", "mail", "settings"].map(String::from).into());
+    // the same text in another case and layout
+    assert_eq!(similarity(&a, &words("SETTINGS\ninbox\tmail")), 1.0);
+    assert_eq!(similarity(&a, &words("inbox mail calendar contacts")), 0.4);
+    assert_eq!(similarity(&a, &words("nothing alike")), 0.0);
+    assert_eq!(similarity(&words(""), &words("x")), 0.0);
+}
+
+#[cfg(feature = "ocr")]
+#[test]
+fn tells_screenshots_apart_from_other_pngs() {
+    use crate::ocr::{is_screen_size, is_screenshot, is_screenshot_tool};
+
+    assert!(is_screen_size(1920, 1080) && is_screen_size(1170, 2532));
+    assert!(!is_screen_size(1080, 1080) && !is_screen_size(1000, 700));
+    assert!(is_screenshot_tool("gnome-screenshot") && is_screenshot_tool("Flameshot"));
+    assert!(!is_screenshot_tool("GIMP 2.10") && !is_screenshot_tool("Adobe Photoshop"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let save = |name: &str, width: u32, height: u32| {
+        let path = dir.path().join(name);
+        image::RgbImage::new(width, height).save(&path).unwrap();
+        path
+    };
+    assert!(is_screenshot(&save("screen.png", 1280, 800)));
+    assert!(!is_screenshot(&save("edited.png", 1000, 700)));
+    assert!(is_screenshot(&save("Screenshot 2024-03-01.png", 1000, 700)));
+    // not a PNG
+    assert!(!is_screenshot(&save("screen.jpg", 1280, 800)));
+
+    // a window capture, named after the tool
+    let path = save("window.png", 1000, 700);
+    let mut data = std::fs::read(&path).unwrap();
+    let text = b"Software\0gnome-screenshot";
+    let mut crc = flate2::Crc::new();
+    crc.update(b"tEXt");
+    crc.update(text);
+    let mut chunk = (text.len() as u32).to_be_bytes().to_vec();
+    chunk.extend(b"tEXt");
+    chunk.extend(text);
+    chunk.extend(crc.sum().to_be_bytes());
+    // after the signature and the header chunk
+    data.splice(33..33, chunk);
+    std::fs::write(&path, data).unwrap();
+    assert!(is_screenshot(&path));
+}
+
+#[cfg(feature = "embed")]
+#[tokio::test]
+async fn serves_the_embedded_client() {
+    let app: Router = crate::assets::Assets::Embedded.routes();
+
+    let response = app.clone().oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/html");
+    let etag = response.headers()["etag"].clone();
+    let request = Request::get("/").header("if-none-match", etag).body(Body::empty()).unwrap();
+    assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(call(&app, Method::GET, "/assets/missing.js").await.0, StatusCode::NOT_FOUND);
+}
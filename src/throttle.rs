@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+use utoipa::ToSchema;
+
+/// completions between two adjustments of the limit
+const WINDOW: usize = 16;
+/// latency growth over the best observed one treated as storage contention
+const BACKOFF_RATIO: f64 = 2.0;
+/// latency close enough to the best observed one to try more workers
+const GROW_RATIO: f64 = 1.25;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConcurrencyAdjustment {
+    after_files: usize,
+    workers: usize,
+    latency_ms: f64,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    active: usize,
+    /// moving average of the recent per-file latency, in milliseconds
+    latency: Option<f64>,
+    /// best moving average seen so far
+    baseline: Option<f64>,
+    processed: usize,
+    adjustments: Vec<ConcurrencyAdjustment>,
+}
+
+/// Limits how many files are read at once, tuning the limit from
+/// observed latency: slow shared storage (NFS) gets fewer concurrent reads,
+/// fast local disks get more.
+#[derive(Debug)]
+pub struct Throttle {
+    min: usize,
+    max: usize,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// A slot to read a file in, freed when dropped. A permit dropped without `done`,
+/// e.g. by a panic, doesn't count towards the latency.
+pub struct Permit<'a> {
+    throttle: &'a Throttle,
+    latency: Option<Duration>,
+}
+
+impl Permit<'_> {
+    /// frees the slot, reporting how long the file took
+    pub fn done(mut self, latency: Duration) {
+        self.latency = Some(latency);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.throttle.release(self.latency);
+    }
+}
+
+impl Throttle {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        let state = State {
+            limit: min,
+            active: 0,
+            latency: None,
+            baseline: None,
+            processed: 0,
+            adjustments: Vec::new(),
+        };
+
+        Self { min, max, state: Mutex::new(state), released: Condvar::new() }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// waits until there is a free slot
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.active >= state.limit {
+            state = self.released.wait(state).unwrap();
+        }
+        state.active += 1;
+        Permit { throttle: self, latency: None }
+    }
+
+    fn release(&self, latency: Option<Duration>) {
+        // released while unwinding too, a panic elsewhere must not leak the slot
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.active -= 1;
+        let Some(latency) = latency else {
+            drop(state);
+            self.released.notify_all();
+            return;
+        };
+        state.processed += 1;
+
+        let sample = latency.as_secs_f64() * 1000.0;
+        let average = state.latency.map_or(sample, |avg| avg * 0.8 + sample * 0.2);
+        state.latency = Some(average);
+
+        if state.processed.is_multiple_of(WINDOW) {
+            let baseline = state.baseline.map_or(average, |b| b.min(average));
+            state.baseline = Some(baseline);
+
+            let limit = if average > baseline * BACKOFF_RATIO {
+                state.limit.saturating_sub(1).max(self.min)
+            } else if average < baseline * GROW_RATIO {
+                (state.limit + 1).min(self.max)
+            } else {
+                state.limit
+            };
+
+            if limit != state.limit {
+                tracing::debug!(workers = limit, latency_ms = average, "concurrency adjusted");
+                state.limit = limit;
+                let adjustment = ConcurrencyAdjustment {
+                    after_files: state.processed,
+                    workers: limit,
+                    latency_ms: average,
+                };
+                state.adjustments.push(adjustment);
+            }
+        }
+
+        drop(state);
+        self.released.notify_all();
+    }
+
+    /// limit changes over time, in order
+    pub fn adjustments(&self) -> Vec<ConcurrencyAdjustment> {
+        self.state.lock().unwrap().adjustments.clone()
+    }
+}
+
+/// Bytes of decoded images held at once, across analyses: reads wait until enough
+/// of the budget is free. Images larger than the whole budget wait for it all and run alone.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    total: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(total: u64) -> Self {
+        Self { total: total.max(1), used: Mutex::new(0), released: Condvar::new() }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// bytes not reserved at the moment
+    pub fn available(&self) -> u64 {
+        self.total - *self.used.lock().unwrap()
+    }
+
+    /// as `reserve` without waiting, `None` unless `bytes` are free right away
+    pub fn try_reserve(&self, bytes: u64) -> Option<Reservation<'_>> {
+        let bytes = bytes.max(1);
+        let mut used = self.used.lock().unwrap();
+        if *used + bytes > self.total {
+            return None;
+        }
+        *used += bytes;
+        Some(Reservation { budget: self, bytes })
+    }
+
+    /// waits until `bytes` are free, given back when the reservation is dropped
+    pub fn reserve(&self, bytes: u64) -> Reservation<'_> {
+        let bytes = bytes.clamp(1, self.total);
+        let mut used = self.used.lock().unwrap();
+        while *used + bytes > self.total {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += bytes;
+        Reservation { budget: self, bytes }
+    }
+}
@@ -0,0 +1,165 @@
+use eyre::Result;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Cursor},
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+use uuid::Uuid;
+
+use crate::{analyzer, metadata, resolve};
+
+/// longest side of previews, larger images are scaled down
+pub const PREVIEW_SIZE: u32 = 4096;
+
+/// of thumbnails, and previews not asking for another
+pub const DEFAULT_QUALITY: u8 = 85;
+
+/// how an image is rendered: scaled down to fit into `width` x `height` once upright, as a JPEG of `quality`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rendition {
+    pub width: u32,
+    pub height: u32,
+    /// 1..=100
+    pub quality: u8,
+}
+
+impl Rendition {
+    pub fn square(size: u32) -> Self {
+        Self { width: size, height: size, quality: DEFAULT_QUALITY }
+    }
+
+    /// tells the cached files and tags of renditions of one image apart
+    pub fn key(&self) -> String {
+        format!("{}x{}q{}", self.width, self.height, self.quality)
+    }
+}
+
+/// rotates the image upright according to EXIF orientation
+pub fn orient(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// whether browsers show files of the format as they are
+pub fn browser_displayable(format: Option<ImageFormat>) -> bool {
+    matches!(
+        format,
+        Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP | ImageFormat::Bmp | ImageFormat::Ico | ImageFormat::Avif)
+    )
+}
+
+fn is_heif(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| ["heic", "heif"].iter().any(|known| extension.eq_ignore_ascii_case(known)))
+}
+
+/// HEIC photos converted by `heif-convert` of libheif, when it's installed
+fn convert_heif(path: &Path, tmp_dir: &Path, size: u32) -> Result<DynamicImage> {
+    fs::create_dir_all(tmp_dir)?;
+    let tmp = tmp_dir.join(Uuid::new_v4().to_string()).with_extension("jpg");
+    let output = Command::new("heif-convert").arg(path).arg(&tmp).output();
+    let converted = match output {
+        Ok(output) if output.status.success() => analyzer::open_image(&tmp, size).map(|(image, _)| image).map_err(Into::into),
+        Ok(output) => Err(eyre::eyre!("heif-convert failed: {}", String::from_utf8_lossy(&output.stderr).trim())),
+        Err(err) => Err(eyre::eyre!("no HEIC decoder, heif-convert is missing: {}", err)),
+    };
+    let _ = fs::remove_file(&tmp);
+    converted
+}
+
+/// Decodes what the image crate can, camera raw files from the preview they embed first as the crate
+/// would only find the tiny thumbnail of their TIFF structure, HEIC photos through `heif-convert`.
+fn decode(path: &Path, size: u32, tmp_dir: &Path) -> Result<DynamicImage> {
+    let embedded = || {
+        let preview = metadata::read_preview(path)?;
+        analyzer::decode_image(image::io::Reader::with_format(Cursor::new(preview), ImageFormat::Jpeg), size).ok().map(|(image, _)| image)
+    };
+    if resolve::is_raw(path) {
+        if let Some(image) = embedded() {
+            return Ok(image);
+        }
+    }
+    let decoded = match analyzer::open_image(path, size) {
+        Ok((image, _)) => return Ok(image),
+        Err(err) => err,
+    };
+    if let Some(image) = embedded() {
+        return Ok(image);
+    }
+    if is_heif(path) {
+        return convert_heif(path, tmp_dir, size);
+    }
+    Err(decoded.into())
+}
+
+/// Downscaled copies of images stored in a designated directory.
+/// Entries are keyed by path, size and modification time,
+/// so a changed original gets a fresh thumbnail.
+#[derive(Debug)]
+pub struct Thumbnails {
+    root: PathBuf,
+}
+
+impl Thumbnails {
+    pub fn new<T>(root: T) -> Self
+    where
+        PathBuf: From<T>
+    {
+        Self { root: PathBuf::from(root) }
+    }
+
+    fn cache_path(&self, path: &Path, rendition: Rendition) -> Result<PathBuf> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta.modified()?.duration_since(SystemTime::UNIX_EPOCH)?;
+        let key = format!("{}|{}|{}|{}", path.display(), rendition.key(), meta.len(), mtime.as_nanos());
+        Ok(self.root.join(sha256::digest(key)).with_extension("jpg"))
+    }
+
+    /// path of the cached thumbnail, generated when missing
+    pub fn get(&self, path: &Path, size: u32) -> Result<PathBuf> {
+        self.render(path, Rendition::square(size))
+    }
+
+    /// path of the cached rendition, generated when missing
+    pub fn render(&self, path: &Path, rendition: Rendition) -> Result<PathBuf> {
+        let dest = self.cache_path(path, rendition)?;
+        if dest.exists() {
+            return Ok(dest);
+        }
+
+        tracing::info!(path = path.to_str(), rendition = rendition.key(), "rendering image");
+        // the box turns with the image, it is fitted into once upright
+        let image = decode(path, rendition.width.max(rendition.height), &self.root)?;
+        // HEIF rotations are applied by the decoder, their EXIF orientation is informative only
+        let orientation = match is_heif(path) {
+            true => 1,
+            false => metadata::read_orientation(path).unwrap_or(1),
+        };
+        let image = orient(image, orientation);
+        let image = match image.width() > rendition.width || image.height() > rendition.height {
+            true => image.thumbnail(rendition.width, rendition.height),
+            false => image,
+        };
+        let image = image.into_rgb8();
+
+        // write under a temporary name, concurrent requests may race for the same file
+        fs::create_dir_all(&self.root)?;
+        let tmp = self.root.join(Uuid::new_v4().to_string()).with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        DynamicImage::ImageRgb8(image).write_to(&mut writer, ImageOutputFormat::Jpeg(rendition.quality))?;
+        drop(writer);
+        fs::rename(&tmp, &dest)?;
+
+        Ok(dest)
+    }
+}
@@ -0,0 +1,74 @@
+//! Where the time of an analysis went, to tell a slow disk from a slow decoder or a slow grouping. The listing and the
+//! grouping are timed from start to end, the steps of hashing files are summed over the threads doing them in
+//! parallel, so their sum may be more than the whole analysis took.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+/// in milliseconds, zero for the steps a run didn't take, e.g. of results analyzed before they were timed
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    /// walking the folders and reading the sizes and dates of the files
+    pub listing_ms: u64,
+    /// looking the hashes up in the cache, reading the files for their checksums with content addressed caching
+    pub cache_lookups_ms: u64,
+    /// reading and decoding the images
+    pub decode_ms: u64,
+    /// hashing the decoded images
+    pub hash_ms: u64,
+    /// grouping the hashes, telling exact copies apart and comparing texts with OCR
+    pub grouping_ms: u64,
+    pub total_ms: u64,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    CacheLookup,
+    Decode,
+    Hash,
+}
+
+/// the time spent in each step of hashing files, by all the threads hashing
+#[derive(Debug, Default)]
+pub struct StepClock {
+    cache_lookups: AtomicU64,
+    decode: AtomicU64,
+    hash: AtomicU64,
+}
+
+impl StepClock {
+    /// runs `f`, adding the time it took to `step`
+    pub fn time<T>(&self, step: Step, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let value = f();
+        let nanos = started.elapsed().as_nanos().try_into().unwrap_or(u64::MAX);
+        let total = match step {
+            Step::CacheLookup => &self.cache_lookups,
+            Step::Decode => &self.decode,
+            Step::Hash => &self.hash,
+        };
+        total.fetch_add(nanos, Ordering::Relaxed);
+        value
+    }
+
+    pub fn timings(&self, listing: Duration, grouping: Duration, total: Duration) -> Timings {
+        let step = |total: &AtomicU64| millis(Duration::from_nanos(total.load(Ordering::Relaxed)));
+        Timings {
+            listing_ms: millis(listing),
+            cache_lookups_ms: step(&self.cache_lookups),
+            decode_ms: step(&self.decode),
+            hash_ms: step(&self.hash),
+            grouping_ms: millis(grouping),
+            total_ms: millis(total),
+        }
+    }
+}
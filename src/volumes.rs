@@ -0,0 +1,139 @@
+//! The drives and volumes folders are picked from, and paths the way users type them. Windows resolves paths into
+//! their verbatim form, `\\?\C:\Photos` and `\\?\UNC\nas\photos`, which no typed path starts with, so resolved paths
+//! are turned back into `C:\Photos` and `\\nas\photos`. Long paths need no prefix, the standard library adds it.
+
+use serde::Serialize;
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Component, Path, PathBuf, Prefix},
+};
+use utoipa::ToSchema;
+
+/// names Windows keeps for devices, only verbatim paths may use them
+const DEVICE_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "lpt1", "lpt2",
+    "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// types of the mount table of shares mounted over the network
+const NETWORK_FILESYSTEMS: [&str; 12] =
+    ["nfs", "nfs4", "cifs", "smb3", "smbfs", "9p", "afs", "ceph", "glusterfs", "fuse.sshfs", "fuse.rclone", "fuse.davfs2"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum VolumeKind {
+    /// `/`, or a drive letter of Windows
+    Root,
+    /// mounted below `/Volumes`, `/media` or `/mnt`
+    Mount,
+    /// one of the configured libraries, the only folders there are then
+    Library,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Volume {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    pub kind: VolumeKind,
+}
+
+/// whether the name means the same without the verbatim prefix
+fn plain_name(name: &std::ffi::OsStr) -> bool {
+    let Some(name) = name.to_str() else {
+        return false;
+    };
+    let stem = name.split('.').next().unwrap_or_default().to_lowercase();
+    !name.ends_with(['.', ' ']) && !DEVICE_NAMES.contains(&stem.as_str())
+}
+
+/// `\\?\C:\Photos` as `C:\Photos`, `\\?\UNC\nas\photos` as `\\nas\photos`, other paths as they are
+pub fn plain(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    let Some(Component::Prefix(prefix)) = components.next() else {
+        return path.to_owned();
+    };
+    let mut plain = match prefix.kind() {
+        Prefix::VerbatimDisk(drive) => OsString::from(format!("{}:", drive as char)),
+        Prefix::VerbatimUNC(server, share) => {
+            let mut unc = OsString::from(r"\\");
+            unc.push(server);
+            unc.push(r"\");
+            unc.push(share);
+            unc
+        }
+        _ => return path.to_owned(),
+    };
+    let rest = components.as_path();
+    if !rest.iter().skip(1).all(plain_name) {
+        return path.to_owned();
+    }
+    plain.push(r"\");
+    PathBuf::from(plain).join(rest.strip_prefix(r"\").unwrap_or(rest))
+}
+
+/// `fs::canonicalize`, in the form users type paths in
+pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    fs::canonicalize(path).map(|path| plain(&path))
+}
+
+/// whether `path` is on a network share, which doesn't notify its changes: by the mount table on Linux,
+/// a UNC path once resolved on Windows, mapped drives included. Other systems never tell
+pub fn is_network(path: &Path) -> bool {
+    let path = canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    if cfg!(windows) {
+        return matches!(path.components().next(), Some(Component::Prefix(prefix)) if matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..)));
+    }
+    let Ok(table) = fs::read_to_string("/proc/mounts") else {
+        return false;
+    };
+    table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            // spaces are escaped as octal
+            let point = PathBuf::from(fields.next()?.replace(r"\040", " "));
+            Some((point, fields.next()?))
+        })
+        .filter(|(point, _)| path.starts_with(point))
+        .max_by_key(|(point, _)| point.components().count())
+        .is_some_and(|(_, kind)| NETWORK_FILESYSTEMS.contains(&kind))
+}
+
+/// the folders below `dir`, none when it can't be read
+fn mounts(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut mounts: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+        .collect();
+    mounts.sort();
+    mounts
+}
+
+/// the drive letters in use on Windows, `/` and the mounted volumes elsewhere
+pub fn list() -> Vec<Volume> {
+    let volume = |kind| move |path| Volume { path, kind };
+    if cfg!(windows) {
+        // mapped network drives too, shares which aren't are typed as `\\nas\photos`
+        return (b'A'..=b'Z')
+            .map(|drive| PathBuf::from(format!(r"{}:\", drive as char)))
+            .filter(|path| path.is_dir())
+            .map(volume(VolumeKind::Root))
+            .collect();
+    }
+    let mut volumes = vec![Volume { path: PathBuf::from("/"), kind: VolumeKind::Root }];
+    // desktops mount below `/media/<user>`, others right below `/media`
+    let user = std::env::var_os("USER");
+    let media = mounts(Path::new("/media")).into_iter().flat_map(|dir| match dir.file_name() == user.as_deref() {
+        true => mounts(&dir),
+        false => vec![dir],
+    });
+    let mounted = mounts(Path::new("/Volumes")).into_iter().chain(media).chain(mounts(Path::new("/mnt")));
+    volumes.extend(mounted.map(volume(VolumeKind::Mount)));
+    volumes
+}
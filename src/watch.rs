@@ -0,0 +1,284 @@
+//! Folders kept analyzed: they are rescanned when the system notifies a change below them and only new or changed
+//! files are hashed again, so their groups are current without submitting analyses. Scans compare sizes and mtimes.
+//! Network shares don't notify the changes made by other machines, they are rescanned every so often instead.
+//! Groups changed by a scan are handed to the auto-resolve rules, if any.
+
+use eyre::Result;
+use image_hasher::ImageHash;
+use notify::{event::{AccessKind, AccessMode}, Event, EventKind, RecursiveMode, Watcher as _};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, RwLock, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use utoipa::ToSchema;
+
+use crate::analyzer::{Analyzer, FileInfo, FileStamp, Group, HashSize, HashType};
+use crate::autoresolve::AutoRule;
+use crate::volumes;
+
+/// waited for more changes once one is notified, copying a batch of files notifies each of them
+const SETTLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchOptions {
+    /// between scans of the folders whose changes aren't notified, and how often dropping the watcher is noticed
+    pub interval: Duration,
+    pub dist: u32,
+    pub hash_type: HashType,
+    pub hash_size: HashSize,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(60), dist: 5, hash_type: HashType::DHash, hash_size: HashSize::default() }
+    }
+}
+
+#[derive(Debug, Default)]
+struct FolderState {
+    hashes: HashMap<PathBuf, (FileInfo, ImageHash)>,
+    /// files which couldn't be hashed, tried again once they change
+    failed: HashMap<PathBuf, FileStamp>,
+    /// `None` until the first scan
+    groups: Option<Arc<Vec<Group>>>,
+    /// ms of the last complete scan
+    scanned: Option<u64>,
+    error: Option<String>,
+    /// files acted on by the auto-resolve rules since the start
+    resolved: usize,
+    /// rescanned every interval, its changes aren't notified
+    polled: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchStatus {
+    #[schema(value_type = String)]
+    path: PathBuf,
+    /// hashed images
+    files: usize,
+    groups: usize,
+    /// ms of the last complete scan, `None` before the first one
+    scanned: Option<u64>,
+    /// files acted on by the auto-resolve rules since the start
+    resolved: usize,
+    /// rescanned every `watch-interval` rather than when notified of a change, e.g. on a network share
+    polled: bool,
+    /// of the last scan, the groups of the one before are kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// applies the auto-resolve rules to the groups of a scan, returns the number of files acted on
+pub type Resolve = Box<dyn Fn(&[AutoRule], &[Group]) -> usize + Send>;
+
+#[derive(Debug, Default)]
+pub struct Watcher {
+    folders: Vec<(PathBuf, RwLock<FolderState>)>,
+    options: WatchOptions,
+    rules: Vec<AutoRule>,
+}
+
+impl Watcher {
+    pub fn new(folders: Vec<PathBuf>, options: WatchOptions) -> Self {
+        let folders = folders.into_iter().map(|folder| (folder, RwLock::default())).collect();
+        Self { folders, options, rules: Vec::new() }
+    }
+
+    pub fn with_rules(self, rules: Vec<AutoRule>) -> Self {
+        Self { rules, ..self }
+    }
+
+    /// scans on a thread of its own until the watcher is dropped
+    pub fn spawn(self: &Arc<Self>, engine: Arc<Analyzer>, resolve: Resolve) {
+        if self.folders.is_empty() {
+            return;
+        }
+        let watcher = Arc::downgrade(self);
+        thread::spawn(move || watch(watcher, engine, resolve));
+    }
+
+    /// Hashes the new and changed files of a folder, and groups them again if there were any.
+    /// Returns the groups when they were made again.
+    fn rescan(&self, engine: &Analyzer, folder: &Path, state: &RwLock<FolderState>) -> Result<Option<Arc<Vec<Group>>>> {
+        let files = engine.scan(folder)?.files;
+        let (mut hashes, mut failed, changed, removed) = {
+            let state = state.read().unwrap();
+            let (mut kept, mut failed, mut changed) = (HashMap::new(), HashMap::new(), Vec::new());
+            for file in files {
+                match (state.hashes.get(&file.path), state.failed.get(&file.path)) {
+                    (Some(known), _) if known.0.stamp() == file.stamp() => {
+                        kept.insert(file.path, known.clone());
+                    }
+                    (_, Some(&stamp)) if stamp == file.stamp() => {
+                        failed.insert(file.path, stamp);
+                    }
+                    _ => changed.push(file),
+                }
+            }
+            let removed = state.hashes.len() - kept.len();
+            (kept, failed, changed, removed)
+        };
+
+        let outcomes: Vec<_> = changed
+            .into_par_iter()
+            .map(|file| {
+                let hash = engine.hash_file(self.options.hash_type, self.options.hash_size, &file.path);
+                (file, hash)
+            })
+            .collect();
+        let mut rehashed = 0;
+        for (file, hash) in outcomes {
+            match hash {
+                Ok(hash) => {
+                    rehashed += 1;
+                    hashes.insert(file.path.clone(), (file, hash));
+                }
+                Err(err) => {
+                    // reported properly by analyses
+                    tracing::debug!(path = file.path.to_str(), "unable to hash: {:?}", err);
+                    failed.insert(file.path.clone(), file.stamp());
+                }
+            }
+        }
+        let dirty = removed > 0 || rehashed > 0;
+        if dirty {
+            tracing::info!(path = folder.to_str(), rehashed, removed, "watched folder changed");
+        }
+
+        let (groups, regrouped, resolved, polled) = {
+            let state = state.read().unwrap();
+            let (groups, regrouped) = match &state.groups {
+                Some(groups) if !dirty => (groups.clone(), false),
+                _ => {
+                    let mut sorted: Vec<_> = hashes.values().cloned().collect();
+                    sorted.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+                    (Arc::new(engine.group(&sorted, self.options.dist)), true)
+                }
+            };
+            (groups, regrouped, state.resolved, state.polled)
+        };
+
+        let scanned = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64;
+        let new_state = FolderState { hashes, failed, groups: Some(groups.clone()), scanned: Some(scanned), error: None, resolved, polled };
+        *state.write().unwrap() = new_state;
+        Ok(regrouped.then_some(groups))
+    }
+
+    pub fn status(&self) -> Vec<WatchStatus> {
+        self.folders
+            .iter()
+            .map(|(path, state)| {
+                let state = state.read().unwrap();
+                WatchStatus {
+                    path: path.clone(),
+                    files: state.hashes.len(),
+                    groups: state.groups.as_ref().map_or(0, |groups| groups.len()),
+                    scanned: state.scanned,
+                    resolved: state.resolved,
+                    polled: state.polled,
+                    error: state.error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// `None` unless the folder is watched, `Some(None)` before its first scan
+    pub fn groups(&self, folder: &Path) -> Option<Option<Arc<Vec<Group>>>> {
+        let (_, state) = self.folders.iter().find(|(path, _)| path == folder)?;
+        Some(state.read().unwrap().groups.clone())
+    }
+}
+
+/// Watches the folders it can for changes, scans them all once, then those notified of a change
+/// and those polled every interval, until the watcher is dropped.
+fn watch(watcher: Weak<Watcher>, engine: Arc<Analyzer>, resolve: Resolve) {
+    let Some(first) = watcher.upgrade() else {
+        return;
+    };
+    let (tx, changes) = mpsc::channel();
+    let mut notifier = notify::recommended_watcher(tx)
+        .map_err(|err| tracing::warn!("unable to watch for changes, the watched folders are polled: {}", err))
+        .ok();
+    // each folder as configured and resolved, the system may tell changes by either, and whether it is notified
+    let mut folders = Vec::new();
+    for (folder, state) in &first.folders {
+        let notified = !volumes::is_network(folder)
+            && notifier.as_mut().is_some_and(|notifier| match notifier.watch(folder, RecursiveMode::Recursive) {
+                Ok(()) => true,
+                Err(err) => {
+                    tracing::warn!(path = folder.to_str(), "unable to watch the folder for changes, it is polled: {}", err);
+                    false
+                }
+            });
+        state.write().unwrap().polled = !notified;
+        folders.push(([folder.clone(), volumes::canonicalize(folder).unwrap_or_else(|_| folder.clone())], notified));
+    }
+    let interval = first.options.interval;
+    drop(first);
+
+    let mut due = vec![true; folders.len()];
+    let mut polled = Instant::now();
+    while let Some(watcher) = watcher.upgrade() {
+        for (((folder, state), _), due) in watcher.folders.iter().zip(&folders).zip(&mut due) {
+            if !std::mem::take(due) {
+                continue;
+            }
+            match watcher.rescan(&engine, folder, state) {
+                // the files acted on leave the groups with the next scan
+                Ok(Some(groups)) if !watcher.rules.is_empty() => {
+                    let resolved = resolve(&watcher.rules, &groups);
+                    state.write().unwrap().resolved += resolved;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::warn!(path = folder.to_str(), "unable to scan the watched folder: {:?}", err);
+                    state.write().unwrap().error = Some(format!("{:#}", err));
+                }
+            }
+        }
+        drop(watcher);
+
+        if let Ok(change) = changes.recv_timeout(interval.saturating_sub(polled.elapsed())) {
+            let settled = Instant::now() + SETTLE;
+            let mut change = Some(change);
+            while let Some(event) = change {
+                mark(&mut due, &folders, event);
+                change = changes.recv_timeout(settled.saturating_duration_since(Instant::now())).ok();
+            }
+        }
+        if polled.elapsed() >= interval {
+            polled = Instant::now();
+            for (due, (_, notified)) in due.iter_mut().zip(&folders) {
+                *due |= !notified;
+            }
+        }
+    }
+}
+
+/// marks the notified folders a change is below, all of them when changes were missed
+fn mark(due: &mut [bool], folders: &[([PathBuf; 2], bool)], event: notify::Result<Event>) {
+    let event = match event {
+        // reads, scans included, change nothing, files written are closed
+        Ok(event) if matches!(event.kind, EventKind::Access(kind) if kind != AccessKind::Close(AccessMode::Write)) => return,
+        Ok(event) if !event.need_rescan() => event,
+        Ok(_) => {
+            tracing::warn!("changes of the watched folders were missed, scanning them all");
+            due.fill(true);
+            return;
+        }
+        Err(err) => {
+            tracing::warn!("unable to watch for changes, scanning the watched folders: {}", err);
+            due.fill(true);
+            return;
+        }
+    };
+    for (due, (spellings, notified)) in due.iter_mut().zip(folders) {
+        *due |= *notified && event.paths.iter().any(|path| spellings.iter().any(|folder| path.starts_with(folder)));
+    }
+}
@@ -0,0 +1,201 @@
+//! Reads images from a WebDAV share, e.g. of a NAS, for `webdav://folder` paths below the configured endpoint.
+//! Folders are listed one level at a time, servers often refuse `Depth: infinity`.
+
+use eyre::{Result, WrapErr};
+use image::ImageFormat;
+use reqwest::{Method, StatusCode};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::runtime::Handle;
+use url::Url;
+
+use crate::analyzer::{FileError, FileInfo, Listing, SkipReason, SkippedFile};
+use crate::storage::Storage;
+use crate::xml::{element, elements, unescape};
+
+pub const SCHEME: &str = "webdav";
+const TIMEOUT: Duration = Duration::from_secs(60);
+const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:"><prop><resourcetype/><getcontentlength/><getlastmodified/><getetag/></prop></propfind>"#;
+
+#[derive(Debug, Clone)]
+pub struct WebDavConfig {
+    /// the share, `webdav://` paths are below it
+    pub endpoint: Url,
+    /// basic auth, anonymous without it
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+/// a file or folder right in the listed folder
+#[derive(Debug)]
+struct Entry {
+    /// below the endpoint, without slashes around
+    relative: String,
+    dir: bool,
+    size: u64,
+    /// in ms
+    modified: u64,
+    etag: Option<String>,
+}
+
+/// Blocking calls for the analysis threads, on the runtime the client was created on.
+pub struct Client {
+    http: reqwest::Client,
+    config: WebDavConfig,
+    runtime: Handle,
+}
+
+impl Client {
+    /// needs to be called on the runtime
+    pub fn new(config: WebDavConfig) -> Result<Self> {
+        let http = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        let runtime = Handle::try_current().wrap_err("the WebDAV client needs a runtime")?;
+        Ok(Self { http, config, runtime })
+    }
+
+    fn url(&self, relative: &str, dir: bool) -> Result<Url> {
+        let mut url = self.config.endpoint.clone();
+        {
+            let mut segments = url.path_segments_mut().map_err(|_| eyre::eyre!("the WebDAV endpoint can't have paths"))?;
+            segments.pop_if_empty().extend(relative.split('/').filter(|segment| !segment.is_empty()));
+            // collections are redirected to their URL with a slash otherwise
+            if dir {
+                segments.push("");
+            }
+        }
+        Ok(url)
+    }
+
+    async fn send(&self, method: Method, url: Url, depth: Option<&str>) -> Result<Vec<u8>> {
+        let mut request = self.http.request(method, url.clone());
+        if let Some(user) = &self.config.user {
+            request = request.basic_auth(user, self.config.password.as_deref());
+        }
+        if let Some(depth) = depth {
+            request = request.header("depth", depth).header("content-type", "application/xml").body(PROPFIND);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            let message = format!("WebDAV answered {} for {}", status, url);
+            if status == StatusCode::NOT_FOUND {
+                return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+            }
+            eyre::bail!(message);
+        }
+        Ok(body.to_vec())
+    }
+
+    /// the files and folders right in a folder
+    fn list(&self, relative: &str) -> Result<Vec<Entry>> {
+        let url = self.url(relative, true)?;
+        let method = Method::from_bytes(b"PROPFIND")?;
+        let body = self.runtime.block_on(self.send(method, url, Some("1")))?;
+        let body = String::from_utf8(body)?;
+        let base = decode(self.config.endpoint.path());
+        let base = base.trim_matches('/');
+
+        let mut entries = Vec::new();
+        for response in elements(&body, "response") {
+            let href = element(response, "href").map(unescape).ok_or_else(|| eyre::eyre!("listed entry without href"))?;
+            // either a path or a whole URL
+            let href = Url::parse(&href).map_or(href.clone(), |url| url.path().to_owned());
+            let href = decode(&href);
+            let path = href.trim_matches('/');
+            let Some(path) = path.strip_prefix(base).filter(|path| base.is_empty() || path.is_empty() || path.starts_with('/')) else {
+                continue;
+            };
+            let path = path.trim_matches('/');
+            // the folder itself
+            if path == relative.trim_matches('/') {
+                continue;
+            }
+
+            let prop = |name| element(response, name).filter(|value| !value.is_empty());
+            let modified = prop("getlastmodified")
+                .and_then(|date| httpdate::parse_http_date(date).ok())
+                .and_then(|date| date.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or(0, |date| date.as_millis() as u64);
+            entries.push(Entry {
+                relative: path.to_owned(),
+                dir: element(response, "collection").is_some(),
+                size: prop("getcontentlength").and_then(|size| size.parse().ok()).unwrap_or(0),
+                modified,
+                etag: prop("getetag").map(|etag| unescape(etag).trim_start_matches("W/").trim_matches('"').to_owned()),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn scan_rec(&self, listing: &mut Listing, relative: &str) -> Result<()> {
+        for entry in self.list(relative)? {
+            let path = PathBuf::from(format!("{}://{}", SCHEME, entry.relative));
+            let info = FileInfo { path: path.clone(), size: entry.size, date: entry.modified, modified: entry.modified, storage_class: None, hash: None, checksum: None };
+            if entry.relative.rsplit('/').next().is_some_and(|name| name.starts_with('.')) {
+                listing.skipped.push(SkippedFile::new(path, SkipReason::Hidden));
+            } else if entry.dir {
+                listing.dirs.push(FileInfo { size: 0, ..info });
+                if let Err(err) = self.scan_rec(listing, &entry.relative) {
+                    tracing::error!("error reading folder content {:?}", path);
+                    listing.errors.push(FileError::new(path, err));
+                }
+            } else if let Ok(format) = ImageFormat::from_path(&path) {
+                *listing.formats.entry(format!("{:?}", format).to_lowercase()).or_default() += 1;
+                // ETags of WebDAV servers only tell versions of the same file apart
+                if let Some(etag) = entry.etag {
+                    listing.tags.insert(path.clone(), format!("etag:{}:{}", path.display(), etag));
+                }
+                listing.files.push(info);
+            } else {
+                listing.skipped.push(SkippedFile::new(path, SkipReason::Unsupported));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the part of a `webdav://` path below the endpoint
+fn relative(path: &Path) -> Result<&str> {
+    let relative = path.to_str().and_then(|path| path.strip_prefix(SCHEME)?.strip_prefix("://"));
+    relative.ok_or_else(|| eyre::eyre!("not a WebDAV path: {}", path.display()))
+}
+
+/// percent-decodes a path
+pub(crate) fn decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+impl Storage for Client {
+    fn scan(&self, dir: &Path) -> Result<Listing> {
+        let mut listing = Listing::default();
+        self.scan_rec(&mut listing, relative(dir)?)?;
+        listing.sort();
+        Ok(listing)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let url = self.url(relative(path)?, false)?;
+        self.runtime.block_on(self.send(Method::GET, url, None))
+    }
+}
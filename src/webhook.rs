@@ -0,0 +1,128 @@
+//! Notifications POSTed to a URL when an analysis finishes, so long scans needn't be polled for.
+//! Failed deliveries are retried a few times, a receiver that stays down misses the notification.
+
+use eyre::Result;
+use reqwest::Client;
+use serde::Serialize;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::oneshot;
+use url::Url;
+use uuid::Uuid;
+
+use crate::analyzer::AnalyzeResult;
+use crate::error::ErrorBody;
+use crate::manager::{Cancelled, TimedOut};
+use crate::report::Reclaimable;
+
+const ATTEMPTS: u32 = 3;
+/// wait before the first retry, doubled on each retry
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Completed,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    groups: usize,
+    /// in the groups
+    files: usize,
+    reclaimable: Reclaimable,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    task_id: Uuid,
+    path: PathBuf,
+    status: Status,
+    /// of a completed task, the groups are for `/poll`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<Summary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+impl Notification {
+    pub fn new(task_id: Uuid, path: PathBuf, result: &Result<AnalyzeResult>) -> Self {
+        let (status, summary, error) = match result {
+            Ok(result) => {
+                let summary = Summary {
+                    groups: result.groups().len(),
+                    files: result.groups().iter().map(|group| group.files().len()).sum(),
+                    reclaimable: Reclaimable::of(result),
+                };
+                (Status::Completed, Some(summary), None)
+            }
+            Err(err) if err.is::<Cancelled>() => (Status::Cancelled, None, None),
+            Err(err) if err.is::<TimedOut>() => (Status::TimedOut, None, None),
+            Err(err) => (Status::Failed, None, Some(ErrorBody::of_report(err))),
+        };
+        Self { task_id, path, status, summary, error }
+    }
+}
+
+/// the client of all deliveries, the URL of tasks without one of their own
+/// and the hosts tasks may name one on
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    client: Client,
+    fallback: Option<Url>,
+    hosts: Vec<String>,
+}
+
+impl Webhooks {
+    pub fn new(fallback: Option<Url>, hosts: Vec<String>) -> Self {
+        Self { client: Client::new(), fallback, hosts }
+    }
+
+    pub fn fallback(&self) -> Option<&Url> {
+        self.fallback.as_ref()
+    }
+
+    /// whether tasks may be given `url` as their callback: the configured webhook,
+    /// or an http(s) URL on one of the allowed hosts, so requests can't be sent into the server's network
+    pub fn allows(&self, url: &Url) -> bool {
+        self.fallback.as_ref() == Some(url)
+            || matches!(url.scheme(), "http" | "https")
+                && url.host_str().is_some_and(|host| self.hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)))
+    }
+
+    /// delivers in the background once the task is done
+    pub fn notify(&self, url: Url, task_id: Uuid, path: PathBuf, done: oneshot::Receiver<Arc<Result<AnalyzeResult>>>) {
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            // dropped from the manager in the meantime
+            let Ok(result) = done.await else {
+                return;
+            };
+            deliver(&client, &url, &Notification::new(task_id, path, &result)).await;
+        });
+    }
+}
+
+async fn deliver(client: &Client, url: &Url, notification: &Notification) {
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=ATTEMPTS {
+        let sent = client.post(url.clone()).timeout(TIMEOUT).json(notification).send().await;
+        match sent.and_then(|response| response.error_for_status()) {
+            Ok(_) => {
+                tracing::info!(task_id = %notification.task_id, "webhook delivered to {}", url);
+                return;
+            }
+            Err(err) if attempt < ATTEMPTS => {
+                tracing::warn!(task_id = %notification.task_id, "webhook to {} failed, retrying: {}", url, err);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => tracing::error!(task_id = %notification.task_id, "unable to deliver webhook to {}: {}", url, err),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Other machines hashing for the server, the coordinator: workers started with `image-analyzer worker`
+//! claim batches of files an analysis didn't find cached hashes for, and send the hashes back.
+//! The coordinator does the grouping, and the hashing only when no worker is left. Workers need the same
+//! paths, e.g. the same mount of the library, or `--from` and `--to` otherwise.
+
+use eyre::{Result, WrapErr};
+use image_hasher::ImageHash;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::analyzer::{self, HashSize, HashType};
+use crate::hasher;
+use crate::manager::CancelToken;
+use crate::tasks::to_millis;
+
+/// carries the `worker-token` of the server
+pub const TOKEN_HEADER: &str = "x-worker-token";
+/// files handed out at once
+pub const BATCH_FILES: usize = 32;
+/// claimed batches not answered by then are handed out again
+const LEASE: Duration = Duration::from_secs(300);
+/// workers which claimed batches more recently than that are counted on
+const ACTIVE: Duration = Duration::from_secs(30);
+/// between checks for answers and cancellation
+const WAIT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Batch {
+    pub id: Uuid,
+    pub hash_type: HashType,
+    pub hash_size: HashSize,
+    #[schema(value_type = Vec<String>)]
+    pub files: Vec<PathBuf>,
+}
+
+impl Batch {
+    pub fn new(hash_type: HashType, hash_size: HashSize, files: Vec<PathBuf>) -> Self {
+        Self { id: Uuid::new_v4(), hash_type, hash_size, files }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HashedFile {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    /// base64, `None` when the worker couldn't hash it, the coordinator tries again
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HashedFile {
+    /// `None` unless it's a hash of that size
+    pub fn hash(&self, hash_size: HashSize) -> Option<ImageHash> {
+        let hash = ImageHash::from_base64(self.hash.as_deref()?).ok()?;
+        (hash.as_bytes().len() * 8 == (hash_size.get() * hash_size.get()) as usize).then_some(hash)
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerStatus {
+    name: String,
+    /// ms since the epoch of the last claim or answer
+    last_seen: u64,
+    batches: usize,
+    files: usize,
+}
+
+#[derive(Debug)]
+struct Seen {
+    at: Instant,
+    time: SystemTime,
+    batches: usize,
+    files: usize,
+}
+
+/// a batch and the analysis it is for
+#[derive(Debug)]
+struct Pending {
+    job: Uuid,
+    batch: Batch,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    workers: HashMap<String, Seen>,
+    queue: VecDeque<Pending>,
+    /// by batch id, with the deadline of the lease
+    claimed: HashMap<Uuid, (Pending, Instant)>,
+    answers: HashMap<Uuid, (Pending, Vec<HashedFile>)>,
+}
+
+impl State {
+    fn active(&self) -> bool {
+        self.workers.values().any(|seen| seen.at.elapsed() < ACTIVE)
+    }
+
+    fn seen(&mut self, worker: &str) -> &mut Seen {
+        let seen = self.workers.entry(worker.to_owned()).or_insert(Seen { at: Instant::now(), time: SystemTime::now(), batches: 0, files: 0 });
+        seen.at = Instant::now();
+        seen.time = SystemTime::now();
+        seen
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Uuid> = self.claimed.iter().filter(|(_, (_, deadline))| *deadline < now).map(|(id, _)| *id).collect();
+        for id in expired {
+            if let Some((pending, _)) = self.claimed.remove(&id) {
+                tracing::warn!(batch = %id, "batch not answered in time, handing it out again");
+                self.queue.push_front(pending);
+            }
+        }
+    }
+
+    fn withdraw(&mut self, job: Uuid) {
+        self.queue.retain(|pending| pending.job != job);
+        self.claimed.retain(|_, (pending, _)| pending.job != job);
+        self.answers.retain(|_, (pending, _)| pending.job != job);
+    }
+}
+
+/// the batches of running analyses and the workers claiming them
+#[derive(Debug, Default)]
+pub struct Workers {
+    state: Mutex<State>,
+    answered: Condvar,
+}
+
+impl Workers {
+    /// whether any worker claimed batches lately
+    pub fn active(&self) -> bool {
+        self.state.lock().unwrap().active()
+    }
+
+    pub fn status(&self) -> Vec<WorkerStatus> {
+        let state = self.state.lock().unwrap();
+        let mut workers: Vec<_> = state
+            .workers
+            .iter()
+            .map(|(name, seen)| WorkerStatus { name: name.clone(), last_seen: to_millis(seen.time), batches: seen.batches, files: seen.files })
+            .collect();
+        workers.sort_by(|a, b| a.name.cmp(&b.name));
+        workers
+    }
+
+    /// the next batch to hash, `None` when there is nothing to do
+    pub fn claim(&self, worker: &str) -> Option<Batch> {
+        let mut state = self.state.lock().unwrap();
+        state.seen(worker);
+        state.expire();
+        let pending = state.queue.pop_front()?;
+        let batch = pending.batch.clone();
+        state.claimed.insert(batch.id, (pending, Instant::now() + LEASE));
+        Some(batch)
+    }
+
+    /// `false` if the batch is unknown, e.g. its analysis is over
+    pub fn answer(&self, worker: &str, id: Uuid, files: Vec<HashedFile>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        // answers after the lease are still taken while nobody else has the batch
+        let pending = match state.claimed.remove(&id) {
+            Some((pending, _)) => Some(pending),
+            None => state.queue.iter().position(|pending| pending.batch.id == id).and_then(|i| state.queue.remove(i)),
+        };
+        let Some(pending) = pending else {
+            return false;
+        };
+        let seen = state.seen(worker);
+        seen.batches += 1;
+        seen.files += files.len();
+        state.answers.insert(id, (pending, files));
+        self.answered.notify_all();
+        true
+    }
+
+    /// Hands the batches out and returns once each is done: `done` gets the answer of a worker,
+    /// or `None` for batches left when no worker is active anymore, to be hashed by the caller.
+    pub fn share(&self, batches: Vec<Batch>, cancel: &CancelToken, mut done: impl FnMut(Batch, Option<Vec<HashedFile>>)) {
+        let job = Uuid::new_v4();
+        let mut open: HashSet<Uuid> = batches.iter().map(|batch| batch.id).collect();
+        self.state.lock().unwrap().queue.extend(batches.into_iter().map(|batch| Pending { job, batch }));
+
+        while !open.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            state.expire();
+            if cancel.is_cancelled() {
+                state.withdraw(job);
+                return;
+            }
+
+            let answered = open.iter().find_map(|id| state.answers.remove(id));
+            if let Some((pending, files)) = answered {
+                open.remove(&pending.batch.id);
+                drop(state);
+                done(pending.batch, Some(files));
+                continue;
+            }
+            let unclaimed = match state.active() {
+                true => None,
+                false => state.queue.iter().position(|pending| pending.job == job).and_then(|i| state.queue.remove(i)),
+            };
+            if let Some(pending) = unclaimed {
+                open.remove(&pending.batch.id);
+                drop(state);
+                done(pending.batch, None);
+                continue;
+            }
+            drop(self.answered.wait_timeout(state, WAIT).unwrap());
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerOptions {
+    pub coordinator: Url,
+    pub name: String,
+    /// sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// sent as `X-Worker-Token`
+    pub worker_token: String,
+    /// paths of the coordinator under `from` are read under `to`
+    pub remap: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Claim {
+    /// how the worker is listed, claims of the same name are one worker
+    pub worker: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Answer {
+    pub worker: String,
+    pub files: Vec<HashedFile>,
+    /// the `HASH_VERSION` of the worker, hashes of another version than the coordinator's are left out
+    #[serde(default = "first_hash_version")]
+    pub hash_version: u32,
+}
+
+/// of workers answering before they told their version
+fn first_hash_version() -> u32 {
+    1
+}
+
+fn hash_batch(batch: &Batch, remap: Option<&(PathBuf, PathBuf)>) -> Vec<HashedFile> {
+    let hasher = hasher::make(batch.hash_type, batch.hash_size);
+    batch
+        .files
+        .par_iter()
+        .map(|path| {
+            let local = match remap.and_then(|(from, to)| Some(to.join(path.strip_prefix(from).ok()?))) {
+                Some(local) => local,
+                None => path.clone(),
+            };
+            // truncated images are left to the coordinator, it reports them
+            let hashed = analyzer::open_image(&local, analyzer::DECODE_SIZE)
+                .map_err(|err| err.to_string())
+                .and_then(|(image, format)| match analyzer::is_truncated(&local, format) {
+                    Ok(false) => Ok(hasher.hash(&image).to_base64()),
+                    Ok(true) => Err("truncated image data".to_owned()),
+                    Err(err) => Err(err.to_string()),
+                });
+            match hashed {
+                Ok(hash) => HashedFile { path: path.clone(), hash: Some(hash), error: None },
+                Err(error) => HashedFile { path: path.clone(), hash: None, error: Some(error) },
+            }
+        })
+        .collect()
+}
+
+/// claims batches of the coordinator until stopped
+pub async fn work(options: WorkerOptions) -> Result<()> {
+    let http = reqwest::Client::builder().timeout(Duration::from_secs(60)).build()?;
+    let url = |path: &str| options.coordinator.join(path).wrap_err("invalid coordinator URL");
+    let authorized = |request: reqwest::RequestBuilder| {
+        let request = request.header(TOKEN_HEADER, &options.worker_token);
+        match &options.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    };
+    tracing::info!(coordinator = %options.coordinator, worker = options.name, "worker started");
+
+    loop {
+        let claimed = authorized(http.post(url("workers/claim")?)).json(&Claim { worker: options.name.clone() }).send().await;
+        let batch: Batch = match claimed {
+            Ok(response) if response.status() == reqwest::StatusCode::NO_CONTENT => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            Ok(response) if response.status().is_success() => response.json().await?,
+            Ok(response) => {
+                tracing::warn!("coordinator answered {} to a claim", response.status());
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+            Err(err) => {
+                tracing::warn!("unable to reach the coordinator: {}", err);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        tracing::info!(batch = %batch.id, files = batch.files.len(), "hashing batch");
+        let remap = options.remap.clone();
+        let id = batch.id;
+        let files = tokio::task::spawn_blocking(move || hash_batch(&batch, remap.as_ref())).await?;
+        let answer = Answer { worker: options.name.clone(), files, hash_version: analyzer::HASH_VERSION };
+        match authorized(http.post(url(&format!("workers/batches/{}", id))?)).json(&answer).send().await {
+            Ok(response) if response.status().is_success() => {}
+            // e.g. the analysis was cancelled meanwhile
+            Ok(response) => tracing::warn!(batch = %id, "coordinator answered {} to the hashes", response.status()),
+            Err(err) => tracing::warn!(batch = %id, "unable to send the hashes: {}", err),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Progress of a task over WebSocket, for proxies and clients that handle it better than SSE.
+//! Besides progress updates the client may ask to poll or cancel the task.
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::Response,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio_stream::wrappers::WatchStream;
+use uuid::Uuid;
+
+use crate::analyzer::Progress;
+use crate::session::Session;
+use crate::server::{request_cancel, request_poll, request_progress, request_task, AnalyzeResponse, AppError, AppResult, AppState, TaskParams};
+
+/// sent by the client
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum WsRequest {
+    Poll,
+    Cancel,
+}
+
+/// sent by the server
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum WsEvent<'a> {
+    Progress { progress: Progress },
+    Poll { response: AnalyzeResponse<'a> },
+    /// `false` if the task is already finished
+    Cancel { cancelled: bool },
+    Error { error: String },
+}
+
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "tasks",
+    params(TaskParams),
+    responses(
+        (status = 101, description = "progress events, polls and cancellations over WebSocket"),
+        (status = 404, description = "unknown task"),
+    ),
+)]
+pub async fn ws(
+    State(state): State<Arc<AppState>>,
+    client: Session,
+    Query(params): Query<TaskParams>,
+    upgrade: WebSocketUpgrade,
+) -> AppResult<Response> {
+    // unknown tasks fail before the upgrade
+    request_task(&state, &client, params.task_id).await?;
+    let progress = request_progress(&state, params.task_id).await?;
+    Ok(upgrade.on_upgrade(move |socket| session(socket, state, params.task_id, progress)))
+}
+
+fn describe(err: AppError) -> String {
+    err.body().message
+}
+
+async fn send(socket: &mut WebSocket, event: &WsEvent<'_>) -> bool {
+    match serde_json::to_string(event) {
+        Ok(text) => socket.send(Message::Text(text)).await.is_ok(),
+        Err(err) => {
+            tracing::error!("unable to serialize WebSocket event: {:?}", err);
+            false
+        }
+    }
+}
+
+async fn reply(socket: &mut WebSocket, state: &AppState, task_id: Uuid, request: WsRequest) -> bool {
+    match request {
+        WsRequest::Poll => match request_poll(state, task_id).await {
+            Ok(resp) => send(socket, &WsEvent::Poll { response: AnalyzeResponse::new(&resp) }).await,
+            Err(err) => send(socket, &WsEvent::Error { error: describe(err) }).await,
+        },
+        WsRequest::Cancel => {
+            tracing::info!("cancelling analyze task {} over WebSocket", task_id);
+            let event = match request_cancel(state, task_id).await {
+                Ok(cancelled) => WsEvent::Cancel { cancelled },
+                Err(err) => WsEvent::Error { error: describe(err) },
+            };
+            send(socket, &event).await
+        }
+    }
+}
+
+async fn session(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    task_id: Uuid,
+    progress: tokio::sync::watch::Receiver<Progress>,
+) {
+    tracing::info!("WebSocket session for {} started", task_id);
+    // ends when the task is done, the session stays open for polls
+    let mut progress = WatchStream::new(progress);
+
+    loop {
+        tokio::select! {
+            Some(progress) = progress.next() => {
+                if !send(&mut socket, &WsEvent::Progress { progress }).await {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // pings are answered by axum
+                    Some(Ok(_)) => continue,
+                };
+                let open = match serde_json::from_str(&text) {
+                    Ok(request) => reply(&mut socket, &state, task_id, request).await,
+                    Err(err) => send(&mut socket, &WsEvent::Error { error: err.to_string() }).await,
+                };
+                if !open {
+                    break;
+                }
+            }
+        }
+    }
+
+    tracing::info!("WebSocket session for {} closed", task_id);
+}
@@ -0,0 +1,43 @@
+//! Just enough XML for the answers of S3 and WebDAV servers: the text of elements by name.
+//! Namespace prefixes are ignored, and elements of the same name are assumed not to nest.
+
+/// text of the first `<name>` element
+pub fn element<'a>(xml: &'a str, name: &'a str) -> Option<&'a str> {
+    elements(xml, name).next()
+}
+
+/// text of every `<name>` element, empty for `<name/>`
+pub fn elements<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find('<')? + 1;
+        let len = rest[start..].find('>')?;
+        let head = &rest[start..start + len];
+        rest = &rest[start + len + 1..];
+        // closing tags, declarations and comments
+        if head.starts_with(['/', '?', '!']) {
+            continue;
+        }
+
+        let qualified = head.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+        if qualified.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        if head.ends_with('/') {
+            return Some("");
+        }
+        let close = format!("</{}>", qualified);
+        let len = rest.find(&close)?;
+        let text = &rest[..len];
+        rest = &rest[len + close.len()..];
+        return Some(text);
+    })
+}
+
+pub fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}